@@ -0,0 +1,54 @@
+# Compares mapping raw JSON with `Logflare.Mapper.map_json/3`, which evaluates
+# the mapping's paths over the parsed JSON and builds only the values they
+# resolve to, against decoding the whole object with Jason and mapping it with
+# `map/3`, on the OTel mapping defaults.
+#
+#   MIX_ENV=test mix run --no-start bench/mapper_json_input.exs
+#   EVENT_TYPES=log BATCH_SIZES=1,100 BENCH_TIME=5 \
+#     MIX_ENV=test mix run --no-start bench/mapper_json_input.exs
+
+Code.require_file("support/clickhouse_pipeline_bench_data.exs", __DIR__)
+
+alias Logflare.Bench.ClickHousePipelineData, as: Data
+alias Logflare.Mapper
+
+list_env = fn name, default ->
+  name
+  |> System.get_env(default)
+  |> String.split(",", trim: true)
+  |> Enum.map(&String.trim/1)
+end
+
+event_types = list_env.("EVENT_TYPES", "log,metric,trace") |> Enum.map(&String.to_atom/1)
+batch_sizes = list_env.("BATCH_SIZES", "1,100,1000") |> Enum.map(&String.to_integer/1)
+bench_time = "BENCH_TIME" |> System.get_env("3") |> String.to_integer()
+
+for type <- event_types do
+  {compiled, _config_id} = Data.compiled(type)
+
+  inputs =
+    for size <- batch_sizes, into: %{} do
+      jsons = type |> Data.batch(size) |> Enum.map(&Jason.encode!(&1.body))
+      {"#{size} events", jsons}
+    end
+
+  IO.puts("\n== #{type} ==")
+
+  Benchee.run(
+    %{
+      "map_json" => fn jsons -> Enum.each(jsons, &Mapper.map_json(&1, compiled)) end,
+      "Jason.decode! + map" => fn jsons ->
+        Enum.each(jsons, &(&1 |> Jason.decode!() |> Mapper.map(compiled)))
+      end,
+      "map_many_json" => fn jsons -> Mapper.map_many_json(jsons, compiled) end,
+      "Jason.decode! + map_many" => fn jsons ->
+        jsons |> Enum.map(&Jason.decode!/1) |> Mapper.map_many(compiled)
+      end
+    },
+    inputs: inputs,
+    time: bench_time,
+    warmup: 1,
+    memory_time: 1,
+    print: [configuration: false]
+  )
+end
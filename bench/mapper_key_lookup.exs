@@ -0,0 +1,67 @@
+# Measures the per-event cost of `Logflare.Mapper` path evaluation on the OTel
+# mapping defaults, against a saved baseline run. Keys read by many paths below
+# one map are matched by scanning that map once per document, so a single
+# `map/3` call should not pay for building key terms either.
+#
+# Run once on the baseline checkout, then again on the change; the second run
+# loads the first and prints each job relative to it. The baseline predates
+# this script, so run it from a copy outside the tree:
+#
+#   cp bench/mapper_key_lookup.exs /tmp/ && git checkout e530aad~1
+#   TAG=baseline MIX_ENV=test mix run --no-start /tmp/mapper_key_lookup.exs
+#   git checkout -
+#   TAG=current MIX_ENV=test mix run --no-start bench/mapper_key_lookup.exs
+#
+#   EVENT_TYPES=log BATCH_SIZES=1,100,1000 BENCH_TIME=5 TAG=current \
+#     MIX_ENV=test mix run --no-start bench/mapper_key_lookup.exs
+
+# Relative to the project root, so a copy of this script run elsewhere finds it.
+Code.require_file("bench/support/clickhouse_pipeline_bench_data.exs")
+
+alias Logflare.Bench.ClickHousePipelineData, as: Data
+alias Logflare.Mapper
+
+list_env = fn name, default ->
+  name
+  |> System.get_env(default)
+  |> String.split(",", trim: true)
+  |> Enum.map(&String.trim/1)
+end
+
+event_types = list_env.("EVENT_TYPES", "log,metric,trace") |> Enum.map(&String.to_atom/1)
+batch_sizes = list_env.("BATCH_SIZES", "1,100,1000") |> Enum.map(&String.to_integer/1)
+bench_time = "BENCH_TIME" |> System.get_env("3") |> String.to_integer()
+tag = System.get_env("TAG", "untagged")
+baseline_tag = System.get_env("BASELINE_TAG", "baseline")
+save_dir = System.get_env("BENCH_SAVE_DIR", "/tmp")
+File.mkdir_p!(save_dir)
+
+for type <- event_types do
+  {compiled, _config_id} = Data.compiled(type)
+
+  inputs =
+    for size <- batch_sizes, into: %{} do
+      bodies = type |> Data.batch(size) |> Enum.map(& &1.body)
+      {"#{size} events", bodies}
+    end
+
+  baseline = Path.join(save_dir, "mapper_key_lookup_#{type}_#{baseline_tag}.benchee")
+  load = if tag != baseline_tag and File.exists?(baseline), do: [load: baseline], else: []
+
+  IO.puts("\n== #{type} (#{tag}) ==")
+
+  Benchee.run(
+    %{
+      "map (per event)" => fn bodies -> Enum.each(bodies, &Mapper.map(&1, compiled)) end,
+      "map_many (batch)" => fn bodies -> Mapper.map_many(bodies, compiled) end
+    },
+    [
+      inputs: inputs,
+      time: bench_time,
+      warmup: 1,
+      memory_time: 1,
+      save: [path: Path.join(save_dir, "mapper_key_lookup_#{type}_#{tag}.benchee"), tag: tag],
+      print: [configuration: false]
+    ] ++ load
+  )
+end
@@ -15,11 +15,26 @@ defmodule Logflare.Mapper do
   When the input is a pre-flattened map where nested paths exist as literal
   dot-notation keys, pass `flat_keys: true` to `map/3` so that paths are
   resolved as direct key lookups instead of nested map traversal.
+
+  Batches can be mapped with `map_many/3`, which returns one list of maps for
+  map output or a single payload for serialized outputs such as a ClickHouse
+  Native block.
+
+  Raw JSON can be mapped with `map_json/3` and `map_many_json/3` without
+  decoding it to an Elixir map first: the NIF evaluates the mapping's paths
+  over the parsed JSON and builds only the values they resolve to.
+
+  Configs with `:drop_when`, `:sample` or `:route` sections are applied with
+  `map_routed/3` and `map_many_routed/3`, which drop or tag each document
+  before mapping it, so no coercion work is spent on dropped documents.
   """
 
   alias __MODULE__.MappingConfig
   alias __MODULE__.Native
 
+  @typedoc "Serialized output: a binary, or an Arrow IPC schema and record batch messages."
+  @type encoded :: binary() | {binary(), [binary()]}
+
   @doc "Compiles a mapping config into a NIF resource."
   @spec compile(MappingConfig.t()) :: {:ok, reference()} | {:error, String.t()}
   def compile(%MappingConfig{} = config) do
@@ -37,9 +52,209 @@ defmodule Logflare.Mapper do
     end
   end
 
+  @doc """
+  Serializes a compiled mapping into a versioned binary that `import_mapping/2`
+  compiles back, on this node or another one.
+
+  The binary holds the canonical form of the config the mapping was compiled
+  from. A `:hash_key` secret is left out and replaced by a key ID, so the
+  importing node must supply the key itself.
+  """
+  @spec export_mapping(reference()) :: binary()
+  def export_mapping(compiled_mapping), do: Native.export_mapping(compiled_mapping)
+
+  @doc """
+  Compiles a binary produced by `export_mapping/1`.
+
+  Fails for malformed binaries, for export format versions this node does not
+  support, and when `:hash_key` is missing or differs from the key the mapping
+  was compiled with.
+
+  ## Options
+
+    * `:hash_key` - the mapping's `:hash_key`, required when it has one.
+  """
+  @spec import_mapping(binary(), keyword()) :: {:ok, reference()} | {:error, String.t()}
+  def import_mapping(exported, opts \\ []) when is_binary(exported) and is_list(opts),
+    do: Native.import_mapping(exported, Keyword.get(opts, :hash_key))
+
+  @doc """
+  Returns a content fingerprint of a compiled mapping, formatted as a UUID.
+
+  Mappings compiled from equal configs share a fingerprint, so it can serve
+  as a `mapping_config_id` or cache key. Configs that spell the same settings
+  differently, such as a `"String"` type, a one-element `:paths` or a
+  `:default` equal to the type's own, share it too. The `:hash_key` counts by
+  its key ID. It survives `export_mapping/1` and `import_mapping/2`.
+  """
+  @spec fingerprint(reference()) :: String.t()
+  def fingerprint(compiled_mapping), do: Native.mapping_fingerprint(compiled_mapping)
+
+  @doc """
+  Drains the counters of a mapping compiled with `collect_stats: true`.
+
+  Returns the counts accumulated since the previous call, across every process
+  mapping with `compiled_mapping`, and resets them; `nil` when the mapping does
+  not collect stats. The result holds `:documents`, the number of documents
+  mapped, and `:fields`, one map per field in output order with its `:field`
+  name and these counts:
+
+    * `:default_used` - values replaced by the field's default
+    * `:coercion_failed` - values the field's type could not represent, mapped to
+      its zero value or clamped into its range
+    * `:filter_rejected` - resolved strings rejected by the field's `filters`,
+      once per rejected coalesce path
+    * `:value_map_miss` - values missing from the field's `value_map`
+    * `:enum8_unmatched` - Enum8 values matching no `enum_values` entry or
+      `infer` rule
+
+  A drain waits for documents being mapped to finish, so each document and its
+  field counts land in the same drain. Call it periodically, for example from a
+  telemetry poller, and pass the result to `emit_stats/2`.
+  """
+  @spec mapping_stats(reference()) :: map() | nil
+  def mapping_stats(compiled_mapping), do: Native.mapping_stats(compiled_mapping)
+
+  @doc """
+  Drains a mapping's counters with `mapping_stats/1` and emits them as telemetry.
+
+  Emits `[:logflare, :mapper, :stats]` with the `:documents` count, then
+  `[:logflare, :mapper, :field_stats]` per field with its counts as
+  measurements and its name under `:field` in the metadata. `metadata` is added
+  to every event. Emits nothing when the mapping does not collect stats.
+  """
+  @spec emit_stats(reference(), map()) :: :ok
+  def emit_stats(compiled_mapping, metadata \\ %{}) do
+    case mapping_stats(compiled_mapping) do
+      nil ->
+        :ok
+
+      %{documents: documents, fields: fields} ->
+        :telemetry.execute([:logflare, :mapper, :stats], %{documents: documents}, metadata)
+
+        Enum.each(fields, fn %{field: field} = counts ->
+          :telemetry.execute(
+            [:logflare, :mapper, :field_stats],
+            Map.delete(counts, :field),
+            Map.put(metadata, :field, field)
+          )
+        end)
+    end
+  end
+
+  @doc """
+  Compares two mapping configs to preview what changing from `old` to `new` does.
+
+  Fields are matched by name. Returns a map with:
+
+    * `:added` and `:removed` - `%{field: name, type: type}` for fields only in
+      one config
+    * `:retyped` - `%{field: name, old: type, new: type}`; DateTime64 types carry
+      their precision, e.g. `"datetime64(3)"`
+    * `:source_changed` - `%{field: name, old: source, new: source}` where a source
+      holds the field's `"path"`, `"paths"`, `"from_output"`, `"cases"` and
+      `"aggregate"` settings
+    * `:default_changed` - `%{field: name, old: default, new: default}`
+
+  With the `:documents` option, each document is also mapped with both configs
+  (as map output, whatever their output format) and the result gains
+  `:documents`, the sample size, and `:values`: for each field in both configs
+  whose values differ, `%{field: name, changed: count, samples: samples}` where
+  `samples` holds up to three `%{index: index, old: value, new: value}`.
+
+  ## Options
+
+    * `:documents` - sample documents to map with both configs.
+    * `:flat_keys` - map the documents with `flat_keys: true`.
+  """
+  @spec diff_mappings(MappingConfig.t(), MappingConfig.t(), keyword()) ::
+          {:ok, map()} | {:error, String.t()}
+  def diff_mappings(%MappingConfig{} = old, %MappingConfig{} = new, opts \\ []) do
+    Native.diff_mappings(
+      MappingConfig.to_nif_map(old),
+      MappingConfig.to_nif_map(new),
+      Keyword.get(opts, :documents, []),
+      Keyword.get(opts, :flat_keys, false)
+    )
+  end
+
+  @doc """
+  Compiles a config and warns about settings that compile but cannot take effect.
+
+  Returns `{:error, reason}` when the config does not compile, like `compile/1`.
+  Otherwise returns a list of warnings, each a map with the `:field` name, a
+  `:code` and a `:message`. Codes:
+
+    * `:value_map_not_allowed` - a `value_map` key rejected by `allowed_values`
+    * `:unreachable_path` - a coalesce path after `"$"`, which always resolves
+    * `:duplicate_path` - a coalesce path listed twice
+    * `:filters_ignored` - `filters` on a non-string field
+    * `:pick_ignored` - `pick` on a field other than `json` or `flat_map`
+    * `:unknown_enum_result` - an Enum8 `infer` result missing from `enum_values`
+    * `:enum_value_out_of_range` - an `enum_values` value outside -128..127
+    * `:invalid_precision` - a DateTime64 `precision` outside 0..9
+  """
+  @spec lint_mapping(MappingConfig.t()) :: {:ok, [map()]} | {:error, String.t()}
+  def lint_mapping(%MappingConfig{} = config) do
+    config
+    |> MappingConfig.to_nif_map()
+    |> Native.lint_mapping()
+  end
+
+  @doc """
+  Proposes a mapping config for a new source from sample documents.
+
+  Every path found in the samples becomes a field named after it (`$.metadata.level`
+  becomes `"metadata_level"`). Types are inferred from the values seen: RFC3339
+  strings and integer timestamps under time-like keys become `datetime64` with a
+  detected precision, and maps nested deeper than `:max_depth` become `flat_map`
+  or `json`. String fields with few distinct values get an identity `value_map`
+  as a starting point for remapping. Keys that cannot be spelled as a path, such
+  as ones containing dots, are skipped.
+
+  Returns `:config`, a string-keyed map in the shape `MappingConfig.changeset/2`
+  casts, and `:paths`, per-field statistics: `:count` of documents holding the
+  path, `:nulls`, `:coverage` and `:null_rate` ratios, and the number of
+  `:distinct` strings (`nil` above `:value_map_limit`).
+
+  ## Options
+
+    * `:max_depth` - map nesting to descend into (default `4`).
+    * `:value_map_limit` - most distinct strings for a candidate `value_map`
+      (default `10`).
+  """
+  @spec infer_mapping([map()], keyword()) ::
+          {:ok, %{config: map(), paths: [map()]}} | {:error, String.t()}
+  def infer_mapping(documents, opts \\ []) when is_list(documents) do
+    Native.infer_mapping(documents, Map.new(opts))
+  end
+
+  @doc """
+  Describes how a compiled mapping resolves its fields.
+
+  Returns a map with:
+
+    * `:fields` - one map per field, in output order, with its `:name`, resolved
+      `:type` and `:precision`, its `:source` (`:root`, `:path`, `:coalesce`,
+      `:from_output`, `:cases` or `:aggregate`) and `:aggregate` function name, the
+      `:paths` it reads in the order they are tried, and the names of the fields
+      it reads through `:from_output`
+    * `:path_cache_size`, `:root_cache_size` and `:root_cache_keys` - the layout
+      of the per-document path cache
+    * `:key_slot_count`, `:key_mode` and `:output`
+
+  Each path map holds the `:path` string, its `:segments` (key binaries,
+  integer indices and `:wildcard`), and the path cache slot of each segment's
+  prefix in `:cache_indices` (`nil` where the prefix is not cached). Paths that
+  share a prefix share its slot. `:flat_key` and `:flat_cache_index` describe
+  the lookup used when mapping with `flat_keys: true`.
+  """
+  @spec inspect_mapping(reference()) :: map()
+  def inspect_mapping(compiled_mapping), do: Native.inspect_mapping(compiled_mapping)
+
   @doc "Compiles and maps a single document in one step. Not suited for high-throughput pipelines."
   @spec run(map(), MappingConfig.t(), keyword()) ::
-          {:ok, map() | binary()} | {:error, String.t()}
+          {:ok, map() | encoded()} | {:error, String.t()}
   def run(document, %MappingConfig{} = config, opts \\ []) when is_map(document) do
     case compile(config) do
       {:ok, compiled} -> map_result(document, compiled, opts)
@@ -64,9 +279,15 @@ defmodule Logflare.Mapper do
   The compiled mapping configuration selects the output representation. Map
   output returns a map; ClickHouse RowBinary output maps the supplied document
   and returns one encoded row binary without constructing an intermediate
-  Elixir map.
+  Elixir map. Arrow IPC output returns `{schema_message, [record_batch]}`,
+  JSON output returns one encoded JSON object, and OTLP protobuf output returns
+  an export request containing the single record.
+
+  A config's `:sample` is applied first: documents it does not keep return
+  `:drop` before any field is mapped. `:drop_when` and `:route` are ignored;
+  see `map_routed/3`.
   """
-  @spec map(map(), reference(), keyword()) :: map() | binary()
+  @spec map(map(), reference(), keyword()) :: map() | encoded() | :drop
   def map(document, compiled_mapping, opts \\ []) when is_map(document) do
     document
     |> map_result(compiled_mapping, opts)
@@ -75,7 +296,7 @@ defmodule Logflare.Mapper do
 
   @doc "Maps a document and returns a result tuple instead of raising on output errors."
   @spec map_result(map(), reference(), keyword()) ::
-          {:ok, map() | binary()} | {:error, String.t()}
+          {:ok, map() | encoded() | :drop} | {:error, String.t()}
   def map_result(document, compiled_mapping, opts \\ []) when is_map(document) do
     flat_keys = Keyword.get(opts, :flat_keys, false)
     output_context = Keyword.get(opts, :output_context)
@@ -87,6 +308,177 @@ defmodule Logflare.Mapper do
     end
   end
 
+  @doc """
+  Maps a raw JSON object using a compiled mapping.
+
+  Returns what `map/3` returns for the decoded object and accepts the same
+  options. Paths are evaluated over the parsed JSON, which borrows its strings
+  from `json`, and only the values they resolve to are built as terms; the
+  object itself is only built when a field reads the whole document (`"$"`).
+  Object keys are strings, so mappings with `key_mode: :atom` are refused with
+  an error. Raises on invalid JSON.
+  """
+  @spec map_json(binary(), reference(), keyword()) :: map() | encoded()
+  def map_json(json, compiled_mapping, opts \\ []) when is_binary(json) do
+    json
+    |> map_json_result(compiled_mapping, opts)
+    |> unwrap_result()
+  end
+
+  @doc "Like `map_json/3`, but returns a result tuple instead of raising."
+  @spec map_json_result(binary(), reference(), keyword()) ::
+          {:ok, map() | encoded()} | {:error, String.t()}
+  def map_json_result(json, compiled_mapping, opts \\ []) when is_binary(json) do
+    flat_keys = Keyword.get(opts, :flat_keys, false)
+    output_context = Keyword.get(opts, :output_context)
+
+    case Native.map_json(json, compiled_mapping, {flat_keys, output_context}) do
+      {:ok, output} -> {:ok, output}
+      {:error, _reason} = error -> error
+      output -> {:ok, output}
+    end
+  end
+
+  @doc """
+  Maps a batch of raw JSON objects like `map_many/3`. See `map_json/3`.
+  """
+  @spec map_many_json([binary()], reference(), keyword()) :: [map()] | encoded()
+  def map_many_json(jsons, compiled_mapping, opts \\ []) when is_list(jsons) do
+    flat_keys = Keyword.get(opts, :flat_keys, false)
+    output_contexts = Keyword.get(opts, :output_contexts, [])
+
+    case Native.map_many_json(jsons, compiled_mapping, {flat_keys, output_contexts}) do
+      {:ok, output} -> output
+      {:error, _reason} = error -> unwrap_result(error)
+      output -> output
+    end
+  end
+
+  @doc """
+  Maps a batch of documents using a compiled mapping.
+
+  ## Options
+
+    * `:flat_keys` - see `map/3`.
+
+    * `:output_contexts` - one output context per document, in the same order.
+      Required by ClickHouse outputs; map output ignores it.
+
+  Map output returns the mapped maps in input order. ClickHouse RowBinary
+  output returns the concatenated rows, ClickHouse Native output returns a
+  single block containing every document, Arrow IPC output returns the schema
+  message with one record batch for the whole list, JSON output returns NDJSON
+  with one line per document, and OTLP protobuf output returns one export
+  request grouping every document by resource and scope.
+
+  As with `map/3`, a config's `:sample` is applied first and no mapping work is
+  spent on the documents it drops: they are `:drop` in map output and left out
+  of serialized payloads, along with their `:output_contexts`.
+  """
+  @spec map_many([map()], reference(), keyword()) :: [map() | :drop] | encoded()
+  def map_many(documents, compiled_mapping, opts \\ []) when is_list(documents) do
+    documents
+    |> map_many_result(compiled_mapping, opts)
+    |> unwrap_result()
+  end
+
+  @doc "Maps a batch of documents and returns a result tuple instead of raising on output errors."
+  @spec map_many_result([map()], reference(), keyword()) ::
+          {:ok, [map() | :drop] | encoded()} | {:error, String.t()}
+  def map_many_result(documents, compiled_mapping, opts \\ []) when is_list(documents) do
+    flat_keys = Keyword.get(opts, :flat_keys, false)
+    output_contexts = Keyword.get(opts, :output_contexts, [])
+
+    case Native.map_many(documents, compiled_mapping, {flat_keys, output_contexts}) do
+      {:ok, output} -> {:ok, output}
+      {:error, _reason} = error -> error
+      output -> {:ok, output}
+    end
+  end
+
+  @typedoc "Route name of the first matching `Route`, or `nil` when none matched."
+  @type route :: String.t() | nil
+
+  @doc """
+  Maps a single document after applying the config's `:drop_when`, `:sample`
+  and `:route` sections.
+
+  Returns `:drop` when `:drop_when` matches the document or `:sample` does not
+  keep it, and `{route, output}` otherwise, where `route` names the first
+  matching route (or is `nil`) and `output` is what `map/3` returns. Accepts
+  the options of `map/3` and raises on output errors.
+  """
+  @spec map_routed(map(), reference(), keyword()) :: :drop | {route(), map() | encoded()}
+  def map_routed(document, compiled_mapping, opts \\ []) when is_map(document) do
+    flat_keys = Keyword.get(opts, :flat_keys, false)
+    output_context = Keyword.get(opts, :output_context)
+
+    case Native.map_routed(document, compiled_mapping, {flat_keys, output_context}) do
+      :drop -> :drop
+      {:error, _reason} = error -> unwrap_result(error)
+      {route, {:ok, output}} -> {route, output}
+      {_route, {:error, _reason} = error} -> unwrap_result(error)
+      {route, output} -> {route, output}
+    end
+  end
+
+  @doc """
+  Maps a batch of documents after applying the config's `:drop_when`,
+  `:sample` and `:route` sections to each of them.
+
+  Map output returns, in input order, `:drop` or `{route, map}` per document.
+  Serialized outputs return `{route, payload}` pairs, one per route and ordered
+  by the first document of each route, where `payload` is what `map_many/3`
+  returns for that route's documents. Dropped documents are left out, as are
+  their `:output_contexts`. Accepts the options of `map_many/3` and raises on
+  output errors.
+  """
+  @spec map_many_routed([map()], reference(), keyword()) ::
+          [:drop | {route(), map()}] | [{route(), encoded()}]
+  def map_many_routed(documents, compiled_mapping, opts \\ []) when is_list(documents) do
+    flat_keys = Keyword.get(opts, :flat_keys, false)
+    output_contexts = Keyword.get(opts, :output_contexts, [])
+
+    case Native.map_many_routed(documents, compiled_mapping, {flat_keys, output_contexts}) do
+      {:ok, payloads} -> payloads
+      {:error, _reason} = error -> unwrap_result(error)
+      rows -> rows
+    end
+  end
+
+  @doc """
+  Maps a single document and explains how each field's value was decided.
+
+  Returns one map per field, in field order, with:
+
+    * `:field` - the output field name.
+    * `:case` - index of the matching `:cases` clause, if the field has cases.
+    * `:source` - where the value came from: `:root`, `:path`,
+      `{:coalesce, index}` for the first usable coalesce path,
+      `{:from_output, field_name}`, `:wildcard` for element-wise array mapping,
+      `:aggregate` for an aggregate source, `:literal` for a case value, or `nil`
+      when no usable value was found.
+    * `:raw` - the resolved value before any fallback or lookup.
+    * `:skipped` - `{path_index, reason, value}` for each path passed over,
+      where reason is `:missing`, `:empty` or `{:filter, name}`.
+    * `:default_used` / `:default_reason` - whether the default was substituted
+      and which step first required it: `:source`, `:allowed_values`,
+      `:value_map`, `:enum8` or `:redacted` when a `drop` redaction rule matched.
+    * `:allowed_values` - `:allowed`, `:rejected`, or `nil` when not checked.
+    * `:value_map` - `:hit`, `:miss`, or `nil` when not configured.
+    * `:enum8` - `:lookup`, `:integer`, `{:infer_rule, index}` or `:default`
+      for Enum8 fields.
+    * `:value` - the final mapped value, as returned by map output.
+
+  The explanation is independent of the compiled output format. Accepts the
+  `:flat_keys` option of `map/3`.
+  """
+  @spec explain(map(), reference(), keyword()) :: {:ok, [map()]} | {:error, String.t()}
+  def explain(document, compiled_mapping, opts \\ []) when is_map(document) do
+    flat_keys = Keyword.get(opts, :flat_keys, false)
+    Native.explain(document, compiled_mapping, {flat_keys, nil})
+  end
+
   defp unwrap_result({:ok, output}), do: output
 
   defp unwrap_result({:error, reason}) do
@@ -24,6 +24,42 @@ defmodule Logflare.Mapper.MappingConfig do
   filters, it is skipped and the next coalesce path is tried. See
   `FieldConfig` for the full list of filter keys.
 
+  Any field can pick its source per document with `:cases`: `when`-style
+  clauses whose conditions are evaluated in order, each with its own path or
+  literal value. See `FieldCase`.
+
+  A config can also decide per document whether to keep it at all: `:drop_when`
+  is an `InferCondition` that drops matching documents, `:sample` keeps a
+  deterministic fraction of documents (see `Sample`), and `:route` is a list of
+  `Route`s whose first match tags the document with a route name. They are
+  applied in that order by `Mapper.map_routed/3` and `Mapper.map_many_routed/3`.
+  `map/3` and `map_many/3` apply only `:sample`, returning `:drop` for the
+  documents it does not keep.
+
+  `:redact` scrubs PII such as emails, card numbers, bearer tokens and IP
+  addresses from string, JSON and map values while they are mapped. See
+  `RedactRule`.
+
+  `:hash_key` is the secret for keyed hashing: `transform: "hash"` on string and
+  `uint64` fields, `:hash_keys` on `flat_map` fields and the `"hash"` redaction
+  action pseudonymise values such as user IDs and emails. Keep it stable, since
+  changing it changes every pseudonym. Exported mappings leave it out, so pass it
+  to `Logflare.Mapper.import_mapping/2` again.
+
+  Fields accept `:limits` that truncate oversized values (see `FieldConfig`), and
+  `:max_row_bytes` bounds a whole mapped row: while its estimated size is over the
+  limit, the largest string, JSON, map and array values are emptied, biggest first.
+  Emptied `json` and `flat_map` values keep only a `"_truncated"` marker key.
+
+  `:key_mode` picks which document keys are read: `:binary` (the default),
+  `:atom` for internally built documents such as `%{message: ..., metadata: ...}`,
+  or `:either`, which tries the string key first. Paths, `exclude_keys`,
+  `elevate_keys` and flattening all follow it; flattened atom keys become strings.
+
+  `:collect_stats` makes the compiled mapping count, per field, defaults used,
+  failed coercions, filter rejections, `value_map` misses and unmatched Enum8
+  values across every call that maps with it. See `Logflare.Mapper.mapping_stats/1`.
+
   Every field reads from the **original input document** — operations like
   `exclude_keys` and `elevate_keys` only transform that field's own output value.
   The only cross-field mechanism is `from_output:`, which reads a previously
@@ -71,31 +107,74 @@ defmodule Logflare.Mapper.MappingConfig do
   import Ecto.Changeset
   import Logflare.Utils.Guards, only: [is_empty_map: 1, is_non_empty_binary: 1]
 
+  alias __MODULE__.FieldCase
   alias __MODULE__.FieldConfig
   alias __MODULE__.InferCondition
   alias __MODULE__.InferRule
   alias __MODULE__.OutputFormat
   alias __MODULE__.PickEntry
+  alias __MODULE__.RedactRule
+  alias __MODULE__.Route
+  alias __MODULE__.Sample
 
   @derive Jason.Encoder
 
   @primary_key false
   typed_embedded_schema do
+    field(:hash_key, :string, redact: true)
+    field(:max_row_bytes, :integer)
+    field(:key_mode, Ecto.Enum, values: [:binary, :atom, :either])
+    field(:collect_stats, :boolean, default: false)
     embeds_many(:fields, FieldConfig)
     embeds_one(:output, OutputFormat)
+    embeds_one(:drop_when, InferCondition)
+    embeds_one(:sample, Sample)
+    embeds_many(:route, Route)
+    embeds_many(:redact, RedactRule)
   end
 
   @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
   def changeset(struct_or_changeset, attrs) do
     struct_or_changeset
-    |> cast(attrs, [])
+    |> cast(attrs, [:hash_key, :max_row_bytes, :key_mode, :collect_stats])
+    |> validate_number(:max_row_bytes, greater_than: 0)
     |> cast_embed(:fields, with: &FieldConfig.changeset/2)
     |> cast_embed(:output, with: &OutputFormat.changeset/2)
+    |> cast_embed(:drop_when, with: &InferCondition.changeset/2)
+    |> cast_embed(:sample, with: &Sample.changeset/2)
+    |> cast_embed(:route, with: &Route.changeset/2)
+    |> cast_embed(:redact, with: &RedactRule.changeset/2)
   end
 
+  @doc """
+  Builds a config from field definitions.
+
+  ## Options
+
+    * `:output` - an `OutputFormat`; map output when omitted.
+    * `:drop_when` - an `InferCondition` matching documents to drop.
+    * `:sample` - a `Sample` keeping a deterministic fraction of documents.
+    * `:route` - a list of `Route`s tagging documents with a route name.
+    * `:redact` - a list of `RedactRule`s scrubbing PII from mapped values.
+    * `:hash_key` - the secret used by `"hash"` transforms and `:hash_keys`.
+    * `:max_row_bytes` - estimated row size above which the largest values are emptied.
+    * `:key_mode` - `:binary` (default), `:atom` or `:either` document keys.
+    * `:collect_stats` - accumulate per-field mapping counters (default `false`).
+  """
   @spec new([FieldConfig.t()], keyword()) :: t()
   def new(fields, opts \\ []) when is_list(fields) and is_list(opts) do
-    %__MODULE__{fields: fields, output: Keyword.get(opts, :output)}
+    %__MODULE__{
+      fields: fields,
+      output: Keyword.get(opts, :output),
+      drop_when: Keyword.get(opts, :drop_when),
+      sample: Keyword.get(opts, :sample),
+      route: Keyword.get(opts, :route, []),
+      redact: Keyword.get(opts, :redact, []),
+      hash_key: Keyword.get(opts, :hash_key),
+      max_row_bytes: Keyword.get(opts, :max_row_bytes),
+      key_mode: Keyword.get(opts, :key_mode),
+      collect_stats: Keyword.get(opts, :collect_stats, false)
+    }
   end
 
   @spec to_json(t()) :: {:ok, String.t()} | {:error, Jason.EncodeError.t()}
@@ -111,15 +190,65 @@ defmodule Logflare.Mapper.MappingConfig do
   end
 
   @spec to_nif_map(t()) :: map()
-  def to_nif_map(%__MODULE__{fields: fields, output: output}) do
-    config = %{"fields" => Enum.map(fields, &field_to_nif_map/1)}
+  def to_nif_map(%__MODULE__{fields: fields, output: output} = config) do
+    nif_config =
+      %{"fields" => Enum.map(fields, &field_to_nif_map/1)}
+      |> maybe_add_drop_when(config.drop_when)
+      |> maybe_add_sample(config.sample)
+      |> maybe_add_routes(config.route)
+      |> maybe_add_redact(config.redact)
+      |> maybe_add("hash_key", config.hash_key)
+      |> maybe_add("max_row_bytes", config.max_row_bytes)
+      |> maybe_add_key_mode(config.key_mode)
+      |> maybe_add_collect_stats(config.collect_stats)
 
     case output do
-      %OutputFormat{} -> Map.put(config, "output", OutputFormat.to_nif_map(output))
-      nil -> config
+      %OutputFormat{} -> Map.put(nif_config, "output", OutputFormat.to_nif_map(output))
+      nil -> nif_config
     end
   end
 
+  @spec maybe_add_drop_when(map(), InferCondition.t() | nil) :: map()
+  defp maybe_add_drop_when(map, nil), do: map
+
+  defp maybe_add_drop_when(map, %InferCondition{} = condition),
+    do: Map.put(map, "drop_when", condition_to_nif_map(condition))
+
+  @spec maybe_add_sample(map(), Sample.t() | nil) :: map()
+  defp maybe_add_sample(map, nil), do: map
+  defp maybe_add_sample(map, %Sample{} = sample),
+    do: Map.put(map, "sample", Sample.to_nif_map(sample))
+
+  @spec maybe_add_key_mode(map(), atom() | nil) :: map()
+  defp maybe_add_key_mode(map, nil), do: map
+
+  defp maybe_add_key_mode(map, key_mode),
+    do: Map.put(map, "key_mode", Atom.to_string(key_mode))
+
+  @spec maybe_add_collect_stats(map(), boolean()) :: map()
+  defp maybe_add_collect_stats(map, true), do: Map.put(map, "collect_stats", true)
+  defp maybe_add_collect_stats(map, _collect_stats), do: map
+
+  @spec maybe_add_redact(map(), [RedactRule.t()]) :: map()
+  defp maybe_add_redact(map, []), do: map
+
+  defp maybe_add_redact(map, rules),
+    do: Map.put(map, "redact", Enum.map(rules, &RedactRule.to_nif_map/1))
+
+  @spec maybe_add_routes(map(), [Route.t()]) :: map()
+  defp maybe_add_routes(map, []), do: map
+
+  defp maybe_add_routes(map, routes) do
+    nif_routes =
+      Enum.map(routes, fn %Route{} = route ->
+        %{"name" => route.name}
+        |> maybe_add_conditions("any", route.any)
+        |> maybe_add_conditions("all", route.all)
+      end)
+
+    Map.put(map, "route", nif_routes)
+  end
+
   @spec field_to_nif_map(FieldConfig.t()) :: map()
   defp field_to_nif_map(%FieldConfig{} = f) do
     base = %{"name" => f.name, "type" => f.type}
@@ -130,17 +259,26 @@ defmodule Logflare.Mapper.MappingConfig do
     |> maybe_add("default", encode_nif_default(f))
     |> maybe_add("precision", f.precision)
     |> maybe_add("transform", f.transform)
+    |> maybe_add("hash_algorithm", f.hash_algorithm)
+    |> maybe_add("hash_keys", f.hash_keys)
+    |> maybe_add("include_keys", f.include_keys)
+    |> maybe_add("rename_keys", f.rename_keys)
+    |> maybe_add("normalize_keys", f.normalize_keys)
     |> maybe_add("allowed_values", f.allowed_values)
     |> maybe_add("from_output", f.from_output)
+    |> maybe_add("aggregate", f.aggregate)
     |> maybe_add("value_map", f.value_map)
     |> maybe_add("enum_values", f.enum_values)
     |> maybe_add("exclude_keys", f.exclude_keys)
     |> maybe_add("elevate_keys", f.elevate_keys)
     |> maybe_add("value_type", f.value_type)
     |> maybe_add_filters(f.filters)
+    |> maybe_add_limits(f.limits)
+    |> maybe_add_flatten(f.flatten)
     |> maybe_add_filter_nil(f.filter_nil)
     |> maybe_add_pick(f.pick)
     |> maybe_add_infer(f.infer)
+    |> maybe_add_cases(f)
   end
 
   @spec encode_nif_default(FieldConfig.t()) :: term()
@@ -179,6 +317,10 @@ defmodule Logflare.Mapper.MappingConfig do
   defp maybe_add(map, _key, []), do: map
   defp maybe_add(map, key, value), do: Map.put(map, key, value)
 
+  @char_classes ~w(alpha numeric alphanumeric unicode_alpha unicode_numeric
+                   unicode_alphanumeric hex uuid base64)
+  @string_matchers [:starts_with, :ends_with, :contains, :matches]
+
   @spec maybe_add_filters(map(), map() | nil) :: map()
   defp maybe_add_filters(map, nil), do: map
   defp maybe_add_filters(map, filters) when is_empty_map(filters), do: map
@@ -201,9 +343,15 @@ defmodule Logflare.Mapper.MappingConfig do
         {:len_lte, v}, acc when is_integer(v) ->
           Map.put(acc, "len_lte", v)
 
-        {:char_class, v}, acc when v in ~w(alpha numeric alphanumeric) ->
+        {:char_class, v}, acc when v in @char_classes ->
           Map.put(acc, "char_class", v)
 
+        {op, v}, acc when op in @string_matchers and is_binary(v) ->
+          Map.put(acc, Atom.to_string(op), v)
+
+        {:case_insensitive, v}, acc when is_boolean(v) ->
+          Map.put(acc, "case_insensitive", v)
+
         _, acc ->
           acc
       end)
@@ -211,6 +359,27 @@ defmodule Logflare.Mapper.MappingConfig do
     if nif_filters == %{}, do: map, else: Map.put(map, "filters", nif_filters)
   end
 
+  @spec maybe_add_limits(map(), map() | nil) :: map()
+  defp maybe_add_limits(map, nil), do: map
+
+  defp maybe_add_limits(map, limits) do
+    nif_limits =
+      for {key, value} <- limits,
+          key = to_string(key),
+          key in FieldConfig.limit_keys(),
+          is_integer(value) and value > 0,
+          into: %{},
+          do: {key, value}
+
+    if nif_limits == %{}, do: map, else: Map.put(map, "limits", nif_limits)
+  end
+
+  @spec maybe_add_flatten(map(), map() | nil) :: map()
+  defp maybe_add_flatten(map, nil), do: map
+
+  defp maybe_add_flatten(map, flatten),
+    do: Map.put(map, "flatten", Map.new(flatten, fn {key, value} -> {to_string(key), value} end))
+
   @spec maybe_add_filter_nil(map(), boolean()) :: map()
   defp maybe_add_filter_nil(map, false), do: map
   defp maybe_add_filter_nil(map, true), do: Map.put(map, "filter_nil", true)
@@ -243,20 +412,52 @@ defmodule Logflare.Mapper.MappingConfig do
     Map.put(map, "infer", infer)
   end
 
+  @spec maybe_add_cases(map(), FieldConfig.t()) :: map()
+  defp maybe_add_cases(map, %FieldConfig{cases: []}), do: map
+
+  defp maybe_add_cases(map, %FieldConfig{cases: cases} = f) do
+    nif_cases =
+      Enum.map(cases, fn %FieldCase{} = c ->
+        value = if c.value, do: encode_nif_default(%FieldConfig{f | default: c.value})
+
+        %{}
+        |> maybe_add("path", c.path)
+        |> maybe_add("paths", c.paths)
+        |> maybe_add("from_output", c.from_output)
+        |> maybe_add("aggregate", c.aggregate)
+        |> maybe_add("value", value)
+        |> maybe_add_conditions("any", c.any)
+        |> maybe_add_conditions("all", c.all)
+      end)
+
+    Map.put(map, "cases", nif_cases)
+  end
+
   @spec maybe_add_conditions(map(), String.t(), [InferCondition.t()] | nil) :: map()
   defp maybe_add_conditions(map, _key, nil), do: map
   defp maybe_add_conditions(map, _key, []), do: map
 
   defp maybe_add_conditions(map, key, conditions) do
-    conds =
-      Enum.map(conditions, fn %InferCondition{} = c ->
-        base = %{"path" => c.path, "predicate" => c.predicate}
+    Map.put(map, key, Enum.map(conditions, &condition_to_nif_map/1))
+  end
 
-        base
-        |> maybe_add("comparison_value", c.comparison_value)
-        |> maybe_add("comparison_values", c.comparison_values)
-      end)
+  @spec condition_to_nif_map(InferCondition.t()) :: map()
+  defp condition_to_nif_map(%InferCondition{not: %InferCondition{} = negated}) do
+    %{"not" => condition_to_nif_map(negated)}
+  end
 
-    Map.put(map, key, conds)
+  defp condition_to_nif_map(%InferCondition{} = c) do
+    if InferCondition.group?(c) do
+      %{}
+      |> maybe_add_conditions("any", c.any)
+      |> maybe_add_conditions("all", c.all)
+    else
+      base = %{"path" => c.path, "predicate" => c.predicate}
+
+      base
+      |> maybe_add("comparison_value", c.comparison_value)
+      |> maybe_add("comparison_values", c.comparison_values)
+      |> maybe_add("case_insensitive", c.case_insensitive || nil)
+    end
   end
 end
@@ -0,0 +1,52 @@
+defmodule Logflare.Mapper.MappingConfig.FieldCase do
+  @moduledoc """
+  A `when` clause in a `FieldConfig`'s `:cases` list.
+
+  Cases are evaluated in order and the first one whose conditions match
+  supplies the field's source. Conditions use the same `:any` (OR) and `:all`
+  (AND) lists of `InferCondition` structs as enum8 `InferRule`s; a case with no
+  conditions always matches.
+
+  A case reads from `:path`, `:paths` or `:from_output` like a field does, or
+  yields the literal `:value`, which is coerced to the field's type. An
+  `:aggregate` applies to the case's own `:path`.
+
+  See `Logflare.Mapper.MappingConfig.FieldConfig` for the full list of predicates.
+  """
+
+  use TypedEctoSchema
+
+  import Ecto.Changeset
+
+  alias Logflare.Mapper.MappingConfig.InferCondition
+
+  @derive Jason.Encoder
+
+  @primary_key false
+  typed_embedded_schema do
+    field(:path, :string)
+    field(:paths, {:array, :string})
+    field(:from_output, :string)
+    field(:aggregate, :string)
+    field(:value, :string)
+    embeds_many(:any, InferCondition)
+    embeds_many(:all, InferCondition)
+  end
+
+  @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
+  def changeset(struct_or_changeset, attrs) do
+    struct_or_changeset
+    |> cast(attrs, [:path, :paths, :from_output, :aggregate, :value])
+    |> cast_embed(:any, with: &InferCondition.changeset/2)
+    |> cast_embed(:all, with: &InferCondition.changeset/2)
+    |> validate_source()
+  end
+
+  defp validate_source(changeset) do
+    if Enum.any?([:path, :paths, :from_output, :value], &get_field(changeset, &1)) do
+      changeset
+    else
+      add_error(changeset, :value, "case requires a value, path, paths or from_output")
+    end
+  end
+end
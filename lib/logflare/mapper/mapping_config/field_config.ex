@@ -15,6 +15,36 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
       input document (e.g. `from_output: "severity_text"`). Fields are resolved in order, so
       the source field must be defined earlier in the config.
     * `:default` — fallback value when no path resolves
+    * `:cases` — list of `FieldCase` structs that choose the source from the document's
+      shape. The first case whose `:any`/`:all` conditions match supplies the value from
+      its own `:path`, `:paths`, `:from_output` or literal `:value`. The field's own
+      `:path`/`:paths`/`:from_output`, if set, applies when no case matches; otherwise the
+      field falls back to `:default`. For example, reading `event_message` from `$.msg`
+      for pino logs and from `$.message` otherwise:
+
+          Field.string("event_message",
+            cases: [
+              %FieldCase{
+                path: "$.msg",
+                all: [%InferCondition{path: "$.logger", predicate: "equals", comparison_value: "pino"}]
+              }
+            ],
+            path: "$.message"
+          )
+    * `:aggregate` — derive a numeric or `bool` field from every value under the wildcard
+      `:path`, e.g. `Field.uint32("event_count", path: "$.events[*]", aggregate: "count")`
+      or `Field.float64("max_latency", path: "$.retries[*].latency_ms", aggregate: "max")`:
+
+      * `"count"` — non-nil values; `"distinct_count"` — distinct non-nil values
+      * `"sum"`, `"min"`, `"max"`, `"avg"` — over integers, floats and numeric strings,
+        skipping other values; `"min"`, `"max"` and `"avg"` of no numbers fall back to
+        `:default`
+      * `"any"` / `"all"` — whether any / every value is `true` (`"all"` of no values
+        is `true`)
+
+      The result is coerced to the field's type, and a missing or non-list array
+      aggregates no values. Aggregates need nested documents, so with `flat_keys: true`
+      the field falls back to `:default`.
     * `:value_map` — case-insensitive lookup applied to the resolved value. The map's
       value type is dictated by the field's output type and is resolved once at compile
       time (in the NIF), not per document — so there is no per-event type inference cost:
@@ -28,12 +58,34 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
       Mixing the two — string values on a non-string field, or integer values on a
       string field — is rejected at compile time rather than silently mishandled. In
       both cases, values absent from the map fall back to the field's `:default`.
+    * `:limits` — size limits applied to the mapped value, as a map of positive integers:
+
+      * `:max_string_bytes` — strings, including those nested in maps and lists, are cut
+        to this many bytes on a UTF-8 character boundary
+      * `:max_map_entries` — maps keep their first entries, in key order
+      * `:max_depth` — maps and lists nested deeper than this are dropped; the field
+        value itself is depth 1, and a `flat_map` key's depth is its dot-separated
+        segment count before the key rules below
+
+      `flat_map` applies both while flattening, so entries past them are never built;
+      with key rules or redaction, the entry cap counts the entries that remain.
+      * `:max_array_length` — lists keep their first elements
+
+      Truncated `json` and `flat_map` values gain a `"_truncated"` key (`true`, or
+      `"true"` for `flat_map`), e.g. `limits: %{max_map_entries: 256, max_depth: 4}`.
 
   ## Type-Specific Options
 
   ### `string/2`
 
-    * `:transform` — `"upcase"` or `"downcase"`, applied after resolution
+    * `:transform` — `"upcase"`, `"downcase"` or `"hash"`, applied after resolution.
+      `"hash"` replaces the value with a keyed hash under the mapping's `:hash_key`,
+      as lowercase hex, giving stable pseudonyms that still join across tables.
+      Integers are hashed by their decimal text; other non-string values become nil.
+      A missing value keeps the field's `:default` unhashed.
+    * `:hash_algorithm` — `"siphash"` (default), `"xxhash"` or `"sha256"` (HMAC-SHA256,
+      64 hex digits; the others give 16). `"xxhash"` is the fastest but not a MAC, so
+      avoid it for guessable inputs such as emails.
     * `:allowed_values` — list of permitted string values. After transform is applied,
       if the value is not in this list it is replaced with the field's default. Useful for
       `LowCardinality(String)` columns where arbitrary strings would pollute the index.
@@ -48,14 +100,27 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
       * `:len_lt` — byte length must be below threshold (integer)
       * `:len_lte` — byte length must be at or below threshold (integer)
       * `:char_class` — `"alpha"` (ASCII letters only), `"numeric"` (ASCII digits only),
-        or `"alphanumeric"` (ASCII letters and digits only)
-
-      Length checks are O(1) and run first, gating the O(n) character class scan.
+        `"alphanumeric"` (ASCII letters and digits only), their Unicode-aware counterparts
+        `"unicode_alpha"`, `"unicode_numeric"` and `"unicode_alphanumeric"`, `"hex"`,
+        `"uuid"` (hyphenated 8-4-4-4-12 form) or `"base64"` (standard alphabet, padded)
+      * `:starts_with` / `:ends_with` / `:contains` — the value must start with, end with
+        or contain the given string
+      * `:matches` — the value must match the given regular expression (Rust `regex`
+        syntax, unanchored; use `^`/`$` to anchor)
+      * `:case_insensitive` — `true` to compare the four options above ignoring case
+
+      Length checks are O(1) and run first, gating the O(n) character class scan. String
+      matches run last. An invalid `:matches` pattern fails `compile_mapping/1`.
       Filters are evaluated inside the NIF with zero additional BEAM reductions.
 
       Example: `filters: %{len_eq: 20, char_class: "alpha"}` ensures the resolved
       value is exactly 20 ASCII alphabetic characters.
 
+  ### `uint64/2`
+
+    * `:transform` — only `"hash"`, which stores the keyed hash as an integer (the first
+      8 bytes, big-endian, for `"sha256"`). Takes `:hash_algorithm` as for `string/2`.
+
   ### `datetime64/2`
 
     * `:precision` — target precision 0-9 (default `9` for nanoseconds). Integer inputs are
@@ -94,6 +159,31 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
     * Scalars: coerced to string (`42` → `"42"`, `true` → `"true"`)
     * nil values: omitted from the output map
     * Accepts the same options as `json/2`: `:exclude_keys`, `:elevate_keys`, `:pick`
+    * `:flatten` — map customising the flattening:
+
+      * `:separator` — joins nested keys (default `"."`), e.g. `"_"` for `a_b`
+      * `:lists` — `"json"` (default), `"index"` to expand lists into one key per
+        element (`%{"tags" => ["a", "b"]}` → `%{"tags.0" => "a", "tags.1" => "b"}`), or
+        `"join"` to join the elements with `:list_delimiter` (default `","`)
+      * `:max_depth` — number of key segments after which nested maps and lists are
+        kept whole as JSON strings
+    * `:exclude_keys` entries containing `*` are glob patterns matched against the
+      flattened keys (`*` matches any run of characters, e.g. `"*.password"`); entries
+      without `*` keep their exact-key behaviour
+    * `:include_keys` — glob patterns; when set, only flattened keys matching one are kept
+      (e.g. `["http.*", "user.id"]`)
+    * `:rename_keys` — `%{String.t() => String.t()}` renaming flattened keys, e.g.
+      `%{"http.status" => "http.response.status_code"}`
+    * `:normalize_keys` — list of `"lowercase"` and `"dash_to_underscore"`, rewriting every
+      flattened key so attributes from different SDKs share one vocabulary
+    * `:hash_keys` — glob patterns over the flattened keys (`*` matches any run of
+      characters, e.g. `"user.*"` or `"*.email"`) whose values are replaced by their
+      keyed hash, as with `transform: "hash"`. Takes `:hash_algorithm`.
+
+  Keys are normalised first, then renamed (the rename table's keys are normalised the
+  same way), then filtered by `:include_keys` and glob `:exclude_keys`; `:hash_keys`
+  matches the resulting keys. When several keys end up the same, the first in key
+  order is kept.
 
   ## Array Types
 
@@ -150,12 +240,20 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
   ## Inference Rules (`InferRule` / `InferCondition`)
 
   Used by `enum8/2` to infer a value from structural cues when explicit path lookup finds
-  no match. Each rule has `:any` (OR) and `:all` (AND) condition lists plus a `:result`
+  no match, and by `FieldCase` to select a field's source. Each rule has `:any` (OR) and `:all` (AND) condition lists plus a `:result`
   string that is looked up in the `:values` map.
 
   A rule matches when `(any is empty OR at least one any-condition matches) AND
   (all is empty OR every all-condition matches)`.
 
+  Conditions nest: an `InferCondition` with its own `:any`/`:all` lists or a `:not`
+  condition is a group instead of a predicate. "(A and B) or (C and not D)" is:
+
+      any: [
+        %InferCondition{all: [a, b]},
+        %InferCondition{all: [c, %InferCondition{not: d}]}
+      ]
+
   ### Supported Predicates
 
   | Predicate        | Description                                  | Extra fields             |
@@ -175,24 +273,39 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
   | `"is_number"`    | Value is integer or float                    |                          |
   | `"is_list"`      | Value is a list                              |                          |
   | `"is_map"`       | Value is a map                               |                          |
+  | `"starts_with"`  | String starts with prefix                    | `comparison_value`       |
+  | `"ends_with"`    | String ends with suffix                      | `comparison_value`       |
+  | `"contains"`     | String contains substring                    | `comparison_value`       |
+  | `"matches"`      | String matches regular expression            | `comparison_value`       |
+  | `"char_class"`   | Every character is in the given char class   | `comparison_value`       |
+
+  The four string matching predicates also accept `case_insensitive: true`.
   """
 
   use TypedEctoSchema
 
   import Ecto.Changeset
 
+  alias Logflare.Mapper.MappingConfig.FieldCase
   alias Logflare.Mapper.MappingConfig.InferRule
   alias Logflare.Mapper.MappingConfig.PickEntry
 
   @valid_types ~w(string uint8 uint32 uint64 int32 float64 bool enum8 datetime64 json flat_map array_string array_uint64 array_float64 array_datetime64 array_json array_map array_flat_map)
-  @valid_transforms ~w(upcase downcase)
+  @valid_transforms ~w(upcase downcase hash)
+  @valid_hash_algorithms ~w(siphash xxhash sha256)
   @valid_value_types ~w(string)
+  @limit_keys ~w(max_string_bytes max_map_entries max_depth max_array_length)
+  @valid_key_normalizations ~w(lowercase dash_to_underscore)
+  @valid_list_strategies ~w(json index join)
+  @valid_aggregates ~w(count sum min max avg any all distinct_count)
 
   @type common_opts :: [
           path: String.t(),
           paths: [String.t()],
           from_output: String.t(),
-          default: term()
+          aggregate: String.t(),
+          default: term(),
+          cases: [FieldCase.t()]
         ]
 
   @derive Jason.Encoder
@@ -206,17 +319,26 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
     field(:default, :string)
     field(:precision, :integer)
     field(:transform, :string)
+    field(:hash_algorithm, :string)
+    field(:hash_keys, {:array, :string})
+    field(:include_keys, {:array, :string})
+    field(:rename_keys, :map)
+    field(:normalize_keys, {:array, :string})
     field(:allowed_values, {:array, :string})
     field(:from_output, :string)
+    field(:aggregate, :string)
     field(:value_map, :map)
     field(:enum_values, :map)
     field(:exclude_keys, {:array, :string})
     field(:elevate_keys, {:array, :string})
     field(:filters, :map)
+    field(:limits, :map)
+    field(:flatten, :map)
     field(:filter_nil, :boolean, default: false)
     field(:value_type, :string)
     embeds_many(:pick, PickEntry)
     embeds_many(:infer, InferRule)
+    embeds_many(:cases, FieldCase)
   end
 
   @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
@@ -232,13 +354,21 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
         :default,
         :precision,
         :transform,
+        :hash_algorithm,
+        :hash_keys,
+        :include_keys,
+        :rename_keys,
+        :normalize_keys,
         :allowed_values,
         :from_output,
+        :aggregate,
         :value_map,
         :enum_values,
         :exclude_keys,
         :elevate_keys,
         :filters,
+        :limits,
+        :flatten,
         :filter_nil,
         :value_type
       ],
@@ -247,14 +377,20 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
     |> validate_required([:name, :type])
     |> validate_inclusion(:type, @valid_types)
     |> validate_inclusion(:transform, @valid_transforms)
+    |> validate_inclusion(:hash_algorithm, @valid_hash_algorithms)
     |> validate_inclusion(:value_type, @valid_value_types)
+    |> validate_inclusion(:aggregate, @valid_aggregates)
+    |> validate_change(:limits, &validate_limits/2)
+    |> validate_subset(:normalize_keys, @valid_key_normalizations)
+    |> validate_change(:flatten, &validate_flatten/2)
     |> cast_embed(:pick, with: &PickEntry.changeset/2)
     |> cast_embed(:infer, with: &InferRule.changeset/2)
+    |> cast_embed(:cases, with: &FieldCase.changeset/2)
   end
 
   @spec string(String.t(), keyword()) :: t()
   def string(name, opts \\ []) do
-    build(name, "string", opts, [:transform, :allowed_values, :filters])
+    build(name, "string", opts, [:transform, :hash_algorithm, :allowed_values, :filters])
   end
 
   @spec uint8(String.t(), keyword()) :: t()
@@ -269,7 +405,7 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
 
   @spec uint64(String.t(), keyword()) :: t()
   def uint64(name, opts \\ []) do
-    build(name, "uint64", opts)
+    build(name, "uint64", opts, [:transform, :hash_algorithm])
   end
 
   @spec int32(String.t(), keyword()) :: t()
@@ -344,7 +480,19 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
   @spec flat_map(String.t(), keyword()) :: t()
   def flat_map(name, opts \\ []) do
     opts = Keyword.put_new(opts, :value_type, "string")
-    base = build(name, "flat_map", opts, [:exclude_keys, :elevate_keys, :value_type])
+    extra_keys = [
+      :exclude_keys,
+      :elevate_keys,
+      :value_type,
+      :include_keys,
+      :rename_keys,
+      :normalize_keys,
+      :flatten,
+      :hash_keys,
+      :hash_algorithm
+    ]
+
+    base = build(name, "flat_map", opts, extra_keys)
     maybe_put_pick(base, opts[:pick])
   end
 
@@ -354,6 +502,10 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
     build(name, "array_flat_map", opts, [:filter_nil, :value_type])
   end
 
+  @doc "Keys accepted in a field's `:limits` map, as strings."
+  @spec limit_keys() :: [String.t()]
+  def limit_keys, do: @limit_keys
+
   defp build(name, type, opts, extra_keys \\ []) do
     base = %__MODULE__{
       name: name,
@@ -361,15 +513,46 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
       path: opts[:path],
       paths: opts[:paths],
       from_output: opts[:from_output],
+      aggregate: opts[:aggregate],
       default: encode_default(opts[:default]),
-      value_map: opts[:value_map]
+      value_map: opts[:value_map],
+      limits: opts[:limits]
     }
 
+    base = maybe_put_cases(base, opts[:cases])
+
     Enum.reduce(extra_keys, base, fn key, acc ->
       maybe_put(acc, key, opts[key])
     end)
   end
 
+  defp validate_limits(field, limits) do
+    Enum.flat_map(limits, fn {key, value} ->
+      if to_string(key) in @limit_keys and is_integer(value) and value > 0,
+        do: [],
+        else: [{field, "#{key} must be a known limit with a positive integer value"}]
+    end)
+  end
+
+  defp validate_flatten(field, flatten) do
+    Enum.flat_map(flatten, fn
+      {key, value} when key in [:separator, "separator"] and is_binary(value) and value != "" ->
+        []
+
+      {key, value} when key in [:lists, "lists"] and value in @valid_list_strategies ->
+        []
+
+      {key, value} when key in [:list_delimiter, "list_delimiter"] and is_binary(value) ->
+        []
+
+      {key, value} when key in [:max_depth, "max_depth"] and is_integer(value) and value > 0 ->
+        []
+
+      {key, _value} ->
+        [{field, "invalid flatten option #{key}"}]
+    end)
+  end
+
   defp encode_default(nil), do: nil
   defp encode_default(val) when is_binary(val), do: val
   defp encode_default(val) when is_integer(val), do: Integer.to_string(val)
@@ -394,6 +577,12 @@ defmodule Logflare.Mapper.MappingConfig.FieldConfig do
     %{struct | pick: pick}
   end
 
+  defp maybe_put_cases(struct, nil), do: struct
+
+  defp maybe_put_cases(struct, cases) when is_list(cases) do
+    %{struct | cases: Enum.map(cases, &%FieldCase{&1 | value: encode_default(&1.value)})}
+  end
+
   defp maybe_put_infer(struct, nil), do: struct
 
   defp maybe_put_infer(struct, rules) when is_list(rules) do
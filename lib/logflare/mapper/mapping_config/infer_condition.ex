@@ -1,9 +1,19 @@
 defmodule Logflare.Mapper.MappingConfig.InferCondition do
   @moduledoc """
-  A single condition within an `InferRule`.
+  A single condition within an `InferRule` or `FieldCase`.
 
   Evaluates a dot-notation path (`:path`) against the input map using a `:predicate`.
-  Some predicates require `:comparison_value` or `:comparison_values`.
+  Some predicates require `:comparison_value` or `:comparison_values`. The string
+  matching predicates also honour `:case_insensitive`.
+
+  A condition can instead be a group of nested conditions, which allows rules such
+  as "(A and B) or (C and not D)":
+
+    * `:any` / `:all` — the group matches when `(any is empty OR at least one
+      matches) AND (all is empty OR every one matches)`, like an `InferRule`.
+    * `:not` — the group matches when the nested condition does not.
+
+  Groups short-circuit and may be nested up to 32 levels deep.
 
   See `Logflare.Mapper.MappingConfig.FieldConfig` for the full list of predicates.
   """
@@ -20,12 +30,38 @@ defmodule Logflare.Mapper.MappingConfig.InferCondition do
     field(:predicate, :string)
     field(:comparison_value, :string)
     field(:comparison_values, {:array, :string})
+    field(:case_insensitive, :boolean, default: false)
+    embeds_many(:any, __MODULE__)
+    embeds_many(:all, __MODULE__)
+    embeds_one(:not, __MODULE__)
   end
 
   @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
   def changeset(struct_or_changeset, attrs) do
     struct_or_changeset
-    |> cast(attrs, [:path, :predicate, :comparison_value, :comparison_values])
-    |> validate_required([:path, :predicate])
+    |> cast(attrs, [
+      :path,
+      :predicate,
+      :comparison_value,
+      :comparison_values,
+      :case_insensitive
+    ])
+    |> cast_embed(:any, with: &changeset/2)
+    |> cast_embed(:all, with: &changeset/2)
+    |> cast_embed(:not, with: &changeset/2)
+    |> validate_predicate_or_group()
+  end
+
+  @doc "True when the condition combines nested conditions instead of testing a path."
+  @spec group?(t()) :: boolean()
+  def group?(%__MODULE__{any: any, all: all, not: negated}),
+    do: any != [] or all != [] or negated != nil
+
+  defp validate_predicate_or_group(changeset) do
+    if group?(apply_changes(changeset)) do
+      changeset
+    else
+      validate_required(changeset, [:path, :predicate])
+    end
   end
 end
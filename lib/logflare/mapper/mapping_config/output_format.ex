@@ -3,8 +3,15 @@ defmodule Logflare.Mapper.MappingConfig.OutputFormat do
   Defines the output produced by a compiled mapping configuration.
 
   Mapping configurations without an output format continue to produce maps.
-  ClickHouse RowBinary output also records the row type so the mapper can
-  compile one schema-specific field layout.
+  ClickHouse RowBinary and Native outputs also record the row type so the
+  mapper can compile one schema-specific field layout. Native output produces
+  a single columnar block per batch and can optionally frame it with LZ4
+  compression.
+
+  Arrow IPC output needs no row type: every mapped field becomes one typed
+  Arrow column, so the compiled field types are the schema. JSON output also
+  writes every mapped field, either as one object per document or as NDJSON
+  for batches.
   """
 
   use TypedEctoSchema
@@ -15,15 +22,30 @@ defmodule Logflare.Mapper.MappingConfig.OutputFormat do
 
   @primary_key false
   typed_embedded_schema do
-    field(:format, Ecto.Enum, values: [:clickhouse_row_binary])
+    field(:format, Ecto.Enum,
+      values: [:clickhouse_row_binary, :clickhouse_native, :arrow_ipc, :json, :otlp_protobuf]
+    )
+
     field(:row_type, Ecto.Enum, values: [:log, :metric, :trace])
+    field(:compression, Ecto.Enum, values: [:none, :lz4, :zstd])
+    field(:key_order, Ecto.Enum, values: [:config, :sorted])
+    field(:key_shape, Ecto.Enum, values: [:dotted, :nested])
+    field(:omit_defaults, :boolean)
   end
 
   @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
   def changeset(struct_or_changeset, attrs) do
     struct_or_changeset
-    |> cast(attrs, [:format, :row_type])
-    |> validate_required([:format, :row_type])
+    |> cast(attrs, [:format, :row_type, :compression, :key_order, :key_shape, :omit_defaults])
+    |> validate_required([:format])
+    |> validate_row_type()
+  end
+
+  defp validate_row_type(changeset) do
+    case get_field(changeset, :format) do
+      format when format in [:arrow_ipc, :json] -> changeset
+      _ -> validate_required(changeset, [:row_type])
+    end
   end
 
   @spec clickhouse_row_binary(:log | :metric | :trace) :: t()
@@ -31,8 +53,88 @@ defmodule Logflare.Mapper.MappingConfig.OutputFormat do
     %__MODULE__{format: :clickhouse_row_binary, row_type: row_type}
   end
 
+  @doc """
+  Builds a ClickHouse Native output format.
+
+  ## Options
+
+    * `:compression` - `:none` (default) or `:lz4`. LZ4 output uses the
+      ClickHouse compressed block framing with CityHash128 checksums.
+  """
+  @spec clickhouse_native(:log | :metric | :trace, keyword()) :: t()
+  def clickhouse_native(row_type, opts \\ []) when row_type in [:log, :metric, :trace] do
+    compression = Keyword.get(opts, :compression, :none)
+    %__MODULE__{format: :clickhouse_native, row_type: row_type, compression: compression}
+  end
+
+  @doc """
+  Builds an Arrow IPC output format.
+
+  Mapping returns `{schema_message, record_batch_messages}`, the same shape as
+  `Logflare.Backends.Adaptor.BigQueryAdaptor.ArrowIPC.get_ipc_bytes/2`.
+
+  ## Options
+
+    * `:compression` - `:none` (default), `:lz4`, or `:zstd` IPC body
+      compression.
+  """
+  @spec arrow_ipc(keyword()) :: t()
+  def arrow_ipc(opts \\ []) do
+    %__MODULE__{format: :arrow_ipc, compression: Keyword.get(opts, :compression, :none)}
+  end
+
+  @doc """
+  Builds a JSON output format.
+
+  `Logflare.Mapper.map/3` returns one encoded JSON object and
+  `Logflare.Mapper.map_many/3` returns newline-delimited JSON.
+
+  ## Options
+
+    * `:key_order` - `:config` (default) writes keys in field order; `:sorted`
+      sorts keys at every object level.
+
+    * `:key_shape` - `:dotted` (default) writes field names as-is; `:nested`
+      splits names on `.` into nested objects.
+
+    * `:omit_defaults` - when `true`, fields whose mapped value is `nil`, the
+      field's configured default, or its type's zero value (`""`, `0`, `false`,
+      `[]`, `%{}`) are left out. Defaults to `false`.
+  """
+  @spec json(keyword()) :: t()
+  def json(opts \\ []) do
+    %__MODULE__{
+      format: :json,
+      key_order: Keyword.get(opts, :key_order, :config),
+      key_shape: Keyword.get(opts, :key_shape, :dotted),
+      omit_defaults: Keyword.get(opts, :omit_defaults, false)
+    }
+  end
+
+  @doc """
+  Builds an OTLP protobuf output format.
+
+  `:log`, `:trace` and `:metric` rows encode `ExportLogsServiceRequest`,
+  `ExportTraceServiceRequest` and `ExportMetricsServiceRequest` bytes
+  respectively. Metric data points are grouped into one metric per name,
+  description, unit, type, temporality and monotonicity.
+  """
+  @spec otlp_protobuf(:log | :metric | :trace) :: t()
+  def otlp_protobuf(row_type) when row_type in [:log, :metric, :trace] do
+    %__MODULE__{format: :otlp_protobuf, row_type: row_type}
+  end
+
   @spec to_nif_map(t()) :: map()
-  def to_nif_map(%__MODULE__{format: format, row_type: row_type}) do
-    %{"format" => Atom.to_string(format), "row_type" => Atom.to_string(row_type)}
+  def to_nif_map(%__MODULE__{format: format} = output) do
+    %{"format" => Atom.to_string(format)}
+    |> maybe_put("row_type", output.row_type)
+    |> maybe_put("compression", output.compression)
+    |> maybe_put("key_order", output.key_order)
+    |> maybe_put("key_shape", output.key_shape)
+    |> maybe_put("omit_defaults", output.omit_defaults)
   end
+
+  defp maybe_put(map, _key, nil), do: map
+  defp maybe_put(map, key, value) when is_boolean(value), do: Map.put(map, key, value)
+  defp maybe_put(map, key, value), do: Map.put(map, key, Atom.to_string(value))
 end
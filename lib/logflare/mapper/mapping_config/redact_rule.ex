@@ -0,0 +1,82 @@
+defmodule Logflare.Mapper.MappingConfig.RedactRule do
+  @moduledoc """
+  A PII redaction rule for a mapping config.
+
+  Rules apply in order to every `string` field value, to each string inside
+  `json`, `flat_map` and array values, and to each value produced while
+  flattening a `flat_map`. A rule finds matches with either a built-in
+  `:detector` or a custom `:pattern` (Rust `regex` syntax):
+
+    * `"email"` — email addresses
+    * `"card_number"` — 13 to 19 digit card numbers passing the Luhn check,
+      optionally separated by spaces or dashes
+    * `"bearer_token"` — the token following `Bearer ` (the prefix is kept)
+    * `"ipv4"` / `"ipv6"` — IP addresses
+
+  A custom pattern with a `(?P<secret>...)` group only replaces that group,
+  e.g. `"api_key=(?P<secret>\\w+)"`.
+
+  `:action` decides what happens to each match:
+
+    * `"mask"` (default) — replaced by `:replacement`, `"[REDACTED]"` by default
+    * `"hash"` — replaced by a 16-hex-digit SipHash digest keyed with the
+      mapping's `:hash_key`, so equal values still group together but cannot be
+      recovered by hashing guesses. Requires `:hash_key`.
+    * `"drop"` — the whole value is discarded: string fields fall back to their
+      default, and map entries and list elements are removed
+  """
+
+  use TypedEctoSchema
+
+  import Ecto.Changeset
+
+  @derive Jason.Encoder
+
+  @detectors ~w(email card_number bearer_token ipv4 ipv6)
+  @actions ~w(mask hash drop)
+
+  @primary_key false
+  typed_embedded_schema do
+    field(:detector, :string)
+    field(:pattern, :string)
+    field(:action, :string, default: "mask")
+    field(:replacement, :string)
+  end
+
+  @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
+  def changeset(struct_or_changeset, attrs) do
+    struct_or_changeset
+    |> cast(attrs, [:detector, :pattern, :action, :replacement])
+    |> validate_inclusion(:detector, @detectors)
+    |> validate_inclusion(:action, @actions)
+    |> validate_matcher()
+  end
+
+  @spec to_nif_map(t()) :: map()
+  def to_nif_map(%__MODULE__{} = rule) do
+    [
+      {"detector", rule.detector},
+      {"pattern", rule.pattern},
+      {"action", rule.action},
+      {"replacement", rule.replacement}
+    ]
+    |> Enum.reject(fn {_key, value} -> is_nil(value) end)
+    |> Map.new()
+  end
+
+  defp validate_matcher(changeset) do
+    case {get_field(changeset, :detector), get_field(changeset, :pattern)} do
+      {nil, nil} ->
+        add_error(changeset, :detector, "requires one of detector or pattern")
+
+      {_detector, nil} ->
+        changeset
+
+      {nil, _pattern} ->
+        changeset
+
+      _both ->
+        add_error(changeset, :pattern, "cannot be combined with detector")
+    end
+  end
+end
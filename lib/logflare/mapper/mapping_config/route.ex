@@ -0,0 +1,36 @@
+defmodule Logflare.Mapper.MappingConfig.Route do
+  @moduledoc """
+  A mapping-level route: tags documents matching its conditions with `:name`.
+
+  Routes are evaluated in order against the input document and the first
+  match wins. A route matches when `(any is empty OR at least one matches) AND
+  (all is empty OR every one matches)`, like an `InferRule`, so a route without
+  conditions matches every document that reaches it.
+
+  See `Logflare.Mapper.map_routed/3`.
+  """
+
+  use TypedEctoSchema
+
+  import Ecto.Changeset
+
+  alias Logflare.Mapper.MappingConfig.InferCondition
+
+  @derive Jason.Encoder
+
+  @primary_key false
+  typed_embedded_schema do
+    field(:name, :string)
+    embeds_many(:any, InferCondition)
+    embeds_many(:all, InferCondition)
+  end
+
+  @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
+  def changeset(struct_or_changeset, attrs) do
+    struct_or_changeset
+    |> cast(attrs, [:name])
+    |> validate_required([:name])
+    |> cast_embed(:any, with: &InferCondition.changeset/2)
+    |> cast_embed(:all, with: &InferCondition.changeset/2)
+  end
+end
@@ -0,0 +1,52 @@
+defmodule Logflare.Mapper.MappingConfig.Sample do
+  @moduledoc """
+  Deterministic sampling for a mapping config.
+
+  The value at `:path` (e.g. `"$.trace_id"`) is hashed and the document is
+  kept when the hash falls within the keep `:rate`, a fraction between 0 and 1.
+  Every document sharing a key gets the same decision, so sampling on a trace
+  ID keeps or drops whole traces. Documents without a string or integer key
+  are kept.
+
+  `:severity_rates` overrides the rate by the value at `:severity_path`,
+  compared case-insensitively. `%{"error" => 1.0}` keeps all errors.
+
+  Sampled-out documents are dropped by `Logflare.Mapper.map_routed/3` before
+  any field is mapped.
+  """
+
+  use TypedEctoSchema
+
+  import Ecto.Changeset
+
+  @derive Jason.Encoder
+
+  @primary_key false
+  typed_embedded_schema do
+    field(:path, :string)
+    field(:rate, :float)
+    field(:severity_path, :string)
+    field(:severity_rates, {:map, :float}, default: %{})
+  end
+
+  @spec changeset(t() | Ecto.Changeset.t(), map()) :: Ecto.Changeset.t()
+  def changeset(struct_or_changeset, attrs) do
+    struct_or_changeset
+    |> cast(attrs, [:path, :rate, :severity_path, :severity_rates])
+    |> validate_required([:path, :rate])
+    |> validate_number(:rate, greater_than_or_equal_to: 0, less_than_or_equal_to: 1)
+  end
+
+  @spec to_nif_map(t()) :: map()
+  def to_nif_map(%__MODULE__{severity_path: nil} = sample),
+    do: %{"path" => sample.path, "rate" => sample.rate}
+
+  def to_nif_map(%__MODULE__{} = sample) do
+    %{
+      "path" => sample.path,
+      "rate" => sample.rate,
+      "severity_path" => sample.severity_path,
+      "severity_rates" => sample.severity_rates
+    }
+  end
+end
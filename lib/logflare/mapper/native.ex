@@ -6,10 +6,68 @@ defmodule Logflare.Mapper.Native do
   @spec compile_mapping(map()) :: {:ok, reference()} | {:error, String.t()}
   def compile_mapping(_config), do: :erlang.nif_error(:nif_not_loaded)
 
+  @spec export_mapping(reference()) :: binary()
+  def export_mapping(_compiled_mapping), do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec import_mapping(binary(), String.t() | nil) :: {:ok, reference()} | {:error, String.t()}
+  def import_mapping(_exported, _hash_key), do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec mapping_fingerprint(reference()) :: String.t()
+  def mapping_fingerprint(_compiled_mapping), do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec mapping_stats(reference()) :: map() | nil
+  def mapping_stats(_compiled_mapping), do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec diff_mappings(map(), map(), [map()], boolean()) :: {:ok, map()} | {:error, String.t()}
+  def diff_mappings(_old_config, _new_config, _documents, _flat_keys),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec lint_mapping(map()) :: {:ok, [map()]} | {:error, String.t()}
+  def lint_mapping(_config), do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec infer_mapping([map()], map()) :: {:ok, map()} | {:error, String.t()}
+  def infer_mapping(_documents, _options), do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec inspect_mapping(reference()) :: map()
+  def inspect_mapping(_compiled_mapping), do: :erlang.nif_error(:nif_not_loaded)
+
   @type map_options :: boolean() | {boolean(), Logflare.Mapper.OutputContext.t() | nil}
 
   @spec map(term(), reference(), map_options()) ::
-          map() | {:ok, binary()} | {:error, String.t()}
+          map() | {:ok, binary() | {binary(), [binary()]}} | {:error, String.t()}
   def map(_document, _compiled_mapping, _options \\ false),
     do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec map_json(binary(), reference(), map_options()) ::
+          map() | {:ok, binary() | {binary(), [binary()]}} | {:error, String.t()}
+  def map_json(_json, _compiled_mapping, _options \\ false),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec map_routed(term(), reference(), map_options()) ::
+          :drop | {String.t() | nil, term()} | {:error, String.t()}
+  def map_routed(_document, _compiled_mapping, _options \\ false),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec explain(term(), reference(), map_options()) :: {:ok, [map()]} | {:error, String.t()}
+  def explain(_document, _compiled_mapping, _options \\ false),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @type map_many_options :: boolean() | {boolean(), [Logflare.Mapper.OutputContext.t()]}
+
+  @spec map_many([term()], reference(), map_many_options()) ::
+          [map()] | {:ok, binary() | {binary(), [binary()]}} | {:error, String.t()}
+  def map_many(_documents, _compiled_mapping, _options \\ false),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec map_many_json([binary()], reference(), map_many_options()) ::
+          [map()] | {:ok, binary() | {binary(), [binary()]}} | {:error, String.t()}
+  def map_many_json(_jsons, _compiled_mapping, _options \\ false),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @spec map_many_routed([term()], reference(), map_many_options()) ::
+          [:drop | {String.t() | nil, map()}]
+          | {:ok, [{String.t() | nil, binary() | {binary(), [binary()]}}]}
+          | {:error, String.t()}
+  def map_many_routed(_documents, _compiled_mapping, _options \\ false),
+    do: :erlang.nif_error(:nif_not_loaded)
 end
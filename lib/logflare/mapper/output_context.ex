@@ -10,23 +10,32 @@ defmodule Logflare.Mapper.OutputContext do
   alias Logflare.LogEvent
 
   @opaque t() ::
-            {:clickhouse_row_binary, binary(), {binary(), binary(), binary(), integer() | nil}}
+            {:clickhouse_row_binary | :clickhouse_native, binary(),
+             {binary(), binary(), binary(), integer() | nil}}
 
   @doc "Builds the per-row context required by ClickHouse RowBinary output."
   @spec clickhouse_row_binary(LogEvent.t(), binary()) :: t()
-  def clickhouse_row_binary(
-        %LogEvent{
-          id: id,
-          source_uuid: source_uuid,
-          source_name: source_name,
-          ingested_at: ingested_at
-        },
-        mapping_config_id
-      )
+  def clickhouse_row_binary(%LogEvent{} = event, mapping_config_id)
       when is_binary(mapping_config_id) do
+    {:clickhouse_row_binary, mapping_config_id, envelope(event)}
+  end
+
+  @doc "Builds the per-row context required by ClickHouse Native output."
+  @spec clickhouse_native(LogEvent.t(), binary()) :: t()
+  def clickhouse_native(%LogEvent{} = event, mapping_config_id)
+      when is_binary(mapping_config_id) do
+    {:clickhouse_native, mapping_config_id, envelope(event)}
+  end
+
+  defp envelope(%LogEvent{
+         id: id,
+         source_uuid: source_uuid,
+         source_name: source_name,
+         ingested_at: ingested_at
+       }) do
     ingested_at = if ingested_at, do: DateTime.to_unix(ingested_at, :microsecond)
     source_uuid = if is_atom(source_uuid), do: Atom.to_string(source_uuid), else: source_uuid
 
-    {:clickhouse_row_binary, mapping_config_id, {id, source_uuid, source_name || "", ingested_at}}
+    {id, source_uuid, source_name || "", ingested_at}
   end
 end
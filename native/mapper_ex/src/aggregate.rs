@@ -0,0 +1,269 @@
+use std::collections::HashSet;
+
+use rustler::{Binary, Encoder, Env, Term};
+
+use crate::path::{self, CompiledPath};
+use crate::query::{self, QueryCache};
+
+/// A scalar summary of the values a wildcard path resolves to, e.g. the
+/// number of span events or the largest retry latency.
+#[derive(Debug)]
+pub struct Aggregate {
+    pub function: AggregateFn,
+    pub path: CompiledPath,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFn {
+    /// Non-nil values.
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    /// Whether any value is `true`.
+    Any,
+    /// Whether every value is `true`; vacuously true for an empty array.
+    All,
+    /// Distinct non-nil values.
+    DistinctCount,
+}
+
+impl AggregateFn {
+    pub fn parse(name: &str) -> Option<AggregateFn> {
+        match name {
+            "count" => Some(AggregateFn::Count),
+            "sum" => Some(AggregateFn::Sum),
+            "min" => Some(AggregateFn::Min),
+            "max" => Some(AggregateFn::Max),
+            "avg" => Some(AggregateFn::Avg),
+            "any" => Some(AggregateFn::Any),
+            "all" => Some(AggregateFn::All),
+            "distinct_count" => Some(AggregateFn::DistinctCount),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AggregateFn::Count => "count",
+            AggregateFn::Sum => "sum",
+            AggregateFn::Min => "min",
+            AggregateFn::Max => "max",
+            AggregateFn::Avg => "avg",
+            AggregateFn::Any => "any",
+            AggregateFn::All => "all",
+            AggregateFn::DistinctCount => "distinct_count",
+        }
+    }
+}
+
+/// Compiles an `aggregate` over `path`, which must contain a `[*]` wildcard.
+pub fn compile(function: &str, path_str: &str) -> Result<Aggregate, String> {
+    let function =
+        AggregateFn::parse(function).ok_or_else(|| format!("unknown aggregate: {}", function))?;
+    let path = path::compile(path_str)
+        .map_err(|e| format!("failed to compile path '{}': {}", path_str, e))?;
+    if path.wildcard_index.is_none() {
+        return Err(format!(
+            "aggregate path '{}' must contain a [*] wildcard",
+            path_str
+        ));
+    }
+    Ok(Aggregate { function, path })
+}
+
+/// Aggregates the values the path resolves to for each array element.
+/// Returns `None` when there is no value to report, i.e. `min`, `max` and
+/// `avg` over no numbers, or with flat keys, which have no arrays to expand.
+///
+/// Numeric aggregates read integers, floats and numeric strings and skip
+/// anything else.
+pub fn evaluate<'a>(
+    env: Env<'a>,
+    body: Term<'a>,
+    aggregate: &Aggregate,
+    nil: Term<'a>,
+    flat_keys: bool,
+    cache: &mut QueryCache<'a>,
+) -> Option<Term<'a>> {
+    let mut state = State::new(aggregate.function);
+    query::evaluate_wildcard_mapped(env, body, &aggregate.path, nil, flat_keys, cache, |value| {
+        if value != nil {
+            state.push(value);
+        }
+        None
+    })?;
+    state.finish(env)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn from_term(value: Term) -> Option<Number> {
+        if let Ok(i) = value.decode::<i64>() {
+            return Some(Number::Int(i));
+        }
+        if let Ok(f) = value.decode::<f64>() {
+            return Some(Number::Float(f));
+        }
+        let binary = value.decode::<Binary>().ok()?;
+        Number::parse(std::str::from_utf8(binary.as_slice()).ok()?)
+    }
+
+    fn parse(s: &str) -> Option<Number> {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Number::Int(i));
+        }
+        s.parse::<f64>()
+            .ok()
+            .filter(|f| f.is_finite())
+            .map(Number::Float)
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+
+    fn less_than(self, other: Number) -> bool {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a < b,
+            _ => self.as_f64() < other.as_f64(),
+        }
+    }
+
+    fn encode<'a>(self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Number::Int(i) => i.encode(env),
+            Number::Float(f) => f.encode(env),
+        }
+    }
+}
+
+/// A running sum that stays integral until a float is added or the total
+/// leaves the `i64` range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sum(Number);
+
+impl Sum {
+    fn add(&mut self, value: Number) {
+        self.0 = match (self.0, value) {
+            (Number::Int(a), Number::Int(b)) => match a.checked_add(b) {
+                Some(sum) => Number::Int(sum),
+                None => Number::Float(a as f64 + b as f64),
+            },
+            (a, b) => Number::Float(a.as_f64() + b.as_f64()),
+        };
+    }
+}
+
+enum State<'a> {
+    Count(u64),
+    Sum(Sum),
+    Min(Option<Number>),
+    Max(Option<Number>),
+    Avg(Sum, u64),
+    Any(bool),
+    All(bool),
+    Distinct(HashSet<Term<'a>>),
+}
+
+impl<'a> State<'a> {
+    fn new(function: AggregateFn) -> Self {
+        match function {
+            AggregateFn::Count => State::Count(0),
+            AggregateFn::Sum => State::Sum(Sum(Number::Int(0))),
+            AggregateFn::Min => State::Min(None),
+            AggregateFn::Max => State::Max(None),
+            AggregateFn::Avg => State::Avg(Sum(Number::Int(0)), 0),
+            AggregateFn::Any => State::Any(false),
+            AggregateFn::All => State::All(true),
+            AggregateFn::DistinctCount => State::Distinct(HashSet::new()),
+        }
+    }
+
+    /// Adds one non-nil value.
+    fn push(&mut self, value: Term<'a>) {
+        match self {
+            State::Count(count) => *count += 1,
+            State::Any(any) => *any |= is_true(value),
+            State::All(all) => *all &= is_true(value),
+            State::Distinct(seen) => {
+                seen.insert(value);
+            }
+            State::Sum(sum) => {
+                if let Some(number) = Number::from_term(value) {
+                    sum.add(number);
+                }
+            }
+            State::Avg(sum, count) => {
+                if let Some(number) = Number::from_term(value) {
+                    sum.add(number);
+                    *count += 1;
+                }
+            }
+            State::Min(min) => {
+                if let Some(number) = Number::from_term(value) {
+                    if min.is_none_or(|current| number.less_than(current)) {
+                        *min = Some(number);
+                    }
+                }
+            }
+            State::Max(max) => {
+                if let Some(number) = Number::from_term(value) {
+                    if max.is_none_or(|current| current.less_than(number)) {
+                        *max = Some(number);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(self, env: Env<'a>) -> Option<Term<'a>> {
+        match self {
+            State::Count(count) => Some(count.encode(env)),
+            State::Sum(Sum(sum)) => Some(sum.encode(env)),
+            State::Min(number) | State::Max(number) => number.map(|number| number.encode(env)),
+            State::Avg(_, 0) => None,
+            State::Avg(Sum(sum), count) => Some((sum.as_f64() / count as f64).encode(env)),
+            State::Any(result) | State::All(result) => Some(result.encode(env)),
+            State::Distinct(seen) => Some((seen.len() as u64).encode(env)),
+        }
+    }
+}
+
+fn is_true(value: Term) -> bool {
+    value.decode::<bool>().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_stay_integral_until_a_float_or_overflow() {
+        let mut sum = Sum(Number::Int(0));
+        sum.add(Number::Int(2));
+        sum.add(Number::Int(3));
+        assert_eq!(sum.0, Number::Int(5));
+
+        sum.add(Number::Float(0.5));
+        assert_eq!(sum.0, Number::Float(5.5));
+
+        let mut sum = Sum(Number::Int(i64::MAX));
+        sum.add(Number::Int(1));
+        assert_eq!(sum.0, Number::Float(i64::MAX as f64 + 1.0));
+
+        assert_eq!(Number::parse("42"), Some(Number::Int(42)));
+        assert_eq!(Number::parse("1.5"), Some(Number::Float(1.5)));
+        assert_eq!(Number::parse("NaN"), None);
+        assert!(Number::Int(1).less_than(Number::Float(1.5)));
+    }
+}
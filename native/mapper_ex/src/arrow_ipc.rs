@@ -0,0 +1,462 @@
+use std::sync::Arc;
+
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Float64Builder, Int32Builder, Int64Builder, Int8Builder, ListBuilder,
+    MapBuilder, StringBuilder, UInt32Builder, UInt64Builder, UInt8Builder,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::ipc::writer::{write_message, DictionaryTracker, IpcDataGenerator, IpcWriteOptions};
+use arrow::ipc::CompressionType;
+use arrow::record_batch::RecordBatch;
+use rustler::types::map::MapIterator;
+use rustler::{Binary, OwnedBinary, Term};
+
+use crate::clickhouse_rowbinary::{self, decode_f64, decode_i64, decode_u64, EncodeResult};
+use crate::mapping::{CompiledField, FieldType};
+
+/// Timezone attached to timestamp columns; matches the BigQuery IPC encoder.
+const TIMESTAMP_TIMEZONE: &str = "+00";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColumnKind {
+    String,
+    Json,
+    UInt8,
+    UInt32,
+    UInt64,
+    Int8,
+    Int32,
+    Float64,
+    Bool,
+    Timestamp(TimeUnit),
+    ListString,
+    ListJson,
+    ListUInt64,
+    ListFloat64,
+    ListTimestamp(TimeUnit),
+    Map,
+    ListMap,
+}
+
+/// Arrow schema and IPC options for one mapping, compiled once.
+///
+/// Every mapped field becomes one nullable column, in field order, typed from
+/// its `FieldType`. The schema message is encoded up front and reused for
+/// every batch.
+#[derive(Debug)]
+pub struct CompiledArrowLayout {
+    schema: SchemaRef,
+    kinds: Box<[ColumnKind]>,
+    schema_message: Vec<u8>,
+    write_options: IpcWriteOptions,
+}
+
+pub fn compile_layout(
+    fields: &[CompiledField],
+    compression: Compression,
+) -> EncodeResult<CompiledArrowLayout> {
+    let kinds = fields
+        .iter()
+        .map(|field| column_kind(field.field_type))
+        .collect::<EncodeResult<Box<[ColumnKind]>>>()?;
+
+    // Derive column types from empty builders so the schema always matches
+    // the arrays produced for each batch.
+    let schema_fields: Vec<Field> = fields
+        .iter()
+        .zip(kinds.iter())
+        .map(|(field, kind)| {
+            let data_type = ColumnBuilder::new(*kind).finish()?.data_type().clone();
+            Ok(Field::new(&field.name, data_type, true))
+        })
+        .collect::<EncodeResult<_>>()?;
+    let schema = Arc::new(Schema::new(schema_fields));
+
+    let compression = match compression {
+        Compression::None => None,
+        Compression::Lz4 => Some(CompressionType::LZ4_FRAME),
+        Compression::Zstd => Some(CompressionType::ZSTD),
+    };
+    let write_options = IpcWriteOptions::default()
+        .try_with_compression(compression)
+        .map_err(|error| format!("invalid Arrow IPC options: {error}"))?;
+
+    let mut dictionary_tracker = DictionaryTracker::new(false);
+    let schema_data = IpcDataGenerator::default().schema_to_bytes_with_dictionary_tracker(
+        &schema,
+        &mut dictionary_tracker,
+        &write_options,
+    );
+    let mut schema_message = Vec::new();
+    write_message(&mut schema_message, schema_data, &write_options)
+        .map_err(|error| format!("failed to encode Arrow schema: {error}"))?;
+
+    Ok(CompiledArrowLayout {
+        schema,
+        kinds,
+        schema_message,
+        write_options,
+    })
+}
+
+impl CompiledArrowLayout {
+    /// Encapsulated IPC schema message for this mapping's columns.
+    pub fn schema_message(&self) -> EncodeResult<OwnedBinary> {
+        owned_binary(&self.schema_message)
+    }
+}
+
+fn column_kind(field_type: FieldType) -> EncodeResult<ColumnKind> {
+    Ok(match field_type {
+        FieldType::String => ColumnKind::String,
+        FieldType::UInt8 => ColumnKind::UInt8,
+        FieldType::UInt32 => ColumnKind::UInt32,
+        FieldType::UInt64 => ColumnKind::UInt64,
+        FieldType::Int32 => ColumnKind::Int32,
+        FieldType::Float64 => ColumnKind::Float64,
+        FieldType::Bool => ColumnKind::Bool,
+        FieldType::Enum8 { .. } => ColumnKind::Int8,
+        FieldType::DateTime64 { precision } => ColumnKind::Timestamp(time_unit(precision)?),
+        FieldType::Json => ColumnKind::Json,
+        FieldType::ArrayString => ColumnKind::ListString,
+        FieldType::ArrayUInt64 => ColumnKind::ListUInt64,
+        FieldType::ArrayFloat64 => ColumnKind::ListFloat64,
+        FieldType::ArrayDateTime64 { precision } => {
+            ColumnKind::ListTimestamp(time_unit(precision)?)
+        }
+        FieldType::ArrayJson | FieldType::ArrayMap => ColumnKind::ListJson,
+        FieldType::FlatMap => ColumnKind::Map,
+        FieldType::ArrayFlatMap => ColumnKind::ListMap,
+    })
+}
+
+fn time_unit(precision: u8) -> EncodeResult<TimeUnit> {
+    match precision {
+        0 => Ok(TimeUnit::Second),
+        3 => Ok(TimeUnit::Millisecond),
+        6 => Ok(TimeUnit::Microsecond),
+        9 => Ok(TimeUnit::Nanosecond),
+        _ => Err(format!(
+            "Arrow IPC output does not support DateTime64 precision {precision} (supported: 0, 3, 6, 9)"
+        )),
+    }
+}
+
+fn timestamp_type(unit: TimeUnit) -> DataType {
+    DataType::Timestamp(unit, Some(TIMESTAMP_TIMEZONE.into()))
+}
+
+/// Typed Arrow builder for one column.
+///
+/// Timestamps are collected as Int64 values and cast to the timestamp type
+/// when the batch is finished; the cast only relabels the buffer.
+enum ColumnBuilder {
+    String(StringBuilder),
+    Json(StringBuilder),
+    UInt8(UInt8Builder),
+    UInt32(UInt32Builder),
+    UInt64(UInt64Builder),
+    Int8(Int8Builder),
+    Int32(Int32Builder),
+    Float64(Float64Builder),
+    Bool(BooleanBuilder),
+    Timestamp(Int64Builder, TimeUnit),
+    ListString(ListBuilder<StringBuilder>),
+    ListJson(ListBuilder<StringBuilder>),
+    ListUInt64(ListBuilder<UInt64Builder>),
+    ListFloat64(ListBuilder<Float64Builder>),
+    ListTimestamp(ListBuilder<Int64Builder>, TimeUnit),
+    Map(MapBuilder<StringBuilder, StringBuilder>),
+    ListMap(ListBuilder<MapBuilder<StringBuilder, StringBuilder>>),
+}
+
+fn string_map_builder() -> MapBuilder<StringBuilder, StringBuilder> {
+    MapBuilder::new(None, StringBuilder::new(), StringBuilder::new())
+}
+
+impl ColumnBuilder {
+    fn new(kind: ColumnKind) -> Self {
+        match kind {
+            ColumnKind::String => Self::String(StringBuilder::new()),
+            ColumnKind::Json => Self::Json(StringBuilder::new()),
+            ColumnKind::UInt8 => Self::UInt8(UInt8Builder::new()),
+            ColumnKind::UInt32 => Self::UInt32(UInt32Builder::new()),
+            ColumnKind::UInt64 => Self::UInt64(UInt64Builder::new()),
+            ColumnKind::Int8 => Self::Int8(Int8Builder::new()),
+            ColumnKind::Int32 => Self::Int32(Int32Builder::new()),
+            ColumnKind::Float64 => Self::Float64(Float64Builder::new()),
+            ColumnKind::Bool => Self::Bool(BooleanBuilder::new()),
+            ColumnKind::Timestamp(unit) => Self::Timestamp(Int64Builder::new(), unit),
+            ColumnKind::ListString => Self::ListString(ListBuilder::new(StringBuilder::new())),
+            ColumnKind::ListJson => Self::ListJson(ListBuilder::new(StringBuilder::new())),
+            ColumnKind::ListUInt64 => Self::ListUInt64(ListBuilder::new(UInt64Builder::new())),
+            ColumnKind::ListFloat64 => Self::ListFloat64(ListBuilder::new(Float64Builder::new())),
+            ColumnKind::ListTimestamp(unit) => {
+                Self::ListTimestamp(ListBuilder::new(Int64Builder::new()), unit)
+            }
+            ColumnKind::Map => Self::Map(string_map_builder()),
+            ColumnKind::ListMap => Self::ListMap(ListBuilder::new(string_map_builder())),
+        }
+    }
+
+    fn append<'a>(&mut self, value: Term<'a>, nil: Term<'a>) -> EncodeResult<()> {
+        if value == nil {
+            return self.append_null();
+        }
+
+        match self {
+            Self::String(builder) => builder.append_value(decode_str(value)?),
+            Self::Json(builder) => builder.append_value(json_text(value, nil)),
+            Self::UInt8(builder) => builder.append_value(
+                u8::try_from(decode_u64(value)?)
+                    .map_err(|_| "mapped UInt8 field is out of range".to_string())?,
+            ),
+            Self::UInt32(builder) => builder.append_value(
+                u32::try_from(decode_u64(value)?)
+                    .map_err(|_| "mapped UInt32 field is out of range".to_string())?,
+            ),
+            Self::UInt64(builder) => builder.append_value(decode_u64(value)?),
+            Self::Int8(builder) => builder.append_value(
+                i8::try_from(decode_i64(value)?)
+                    .map_err(|_| "mapped Int8 field is out of range".to_string())?,
+            ),
+            Self::Int32(builder) => builder.append_value(
+                i32::try_from(decode_i64(value)?)
+                    .map_err(|_| "mapped Int32 field is out of range".to_string())?,
+            ),
+            Self::Float64(builder) => builder.append_value(decode_f64(value)?),
+            Self::Bool(builder) => builder.append_value(
+                value
+                    .decode::<bool>()
+                    .map_err(|_| "mapped boolean field is not a boolean".to_string())?,
+            ),
+            Self::Timestamp(builder, _) => builder.append_value(decode_i64(value)?),
+            Self::ListString(builder) => {
+                for element in clickhouse_rowbinary::list(value)?.1 {
+                    builder.values().append_value(decode_str(element)?);
+                }
+                builder.append(true);
+            }
+            Self::ListJson(builder) => {
+                for element in clickhouse_rowbinary::list(value)?.1 {
+                    builder.values().append_value(json_text(element, nil));
+                }
+                builder.append(true);
+            }
+            Self::ListUInt64(builder) => {
+                for element in clickhouse_rowbinary::list(value)?.1 {
+                    builder.values().append_value(decode_u64(element)?);
+                }
+                builder.append(true);
+            }
+            Self::ListFloat64(builder) => {
+                for element in clickhouse_rowbinary::list(value)?.1 {
+                    builder.values().append_value(decode_f64(element)?);
+                }
+                builder.append(true);
+            }
+            Self::ListTimestamp(builder, _) => {
+                for element in clickhouse_rowbinary::list(value)?.1 {
+                    builder.values().append_value(decode_i64(element)?);
+                }
+                builder.append(true);
+            }
+            Self::Map(builder) => append_string_map(builder, value)?,
+            Self::ListMap(builder) => {
+                for element in clickhouse_rowbinary::list(value)?.1 {
+                    append_string_map(builder.values(), element)?;
+                }
+                builder.append(true);
+            }
+        }
+        Ok(())
+    }
+
+    fn append_null(&mut self) -> EncodeResult<()> {
+        match self {
+            Self::String(builder) | Self::Json(builder) => builder.append_null(),
+            Self::UInt8(builder) => builder.append_null(),
+            Self::UInt32(builder) => builder.append_null(),
+            Self::UInt64(builder) => builder.append_null(),
+            Self::Int8(builder) => builder.append_null(),
+            Self::Int32(builder) => builder.append_null(),
+            Self::Float64(builder) => builder.append_null(),
+            Self::Bool(builder) => builder.append_null(),
+            Self::Timestamp(builder, _) => builder.append_null(),
+            Self::ListString(builder) | Self::ListJson(builder) => builder.append_null(),
+            Self::ListUInt64(builder) => builder.append_null(),
+            Self::ListFloat64(builder) => builder.append_null(),
+            Self::ListTimestamp(builder, _) => builder.append_null(),
+            Self::Map(builder) => builder
+                .append(false)
+                .map_err(|error| format!("failed to build Arrow map: {error}"))?,
+            Self::ListMap(builder) => builder.append_null(),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> EncodeResult<ArrayRef> {
+        let array: ArrayRef = match &mut self {
+            Self::String(builder) | Self::Json(builder) => Arc::new(builder.finish()),
+            Self::UInt8(builder) => Arc::new(builder.finish()),
+            Self::UInt32(builder) => Arc::new(builder.finish()),
+            Self::UInt64(builder) => Arc::new(builder.finish()),
+            Self::Int8(builder) => Arc::new(builder.finish()),
+            Self::Int32(builder) => Arc::new(builder.finish()),
+            Self::Float64(builder) => Arc::new(builder.finish()),
+            Self::Bool(builder) => Arc::new(builder.finish()),
+            Self::Timestamp(builder, unit) => {
+                return cast_array(Arc::new(builder.finish()), &timestamp_type(*unit))
+            }
+            Self::ListString(builder) | Self::ListJson(builder) => Arc::new(builder.finish()),
+            Self::ListUInt64(builder) => Arc::new(builder.finish()),
+            Self::ListFloat64(builder) => Arc::new(builder.finish()),
+            Self::ListTimestamp(builder, unit) => {
+                let item = Field::new_list_field(timestamp_type(*unit), true);
+                return cast_array(Arc::new(builder.finish()), &DataType::List(Arc::new(item)));
+            }
+            Self::Map(builder) => Arc::new(builder.finish()),
+            Self::ListMap(builder) => Arc::new(builder.finish()),
+        };
+        Ok(array)
+    }
+}
+
+fn cast_array(array: ArrayRef, data_type: &DataType) -> EncodeResult<ArrayRef> {
+    cast(&array, data_type).map_err(|error| format!("failed to build Arrow column: {error}"))
+}
+
+fn append_string_map(
+    builder: &mut MapBuilder<StringBuilder, StringBuilder>,
+    value: Term,
+) -> EncodeResult<()> {
+    let entries =
+        MapIterator::new(value).ok_or_else(|| "mapped FlatMap field is not a map".to_string())?;
+    for (key, value) in entries {
+        builder.keys().append_value(decode_str(key)?);
+        builder.values().append_value(decode_str(value)?);
+    }
+    builder
+        .append(true)
+        .map_err(|error| format!("failed to build Arrow map: {error}"))
+}
+
+fn decode_str<'a>(value: Term<'a>) -> EncodeResult<&'a str> {
+    let binary = value
+        .decode::<Binary>()
+        .map_err(|_| "mapped string field is not a binary".to_string())?;
+    std::str::from_utf8(binary.as_slice())
+        .map_err(|_| "mapped string field is not valid UTF-8".to_string())
+}
+
+fn json_text<'a>(value: Term<'a>, nil: Term<'a>) -> String {
+    crate::mapper::term_to_json_string(value, nil, "null")
+}
+
+/// Accumulates mapped rows into one Arrow record batch.
+pub struct ArrowBatchBuilder<'layout> {
+    layout: &'layout CompiledArrowLayout,
+    columns: Vec<ColumnBuilder>,
+    rows: usize,
+}
+
+impl<'layout> ArrowBatchBuilder<'layout> {
+    pub fn new(layout: &'layout CompiledArrowLayout) -> Self {
+        Self {
+            layout,
+            columns: layout
+                .kinds
+                .iter()
+                .map(|kind| ColumnBuilder::new(*kind))
+                .collect(),
+            rows: 0,
+        }
+    }
+
+    pub fn append_row<'a>(&mut self, values: &[Term<'a>], nil: Term<'a>) -> EncodeResult<()> {
+        if values.len() != self.columns.len() {
+            return Err(format!(
+                "expected {} mapped values, got {}",
+                self.columns.len(),
+                values.len()
+            ));
+        }
+        for (column, value) in self.columns.iter_mut().zip(values) {
+            column.append(*value, nil)?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Encodes the rows as one IPC record batch message, or `None` when no
+    /// rows were appended.
+    pub fn finish(self) -> EncodeResult<Option<OwnedBinary>> {
+        if self.rows == 0 {
+            return Ok(None);
+        }
+
+        let columns = self
+            .columns
+            .into_iter()
+            .map(ColumnBuilder::finish)
+            .collect::<EncodeResult<Vec<ArrayRef>>>()?;
+        let batch = RecordBatch::try_new(self.layout.schema.clone(), columns)
+            .map_err(|error| format!("failed to build Arrow record batch: {error}"))?;
+
+        let mut dictionary_tracker = DictionaryTracker::new(false);
+        let (_, encoded_batch) = IpcDataGenerator::default()
+            .encoded_batch(&batch, &mut dictionary_tracker, &self.layout.write_options)
+            .map_err(|error| format!("failed to encode Arrow record batch: {error}"))?;
+        let mut message = Vec::new();
+        write_message(&mut message, encoded_batch, &self.layout.write_options)
+            .map_err(|error| format!("failed to encode Arrow record batch: {error}"))?;
+
+        owned_binary(&message).map(Some)
+    }
+}
+
+fn owned_binary(bytes: &[u8]) -> EncodeResult<OwnedBinary> {
+    let mut binary = OwnedBinary::new(bytes.len())
+        .ok_or_else(|| "failed to allocate Arrow IPC output".to_string())?;
+    binary.as_mut_slice().copy_from_slice(bytes);
+    Ok(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datetime_precisions_map_to_arrow_time_units() {
+        assert_eq!(time_unit(0), Ok(TimeUnit::Second));
+        assert_eq!(time_unit(3), Ok(TimeUnit::Millisecond));
+        assert_eq!(time_unit(6), Ok(TimeUnit::Microsecond));
+        assert_eq!(time_unit(9), Ok(TimeUnit::Nanosecond));
+        assert!(time_unit(4).is_err());
+    }
+
+    #[test]
+    fn empty_builders_produce_schema_types() {
+        let timestamp = ColumnBuilder::new(ColumnKind::ListTimestamp(TimeUnit::Nanosecond))
+            .finish()
+            .unwrap();
+        assert_eq!(
+            timestamp.data_type(),
+            &DataType::List(Arc::new(Field::new_list_field(
+                timestamp_type(TimeUnit::Nanosecond),
+                true
+            )))
+        );
+
+        let map = ColumnBuilder::new(ColumnKind::Map).finish().unwrap();
+        assert!(matches!(map.data_type(), DataType::Map(_, false)));
+    }
+}
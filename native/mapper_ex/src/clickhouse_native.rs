@@ -0,0 +1,733 @@
+use std::collections::HashMap;
+
+use rustler::types::map::MapIterator;
+use rustler::{Binary, OwnedBinary, Term};
+
+use crate::clickhouse_rowbinary::{
+    self, encode_bool, encode_bytes, encode_float64, encode_int32, encode_int64, encode_int8,
+    encode_string, encode_uint32, encode_uint64, encode_uint8, encode_uuid, encode_varuint,
+    log_severity, trace_duration, EncodeResult, RowEnvelope,
+};
+use crate::mapping::{CompiledField, FieldType};
+
+/// ClickHouse's default `max_compress_block_size`; larger blocks are split.
+const MAX_COMPRESS_BLOCK_SIZE: usize = 1024 * 1024;
+const COMPRESSION_METHOD_LZ4: u8 = 0x82;
+const COMPRESSED_HEADER_SIZE: usize = 9;
+const CHECKSUM_SIZE: usize = 16;
+const INGESTED_AT_PRECISION: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColumnKind {
+    Uuid,
+    String,
+    UInt8,
+    UInt32,
+    UInt64,
+    Int8,
+    Int32,
+    Int64,
+    Float64,
+    Bool,
+    NullableInt64,
+    MapStringString,
+    ArrayString,
+    ArrayUInt64,
+    ArrayInt64,
+    ArrayFloat64,
+    ArrayMapStringString,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColumnSource {
+    Id,
+    SourceUuid,
+    SourceName,
+    MappingConfigId,
+    IngestedAt,
+    Field(usize),
+    LogSeverity {
+        alt: usize,
+        mapped: usize,
+    },
+    TraceDuration {
+        duration: usize,
+        start_time: usize,
+        end_time: usize,
+    },
+}
+
+const LOG_COLUMNS: &[(&str, ColumnKind)] = &[
+    ("id", ColumnKind::Uuid),
+    ("source_uuid", ColumnKind::String),
+    ("source_name", ColumnKind::String),
+    ("project", ColumnKind::String),
+    ("trace_id", ColumnKind::String),
+    ("span_id", ColumnKind::String),
+    ("trace_flags", ColumnKind::UInt8),
+    ("severity_text", ColumnKind::String),
+    ("severity_number", ColumnKind::UInt8),
+    ("service_name", ColumnKind::String),
+    ("event_message", ColumnKind::String),
+    ("scope_name", ColumnKind::String),
+    ("scope_version", ColumnKind::String),
+    ("scope_schema_url", ColumnKind::String),
+    ("resource_schema_url", ColumnKind::String),
+    ("resource_attributes", ColumnKind::MapStringString),
+    ("scope_attributes", ColumnKind::MapStringString),
+    ("log_attributes", ColumnKind::MapStringString),
+    ("mapping_config_id", ColumnKind::Uuid),
+    ("ingested_at", ColumnKind::NullableInt64),
+    ("timestamp", ColumnKind::Int64),
+];
+
+const METRIC_COLUMNS: &[(&str, ColumnKind)] = &[
+    ("id", ColumnKind::Uuid),
+    ("source_uuid", ColumnKind::String),
+    ("source_name", ColumnKind::String),
+    ("project", ColumnKind::String),
+    ("time_unix", ColumnKind::NullableInt64),
+    ("start_time_unix", ColumnKind::NullableInt64),
+    ("metric_name", ColumnKind::String),
+    ("metric_description", ColumnKind::String),
+    ("metric_unit", ColumnKind::String),
+    ("metric_type", ColumnKind::Int8),
+    ("service_name", ColumnKind::String),
+    ("event_message", ColumnKind::String),
+    ("scope_name", ColumnKind::String),
+    ("scope_version", ColumnKind::String),
+    ("scope_schema_url", ColumnKind::String),
+    ("resource_schema_url", ColumnKind::String),
+    ("resource_attributes", ColumnKind::MapStringString),
+    ("scope_attributes", ColumnKind::MapStringString),
+    ("attributes", ColumnKind::MapStringString),
+    ("aggregation_temporality", ColumnKind::String),
+    ("is_monotonic", ColumnKind::Bool),
+    ("flags", ColumnKind::UInt32),
+    ("value", ColumnKind::Float64),
+    ("count", ColumnKind::UInt64),
+    ("sum", ColumnKind::Float64),
+    ("min", ColumnKind::Float64),
+    ("max", ColumnKind::Float64),
+    ("scale", ColumnKind::Int32),
+    ("zero_count", ColumnKind::UInt64),
+    ("positive_offset", ColumnKind::Int32),
+    ("negative_offset", ColumnKind::Int32),
+    ("bucket_counts", ColumnKind::ArrayUInt64),
+    ("explicit_bounds", ColumnKind::ArrayFloat64),
+    ("positive_bucket_counts", ColumnKind::ArrayUInt64),
+    ("negative_bucket_counts", ColumnKind::ArrayUInt64),
+    ("quantile_values", ColumnKind::ArrayFloat64),
+    ("quantiles", ColumnKind::ArrayFloat64),
+    (
+        "exemplars.filtered_attributes",
+        ColumnKind::ArrayMapStringString,
+    ),
+    ("exemplars.time_unix", ColumnKind::ArrayInt64),
+    ("exemplars.value", ColumnKind::ArrayFloat64),
+    ("exemplars.span_id", ColumnKind::ArrayString),
+    ("exemplars.trace_id", ColumnKind::ArrayString),
+    ("mapping_config_id", ColumnKind::Uuid),
+    ("ingested_at", ColumnKind::NullableInt64),
+    ("timestamp", ColumnKind::Int64),
+];
+
+const TRACE_COLUMNS: &[(&str, ColumnKind)] = &[
+    ("id", ColumnKind::Uuid),
+    ("source_uuid", ColumnKind::String),
+    ("source_name", ColumnKind::String),
+    ("project", ColumnKind::String),
+    ("trace_id", ColumnKind::String),
+    ("span_id", ColumnKind::String),
+    ("parent_span_id", ColumnKind::String),
+    ("trace_state", ColumnKind::String),
+    ("span_name", ColumnKind::String),
+    ("span_kind", ColumnKind::String),
+    ("service_name", ColumnKind::String),
+    ("event_message", ColumnKind::String),
+    ("duration", ColumnKind::UInt64),
+    ("status_code", ColumnKind::String),
+    ("status_message", ColumnKind::String),
+    ("scope_name", ColumnKind::String),
+    ("scope_version", ColumnKind::String),
+    ("resource_attributes", ColumnKind::MapStringString),
+    ("span_attributes", ColumnKind::MapStringString),
+    ("events.timestamp", ColumnKind::ArrayInt64),
+    ("events.name", ColumnKind::ArrayString),
+    ("events.attributes", ColumnKind::ArrayMapStringString),
+    ("links.trace_id", ColumnKind::ArrayString),
+    ("links.span_id", ColumnKind::ArrayString),
+    ("links.trace_state", ColumnKind::ArrayString),
+    ("links.attributes", ColumnKind::ArrayMapStringString),
+    ("mapping_config_id", ColumnKind::Uuid),
+    ("ingested_at", ColumnKind::NullableInt64),
+    ("timestamp", ColumnKind::Int64),
+];
+
+#[derive(Debug)]
+struct CompiledColumn {
+    name: &'static str,
+    type_name: String,
+    kind: ColumnKind,
+    source: ColumnSource,
+}
+
+/// Column layout for one ClickHouse OTel table, compiled once per mapping.
+///
+/// Field types are validated by the RowBinary layout compiler, so a mapping
+/// accepted here produces the same rows in either format.
+#[derive(Debug)]
+pub struct CompiledNativeLayout {
+    columns: Box<[CompiledColumn]>,
+    compression: Compression,
+}
+
+pub fn compile_layout(
+    row_type: &str,
+    fields: &[CompiledField],
+    compression: Compression,
+) -> EncodeResult<CompiledNativeLayout> {
+    let fields_by_name: HashMap<&str, (usize, FieldType)> = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| (field.name.as_str(), (index, field.field_type)))
+        .collect();
+    clickhouse_rowbinary::compile_layout(row_type, &fields_by_name)?;
+
+    let definitions = match row_type {
+        "log" => LOG_COLUMNS,
+        "metric" => METRIC_COLUMNS,
+        "trace" => TRACE_COLUMNS,
+        _ => return Err(format!("unsupported ClickHouse row type '{row_type}'")),
+    };
+
+    let index_of = |name: &str| {
+        fields_by_name
+            .get(name)
+            .map(|(index, _)| *index)
+            .ok_or_else(|| {
+                format!("compiled mapping is missing required ClickHouse field '{name}'")
+            })
+    };
+
+    let columns = definitions
+        .iter()
+        .map(|(name, kind)| {
+            let source = match (row_type, *name) {
+                (_, "id") => ColumnSource::Id,
+                (_, "source_uuid") => ColumnSource::SourceUuid,
+                (_, "source_name") => ColumnSource::SourceName,
+                (_, "mapping_config_id") => ColumnSource::MappingConfigId,
+                (_, "ingested_at") => ColumnSource::IngestedAt,
+                ("log", "severity_number") => ColumnSource::LogSeverity {
+                    alt: index_of("severity_number_alt")?,
+                    mapped: index_of("severity_number")?,
+                },
+                ("trace", "duration") => ColumnSource::TraceDuration {
+                    duration: index_of("duration")?,
+                    start_time: index_of("start_time")?,
+                    end_time: index_of("end_time")?,
+                },
+                _ => ColumnSource::Field(index_of(name)?),
+            };
+            let field = match source {
+                ColumnSource::Field(index) => fields.get(index),
+                _ => None,
+            };
+            Ok(CompiledColumn {
+                name,
+                type_name: column_type_name(*kind, field),
+                kind: *kind,
+                source,
+            })
+        })
+        .collect::<EncodeResult<Box<[CompiledColumn]>>>()?;
+
+    Ok(CompiledNativeLayout {
+        columns,
+        compression,
+    })
+}
+
+/// Renders the declared ClickHouse type for a column.
+///
+/// `LowCardinality` wrappers are omitted: inserts are sent with
+/// `low_cardinality_allow_in_native_format=0`, so the server converts plain
+/// columns into the table's LowCardinality types.
+fn column_type_name(kind: ColumnKind, field: Option<&CompiledField>) -> String {
+    let precision = match field.map(|field| field.field_type) {
+        Some(FieldType::DateTime64 { precision })
+        | Some(FieldType::ArrayDateTime64 { precision }) => precision,
+        _ => INGESTED_AT_PRECISION,
+    };
+
+    match kind {
+        ColumnKind::Uuid => "UUID".to_string(),
+        ColumnKind::String => "String".to_string(),
+        ColumnKind::UInt8 => "UInt8".to_string(),
+        ColumnKind::UInt32 => "UInt32".to_string(),
+        ColumnKind::UInt64 => "UInt64".to_string(),
+        ColumnKind::Int8 => enum8_type_name(field),
+        ColumnKind::Int32 => "Int32".to_string(),
+        ColumnKind::Int64 => format!("DateTime64({precision})"),
+        ColumnKind::Float64 => "Float64".to_string(),
+        ColumnKind::Bool => "Bool".to_string(),
+        ColumnKind::NullableInt64 => format!("Nullable(DateTime64({precision}))"),
+        ColumnKind::MapStringString => "Map(String, String)".to_string(),
+        ColumnKind::ArrayString => "Array(String)".to_string(),
+        ColumnKind::ArrayUInt64 => "Array(UInt64)".to_string(),
+        ColumnKind::ArrayInt64 => format!("Array(DateTime64({precision}))"),
+        ColumnKind::ArrayFloat64 => "Array(Float64)".to_string(),
+        ColumnKind::ArrayMapStringString => "Array(Map(String, String))".to_string(),
+    }
+}
+
+fn enum8_type_name(field: Option<&CompiledField>) -> String {
+    let Some(enum8) = field.and_then(|field| field.enum8_data.as_ref()) else {
+        return "Int8".to_string();
+    };
+    if enum8.value_map.is_empty() {
+        return "Int8".to_string();
+    }
+
+    let mut values: Vec<(&String, &i8)> = enum8.value_map.iter().collect();
+    values.sort_by_key(|(label, value)| (**value, label.as_str()));
+    let values = values
+        .into_iter()
+        .map(|(label, value)| format!("'{}' = {value}", label.replace('\'', "\\'")))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("Enum8({values})")
+}
+
+struct Offsets {
+    bytes: Vec<u8>,
+    total: u64,
+}
+
+impl Offsets {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            total: 0,
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, length: usize) {
+        self.total += length as u64;
+        self.bytes.extend_from_slice(&self.total.to_le_bytes());
+    }
+}
+
+/// Native column storage. Composite types are serialized as their nested
+/// streams: offsets first, then the flattened element columns.
+enum ColumnData {
+    Values(Vec<u8>),
+    Nullable {
+        null_map: Vec<u8>,
+        values: Vec<u8>,
+    },
+    Array {
+        offsets: Offsets,
+        values: Vec<u8>,
+    },
+    Map {
+        offsets: Offsets,
+        keys: Vec<u8>,
+        values: Vec<u8>,
+    },
+    ArrayMap {
+        offsets: Offsets,
+        map_offsets: Offsets,
+        keys: Vec<u8>,
+        values: Vec<u8>,
+    },
+}
+
+impl ColumnData {
+    fn new(kind: ColumnKind) -> Self {
+        match kind {
+            ColumnKind::NullableInt64 => Self::Nullable {
+                null_map: Vec::new(),
+                values: Vec::new(),
+            },
+            ColumnKind::MapStringString => Self::Map {
+                offsets: Offsets::new(),
+                keys: Vec::new(),
+                values: Vec::new(),
+            },
+            ColumnKind::ArrayString
+            | ColumnKind::ArrayUInt64
+            | ColumnKind::ArrayInt64
+            | ColumnKind::ArrayFloat64 => Self::Array {
+                offsets: Offsets::new(),
+                values: Vec::new(),
+            },
+            ColumnKind::ArrayMapStringString => Self::ArrayMap {
+                offsets: Offsets::new(),
+                map_offsets: Offsets::new(),
+                keys: Vec::new(),
+                values: Vec::new(),
+            },
+            _ => Self::Values(Vec::new()),
+        }
+    }
+
+    fn write_to(&self, output: &mut Vec<u8>) {
+        match self {
+            Self::Values(values) => output.extend_from_slice(values),
+            Self::Nullable { null_map, values } => {
+                output.extend_from_slice(null_map);
+                output.extend_from_slice(values);
+            }
+            Self::Array { offsets, values } => {
+                output.extend_from_slice(&offsets.bytes);
+                output.extend_from_slice(values);
+            }
+            Self::Map {
+                offsets,
+                keys,
+                values,
+            } => {
+                output.extend_from_slice(&offsets.bytes);
+                output.extend_from_slice(keys);
+                output.extend_from_slice(values);
+            }
+            Self::ArrayMap {
+                offsets,
+                map_offsets,
+                keys,
+                values,
+            } => {
+                output.extend_from_slice(&offsets.bytes);
+                output.extend_from_slice(&map_offsets.bytes);
+                output.extend_from_slice(keys);
+                output.extend_from_slice(values);
+            }
+        }
+    }
+}
+
+/// Accumulates mapped rows column by column and serializes one Native block.
+pub struct NativeBlockBuilder<'layout> {
+    layout: &'layout CompiledNativeLayout,
+    columns: Vec<ColumnData>,
+    rows: usize,
+}
+
+impl<'layout> NativeBlockBuilder<'layout> {
+    pub fn new(layout: &'layout CompiledNativeLayout) -> Self {
+        Self {
+            layout,
+            columns: layout
+                .columns
+                .iter()
+                .map(|column| ColumnData::new(column.kind))
+                .collect(),
+            rows: 0,
+        }
+    }
+
+    pub fn append_row(
+        &mut self,
+        values: &[Term],
+        envelope: RowEnvelope,
+        mapping_config_id: Binary,
+    ) -> EncodeResult<()> {
+        if mapping_config_id.len() != 16 {
+            return Err("mapping config ID must be a 16-byte encoded UUID".to_string());
+        }
+
+        for (column, data) in self.layout.columns.iter().zip(self.columns.iter_mut()) {
+            let field = |index: usize| {
+                values.get(index).copied().ok_or_else(|| {
+                    format!(
+                        "compiled mapping did not produce ClickHouse field '{}'",
+                        column.name
+                    )
+                })
+            };
+
+            match (column.source, data) {
+                (ColumnSource::Id, ColumnData::Values(output)) => {
+                    encode_uuid(output, envelope.id.as_slice())?
+                }
+                (ColumnSource::SourceUuid, ColumnData::Values(output)) => {
+                    encode_bytes(output, envelope.source_uuid.as_slice())?
+                }
+                (ColumnSource::SourceName, ColumnData::Values(output)) => {
+                    encode_bytes(output, envelope.source_name.as_slice())?
+                }
+                (ColumnSource::MappingConfigId, ColumnData::Values(output)) => {
+                    output.extend_from_slice(mapping_config_id.as_slice())
+                }
+                (ColumnSource::IngestedAt, ColumnData::Nullable { null_map, values }) => {
+                    push_nullable(null_map, values, envelope.ingested_at)
+                }
+                (ColumnSource::LogSeverity { alt, mapped }, ColumnData::Values(output)) => {
+                    output.push(log_severity(field(alt)?, field(mapped)?)?)
+                }
+                (
+                    ColumnSource::TraceDuration {
+                        duration,
+                        start_time,
+                        end_time,
+                    },
+                    ColumnData::Values(output),
+                ) => {
+                    let duration =
+                        trace_duration(field(duration)?, field(start_time)?, field(end_time)?)?;
+                    output.extend_from_slice(&duration.to_le_bytes())
+                }
+                (ColumnSource::Field(index), data) => {
+                    append_value(data, column.kind, field(index)?)?
+                }
+                _ => {
+                    return Err(format!(
+                        "ClickHouse Native column '{}' has an incompatible source",
+                        column.name
+                    ))
+                }
+            }
+        }
+
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Serializes the block, applying the layout's compression framing.
+    pub fn finish(self) -> EncodeResult<OwnedBinary> {
+        if self.rows == 0 {
+            return owned_binary(&[]);
+        }
+
+        let mut block = Vec::with_capacity(self.estimated_size());
+        encode_varuint(&mut block, self.columns.len() as u64)?;
+        encode_varuint(&mut block, self.rows as u64)?;
+        for (column, data) in self.layout.columns.iter().zip(&self.columns) {
+            encode_bytes(&mut block, column.name.as_bytes())?;
+            encode_bytes(&mut block, column.type_name.as_bytes())?;
+            data.write_to(&mut block);
+        }
+
+        match self.layout.compression {
+            Compression::None => owned_binary(&block),
+            Compression::Lz4 => owned_binary(&compress_lz4(&block)),
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        self.columns
+            .iter()
+            .map(|data| match data {
+                ColumnData::Values(values) => values.len(),
+                ColumnData::Nullable { null_map, values } => null_map.len() + values.len(),
+                ColumnData::Array { offsets, values } => offsets.bytes.len() + values.len(),
+                ColumnData::Map {
+                    offsets,
+                    keys,
+                    values,
+                } => offsets.bytes.len() + keys.len() + values.len(),
+                ColumnData::ArrayMap {
+                    offsets,
+                    map_offsets,
+                    keys,
+                    values,
+                } => offsets.bytes.len() + map_offsets.bytes.len() + keys.len() + values.len(),
+            })
+            .sum::<usize>()
+            + self.layout.columns.len() * 64
+    }
+}
+
+fn append_value(data: &mut ColumnData, kind: ColumnKind, value: Term) -> EncodeResult<()> {
+    match (kind, data) {
+        (ColumnKind::String, ColumnData::Values(output)) => encode_string(output, value),
+        (ColumnKind::UInt8, ColumnData::Values(output)) => encode_uint8(output, value),
+        (ColumnKind::UInt32, ColumnData::Values(output)) => encode_uint32(output, value),
+        (ColumnKind::UInt64, ColumnData::Values(output)) => encode_uint64(output, value),
+        (ColumnKind::Int8, ColumnData::Values(output)) => encode_int8(output, value),
+        (ColumnKind::Int32, ColumnData::Values(output)) => encode_int32(output, value),
+        (ColumnKind::Int64, ColumnData::Values(output)) => encode_int64(output, value),
+        (ColumnKind::Float64, ColumnData::Values(output)) => encode_float64(output, value),
+        (ColumnKind::Bool, ColumnData::Values(output)) => encode_bool(output, value),
+        (ColumnKind::NullableInt64, ColumnData::Nullable { null_map, values }) => {
+            let value = value.decode::<Option<i64>>().map_err(|_| {
+                "mapped nullable Int64 field is neither nil nor an integer".to_string()
+            })?;
+            push_nullable(null_map, values, value);
+            Ok(())
+        }
+        (
+            ColumnKind::MapStringString,
+            ColumnData::Map {
+                offsets,
+                keys,
+                values,
+            },
+        ) => append_map(offsets, keys, values, value),
+        (kind, ColumnData::Array { offsets, values }) => {
+            let (length, elements) = clickhouse_rowbinary::list(value)?;
+            offsets.push(length);
+            for element in elements {
+                match kind {
+                    ColumnKind::ArrayString => encode_string(values, element)?,
+                    ColumnKind::ArrayUInt64 => encode_uint64(values, element)?,
+                    ColumnKind::ArrayInt64 => encode_int64(values, element)?,
+                    _ => encode_float64(values, element)?,
+                }
+            }
+            Ok(())
+        }
+        (
+            ColumnKind::ArrayMapStringString,
+            ColumnData::ArrayMap {
+                offsets,
+                map_offsets,
+                keys,
+                values,
+            },
+        ) => {
+            let (length, elements) = clickhouse_rowbinary::list(value)?;
+            offsets.push(length);
+            for element in elements {
+                append_map(map_offsets, keys, values, element)?;
+            }
+            Ok(())
+        }
+        _ => Err("ClickHouse Native column has an incompatible storage layout".to_string()),
+    }
+}
+
+fn append_map(
+    offsets: &mut Offsets,
+    keys: &mut Vec<u8>,
+    values: &mut Vec<u8>,
+    value: Term,
+) -> EncodeResult<()> {
+    let size = value
+        .map_size()
+        .map_err(|_| "mapped Map(String, String) field is not a map".to_string())?;
+    let entries = MapIterator::new(value)
+        .ok_or_else(|| "mapped Map(String, String) field is not a map".to_string())?;
+    offsets.push(size);
+    for (key, value) in entries {
+        encode_string(keys, key)?;
+        encode_string(values, value)?;
+    }
+    Ok(())
+}
+
+#[inline]
+fn push_nullable(null_map: &mut Vec<u8>, values: &mut Vec<u8>, value: Option<i64>) {
+    null_map.push(u8::from(value.is_none()));
+    values.extend_from_slice(&value.unwrap_or(0).to_le_bytes());
+}
+
+/// Frames `data` as ClickHouse compressed blocks: a CityHash128 (v1.0.2)
+/// checksum, the method byte, compressed and uncompressed sizes, then the
+/// LZ4 payload.
+fn compress_lz4(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() / 2 + CHECKSUM_SIZE + COMPRESSED_HEADER_SIZE);
+    for chunk in data.chunks(MAX_COMPRESS_BLOCK_SIZE) {
+        let compressed = lz4_flex::block::compress(chunk);
+        let start = output.len();
+        output.extend_from_slice(&[0; CHECKSUM_SIZE]);
+        output.push(COMPRESSION_METHOD_LZ4);
+        output
+            .extend_from_slice(&((compressed.len() + COMPRESSED_HEADER_SIZE) as u32).to_le_bytes());
+        output.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        output.extend_from_slice(&compressed);
+
+        let checksum = cityhash_rs::cityhash_102_128(&output[start + CHECKSUM_SIZE..]);
+        output[start..start + 8].copy_from_slice(&((checksum >> 64) as u64).to_le_bytes());
+        output[start + 8..start + CHECKSUM_SIZE].copy_from_slice(&(checksum as u64).to_le_bytes());
+    }
+    output
+}
+
+fn owned_binary(bytes: &[u8]) -> EncodeResult<OwnedBinary> {
+    let mut binary = OwnedBinary::new(bytes.len())
+        .ok_or_else(|| "failed to allocate ClickHouse Native output".to_string())?;
+    binary.as_mut_slice().copy_from_slice(bytes);
+    Ok(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_are_cumulative() {
+        let mut offsets = Offsets::new();
+        offsets.push(2);
+        offsets.push(0);
+        offsets.push(3);
+
+        let decoded: Vec<u64> = offsets
+            .bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(decoded, vec![2, 2, 5]);
+    }
+
+    #[test]
+    fn nullable_columns_write_null_map_before_values() {
+        let mut data = ColumnData::new(ColumnKind::NullableInt64);
+        if let ColumnData::Nullable { null_map, values } = &mut data {
+            push_nullable(null_map, values, Some(7));
+            push_nullable(null_map, values, None);
+        }
+
+        let mut output = Vec::new();
+        data.write_to(&mut output);
+        let mut expected = vec![0, 1];
+        expected.extend_from_slice(&7i64.to_le_bytes());
+        expected.extend_from_slice(&0i64.to_le_bytes());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn lz4_framing_round_trips_with_checksum_header() {
+        let data: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| (i % 97).to_le_bytes())
+            .collect();
+        let framed = compress_lz4(&data);
+
+        assert_eq!(framed[CHECKSUM_SIZE], COMPRESSION_METHOD_LZ4);
+        let compressed_size = u32::from_le_bytes(framed[17..21].try_into().unwrap()) as usize;
+        let uncompressed_size = u32::from_le_bytes(framed[21..25].try_into().unwrap()) as usize;
+        assert_eq!(uncompressed_size, data.len());
+        assert_eq!(framed.len(), CHECKSUM_SIZE + compressed_size);
+
+        let checksum = cityhash_rs::cityhash_102_128(&framed[CHECKSUM_SIZE..]);
+        assert_eq!(&framed[..8], &((checksum >> 64) as u64).to_le_bytes());
+        assert_eq!(&framed[8..16], &(checksum as u64).to_le_bytes());
+
+        let payload = &framed[CHECKSUM_SIZE + COMPRESSED_HEADER_SIZE..];
+        assert_eq!(
+            lz4_flex::block::decompress(payload, uncompressed_size).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn lz4_framing_splits_large_blocks() {
+        let data = vec![7u8; MAX_COMPRESS_BLOCK_SIZE + 1];
+        let framed = compress_lz4(&data);
+
+        let first_size = u32::from_le_bytes(framed[17..21].try_into().unwrap()) as usize;
+        let second = CHECKSUM_SIZE + first_size;
+        let second_uncompressed =
+            u32::from_le_bytes(framed[second + 21..second + 25].try_into().unwrap());
+        assert_eq!(second_uncompressed, 1);
+    }
+}
@@ -73,29 +73,6 @@ impl WireType {
     }
 }
 
-fn field_type_name(field_type: FieldType) -> &'static str {
-    match field_type {
-        FieldType::String => "string",
-        FieldType::UInt8 => "uint8",
-        FieldType::UInt32 => "uint32",
-        FieldType::UInt64 => "uint64",
-        FieldType::Int32 => "int32",
-        FieldType::Float64 => "float64",
-        FieldType::Bool => "bool",
-        FieldType::Enum8 { .. } => "enum8",
-        FieldType::DateTime64 { .. } => "datetime64",
-        FieldType::Json => "json",
-        FieldType::ArrayString => "array_string",
-        FieldType::ArrayUInt64 => "array_uint64",
-        FieldType::ArrayFloat64 => "array_float64",
-        FieldType::ArrayDateTime64 { .. } => "array_datetime64",
-        FieldType::ArrayJson => "array_json",
-        FieldType::ArrayMap => "array_map",
-        FieldType::FlatMap => "flat_map",
-        FieldType::ArrayFlatMap => "array_flat_map",
-    }
-}
-
 const LOG_FIELDS: &[(&str, WireType)] = &[
     ("project", WireType::String),
     ("trace_id", WireType::String),
@@ -222,7 +199,7 @@ pub fn compile_layout(
             if !expected_type.matches(actual_type) {
                 return Err(format!(
                     "compiled mapping field '{name}' has type '{}'; ClickHouse RowBinary requires '{}'",
-                    field_type_name(actual_type),
+                    actual_type.name(),
                     expected_type.name()
                 ));
             }
@@ -245,6 +222,29 @@ pub struct RowEnvelope<'a> {
     pub ingested_at: Option<i64>,
 }
 
+/// Destination for encoded ClickHouse values.
+///
+/// RowBinary rows are written into one growable `OwnedBinary`, while Native
+/// blocks write every column into its own buffer.
+pub(crate) trait ByteSink {
+    fn push(&mut self, value: u8) -> EncodeResult<()>;
+    fn extend_from_slice(&mut self, value: &[u8]) -> EncodeResult<()>;
+}
+
+impl ByteSink for Vec<u8> {
+    #[inline]
+    fn push(&mut self, value: u8) -> EncodeResult<()> {
+        Vec::push(self, value);
+        Ok(())
+    }
+
+    #[inline]
+    fn extend_from_slice(&mut self, value: &[u8]) -> EncodeResult<()> {
+        Vec::extend_from_slice(self, value);
+        Ok(())
+    }
+}
+
 pub struct BinaryBuilder {
     binary: OwnedBinary,
     len: usize,
@@ -266,20 +266,6 @@ impl BinaryBuilder {
         Ok(self.binary)
     }
 
-    fn push(&mut self, value: u8) -> EncodeResult<()> {
-        let end = self.reserve(1)?;
-        self.binary.as_mut_slice()[self.len] = value;
-        self.len = end;
-        Ok(())
-    }
-
-    fn extend_from_slice(&mut self, value: &[u8]) -> EncodeResult<()> {
-        let end = self.reserve(value.len())?;
-        self.binary.as_mut_slice()[self.len..end].copy_from_slice(value);
-        self.len = end;
-        Ok(())
-    }
-
     fn reserve(&mut self, additional: usize) -> EncodeResult<usize> {
         let required = self
             .len
@@ -314,6 +300,22 @@ impl BinaryBuilder {
     }
 }
 
+impl ByteSink for BinaryBuilder {
+    fn push(&mut self, value: u8) -> EncodeResult<()> {
+        let end = self.reserve(1)?;
+        self.binary.as_mut_slice()[self.len] = value;
+        self.len = end;
+        Ok(())
+    }
+
+    fn extend_from_slice(&mut self, value: &[u8]) -> EncodeResult<()> {
+        let end = self.reserve(value.len())?;
+        self.binary.as_mut_slice()[self.len..end].copy_from_slice(value);
+        self.len = end;
+        Ok(())
+    }
+}
+
 struct RowValues<'values, 'env> {
     values: &'values [Term<'env>],
     layout: &'values [usize],
@@ -399,14 +401,11 @@ fn append_log(
     encode_uint8(output, values.next("trace_flags")?)?;
     encode_string(output, values.next("severity_text")?)?;
 
-    let severity_alt = decode_u64(values.next("severity_number_alt")?)?;
-    let mapped_severity = values.next("severity_number")?;
-    let severity = if severity_alt > 0 {
-        severity_alt
-    } else {
-        decode_u64(mapped_severity)?
-    };
-    output.push(to_u8(severity, "severity_number")?)?;
+    let severity = log_severity(
+        values.next("severity_number_alt")?,
+        values.next("severity_number")?,
+    )?;
+    output.push(severity)?;
 
     encode_string(output, values.next("service_name")?)?;
     encode_string(output, values.next("event_message")?)?;
@@ -510,16 +509,11 @@ fn append_trace(
     encode_string(output, values.next("service_name")?)?;
     encode_string(output, values.next("event_message")?)?;
 
-    let mut duration = decode_u64(values.next("duration")?)?;
-    let start_time = decode_i64(values.next("start_time")?);
-    let end_time = decode_i64(values.next("end_time")?);
-    if duration == 0 {
-        if let (Ok(start_time), Ok(end_time)) = (start_time, end_time) {
-            if end_time > start_time {
-                duration = end_time.abs_diff(start_time);
-            }
-        }
-    }
+    let duration = trace_duration(
+        values.next("duration")?,
+        values.next("start_time")?,
+        values.next("end_time")?,
+    )?;
     output.extend_from_slice(&duration.to_le_bytes())?;
 
     encode_string(output, values.next("status_code")?)?;
@@ -544,6 +538,35 @@ fn append_trace(
     )
 }
 
+/// Explicit `severity_number_alt` values win over the number mapped from text.
+pub(crate) fn log_severity(alt: Term, mapped: Term) -> EncodeResult<u8> {
+    let severity_alt = decode_u64(alt)?;
+    let severity = if severity_alt > 0 {
+        severity_alt
+    } else {
+        decode_u64(mapped)?
+    };
+    to_u8(severity, "severity_number")
+}
+
+/// Spans without an explicit duration derive one from their start and end times.
+pub(crate) fn trace_duration(
+    duration: Term,
+    start_time: Term,
+    end_time: Term,
+) -> EncodeResult<u64> {
+    let duration = decode_u64(duration)?;
+    if duration != 0 {
+        return Ok(duration);
+    }
+    match (decode_i64(start_time), decode_i64(end_time)) {
+        (Ok(start_time), Ok(end_time)) if end_time > start_time => {
+            Ok(end_time.abs_diff(start_time))
+        }
+        _ => Ok(duration),
+    }
+}
+
 fn encode_envelope(
     output: &mut BinaryBuilder,
     id: Binary,
@@ -576,7 +599,7 @@ fn encode_suffix(
     encode_int64(output, timestamp)
 }
 
-fn encode_uuid(output: &mut BinaryBuilder, value: &[u8]) -> EncodeResult<()> {
+pub(crate) fn encode_uuid(output: &mut impl ByteSink, value: &[u8]) -> EncodeResult<()> {
     let mut raw = [0_u8; 16];
 
     if value.len() == 36
@@ -645,19 +668,19 @@ fn decode_hex(value: u8) -> Option<u8> {
     }
 }
 
-fn encode_string(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_string(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     let binary = value
         .decode::<Binary>()
         .map_err(|_| "mapped string field is not a binary".to_string())?;
     encode_bytes(output, binary.as_slice())
 }
 
-fn encode_bytes(output: &mut BinaryBuilder, value: &[u8]) -> EncodeResult<()> {
+pub(crate) fn encode_bytes(output: &mut impl ByteSink, value: &[u8]) -> EncodeResult<()> {
     encode_varuint(output, value.len() as u64)?;
     output.extend_from_slice(value)
 }
 
-fn encode_varuint(output: &mut BinaryBuilder, mut value: u64) -> EncodeResult<()> {
+pub(crate) fn encode_varuint(output: &mut impl ByteSink, mut value: u64) -> EncodeResult<()> {
     loop {
         let mut byte = (value & 0x7f) as u8;
         value >>= 7;
@@ -671,43 +694,43 @@ fn encode_varuint(output: &mut BinaryBuilder, mut value: u64) -> EncodeResult<()
     }
 }
 
-fn encode_bool(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_bool(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     let value = value
         .decode::<bool>()
         .map_err(|_| "mapped boolean field is not a boolean".to_string())?;
     output.push(u8::from(value))
 }
 
-fn encode_uint8(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_uint8(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     let value = decode_u64(value)?;
     output.push(to_u8(value, "UInt8")?)
 }
 
-fn encode_uint32(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_uint32(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     let value = decode_u64(value)?;
     let value =
         u32::try_from(value).map_err(|_| "mapped UInt32 field is out of range".to_string())?;
     output.extend_from_slice(&value.to_le_bytes())
 }
 
-fn encode_uint64(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_uint64(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     output.extend_from_slice(&decode_u64(value)?.to_le_bytes())
 }
 
-fn encode_int8(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_int8(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     let value = decode_i64(value)?;
     let value = i8::try_from(value).map_err(|_| "mapped Int8 field is out of range".to_string())?;
     output.push(value as u8)
 }
 
-fn encode_int32(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_int32(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     let value = decode_i64(value)?;
     let value =
         i32::try_from(value).map_err(|_| "mapped Int32 field is out of range".to_string())?;
     output.extend_from_slice(&value.to_le_bytes())
 }
 
-fn encode_int64(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_int64(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
     output.extend_from_slice(&decode_i64(value)?.to_le_bytes())
 }
 
@@ -722,18 +745,21 @@ fn encode_nullable_int64(output: &mut BinaryBuilder, value: Term) -> EncodeResul
     }
 }
 
-fn encode_float64(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
-    let value = if let Ok(value) = value.decode::<f64>() {
-        value
+pub(crate) fn encode_float64(output: &mut impl ByteSink, value: Term) -> EncodeResult<()> {
+    output.extend_from_slice(&decode_f64(value)?.to_le_bytes())
+}
+
+pub(crate) fn decode_f64(value: Term) -> EncodeResult<f64> {
+    if let Ok(value) = value.decode::<f64>() {
+        Ok(value)
     } else if let Ok(value) = value.decode::<i64>() {
-        value as f64
+        Ok(value as f64)
     } else {
-        return Err("mapped Float64 field is not numeric".to_string());
-    };
-    output.extend_from_slice(&value.to_le_bytes())
+        Err("mapped Float64 field is not numeric".to_string())
+    }
 }
 
-fn decode_u64(value: Term) -> EncodeResult<u64> {
+pub(crate) fn decode_u64(value: Term) -> EncodeResult<u64> {
     if let Ok(value) = value.decode::<u64>() {
         Ok(value)
     } else if let Ok(value) = value.decode::<i64>() {
@@ -743,7 +769,7 @@ fn decode_u64(value: Term) -> EncodeResult<u64> {
     }
 }
 
-fn decode_i64(value: Term) -> EncodeResult<i64> {
+pub(crate) fn decode_i64(value: Term) -> EncodeResult<i64> {
     value
         .decode::<i64>()
         .map_err(|_| "mapped signed field is not an integer".to_string())
@@ -753,7 +779,10 @@ fn to_u8(value: u64, field: &str) -> EncodeResult<u8> {
     u8::try_from(value).map_err(|_| format!("mapped {field} field is out of range"))
 }
 
-fn encode_map_string_string(output: &mut BinaryBuilder, value: Term) -> EncodeResult<()> {
+pub(crate) fn encode_map_string_string(
+    output: &mut impl ByteSink,
+    value: Term,
+) -> EncodeResult<()> {
     let size = value
         .map_size()
         .map_err(|_| "mapped Map(String, String) field is not a map".to_string())?;
@@ -768,7 +797,7 @@ fn encode_map_string_string(output: &mut BinaryBuilder, value: Term) -> EncodeRe
     Ok(())
 }
 
-fn list<'a>(value: Term<'a>) -> EncodeResult<(usize, ListIterator<'a>)> {
+pub(crate) fn list<'a>(value: Term<'a>) -> EncodeResult<(usize, ListIterator<'a>)> {
     let length = value
         .list_length()
         .map_err(|_| "mapped array field is not a proper list".to_string())?;
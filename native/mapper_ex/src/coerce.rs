@@ -4,7 +4,9 @@ use chrono::DateTime as ChronoDateTime;
 use rustler::types::list::ListIterator;
 use rustler::{Binary, Encoder, Env, NewBinary, Term};
 
-use crate::mapping::{DefaultValue, FieldTransform, FieldType};
+use crate::keyed_hash::KeyedHash;
+use crate::mapping::{DefaultValue, FieldTransform, FieldType, FlattenOptions};
+use crate::query::KeyMode;
 
 /// Case-insensitive lookup using stack-allocated buffer for ASCII values.
 /// Falls back to heap allocation for values > 128 bytes or non-ASCII.
@@ -37,12 +39,25 @@ pub fn coerce<'a>(
     field_type: &FieldType,
     nil: Term<'a>,
 ) -> Term<'a> {
+    coerce_checked(env, value, field_type, nil).0
+}
+
+/// `coerce`, also returning whether the value fell back: to the type's zero
+/// value because it did not convert, or clamped into the type's range. Nil
+/// coerces to the zero value by design and does not count.
+#[inline]
+pub fn coerce_checked<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    field_type: &FieldType,
+    nil: Term<'a>,
+) -> (Term<'a>, bool) {
     if value == nil {
         // For numeric types, nil coerces to their zero value.
         // DateTime64 is intentionally excluded — nil flows through so the
         // Elixir side can substitute the event's real timestamp instead of
         // silently inserting epoch time (1970-01-01).
-        return match field_type {
+        let zero = match field_type {
             FieldType::String => crate::encode_string(env, ""),
             FieldType::UInt8 | FieldType::UInt32 | FieldType::UInt64 => 0u64.encode(env),
             FieldType::Int32 => 0i32.encode(env),
@@ -51,6 +66,7 @@ pub fn coerce<'a>(
             FieldType::Enum8 { .. } => 0i8.encode(env),
             _ => nil,
         };
+        return (zero, false);
     }
 
     match field_type {
@@ -63,7 +79,7 @@ pub fn coerce<'a>(
         FieldType::Bool => coerce_bool(env, value),
         FieldType::Enum8 { .. } => coerce_enum8(env, value),
         FieldType::DateTime64 { precision } => coerce_datetime64(env, value, *precision),
-        FieldType::Json | FieldType::FlatMap => value, // pass-through
+        FieldType::Json | FieldType::FlatMap => (value, false), // pass-through
         // Array types are handled by coerce_array, not coerce
         FieldType::ArrayString
         | FieldType::ArrayUInt64
@@ -71,7 +87,7 @@ pub fn coerce<'a>(
         | FieldType::ArrayDateTime64 { .. }
         | FieldType::ArrayJson
         | FieldType::ArrayMap
-        | FieldType::ArrayFlatMap => Vec::<Term>::new().encode(env),
+        | FieldType::ArrayFlatMap => (Vec::<Term>::new().encode(env), false),
     }
 }
 
@@ -87,12 +103,21 @@ pub fn apply_transform<'a>(
         return nil;
     }
 
+    let upcase = match transform {
+        FieldTransform::Upcase => true,
+        FieldTransform::Downcase => false,
+        FieldTransform::Hash { hash, integer } => {
+            return hash_term(env, value, hash, *integer, nil)
+        }
+    };
+
     if let Ok(binary) = value.decode::<Binary>() {
         let bytes = binary.as_slice();
         if bytes.is_ascii() {
-            let unchanged = match transform {
-                FieldTransform::Upcase => !bytes.iter().any(u8::is_ascii_lowercase),
-                FieldTransform::Downcase => !bytes.iter().any(u8::is_ascii_uppercase),
+            let unchanged = if upcase {
+                !bytes.iter().any(u8::is_ascii_lowercase)
+            } else {
+                !bytes.iter().any(u8::is_ascii_uppercase)
             };
             if unchanged {
                 return value;
@@ -100,9 +125,10 @@ pub fn apply_transform<'a>(
 
             let mut transformed = NewBinary::new(env, bytes.len());
             for (output, input) in transformed.as_mut_slice().iter_mut().zip(bytes) {
-                *output = match transform {
-                    FieldTransform::Upcase => input.to_ascii_uppercase(),
-                    FieldTransform::Downcase => input.to_ascii_lowercase(),
+                *output = if upcase {
+                    input.to_ascii_uppercase()
+                } else {
+                    input.to_ascii_lowercase()
                 };
             }
             return transformed.into();
@@ -110,15 +136,43 @@ pub fn apply_transform<'a>(
     }
 
     if let Ok(s) = value.decode::<String>() {
-        match transform {
-            FieldTransform::Upcase => crate::encode_string(env, &s.to_uppercase()),
-            FieldTransform::Downcase => crate::encode_string(env, &s.to_lowercase()),
+        if upcase {
+            crate::encode_string(env, &s.to_uppercase())
+        } else {
+            crate::encode_string(env, &s.to_lowercase())
         }
     } else {
         value
     }
 }
 
+/// Hashes a string, or an integer by its decimal text so `42` and `"42"`
+/// get the same pseudonym. Any other value becomes nil rather than passing
+/// through unhashed.
+pub fn hash_term<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    hash: &KeyedHash,
+    integer: bool,
+    nil: Term<'a>,
+) -> Term<'a> {
+    let mut buffer = itoa::Buffer::new();
+    let input = if let Ok(binary) = value.decode::<Binary>() {
+        binary.as_slice()
+    } else if let Ok(i) = value.decode::<i64>() {
+        buffer.format(i).as_bytes()
+    } else if let Ok(u) = value.decode::<u64>() {
+        buffer.format(u).as_bytes()
+    } else {
+        return nil;
+    };
+    if integer {
+        hash.hash_u64(input).encode(env)
+    } else {
+        crate::encode_string(env, &hash.hash_hex(input))
+    }
+}
+
 /// Encode a default value to a BEAM term.
 #[inline]
 pub fn encode_default<'a>(env: Env<'a>, default: &DefaultValue, nil: Term<'a>) -> Term<'a> {
@@ -186,6 +240,7 @@ pub fn coerce_array<'a>(
     value: Term<'a>,
     field_type: &FieldType,
     filter_nil: bool,
+    key_mode: KeyMode,
     nil: Term<'a>,
 ) -> Term<'a> {
     // If value is nil or not a list, return empty list
@@ -202,9 +257,15 @@ pub fn coerce_array<'a>(
     let mut result: Vec<Term<'a>> = Vec::new();
 
     for elem in iter {
-        if let Some(elem) =
-            coerce_array_element(env, elem, field_type, inner_type.as_ref(), filter_nil, nil)
-        {
+        if let Some(elem) = coerce_array_element(
+            env,
+            elem,
+            field_type,
+            inner_type.as_ref(),
+            filter_nil,
+            key_mode,
+            nil,
+        ) {
             result.push(elem);
         }
     }
@@ -219,6 +280,7 @@ pub fn coerce_array_element<'a>(
     field_type: &FieldType,
     inner_type: Option<&FieldType>,
     filter_nil: bool,
+    key_mode: KeyMode,
     nil: Term<'a>,
 ) -> Option<Term<'a>> {
     if elem == nil {
@@ -230,9 +292,10 @@ pub fn coerce_array_element<'a>(
     }
 
     match field_type {
-        FieldType::ArrayFlatMap => elem
-            .is_map()
-            .then(|| crate::mapper::flatten_and_stringify(env, elem, nil)),
+        FieldType::ArrayFlatMap => elem.is_map().then(|| {
+            let options = &FlattenOptions::DEFAULT;
+            crate::mapper::flatten_and_stringify(env, elem, nil, options, key_mode, None)
+        }),
         FieldType::ArrayMap => elem.is_map().then_some(elem),
         FieldType::ArrayJson => Some(elem),
         _ => Some(match inner_type {
@@ -272,164 +335,174 @@ fn array_nil_value<'a>(env: Env<'a>, field_type: &FieldType) -> Term<'a> {
 
 // ── Private coercion functions ─────────────────────────────────────────────
 
-fn coerce_string<'a>(env: Env<'a>, value: Term<'a>) -> Term<'a> {
+fn coerce_string<'a>(env: Env<'a>, value: Term<'a>) -> (Term<'a>, bool) {
     if value.is_binary() {
-        return value;
+        return (value, false);
     }
 
     if let Ok(i) = value.decode::<i64>() {
-        return crate::encode_integer(env, i);
+        return (crate::encode_integer(env, i), false);
     }
 
     if let Ok(f) = value.decode::<f64>() {
-        return crate::encode_string(env, &f.to_string());
+        return (crate::encode_string(env, &f.to_string()), false);
     }
 
     if let Ok(b) = value.decode::<bool>() {
-        return crate::encode_string(env, if b { "true" } else { "false" });
+        return (
+            crate::encode_string(env, if b { "true" } else { "false" }),
+            false,
+        );
     }
 
     // For atoms (non-bool), try to get string representation
     if value.is_atom() {
         if let Ok(s) = value.atom_to_string() {
-            return crate::encode_string(env, &s);
+            return (crate::encode_string(env, &s), false);
         }
     }
 
-    crate::encode_string(env, "")
+    (crate::encode_string(env, ""), true)
 }
 
-fn coerce_uint<'a>(env: Env<'a>, value: Term<'a>, max: u64) -> Term<'a> {
+fn coerce_uint<'a>(env: Env<'a>, value: Term<'a>, max: u64) -> (Term<'a>, bool) {
     if let Ok(i) = value.decode::<i64>() {
         if i < 0 {
-            return 0u64.encode(env);
+            return (0u64.encode(env), true);
         }
         let u = i as u64;
-        return u.min(max).encode(env);
+        return (u.min(max).encode(env), u > max);
     }
 
     if let Ok(f) = value.decode::<f64>() {
         if f < 0.0 {
-            return 0u64.encode(env);
+            return (0u64.encode(env), true);
         }
         let u = f as u64;
-        return u.min(max).encode(env);
+        return (u.min(max).encode(env), u > max);
     }
 
     if let Ok(binary) = value.decode::<Binary>() {
         if let Ok(s) = std::str::from_utf8(binary.as_slice()) {
             if let Ok(u) = s.parse::<u64>() {
-                return u.min(max).encode(env);
+                return (u.min(max).encode(env), u > max);
             }
         }
     }
 
     if let Ok(b) = value.decode::<bool>() {
-        return if b { 1u64 } else { 0u64 }.encode(env);
+        return (if b { 1u64 } else { 0u64 }.encode(env), false);
     }
 
-    0u64.encode(env)
+    (0u64.encode(env), true)
 }
 
-fn coerce_int32<'a>(env: Env<'a>, value: Term<'a>) -> Term<'a> {
+fn coerce_int32<'a>(env: Env<'a>, value: Term<'a>) -> (Term<'a>, bool) {
     if let Ok(i) = value.decode::<i64>() {
         let clamped = i.max(i32::MIN as i64).min(i32::MAX as i64);
-        return (clamped as i32).encode(env);
+        return ((clamped as i32).encode(env), clamped != i);
     }
 
     if let Ok(f) = value.decode::<f64>() {
         let clamped = f.max(i32::MIN as f64).min(i32::MAX as f64);
-        return (clamped as i32).encode(env);
+        return ((clamped as i32).encode(env), clamped != f);
     }
 
     if let Ok(binary) = value.decode::<Binary>() {
         if let Ok(s) = std::str::from_utf8(binary.as_slice()) {
             if let Ok(i) = s.parse::<i32>() {
-                return i.encode(env);
+                return (i.encode(env), false);
             }
         }
     }
 
-    0i32.encode(env)
+    (0i32.encode(env), true)
 }
 
-fn coerce_float64<'a>(env: Env<'a>, value: Term<'a>) -> Term<'a> {
+fn coerce_float64<'a>(env: Env<'a>, value: Term<'a>) -> (Term<'a>, bool) {
     if let Ok(f) = value.decode::<f64>() {
-        return f.encode(env);
+        return (f.encode(env), false);
     }
 
     if let Ok(i) = value.decode::<i64>() {
-        return (i as f64).encode(env);
+        return ((i as f64).encode(env), false);
     }
 
     if let Ok(binary) = value.decode::<Binary>() {
         if let Ok(s) = std::str::from_utf8(binary.as_slice()) {
             if let Ok(f) = s.parse::<f64>() {
-                return f.encode(env);
+                return (f.encode(env), false);
             }
         }
     }
 
-    0.0f64.encode(env)
+    (0.0f64.encode(env), true)
 }
 
-fn coerce_bool<'a>(env: Env<'a>, value: Term<'a>) -> Term<'a> {
+fn coerce_bool<'a>(env: Env<'a>, value: Term<'a>) -> (Term<'a>, bool) {
     if let Ok(b) = value.decode::<bool>() {
-        return b.encode(env);
+        return (b.encode(env), false);
     }
 
     if let Ok(binary) = value.decode::<Binary>() {
         let bytes = binary.as_slice();
-        return (bytes.eq_ignore_ascii_case(b"true") || bytes == b"1").encode(env);
+        let truthy = bytes.eq_ignore_ascii_case(b"true") || bytes == b"1";
+        let falsy = bytes.eq_ignore_ascii_case(b"false") || bytes == b"0";
+        return (truthy.encode(env), !truthy && !falsy);
     }
 
     if let Ok(i) = value.decode::<i64>() {
-        return (i != 0).encode(env);
+        return ((i != 0).encode(env), false);
     }
 
-    false.encode(env)
+    (false.encode(env), true)
 }
 
-fn coerce_enum8<'a>(env: Env<'a>, value: Term<'a>) -> Term<'a> {
+fn coerce_enum8<'a>(env: Env<'a>, value: Term<'a>) -> (Term<'a>, bool) {
     // Enum8 values should already be resolved to integers by the mapper
     if let Ok(i) = value.decode::<i64>() {
-        return (i as i8).encode(env);
+        return ((i as i8).encode(env), i8::try_from(i).is_err());
     }
 
-    0i8.encode(env)
+    (0i8.encode(env), true)
 }
 
-fn coerce_datetime64<'a>(env: Env<'a>, value: Term<'a>, precision: u8) -> Term<'a> {
+fn coerce_datetime64<'a>(env: Env<'a>, value: Term<'a>, precision: u8) -> (Term<'a>, bool) {
     if let Ok(i) = value.decode::<i64>() {
         let source_precision = detect_precision(i);
-        return scale(i, source_precision, precision).encode(env);
+        return (scale(i, source_precision, precision).encode(env), false);
     }
 
     if let Ok(binary) = value.decode::<Binary>() {
-        if let Ok(s) = std::str::from_utf8(binary.as_slice()) {
-            if let Ok(dt) = ChronoDateTime::parse_from_rfc3339(s) {
-                if let Some(nanos) = dt.timestamp_nanos_opt() {
-                    return scale(nanos, 9, precision).encode(env);
-                }
-            }
-            // Try ISO8601 with space separator (e.g., "2026-01-21 17:54:48.144506Z")
-            if s.contains(' ') {
-                let rfc3339_attempt = s.replace(' ', "T");
-                if let Ok(dt) = ChronoDateTime::parse_from_rfc3339(&rfc3339_attempt) {
-                    if let Some(nanos) = dt.timestamp_nanos_opt() {
-                        return scale(nanos, 9, precision).encode(env);
-                    }
-                }
-            }
+        if let Some(nanos) = std::str::from_utf8(binary.as_slice())
+            .ok()
+            .and_then(parse_datetime_nanos)
+        {
+            return (scale(nanos, 9, precision).encode(env), false);
         }
     }
 
     // Return 0 for unparseable values
-    0i64.encode(env)
+    (0i64.encode(env), true)
+}
+
+/// Parses an RFC3339 timestamp into unix nanoseconds.
+pub fn parse_datetime_nanos(s: &str) -> Option<i64> {
+    if let Ok(dt) = ChronoDateTime::parse_from_rfc3339(s) {
+        return dt.timestamp_nanos_opt();
+    }
+    // Try ISO8601 with space separator (e.g., "2026-01-21 17:54:48.144506Z")
+    if s.contains(' ') {
+        let rfc3339_attempt = s.replace(' ', "T");
+        if let Ok(dt) = ChronoDateTime::parse_from_rfc3339(&rfc3339_attempt) {
+            return dt.timestamp_nanos_opt();
+        }
+    }
+    None
 }
 
 /// Detect the precision of a unix timestamp by its digit count.
-fn detect_precision(value: i64) -> u8 {
+pub fn detect_precision(value: i64) -> u8 {
     match value.unsigned_abs() {
         0..=9_999_999_999 => 0,
         10_000_000_000..=9_999_999_999_999 => 3,
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+
+use rustler::{Encoder, Env, Term};
+
+use crate::mapper::{self, MapScratch};
+use crate::mapping::{get_string_key, get_term_key, CompiledField, CompiledMapping, FieldType};
+
+mod atoms {
+    rustler::atoms! {
+        nil,
+        added,
+        removed,
+        retyped,
+        source_changed,
+        default_changed,
+        values,
+        documents,
+        field,
+        type_ = "type",
+        old,
+        new,
+        changed,
+        samples,
+        index,
+    }
+}
+
+/// Config keys that together pick where a field's value comes from.
+const SOURCE_KEYS: [&str; 5] = ["path", "paths", "from_output", "cases", "aggregate"];
+
+/// Value differences kept per field when comparing mapped documents.
+const MAX_SAMPLES: usize = 3;
+
+/// One side of a comparison: a config and the mapping compiled from it.
+pub struct Side<'a, 'm> {
+    pub config: Term<'a>,
+    pub mapping: &'m CompiledMapping,
+}
+
+impl<'a> Side<'a, '_> {
+    /// The config map of each field, by name.
+    fn field_terms(&self, env: Env<'a>) -> HashMap<String, Term<'a>> {
+        get_term_key(env, self.config, "fields")
+            .and_then(|fields| fields.decode::<Vec<Term<'a>>>().ok())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|field| Some((get_string_key(env, field, "name").ok()??, field)))
+            .collect()
+    }
+}
+
+/// Compares two mappings field by field: added, removed and retyped fields,
+/// and changed sources and defaults. With `documents`, also maps each one
+/// with both and counts, per shared field, the documents whose values differ.
+pub fn diff_mappings<'a>(
+    env: Env<'a>,
+    old: Side<'a, '_>,
+    new: Side<'a, '_>,
+    documents: &[Term<'a>],
+    flat_keys: bool,
+) -> Term<'a> {
+    let old_index = field_index(old.mapping);
+    let new_index = field_index(new.mapping);
+    let old_terms = old.field_terms(env);
+    let new_terms = new.field_terms(env);
+
+    let removed: Vec<Term<'a>> = old
+        .mapping
+        .fields
+        .iter()
+        .filter(|field| !new_index.contains_key(field.name.as_str()))
+        .map(|field| describe_field(env, field))
+        .collect();
+    let added: Vec<Term<'a>> = new
+        .mapping
+        .fields
+        .iter()
+        .filter(|field| !old_index.contains_key(field.name.as_str()))
+        .map(|field| describe_field(env, field))
+        .collect();
+
+    let mut retyped = Vec::new();
+    let mut source_changed = Vec::new();
+    let mut default_changed = Vec::new();
+    let mut shared = Vec::new();
+    for (new_position, field) in new.mapping.fields.iter().enumerate() {
+        let Some(&old_position) = old_index.get(field.name.as_str()) else {
+            continue;
+        };
+        shared.push((field.name.as_str(), old_position, new_position));
+        let old_field = &old.mapping.fields[old_position];
+        let name = crate::encode_string(env, &field.name);
+
+        if old_field.field_type != field.field_type {
+            retyped.push(change(
+                env,
+                name,
+                crate::encode_string(env, &type_label(old_field.field_type)),
+                crate::encode_string(env, &type_label(field.field_type)),
+            ));
+        }
+
+        let old_term = old_terms.get(&field.name).copied();
+        let new_term = new_terms.get(&field.name).copied();
+        let old_source = config_subset(env, old_term, &SOURCE_KEYS);
+        let new_source = config_subset(env, new_term, &SOURCE_KEYS);
+        if old_source != new_source {
+            source_changed.push(change(env, name, old_source, new_source));
+        }
+        let old_default = config_value(env, old_term, "default");
+        let new_default = config_value(env, new_term, "default");
+        if old_default != new_default {
+            default_changed.push(change(env, name, old_default, new_default));
+        }
+    }
+
+    let mut keys = vec![
+        atoms::added().encode(env),
+        atoms::removed().encode(env),
+        atoms::retyped().encode(env),
+        atoms::source_changed().encode(env),
+        atoms::default_changed().encode(env),
+    ];
+    let mut values = vec![
+        added.encode(env),
+        removed.encode(env),
+        retyped.encode(env),
+        source_changed.encode(env),
+        default_changed.encode(env),
+    ];
+    if !documents.is_empty() {
+        keys.push(atoms::values().encode(env));
+        values.push(diff_values(env, &old, &new, &shared, documents, flat_keys));
+        keys.push(atoms::documents().encode(env));
+        values.push(documents.len().encode(env));
+    }
+    encode_map(env, &keys, &values)
+}
+
+/// Maps every document with both mappings and reports, for each shared field
+/// whose values differ, how many documents differ and the first few of them.
+fn diff_values<'a>(
+    env: Env<'a>,
+    old: &Side<'a, '_>,
+    new: &Side<'a, '_>,
+    shared: &[(&str, usize, usize)],
+    documents: &[Term<'a>],
+    flat_keys: bool,
+) -> Term<'a> {
+    let nil = atoms::nil().encode(env);
+    let mut old_scratch = MapScratch::new(old.mapping, nil);
+    let mut new_scratch = MapScratch::new(new.mapping, nil);
+    let mut changed = vec![0usize; shared.len()];
+    let mut samples: Vec<Vec<Term<'a>>> = vec![Vec::new(); shared.len()];
+
+    for (index, &document) in documents.iter().enumerate() {
+        old_scratch.clear();
+        new_scratch.clear();
+        mapper::map_values_into(env, document, old.mapping, flat_keys, nil, &mut old_scratch);
+        mapper::map_values_into(env, document, new.mapping, flat_keys, nil, &mut new_scratch);
+
+        for (slot, &(_, old_position, new_position)) in shared.iter().enumerate() {
+            let old_value = old_scratch.values()[old_position];
+            let new_value = new_scratch.values()[new_position];
+            if old_value == new_value {
+                continue;
+            }
+            changed[slot] += 1;
+            if samples[slot].len() < MAX_SAMPLES {
+                samples[slot].push(encode_map(
+                    env,
+                    &[
+                        atoms::index().encode(env),
+                        atoms::old().encode(env),
+                        atoms::new().encode(env),
+                    ],
+                    &[index.encode(env), old_value, new_value],
+                ));
+            }
+        }
+    }
+
+    shared
+        .iter()
+        .zip(changed)
+        .zip(samples)
+        .filter(|((_, changed), _)| *changed > 0)
+        .map(|(((name, _, _), changed), samples)| {
+            encode_map(
+                env,
+                &[
+                    atoms::field().encode(env),
+                    atoms::changed().encode(env),
+                    atoms::samples().encode(env),
+                ],
+                &[
+                    crate::encode_string(env, name),
+                    changed.encode(env),
+                    samples.encode(env),
+                ],
+            )
+        })
+        .collect::<Vec<_>>()
+        .encode(env)
+}
+
+fn field_index(mapping: &CompiledMapping) -> HashMap<&str, usize> {
+    mapping
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| (field.name.as_str(), index))
+        .collect()
+}
+
+/// A type name, with the precision of DateTime64 types, e.g. `datetime64(3)`.
+fn type_label(field_type: FieldType) -> String {
+    match field_type {
+        FieldType::DateTime64 { precision } | FieldType::ArrayDateTime64 { precision } => {
+            format!("{}({})", field_type.name(), precision)
+        }
+        _ => field_type.name().to_string(),
+    }
+}
+
+fn describe_field<'a>(env: Env<'a>, field: &CompiledField) -> Term<'a> {
+    encode_map(
+        env,
+        &[atoms::field().encode(env), atoms::type_().encode(env)],
+        &[
+            crate::encode_string(env, &field.name),
+            crate::encode_string(env, &type_label(field.field_type)),
+        ],
+    )
+}
+
+fn change<'a>(env: Env<'a>, name: Term<'a>, old: Term<'a>, new: Term<'a>) -> Term<'a> {
+    encode_map(
+        env,
+        &[
+            atoms::field().encode(env),
+            atoms::old().encode(env),
+            atoms::new().encode(env),
+        ],
+        &[name, old, new],
+    )
+}
+
+/// The value a field config holds under `key`, or `nil`.
+fn config_value<'a>(env: Env<'a>, field: Option<Term<'a>>, key: &str) -> Term<'a> {
+    field
+        .and_then(|field| get_term_key(env, field, key))
+        .unwrap_or_else(|| atoms::nil().encode(env))
+}
+
+/// The `keys` a field config sets, as a map with binary keys.
+fn config_subset<'a>(env: Env<'a>, field: Option<Term<'a>>, keys: &[&str]) -> Term<'a> {
+    let (keys, values): (Vec<Term<'a>>, Vec<Term<'a>>) = keys
+        .iter()
+        .filter_map(|key| {
+            let value = get_term_key(env, field?, key)?;
+            Some((crate::encode_string(env, key), value))
+        })
+        .unzip();
+    encode_map(env, &keys, &values)
+}
+
+fn encode_map<'a>(env: Env<'a>, keys: &[Term<'a>], values: &[Term<'a>]) -> Term<'a> {
+    Term::map_from_term_arrays(env, keys, values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_datetime_precision() {
+        assert_eq!(type_label(FieldType::String), "string");
+        assert_eq!(
+            type_label(FieldType::DateTime64 { precision: 3 }),
+            "datetime64(3)"
+        );
+        assert_ne!(
+            type_label(FieldType::ArrayDateTime64 { precision: 6 }),
+            type_label(FieldType::ArrayDateTime64 { precision: 9 })
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use rustler::{Encoder, Env, Term};
+
+use crate::mapper::{self, DefaultReason, Enum8Match, MapScratch, MapTrace, SourceMatch};
+use crate::mapping::CompiledMapping;
+use crate::query::Skip;
+
+mod atoms {
+    rustler::atoms! {
+        nil,
+        field,
+        source,
+        raw,
+        skipped,
+        default_used,
+        default_reason,
+        allowed_values,
+        value_map,
+        enum8,
+        value,
+        root,
+        path,
+        coalesce,
+        from_output,
+        wildcard,
+        aggregate,
+        literal,
+        case,
+        missing,
+        empty,
+        filter,
+        allowed,
+        rejected,
+        hit,
+        miss,
+        lookup,
+        integer,
+        infer_rule,
+        default,
+        redacted,
+    }
+}
+
+/// Decisions recorded for one field while mapping a document.
+struct FieldExplain<'a> {
+    case: Option<usize>,
+    source: Option<(SourceMatch, Term<'a>)>,
+    skipped: Vec<(usize, Term<'a>, Skip)>,
+    default_reason: Option<DefaultReason>,
+    allowed: Option<bool>,
+    value_map_hit: Option<bool>,
+    enum8: Option<Enum8Match>,
+    value: Option<Term<'a>>,
+}
+
+#[derive(Default)]
+struct ExplainTrace<'a> {
+    fields: Vec<FieldExplain<'a>>,
+}
+
+impl<'a> ExplainTrace<'a> {
+    fn current(&mut self) -> &mut FieldExplain<'a> {
+        self.fields
+            .last_mut()
+            .expect("field() is traced before any decision")
+    }
+}
+
+impl<'a> MapTrace<'a> for ExplainTrace<'a> {
+    fn field(&mut self, _index: usize) {
+        self.fields.push(FieldExplain {
+            case: None,
+            source: None,
+            skipped: Vec::new(),
+            default_reason: None,
+            allowed: None,
+            value_map_hit: None,
+            enum8: None,
+            value: None,
+        });
+    }
+
+    fn case(&mut self, index: usize) {
+        self.current().case = Some(index);
+    }
+
+    fn source(&mut self, source: SourceMatch, raw: Term<'a>) {
+        self.current().source = Some((source, raw));
+    }
+
+    fn skipped(&mut self, index: usize, value: Term<'a>, skip: Skip) {
+        self.current().skipped.push((index, value, skip));
+    }
+
+    fn default_used(&mut self, reason: DefaultReason) {
+        let field = self.current();
+        // The first fallback explains the outcome; later steps only see the default.
+        field.default_reason.get_or_insert(reason);
+    }
+
+    fn allowed_values(&mut self, allowed: bool) {
+        self.current().allowed = Some(allowed);
+    }
+
+    fn value_map(&mut self, hit: bool) {
+        self.current().value_map_hit = Some(hit);
+    }
+
+    fn enum8(&mut self, resolution: Enum8Match) {
+        self.current().enum8 = Some(resolution);
+    }
+
+    fn value(&mut self, value: Term<'a>) {
+        self.current().value = Some(value);
+    }
+}
+
+/// Maps one document and returns, per field and in field order, how its
+/// value was decided.
+pub fn explain_single<'a>(
+    env: Env<'a>,
+    body: Term<'a>,
+    mapping: &CompiledMapping,
+    flat_keys: bool,
+) -> Term<'a> {
+    let nil = atoms::nil().encode(env);
+    let mut scratch = MapScratch::new(mapping, nil);
+    let mut trace = ExplainTrace::default();
+    mapper::map_values_traced(env, body, mapping, flat_keys, nil, &mut scratch, &mut trace);
+
+    trace
+        .fields
+        .iter()
+        .zip(&mapping.fields)
+        .map(|(explain, field)| encode_field(env, explain, &field.name, mapping, nil))
+        .collect::<Vec<Term<'a>>>()
+        .encode(env)
+}
+
+fn encode_field<'a>(
+    env: Env<'a>,
+    explain: &FieldExplain<'a>,
+    name: &str,
+    mapping: &CompiledMapping,
+    nil: Term<'a>,
+) -> Term<'a> {
+    let (source, raw) = match explain.source {
+        Some((source, raw)) => (encode_source(env, source, mapping), raw),
+        None => (nil, nil),
+    };
+    let skipped: Vec<Term<'a>> = explain
+        .skipped
+        .iter()
+        .map(|(index, value, skip)| (*index, encode_skip(env, *skip), *value).encode(env))
+        .collect();
+    let default_reason = match explain.default_reason {
+        Some(DefaultReason::Source) => atoms::source().encode(env),
+        Some(DefaultReason::AllowedValues) => atoms::allowed_values().encode(env),
+        Some(DefaultReason::ValueMap) => atoms::value_map().encode(env),
+        Some(DefaultReason::Enum8) => atoms::enum8().encode(env),
+        Some(DefaultReason::Redacted) => atoms::redacted().encode(env),
+        None => nil,
+    };
+    let allowed = match explain.allowed {
+        Some(true) => atoms::allowed().encode(env),
+        Some(false) => atoms::rejected().encode(env),
+        None => nil,
+    };
+    let value_map = match explain.value_map_hit {
+        Some(true) => atoms::hit().encode(env),
+        Some(false) => atoms::miss().encode(env),
+        None => nil,
+    };
+    let enum8 = match explain.enum8 {
+        Some(Enum8Match::Lookup) => atoms::lookup().encode(env),
+        Some(Enum8Match::Integer) => atoms::integer().encode(env),
+        Some(Enum8Match::InferRule(index)) => (atoms::infer_rule(), index).encode(env),
+        Some(Enum8Match::Default) => atoms::default().encode(env),
+        None => nil,
+    };
+
+    let keys = [
+        atoms::field(),
+        atoms::case(),
+        atoms::source(),
+        atoms::raw(),
+        atoms::skipped(),
+        atoms::default_used(),
+        atoms::default_reason(),
+        atoms::allowed_values(),
+        atoms::value_map(),
+        atoms::enum8(),
+        atoms::value(),
+    ]
+    .map(|key| key.encode(env));
+    let values = [
+        crate::encode_string(env, name),
+        explain.case.encode(env),
+        source,
+        raw,
+        skipped.encode(env),
+        explain.default_reason.is_some().encode(env),
+        default_reason,
+        allowed,
+        value_map,
+        enum8,
+        explain.value.unwrap_or(nil),
+    ];
+    Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+fn encode_source<'a>(env: Env<'a>, source: SourceMatch, mapping: &CompiledMapping) -> Term<'a> {
+    match source {
+        SourceMatch::Root => atoms::root().encode(env),
+        SourceMatch::Path => atoms::path().encode(env),
+        SourceMatch::Coalesce(index) => (atoms::coalesce(), index).encode(env),
+        SourceMatch::FromOutput(index) => (
+            atoms::from_output(),
+            crate::encode_string(env, &mapping.fields[index].name),
+        )
+            .encode(env),
+        SourceMatch::Wildcard => atoms::wildcard().encode(env),
+        SourceMatch::Aggregate => atoms::aggregate().encode(env),
+        SourceMatch::Literal => atoms::literal().encode(env),
+    }
+}
+
+fn encode_skip(env: Env<'_>, skip: Skip) -> Term<'_> {
+    match skip {
+        Skip::Missing => atoms::missing().encode(env),
+        Skip::Empty => atoms::empty().encode(env),
+        Skip::Filter(name) => match rustler::types::atom::Atom::from_str(env, name) {
+            Ok(name) => (atoms::filter(), name).encode(env),
+            Err(_) => atoms::filter().encode(env),
+        },
+    }
+}
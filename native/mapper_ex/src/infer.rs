@@ -0,0 +1,555 @@
+use std::collections::{BTreeSet, HashMap};
+
+use rustler::types::map::MapIterator;
+use rustler::types::ListIterator;
+use rustler::{Binary, Encoder, Env, Term};
+
+use crate::coerce::{detect_precision, parse_datetime_nanos};
+
+mod atoms {
+    rustler::atoms! {
+        config,
+        paths,
+        path,
+        field,
+        count,
+        nulls,
+        coverage,
+        null_rate,
+        distinct,
+        nil,
+    }
+}
+
+/// How far below the root maps are descended into before they are proposed
+/// as a single `json` or `flat_map` field.
+const DEFAULT_MAX_DEPTH: usize = 4;
+
+/// Most distinct strings a field may hold to get a candidate `value_map`.
+const DEFAULT_VALUE_MAP_LIMIT: usize = 10;
+
+/// Smallest integer read as a unix timestamp (2001-09-09 in seconds).
+const MIN_TIMESTAMP: i128 = 1_000_000_000;
+
+pub struct InferOptions {
+    pub max_depth: usize,
+    pub value_map_limit: usize,
+}
+
+impl Default for InferOptions {
+    fn default() -> Self {
+        InferOptions {
+            max_depth: DEFAULT_MAX_DEPTH,
+            value_map_limit: DEFAULT_VALUE_MAP_LIMIT,
+        }
+    }
+}
+
+/// What was seen at one path across the sample documents.
+#[derive(Default)]
+struct Node {
+    present: usize,
+    nulls: usize,
+    bools: usize,
+    ints: usize,
+    min_int: i128,
+    max_int: i128,
+    int_precision: u8,
+    floats: usize,
+    strings: usize,
+    datetimes: usize,
+    datetime_precision: u8,
+    /// Distinct strings, kept up to one past the value map limit.
+    distinct: BTreeSet<String>,
+    maps: usize,
+    /// Whether every map seen here held only scalar values.
+    scalar_maps: bool,
+    lists: usize,
+    elements: Elements,
+    other: usize,
+    children: Vec<(String, Node)>,
+    child_index: HashMap<String, usize>,
+}
+
+/// Kinds of the list elements seen at one path.
+#[derive(Default)]
+struct Elements {
+    total: usize,
+    strings: usize,
+    datetimes: usize,
+    datetime_precision: u8,
+    ints: usize,
+    negative_ints: usize,
+    floats: usize,
+    maps: usize,
+}
+
+struct Sampler {
+    options: InferOptions,
+    binary_keys: bool,
+    atom_keys: bool,
+}
+
+/// Proposes a mapping config from sample documents: a field per discovered
+/// path with an inferred type, plus per-path coverage and null statistics.
+pub fn infer_mapping<'a>(
+    env: Env<'a>,
+    documents: &[Term<'a>],
+    options: InferOptions,
+) -> Result<Term<'a>, String> {
+    if documents.is_empty() {
+        return Err("no sample documents".to_string());
+    }
+
+    let mut sampler = Sampler {
+        options,
+        binary_keys: false,
+        atom_keys: false,
+    };
+    let mut root = Node::default();
+    for document in documents {
+        if !document.is_map() {
+            return Err("sample documents must be maps".to_string());
+        }
+        sampler.visit_map(&mut root, *document, 0);
+    }
+
+    let mut proposals = Vec::new();
+    let mut segments = Vec::new();
+    sampler.propose(&root, &mut segments, &mut proposals);
+
+    let mut taken = HashMap::new();
+    let mut fields = Vec::with_capacity(proposals.len());
+    let mut stats = Vec::with_capacity(proposals.len());
+    for proposal in &proposals {
+        let name = unique_name(&proposal.segments, &mut taken);
+        fields.push(sampler.encode_field(env, &name, proposal));
+        stats.push(sampler.encode_stats(env, &name, proposal, documents.len()));
+    }
+
+    let mut config = vec![(crate::encode_string(env, "fields"), fields.encode(env))];
+    if sampler.atom_keys {
+        let key_mode = if sampler.binary_keys {
+            "either"
+        } else {
+            "atom"
+        };
+        config.push((
+            crate::encode_string(env, "key_mode"),
+            crate::encode_string(env, key_mode),
+        ));
+    }
+
+    Ok(encode_map(
+        env,
+        &[
+            (atoms::config().encode(env), encode_map(env, &config)),
+            (atoms::paths().encode(env), stats.encode(env)),
+        ],
+    ))
+}
+
+/// A proposed field: the path it reads and what was seen there.
+struct Proposal<'n> {
+    segments: Vec<&'n str>,
+    node: &'n Node,
+    field_type: &'static str,
+    precision: Option<u8>,
+}
+
+impl Sampler {
+    fn key_name(&mut self, key: Term) -> Option<String> {
+        if let Ok(binary) = key.decode::<Binary>() {
+            self.binary_keys = true;
+            return std::str::from_utf8(binary.as_slice())
+                .ok()
+                .map(str::to_string);
+        }
+        if key.is_atom() {
+            self.atom_keys = true;
+            return key.atom_to_string().ok();
+        }
+        None
+    }
+
+    fn visit_map(&mut self, node: &mut Node, map: Term, depth: usize) {
+        let Some(entries) = MapIterator::new(map) else {
+            return;
+        };
+        for (key, value) in entries {
+            // Keys that a path cannot spell are left out.
+            let Some(key) = self.key_name(key).filter(|key| is_path_key(key)) else {
+                continue;
+            };
+            let index = match node.child_index.get(&key) {
+                Some(&index) => index,
+                None => {
+                    node.child_index.insert(key.clone(), node.children.len());
+                    node.children.push((
+                        key,
+                        Node {
+                            scalar_maps: true,
+                            ..Default::default()
+                        },
+                    ));
+                    node.children.len() - 1
+                }
+            };
+            self.visit_value(&mut node.children[index].1, value, depth + 1);
+        }
+    }
+
+    fn visit_value(&mut self, node: &mut Node, value: Term, depth: usize) {
+        node.present += 1;
+        if value.is_atom() {
+            match value.decode::<bool>() {
+                Ok(_) => node.bools += 1,
+                Err(_) if value.decode::<rustler::Atom>().ok() == Some(atoms::nil()) => {
+                    node.nulls += 1
+                }
+                Err(_) => self.visit_string(node, &value.atom_to_string().unwrap_or_default()),
+            }
+        } else if let Some(int) = decode_int(value) {
+            let (min, max) = match node.ints {
+                0 => (int, int),
+                _ => (node.min_int.min(int), node.max_int.max(int)),
+            };
+            node.min_int = min;
+            node.max_int = max;
+            node.int_precision = node.int_precision.max(int_precision(int));
+            node.ints += 1;
+        } else if value.decode::<f64>().is_ok() {
+            node.floats += 1;
+        } else if let Ok(binary) = value.decode::<Binary>() {
+            self.visit_string(node, &String::from_utf8_lossy(binary.as_slice()));
+        } else if value.is_map() {
+            node.maps += 1;
+            node.scalar_maps &= MapIterator::new(value)
+                .map(|mut entries| entries.all(|(_, value)| is_scalar(value)))
+                .unwrap_or(true);
+            if depth < self.options.max_depth {
+                self.visit_map(node, value, depth);
+            }
+        } else if let Ok(elements) = value.decode::<ListIterator>() {
+            node.lists += 1;
+            for element in elements {
+                visit_element(&mut node.elements, element);
+            }
+        } else {
+            node.other += 1;
+        }
+    }
+
+    fn visit_string(&self, node: &mut Node, value: &str) {
+        node.strings += 1;
+        if let Some(precision) = datetime_precision(value) {
+            node.datetimes += 1;
+            node.datetime_precision = node.datetime_precision.max(precision);
+        }
+        if node.distinct.len() <= self.options.value_map_limit {
+            node.distinct.insert(value.to_string());
+        }
+    }
+
+    /// Walks the sampled tree depth first, proposing a field for every path
+    /// that is not a map of further proposals.
+    fn propose<'n>(
+        &self,
+        node: &'n Node,
+        segments: &mut Vec<&'n str>,
+        out: &mut Vec<Proposal<'n>>,
+    ) {
+        for (key, child) in &node.children {
+            segments.push(key);
+            if child.maps > 0
+                && child.maps + child.nulls == child.present
+                && !child.children.is_empty()
+            {
+                self.propose(child, segments, out);
+            } else {
+                let (field_type, precision) = infer_type(child, key);
+                out.push(Proposal {
+                    segments: segments.clone(),
+                    node: child,
+                    field_type,
+                    precision,
+                });
+            }
+            segments.pop();
+        }
+    }
+
+    fn encode_field<'a>(&self, env: Env<'a>, name: &str, proposal: &Proposal) -> Term<'a> {
+        let mut entries = vec![
+            (
+                crate::encode_string(env, "name"),
+                crate::encode_string(env, name),
+            ),
+            (
+                crate::encode_string(env, "type"),
+                crate::encode_string(env, proposal.field_type),
+            ),
+            (
+                crate::encode_string(env, "path"),
+                crate::encode_string(env, &format!("$.{}", proposal.segments.join("."))),
+            ),
+        ];
+        if let Some(precision) = proposal.precision {
+            entries.push((
+                crate::encode_string(env, "precision"),
+                precision.encode(env),
+            ));
+        }
+
+        // Low-cardinality strings get an identity value map as a starting point
+        // for remapping.
+        let node = proposal.node;
+        let distinct = node.distinct.len();
+        if proposal.field_type == "string"
+            && node.strings == node.present - node.nulls
+            && distinct > 0
+            && distinct <= self.options.value_map_limit
+            && node.strings >= 2 * distinct
+        {
+            let values: Vec<(Term<'a>, Term<'a>)> = node
+                .distinct
+                .iter()
+                .map(|value| {
+                    (
+                        crate::encode_string(env, value),
+                        crate::encode_string(env, value),
+                    )
+                })
+                .collect();
+            entries.push((
+                crate::encode_string(env, "value_map"),
+                encode_map(env, &values),
+            ));
+        }
+
+        encode_map(env, &entries)
+    }
+
+    fn encode_stats<'a>(
+        &self,
+        env: Env<'a>,
+        name: &str,
+        proposal: &Proposal,
+        documents: usize,
+    ) -> Term<'a> {
+        let node = proposal.node;
+        let null_rate = if node.present == 0 {
+            0.0
+        } else {
+            node.nulls as f64 / node.present as f64
+        };
+        let distinct = if node.strings > 0 && node.distinct.len() <= self.options.value_map_limit {
+            node.distinct.len().encode(env)
+        } else {
+            atoms::nil().encode(env)
+        };
+
+        encode_map(
+            env,
+            &[
+                (
+                    atoms::path().encode(env),
+                    crate::encode_string(env, &format!("$.{}", proposal.segments.join("."))),
+                ),
+                (atoms::field().encode(env), crate::encode_string(env, name)),
+                (atoms::count().encode(env), node.present.encode(env)),
+                (atoms::nulls().encode(env), node.nulls.encode(env)),
+                (
+                    atoms::coverage().encode(env),
+                    (node.present as f64 / documents as f64).encode(env),
+                ),
+                (atoms::null_rate().encode(env), null_rate.encode(env)),
+                (atoms::distinct().encode(env), distinct),
+            ],
+        )
+    }
+}
+
+fn visit_element(elements: &mut Elements, element: Term) {
+    elements.total += 1;
+    if let Some(int) = decode_int(element) {
+        elements.ints += 1;
+        if int < 0 {
+            elements.negative_ints += 1;
+        }
+    } else if element.decode::<f64>().is_ok() {
+        elements.floats += 1;
+    } else if let Ok(binary) = element.decode::<Binary>() {
+        elements.strings += 1;
+        let precision = std::str::from_utf8(binary.as_slice())
+            .ok()
+            .and_then(datetime_precision);
+        if let Some(precision) = precision {
+            elements.datetimes += 1;
+            elements.datetime_precision = elements.datetime_precision.max(precision);
+        }
+    } else if element.is_map() {
+        elements.maps += 1;
+    }
+}
+
+fn infer_type(node: &Node, key: &str) -> (&'static str, Option<u8>) {
+    let values = node.present - node.nulls;
+    if values == 0 {
+        return ("string", None);
+    }
+    if node.other > 0
+        || (node.maps > 0 && node.maps < values)
+        || (node.lists > 0 && node.lists < values)
+    {
+        return ("json", None);
+    }
+    if node.maps > 0 {
+        return (if node.scalar_maps { "flat_map" } else { "json" }, None);
+    }
+    if node.lists > 0 {
+        return infer_array_type(&node.elements);
+    }
+    if node.bools == values {
+        return ("bool", None);
+    }
+    if node.strings == values {
+        if node.datetimes == values {
+            return ("datetime64", Some(node.datetime_precision));
+        }
+        return ("string", None);
+    }
+    if node.ints == values {
+        if is_time_key(key) && node.min_int >= MIN_TIMESTAMP {
+            return ("datetime64", Some(node.int_precision));
+        }
+        if node.min_int >= 0 {
+            return ("uint64", None);
+        }
+        if node.min_int >= i32::MIN as i128 && node.max_int <= i32::MAX as i128 {
+            return ("int32", None);
+        }
+        return ("float64", None);
+    }
+    if node.ints + node.floats == values {
+        return ("float64", None);
+    }
+    ("string", None)
+}
+
+fn infer_array_type(elements: &Elements) -> (&'static str, Option<u8>) {
+    match elements {
+        Elements { total: 0, .. } => ("array_json", None),
+        Elements {
+            total, datetimes, ..
+        } if datetimes == total => ("array_datetime64", Some(elements.datetime_precision)),
+        Elements { total, strings, .. } if strings == total => ("array_string", None),
+        Elements {
+            total,
+            ints,
+            negative_ints: 0,
+            ..
+        } if ints == total => ("array_uint64", None),
+        Elements {
+            total,
+            ints,
+            floats,
+            ..
+        } if ints + floats == *total => ("array_float64", None),
+        _ => ("array_json", None),
+    }
+}
+
+fn decode_int(value: Term) -> Option<i128> {
+    value
+        .decode::<i64>()
+        .map(i128::from)
+        .or_else(|_| value.decode::<u64>().map(i128::from))
+        .ok()
+}
+
+fn int_precision(value: i128) -> u8 {
+    i64::try_from(value).map(detect_precision).unwrap_or(9)
+}
+
+fn is_scalar(value: Term) -> bool {
+    !value.is_map() && value.decode::<ListIterator>().is_err()
+}
+
+/// The precision of an RFC3339 timestamp's fraction, rounded up to 3, 6 or
+/// 9 digits; `None` when `value` is not a timestamp.
+fn datetime_precision(value: &str) -> Option<u8> {
+    // Bare dates and numbers are not proposed as timestamps.
+    if value.len() < 19 || !value.as_bytes()[0].is_ascii_digit() {
+        return None;
+    }
+    parse_datetime_nanos(value)?;
+    let digits = value
+        .split_once('.')
+        .map(|(_, fraction)| fraction.bytes().take_while(u8::is_ascii_digit).count())
+        .unwrap_or(0);
+    Some(match digits {
+        0 => 0,
+        1..=3 => 3,
+        4..=6 => 6,
+        _ => 9,
+    })
+}
+
+/// Whether an integer under `key` reads as a unix timestamp.
+fn is_time_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    ["time", "timestamp", "ts"].contains(&key.as_str())
+        || [
+            "_at",
+            "_time",
+            "timestamp",
+            "_ts",
+            "_unix_nano",
+            "_nanos",
+            "_ms",
+        ]
+        .iter()
+        .any(|suffix| key.ends_with(suffix))
+}
+
+fn is_path_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(['.', '[', ']', '*', '$'])
+}
+
+/// Names a field after its path, e.g. `metadata_level` for
+/// `$.metadata.level`, suffixing repeats so names stay unique.
+fn unique_name(segments: &[&str], taken: &mut HashMap<String, usize>) -> String {
+    let base = segments.join("_");
+    let mut name = base.clone();
+    while let Some(count) = taken.get_mut(&name) {
+        *count += 1;
+        name = format!("{}_{}", base, count);
+    }
+    taken.insert(name.clone(), 1);
+    name
+}
+
+fn encode_map<'a>(env: Env<'a>, entries: &[(Term<'a>, Term<'a>)]) -> Term<'a> {
+    let keys: Vec<Term<'a>> = entries.iter().map(|(key, _)| *key).collect();
+    let values: Vec<Term<'a>> = entries.iter().map(|(_, value)| *value).collect();
+    Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_timestamp_precision() {
+        assert_eq!(datetime_precision("2026-01-21T17:54:48Z"), Some(0));
+        assert_eq!(datetime_precision("2026-01-21T17:54:48.144Z"), Some(3));
+        assert_eq!(datetime_precision("2026-01-21 17:54:48.144506Z"), Some(6));
+        assert_eq!(
+            datetime_precision("2026-01-21T17:54:48.1445061+02:00"),
+            Some(9)
+        );
+        assert_eq!(datetime_precision("2026-01-21"), None);
+        assert_eq!(datetime_precision("not a timestamp at all"), None);
+    }
+}
@@ -0,0 +1,248 @@
+use rustler::{Encoder, Env, Term};
+
+use crate::mapping::{
+    CaseSource, CompiledField, CompiledMapping, CompiledOutput, FieldType, PathSource,
+};
+use crate::path::{CompiledPath, PathSegment};
+use crate::query::KeyMode;
+
+mod atoms {
+    rustler::atoms! {
+        nil,
+        fields,
+        name,
+        type_ = "type",
+        precision,
+        source,
+        paths,
+        from_output,
+        cases,
+        path,
+        segments,
+        cached,
+        cache_indices,
+        flat_key,
+        flat_cache_index,
+        wildcard,
+        aggregate,
+        wildcard_index,
+        root,
+        coalesce,
+        output,
+        key_mode,
+        path_cache_size,
+        root_cache_size,
+        root_cache_keys,
+        key_slot_count,
+        map,
+        clickhouse_row_binary,
+        clickhouse_native,
+        arrow_ipc,
+        json,
+        otlp_protobuf,
+        binary,
+        atom,
+        either,
+    }
+}
+
+/// Describes a compiled mapping: per field, its type, source kind and paths
+/// in coalesce order with their cache assignments, plus the mapping-wide
+/// path cache layout.
+pub fn inspect_mapping<'a>(env: Env<'a>, mapping: &CompiledMapping) -> Term<'a> {
+    let fields: Vec<Term<'a>> = mapping
+        .fields
+        .iter()
+        .map(|field| encode_field(env, field, mapping))
+        .collect();
+
+    let root = mapping
+        .preloads
+        .nodes
+        .iter()
+        .find(|node| node.prefix.is_empty());
+    let mut root_cache_keys: Vec<(&Vec<u8>, &usize)> =
+        root.map_or_else(Vec::new, |node| node.keys.iter().collect());
+    let root_cache_size = root_cache_keys.len();
+    root_cache_keys.sort_by_key(|(_, index)| **index);
+    let root_cache_keys: Vec<Term<'a>> = root_cache_keys
+        .into_iter()
+        .map(|(key, index)| {
+            let key = crate::encode_string(env, &String::from_utf8_lossy(key));
+            (key, *index).encode(env)
+        })
+        .collect();
+
+    let output = match &mapping.output {
+        CompiledOutput::Map => atoms::map(),
+        CompiledOutput::ClickHouseRowBinary(_) => atoms::clickhouse_row_binary(),
+        CompiledOutput::ClickHouseNative(_) => atoms::clickhouse_native(),
+        CompiledOutput::ArrowIpc(_) => atoms::arrow_ipc(),
+        CompiledOutput::Json(_) => atoms::json(),
+        CompiledOutput::OtlpProtobuf(_) => atoms::otlp_protobuf(),
+    };
+    let key_mode = match mapping.key_mode {
+        KeyMode::Binary => atoms::binary(),
+        KeyMode::Atom => atoms::atom(),
+        KeyMode::Either => atoms::either(),
+    };
+
+    encode_map(
+        env,
+        &[
+            (atoms::fields(), fields.encode(env)),
+            (atoms::output(), output.encode(env)),
+            (atoms::key_mode(), key_mode.encode(env)),
+            (
+                atoms::path_cache_size(),
+                mapping.path_cache_size.encode(env),
+            ),
+            (atoms::root_cache_size(), root_cache_size.encode(env)),
+            (atoms::root_cache_keys(), root_cache_keys.encode(env)),
+            (atoms::key_slot_count(), mapping.key_slot_count.encode(env)),
+        ],
+    )
+}
+
+fn encode_field<'a>(env: Env<'a>, field: &CompiledField, mapping: &CompiledMapping) -> Term<'a> {
+    let nil = atoms::nil().encode(env);
+    let precision = match field.field_type {
+        FieldType::DateTime64 { precision } | FieldType::ArrayDateTime64 { precision } => {
+            precision.encode(env)
+        }
+        _ => nil,
+    };
+    let (source, cases) = match &field.path_source {
+        PathSource::Root => (atoms::root().encode(env), nil),
+        PathSource::Single(_) => (atoms::path().encode(env), nil),
+        PathSource::Aggregate(_) => (atoms::aggregate().encode(env), nil),
+        PathSource::Coalesce(_) => (atoms::coalesce().encode(env), nil),
+        PathSource::FromOutput(_) | PathSource::FromOutputName(_) => {
+            (atoms::from_output().encode(env), nil)
+        }
+        PathSource::Cases(cases) => (atoms::cases().encode(env), cases.len().encode(env)),
+    };
+
+    let aggregate = match &field.path_source {
+        PathSource::Aggregate(aggregate) => crate::encode_string(env, aggregate.function.name()),
+        _ => nil,
+    };
+
+    let mut paths = Vec::new();
+    let mut from_output = Vec::new();
+    collect_source(&field.path_source, &mut paths, &mut from_output);
+    let paths: Vec<Term<'a>> = paths
+        .into_iter()
+        .map(|path| encode_path(env, path))
+        .collect();
+    let from_output: Vec<Term<'a>> = from_output
+        .into_iter()
+        .map(|index| crate::encode_string(env, &mapping.fields[index].name))
+        .collect();
+
+    encode_map(
+        env,
+        &[
+            (atoms::name(), crate::encode_string(env, &field.name)),
+            (
+                atoms::type_(),
+                crate::encode_string(env, field.field_type.name()),
+            ),
+            (atoms::precision(), precision),
+            (atoms::source(), source),
+            (atoms::cases(), cases),
+            (atoms::aggregate(), aggregate),
+            (atoms::paths(), paths.encode(env)),
+            (atoms::from_output(), from_output.encode(env)),
+        ],
+    )
+}
+
+/// Collects a source's paths in the order they are tried, and the indices of
+/// the fields it reads through `from_output`.
+fn collect_source<'m>(
+    source: &'m PathSource,
+    paths: &mut Vec<&'m CompiledPath>,
+    from_output: &mut Vec<usize>,
+) {
+    match source {
+        PathSource::Single(path) => paths.push(path),
+        PathSource::Aggregate(aggregate) => paths.push(&aggregate.path),
+        PathSource::Coalesce(candidates) => paths.extend(candidates),
+        PathSource::FromOutput(index) => from_output.push(*index),
+        PathSource::Cases(cases) => {
+            for case in cases {
+                if let CaseSource::Path(source) = &case.source {
+                    collect_source(source, paths, from_output);
+                }
+            }
+        }
+        PathSource::Root | PathSource::FromOutputName(_) => {}
+    }
+}
+
+fn encode_path<'a>(env: Env<'a>, path: &CompiledPath) -> Term<'a> {
+    let segments: Vec<Term<'a>> = path
+        .segments
+        .iter()
+        .map(|segment| match segment {
+            PathSegment::Key(key) => crate::encode_string(env, key),
+            PathSegment::Index(index) => index.encode(env),
+            PathSegment::Wildcard => atoms::wildcard().encode(env),
+        })
+        .collect();
+    let flat_key = match &path.flat_key {
+        Some(key) => crate::encode_string(env, key),
+        None => atoms::nil().encode(env),
+    };
+
+    encode_map(
+        env,
+        &[
+            (
+                atoms::path(),
+                crate::encode_string(env, &path_string(&path.segments)),
+            ),
+            (atoms::segments(), segments.encode(env)),
+            (atoms::cached(), path.cached.encode(env)),
+            (atoms::cache_indices(), path.cache_indices.encode(env)),
+            (atoms::wildcard_index(), path.wildcard_index.encode(env)),
+            (atoms::flat_key(), flat_key),
+            (atoms::flat_cache_index(), path.flat_cache_index.encode(env)),
+        ],
+    )
+}
+
+/// Renders segments back into `$`-prefixed path notation.
+fn path_string(segments: &[PathSegment]) -> String {
+    let mut path = String::from("$");
+    for segment in segments {
+        match segment {
+            PathSegment::Key(key) => {
+                path.push('.');
+                path.push_str(key);
+            }
+            PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+            PathSegment::Wildcard => path.push_str("[*]"),
+        }
+    }
+    path
+}
+
+fn encode_map<'a>(env: Env<'a>, entries: &[(rustler::Atom, Term<'a>)]) -> Term<'a> {
+    let keys: Vec<Term<'a>> = entries.iter().map(|(key, _)| key.encode(env)).collect();
+    let values: Vec<Term<'a>> = entries.iter().map(|(_, value)| *value).collect();
+    Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_paths() {
+        for path in ["$", "$.a", "$.a.b[0]", "$.events[*].name"] {
+            assert_eq!(path_string(&crate::path::parse(path).unwrap()), path);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use rustler::{Encoder, Env, Term};
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+
+use crate::path::{CompiledPath, PathSegment};
+
+/// The parts of a JSON document a mapping reads, built from its paths at
+/// compile time. Parsing keeps only these parts and skips the rest of the
+/// input.
+#[derive(Debug, Default)]
+pub struct JsonProjection {
+    /// Keep this value and everything below it.
+    whole: bool,
+    keys: HashMap<String, JsonProjection>,
+    /// Projection applied to every element when the value is an array.
+    elements: Option<Box<JsonProjection>>,
+}
+
+/// Projections for nested and `flat_keys` lookups.
+#[derive(Debug, Default)]
+pub struct JsonProjections {
+    pub nested: JsonProjection,
+    pub flat: JsonProjection,
+}
+
+impl JsonProjection {
+    /// A projection keeping the whole document.
+    pub fn whole() -> Self {
+        JsonProjection {
+            whole: true,
+            ..Default::default()
+        }
+    }
+
+    /// Keeps what `segments` read, including the whole value they end at.
+    pub fn insert(&mut self, segments: &[PathSegment]) {
+        if self.whole {
+            return;
+        }
+        let Some((segment, rest)) = segments.split_first() else {
+            *self = JsonProjection::whole();
+            return;
+        };
+        let child = match segment {
+            PathSegment::Key(key) => self.keys.entry(key.clone()).or_default(),
+            // Indexed and wildcard segments keep every element, so positions
+            // in the decoded array match the input.
+            PathSegment::Index(_) | PathSegment::Wildcard => {
+                self.elements.get_or_insert_with(Default::default)
+            }
+        };
+        child.insert(rest);
+    }
+
+    /// Keeps the root-level key a `flat_keys` lookup of `path` reads.
+    pub fn insert_flat(&mut self, path: &CompiledPath) {
+        match &path.flat_key {
+            _ if path.segments.is_empty() => *self = JsonProjection::whole(),
+            Some(key) if !self.whole => {
+                self.keys.insert(key.clone(), JsonProjection::whole());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A JSON value borrowed from the input bytes: strings and keys without
+/// escapes point into the input. Paths are evaluated over it, and only the
+/// values they resolve to are built as terms.
+#[derive(Debug, PartialEq)]
+pub enum JsonValue<'j> {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Unsigned(u64),
+    Float(f64),
+    String(Cow<'j, str>),
+    Array(Vec<JsonValue<'j>>),
+    /// Entries in input order, duplicates included; lookups take the last.
+    Object(Vec<(Cow<'j, str>, JsonValue<'j>)>),
+}
+
+/// Parses a JSON object, keeping only what `projection` keeps.
+pub fn parse<'j>(json: &'j [u8], projection: &JsonProjection) -> Result<JsonValue<'j>, String> {
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    let document = ProjectedSeed {
+        projection: Some(projection).filter(|projection| !projection.whole),
+    }
+    .deserialize(&mut deserializer)
+    .and_then(|document| deserializer.end().map(|_| document))
+    .map_err(|error| format!("invalid JSON: {}", error))?;
+
+    match document {
+        JsonValue::Object(_) => Ok(document),
+        _ => Err("JSON document must be an object".to_string()),
+    }
+}
+
+impl<'j> JsonValue<'j> {
+    /// The value of `key` in an object; the last one when it repeats.
+    pub fn get(&self, key: &str) -> Option<&JsonValue<'j>> {
+        match self {
+            JsonValue::Object(entries) => entries
+                .iter()
+                .rev()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Follows key and index segments, stopping before any wildcard.
+    pub fn select(&self, segments: &[PathSegment]) -> Option<&JsonValue<'j>> {
+        segments
+            .iter()
+            .try_fold(self, |current, segment| match (segment, current) {
+                (PathSegment::Key(key), _) => current.get(key),
+                (PathSegment::Index(index), JsonValue::Array(elements)) => elements.get(*index),
+                _ => None,
+            })
+    }
+
+    /// Evaluates `segments` from this value, as `query::evaluate` does over
+    /// terms: a wildcard maps the rest of the path over each element.
+    pub fn evaluate<'a>(&self, env: Env<'a>, segments: &[PathSegment], nil: Term<'a>) -> Term<'a> {
+        let wildcard = segments
+            .iter()
+            .position(|segment| matches!(segment, PathSegment::Wildcard));
+        let prefix = &segments[..wildcard.unwrap_or(segments.len())];
+        let Some(current) = self.select(prefix) else {
+            return nil;
+        };
+        let Some(wildcard) = wildcard else {
+            return current.to_term(env, nil);
+        };
+        match current {
+            JsonValue::Array(elements) => elements
+                .iter()
+                .map(|element| element.evaluate(env, &segments[wildcard + 1..], nil))
+                .collect::<Vec<Term<'a>>>()
+                .encode(env),
+            _ => nil,
+        }
+    }
+
+    /// Builds this value as a term. Object keys become binaries.
+    pub fn to_term<'a>(&self, env: Env<'a>, nil: Term<'a>) -> Term<'a> {
+        match self {
+            JsonValue::Null => nil,
+            JsonValue::Bool(value) => value.encode(env),
+            JsonValue::Integer(value) => value.encode(env),
+            JsonValue::Unsigned(value) => value.encode(env),
+            JsonValue::Float(value) => value.encode(env),
+            JsonValue::String(value) => crate::encode_string(env, value),
+            JsonValue::Array(elements) => elements
+                .iter()
+                .map(|element| element.to_term(env, nil))
+                .collect::<Vec<Term<'a>>>()
+                .encode(env),
+            JsonValue::Object(entries) => {
+                let keys: Vec<Term<'a>> = entries
+                    .iter()
+                    .map(|(key, _)| crate::encode_string(env, key))
+                    .collect();
+                let values: Vec<Term<'a>> = entries
+                    .iter()
+                    .map(|(_, value)| value.to_term(env, nil))
+                    .collect();
+                // Duplicate keys fail the bulk build; the last one wins, as in Jason.
+                Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| {
+                    keys.iter()
+                        .zip(&values)
+                        .fold(Term::map_new(env), |map, (&key, &value)| {
+                            map.map_put(key, value).unwrap_or(map)
+                        })
+                })
+            }
+        }
+    }
+}
+
+/// Parses one JSON value; `None` keeps the whole value.
+#[derive(Clone, Copy)]
+struct ProjectedSeed<'p> {
+    projection: Option<&'p JsonProjection>,
+}
+
+impl<'p> ProjectedSeed<'p> {
+    fn child(self, projection: &'p JsonProjection) -> Self {
+        ProjectedSeed {
+            projection: Some(projection).filter(|projection| !projection.whole),
+        }
+    }
+}
+
+impl<'de, 'p> DeserializeSeed<'de> for ProjectedSeed<'p> {
+    type Value = JsonValue<'de>;
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<JsonValue<'de>, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'p> Visitor<'de> for ProjectedSeed<'p> {
+    type Value = JsonValue<'de>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Null)
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Bool(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Integer(value))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<JsonValue<'de>, E> {
+        Ok(match i64::try_from(value) {
+            Ok(value) => JsonValue::Integer(value),
+            Err(_) => JsonValue::Unsigned(value),
+        })
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::Float(value))
+    }
+
+    fn visit_borrowed_str<E>(self, value: &'de str) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::String(Cow::Borrowed(value)))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<JsonValue<'de>, E> {
+        Ok(JsonValue::String(Cow::Owned(value.to_string())))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JsonValue<'de>, A::Error> {
+        let element = match self.projection {
+            None => self,
+            Some(projection) => match &projection.elements {
+                Some(elements) => self.child(elements),
+                None => {
+                    while seq.next_element::<IgnoredAny>()?.is_some() {}
+                    return Ok(JsonValue::Array(Vec::new()));
+                }
+            },
+        };
+
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element_seed(element)? {
+            elements.push(value);
+        }
+        Ok(JsonValue::Array(elements))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonValue<'de>, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(JsonKey(key)) = map.next_key()? {
+            let seed = match self.projection {
+                None => self,
+                Some(projection) => match projection.keys.get(key.as_ref()) {
+                    Some(child) => self.child(child),
+                    None => {
+                        map.next_value::<IgnoredAny>()?;
+                        continue;
+                    }
+                },
+            };
+            entries.push((key, map.next_value_seed(seed)?));
+        }
+        Ok(JsonValue::Object(entries))
+    }
+}
+
+/// An object key, borrowed from the input unless it contains escapes.
+struct JsonKey<'de>(Cow<'de, str>);
+
+impl<'de> Deserialize<'de> for JsonKey<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyVisitor;
+
+        impl<'de> Visitor<'de> for KeyVisitor {
+            type Value = JsonKey<'de>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a string key")
+            }
+
+            fn visit_borrowed_str<E>(self, value: &'de str) -> Result<JsonKey<'de>, E> {
+                Ok(JsonKey(Cow::Borrowed(value)))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<JsonKey<'de>, E> {
+                Ok(JsonKey(Cow::Owned(value.to_string())))
+            }
+        }
+
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsed_documents_borrow_strings_and_keep_the_last_duplicate() {
+        let segments = |path: &str| crate::path::compile(path).unwrap().segments;
+        let json = br#"{"a": {"b": [1, {"c": "plain"}]}, "e": "esc\"aped", "a": {"b": [2]}}"#;
+        let document = parse(json, &JsonProjection::whole()).unwrap();
+
+        assert_eq!(
+            document.select(&segments("$.a.b[0]")),
+            Some(&JsonValue::Integer(2))
+        );
+        assert!(matches!(
+            document.get("e"),
+            Some(JsonValue::String(Cow::Owned(value))) if value == "esc\"aped"
+        ));
+
+        let document = parse(
+            br#"{"a": {"b": [1, {"c": "plain"}]}}"#,
+            &JsonProjection::whole(),
+        );
+        let document = document.unwrap();
+        assert!(matches!(
+            document.select(&segments("$.a.b[1].c")),
+            Some(JsonValue::String(Cow::Borrowed("plain")))
+        ));
+        assert_eq!(document.select(&segments("$.a.x")), None);
+    }
+
+    #[test]
+    fn parsing_skips_what_the_projection_leaves_out() {
+        let mut projection = JsonProjection::default();
+        projection.insert(&crate::path::compile("$.kept").unwrap().segments);
+        let document = parse(br#"{"kept": 1, "skipped": {"deep": [1, 2]}}"#, &projection);
+
+        assert_eq!(
+            document.unwrap(),
+            JsonValue::Object(vec![(Cow::Borrowed("kept"), JsonValue::Integer(1))])
+        );
+        assert!(parse(b"[1]", &projection).is_err());
+        assert!(parse(br#"{"kept": }"#, &projection).is_err());
+    }
+
+    #[test]
+    fn projections_keep_what_paths_read() {
+        let segments = |path: &str| crate::path::compile(path).unwrap().segments;
+        let mut projection = JsonProjection::default();
+        projection.insert(&segments("$.metadata.level"));
+        projection.insert(&segments("$.events[*].name"));
+
+        let metadata = &projection.keys["metadata"];
+        assert!(!metadata.whole && metadata.keys["level"].whole);
+        let events = projection.keys["events"].elements.as_ref().unwrap();
+        assert!(events.keys["name"].whole);
+
+        // A path ending at a prefix keeps the whole subtree.
+        projection.insert(&segments("$.metadata"));
+        assert!(projection.keys["metadata"].whole);
+        projection.insert(&segments("$"));
+        assert!(projection.whole);
+    }
+}
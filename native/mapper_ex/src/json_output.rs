@@ -0,0 +1,269 @@
+use rustler::{Binary, Term};
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::clickhouse_rowbinary::EncodeResult;
+use crate::mapper::JsonTerm;
+use crate::mapping::{CompiledField, DefaultValue};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyOrder {
+    /// Keys follow the order of the mapping's fields.
+    Config,
+    /// Keys are sorted lexicographically at every object level.
+    Sorted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyShape {
+    /// Field names are written as-is, e.g. `{"resource.service": "api"}`.
+    Dotted,
+    /// Field names are split on `.` into nested objects, e.g.
+    /// `{"resource": {"service": "api"}}`.
+    Nested,
+}
+
+#[derive(Debug)]
+enum JsonNode {
+    Value {
+        key: String,
+        index: usize,
+    },
+    Object {
+        key: String,
+        children: Vec<JsonNode>,
+    },
+}
+
+impl JsonNode {
+    fn key(&self) -> &str {
+        match self {
+            JsonNode::Value { key, .. } | JsonNode::Object { key, .. } => key,
+        }
+    }
+}
+
+/// Object layout for JSON output, compiled once per mapping.
+#[derive(Debug)]
+pub struct CompiledJsonLayout {
+    nodes: Vec<JsonNode>,
+    defaults: Box<[DefaultValue]>,
+    omit_defaults: bool,
+}
+
+pub fn compile_layout(
+    fields: &[CompiledField],
+    key_order: KeyOrder,
+    key_shape: KeyShape,
+    omit_defaults: bool,
+) -> EncodeResult<CompiledJsonLayout> {
+    let mut nodes = Vec::with_capacity(fields.len());
+    for (index, field) in fields.iter().enumerate() {
+        match key_shape {
+            KeyShape::Dotted => nodes.push(JsonNode::Value {
+                key: field.name.clone(),
+                index,
+            }),
+            KeyShape::Nested => {
+                let segments: Vec<&str> = field.name.split('.').collect();
+                insert_nested(&mut nodes, &segments, index, &field.name)?;
+            }
+        }
+    }
+    if key_order == KeyOrder::Sorted {
+        sort_nodes(&mut nodes);
+    }
+
+    Ok(CompiledJsonLayout {
+        nodes,
+        defaults: fields.iter().map(|field| field.default.clone()).collect(),
+        omit_defaults,
+    })
+}
+
+fn insert_nested(
+    nodes: &mut Vec<JsonNode>,
+    segments: &[&str],
+    index: usize,
+    name: &str,
+) -> EncodeResult<()> {
+    let conflict = || format!("JSON output key '{name}' conflicts with another nested field");
+    let (segment, rest) = segments.split_first().ok_or_else(conflict)?;
+
+    let existing = nodes.iter_mut().find(|node| node.key() == *segment);
+    match (existing, rest.is_empty()) {
+        (None, true) => nodes.push(JsonNode::Value {
+            key: segment.to_string(),
+            index,
+        }),
+        (None, false) => {
+            let mut children = Vec::new();
+            insert_nested(&mut children, rest, index, name)?;
+            nodes.push(JsonNode::Object {
+                key: segment.to_string(),
+                children,
+            });
+        }
+        (Some(JsonNode::Object { children, .. }), false) => {
+            insert_nested(children, rest, index, name)?
+        }
+        (Some(_), _) => return Err(conflict()),
+    }
+    Ok(())
+}
+
+fn sort_nodes(nodes: &mut [JsonNode]) {
+    nodes.sort_by(|left, right| left.key().cmp(right.key()));
+    for node in nodes {
+        if let JsonNode::Object { children, .. } = node {
+            sort_nodes(children);
+        }
+    }
+}
+
+/// Appends one mapped row to `output` as a JSON object.
+pub fn write_row<'a>(
+    output: &mut Vec<u8>,
+    layout: &CompiledJsonLayout,
+    values: &[Term<'a>],
+    nil: Term<'a>,
+) -> EncodeResult<()> {
+    let row = JsonRow {
+        layout,
+        values,
+        nil,
+    };
+    let mut serializer = serde_json::Serializer::new(output);
+    JsonObject {
+        row: &row,
+        nodes: &row.layout.nodes,
+    }
+    .serialize(&mut serializer)
+    .map_err(|error| format!("failed to encode JSON output: {error}"))
+}
+
+struct JsonRow<'r, 'a> {
+    layout: &'r CompiledJsonLayout,
+    values: &'r [Term<'a>],
+    nil: Term<'a>,
+}
+
+impl JsonRow<'_, '_> {
+    fn includes(&self, node: &JsonNode) -> bool {
+        match node {
+            JsonNode::Value { index, .. } => !self.omitted(*index),
+            JsonNode::Object { children, .. } => children.iter().any(|child| self.includes(child)),
+        }
+    }
+
+    fn omitted(&self, index: usize) -> bool {
+        if !self.layout.omit_defaults {
+            return false;
+        }
+        let value = self.values[index];
+        value == self.nil || is_zero(value) || is_default(value, &self.layout.defaults[index])
+    }
+}
+
+struct JsonObject<'o, 'r, 'a> {
+    row: &'o JsonRow<'r, 'a>,
+    nodes: &'o [JsonNode],
+}
+
+impl Serialize for JsonObject<'_, '_, '_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        for node in self.nodes.iter().filter(|node| self.row.includes(node)) {
+            match node {
+                JsonNode::Value { key, index } => map.serialize_entry(
+                    key,
+                    &JsonTerm {
+                        value: self.row.values[*index],
+                        nil: self.row.nil,
+                    },
+                )?,
+                JsonNode::Object { key, children } => map.serialize_entry(
+                    key,
+                    &JsonObject {
+                        row: self.row,
+                        nodes: children,
+                    },
+                )?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// True for the values a field type coerces `nil` to: `""`, `0`, `0.0`,
+/// `false`, `[]` and `%{}`.
+fn is_zero(value: Term) -> bool {
+    if let Ok(binary) = value.decode::<Binary>() {
+        return binary.is_empty();
+    }
+    if let Ok(number) = value.decode::<i64>() {
+        return number == 0;
+    }
+    if let Ok(number) = value.decode::<f64>() {
+        return number == 0.0;
+    }
+    if let Ok(flag) = value.decode::<bool>() {
+        return !flag;
+    }
+    if let Ok(length) = value.list_length() {
+        return length == 0;
+    }
+    value.map_size().is_ok_and(|size| size == 0)
+}
+
+fn is_default(value: Term, default: &DefaultValue) -> bool {
+    match default {
+        DefaultValue::Str(expected) => value
+            .decode::<Binary>()
+            .is_ok_and(|binary| binary.as_slice() == expected.as_bytes()),
+        DefaultValue::Int(expected) => value.decode::<i64>().is_ok_and(|v| v == *expected),
+        DefaultValue::Uint(expected) => value.decode::<u64>().is_ok_and(|v| v == *expected),
+        DefaultValue::Flt(expected) => value.decode::<f64>().is_ok_and(|v| v == *expected),
+        DefaultValue::Bool(expected) => value.decode::<bool>().is_ok_and(|v| v == *expected),
+        DefaultValue::Nil | DefaultValue::EmptyList | DefaultValue::EmptyMap => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(nodes: &[JsonNode]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|node| match node {
+                JsonNode::Value { key, index } => format!("{key}={index}"),
+                JsonNode::Object { key, children } => format!("{key}{:?}", keys(children)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nested_keys_group_by_prefix_in_field_order() {
+        let mut nodes = Vec::new();
+        for (index, name) in ["b.y", "a", "b.x"].iter().enumerate() {
+            let segments: Vec<&str> = name.split('.').collect();
+            insert_nested(&mut nodes, &segments, index, name).unwrap();
+        }
+        assert_eq!(keys(&nodes), vec!["b[\"y=0\", \"x=2\"]", "a=1"]);
+
+        sort_nodes(&mut nodes);
+        assert_eq!(keys(&nodes), vec!["a=1", "b[\"x=2\", \"y=0\"]"]);
+    }
+
+    #[test]
+    fn nested_keys_reject_value_and_object_at_same_path() {
+        let mut nodes = Vec::new();
+        insert_nested(&mut nodes, &["a"], 0, "a").unwrap();
+        let error = insert_nested(&mut nodes, &["a", "b"], 1, "a.b").unwrap_err();
+        assert!(error.contains("'a.b' conflicts"));
+    }
+}
@@ -0,0 +1,133 @@
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    SipHash,
+    XxHash,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "siphash" => Some(HashAlgorithm::SipHash),
+            "xxhash" => Some(HashAlgorithm::XxHash),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// A hash keyed with the mapping's secret, so pseudonyms are stable across
+/// tables and nodes but cannot be recomputed without the key.
+///
+/// SipHash-2-4 and XXH3 take their key or seed from SHA-256 of the secret;
+/// SHA-256 is HMAC-SHA256 over the secret itself. XXH3 is the fastest but is
+/// not a cryptographic MAC, so prefer the other two when inputs are guessable.
+#[derive(Debug, Clone)]
+pub struct KeyedHash {
+    keyed: Keyed,
+}
+
+#[derive(Debug, Clone)]
+enum Keyed {
+    SipHash([u8; 16]),
+    XxHash(u64),
+    /// HMAC key block, pre-padded to the SHA-256 block size.
+    Sha256([u8; 64]),
+}
+
+const SHA256_BLOCK: usize = 64;
+
+impl KeyedHash {
+    pub fn new(algorithm: HashAlgorithm, secret: &[u8]) -> Self {
+        let derived: [u8; 32] = Sha256::digest(secret).into();
+        let keyed = match algorithm {
+            HashAlgorithm::SipHash => {
+                Keyed::SipHash(derived[..16].try_into().expect("16-byte prefix"))
+            }
+            HashAlgorithm::XxHash => Keyed::XxHash(u64::from_le_bytes(
+                derived[..8].try_into().expect("8-byte prefix"),
+            )),
+            HashAlgorithm::Sha256 => {
+                let mut block = [0u8; SHA256_BLOCK];
+                if secret.len() > SHA256_BLOCK {
+                    block[..32].copy_from_slice(&derived);
+                } else {
+                    block[..secret.len()].copy_from_slice(secret);
+                }
+                Keyed::Sha256(block)
+            }
+        };
+        KeyedHash { keyed }
+    }
+
+    /// The digest as an unsigned integer, for `UInt64` fields. SHA-256 uses
+    /// its first 8 bytes, big-endian.
+    pub fn hash_u64(&self, input: &[u8]) -> u64 {
+        match &self.keyed {
+            Keyed::SipHash(key) => SipHasher24::new_with_key(key).hash(input),
+            Keyed::XxHash(seed) => xxhash_rust::xxh3::xxh3_64_with_seed(input, *seed),
+            Keyed::Sha256(block) => {
+                let digest = hmac_sha256(block, input);
+                u64::from_be_bytes(digest[..8].try_into().expect("8-byte prefix"))
+            }
+        }
+    }
+
+    /// The digest as lowercase hex: 16 digits, or 64 for SHA-256.
+    pub fn hash_hex(&self, input: &[u8]) -> String {
+        match &self.keyed {
+            Keyed::Sha256(block) => hmac_sha256(block, input)
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+            _ => format!("{:016x}", self.hash_u64(input)),
+        }
+    }
+}
+
+fn hmac_sha256(key_block: &[u8; SHA256_BLOCK], input: &[u8]) -> [u8; 32] {
+    let pad = |byte: u8| key_block.map(|k| k ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(input)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_digests() {
+        // RFC 4231 test case 2.
+        let hmac = KeyedHash::new(HashAlgorithm::Sha256, b"Jefe");
+        assert_eq!(
+            hmac.hash_hex(b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hmac.hash_u64(b"what do ya want for nothing?"),
+            0x5bdcc146bf60754e
+        );
+
+        for algorithm in [HashAlgorithm::SipHash, HashAlgorithm::XxHash] {
+            let hash = KeyedHash::new(algorithm, b"secret");
+            let other_key = KeyedHash::new(algorithm, b"other");
+            assert_eq!(hash.hash_u64(b"user-1"), hash.hash_u64(b"user-1"));
+            assert_ne!(hash.hash_u64(b"user-1"), hash.hash_u64(b"user-2"));
+            assert_ne!(hash.hash_u64(b"user-1"), other_key.hash_u64(b"user-1"));
+            assert_eq!(
+                hash.hash_hex(b"user-1"),
+                format!("{:016x}", hash.hash_u64(b"user-1"))
+            );
+        }
+    }
+}
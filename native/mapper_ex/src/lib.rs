@@ -1,9 +1,26 @@
+mod aggregate;
+mod arrow_ipc;
+mod clickhouse_native;
 mod clickhouse_rowbinary;
 mod coerce;
+mod diff;
+mod explain;
+mod infer;
+mod inspect;
+mod json_input;
+mod json_output;
+mod keyed_hash;
+mod limits;
+mod lint;
 mod mapper;
 mod mapping;
+mod mapping_export;
+mod otlp_protobuf;
 mod path;
 mod query;
+mod redact;
+mod sampling;
+mod stats;
 mod string_filters;
 
 use rustler::{Binary, Encoder, Env, NewBinary, NifResult, Resource, ResourceArc, Term};
@@ -16,6 +33,8 @@ mod atoms {
         error,
         nil,
         clickhouse_row_binary,
+        clickhouse_native,
+        drop,
     }
 }
 
@@ -37,6 +56,10 @@ fn encode_integer<'a>(env: Env<'a>, value: i64) -> Term<'a> {
 /// Created once via compile_mapping/1 and reused for every map/2 call.
 pub struct CompiledMappingResource {
     pub mapping: CompiledMapping,
+    /// Canonical encoding of the config it was compiled from, with the
+    /// `hash_key` sealed; see `mapping_export`.
+    pub config: Vec<u8>,
+    pub fingerprint: String,
 }
 
 impl Resource for CompiledMappingResource {}
@@ -46,16 +69,79 @@ impl Resource for CompiledMappingResource {}
 /// Returns `{:ok, resource}` if valid, or `{:error, reason}` if invalid.
 #[rustler::nif]
 fn compile_mapping<'a>(env: Env<'a>, config: Term<'a>) -> NifResult<Term<'a>> {
-    match mapping::decode_mapping(env, config) {
-        Ok(compiled) => {
-            let resource = ResourceArc::new(CompiledMappingResource { mapping: compiled });
-            Ok((atoms::ok(), resource).encode(env))
-        }
+    let compiled = mapping_export::seal_hash_key(env, config)
+        .and_then(|sealed| compile_resource(env, config, sealed));
+    match compiled {
+        Ok(resource) => Ok((atoms::ok(), resource).encode(env)),
         Err(reason) => Ok((atoms::error(), reason).encode(env)),
     }
 }
 
-/// Maps a single document using a pre-compiled mapping and its configured output.
+/// Compiles `config`. `sealed` is the same config with its `hash_key`
+/// sealed, which is what gets exported and fingerprinted.
+fn compile_resource<'a>(
+    env: Env<'a>,
+    config: Term<'a>,
+    sealed: Term<'a>,
+) -> Result<ResourceArc<CompiledMappingResource>, String> {
+    let mapping = mapping::decode_mapping(env, config)?;
+    let normalized = mapping_export::normalize(env, sealed)?;
+    Ok(ResourceArc::new(CompiledMappingResource {
+        mapping,
+        config: mapping_export::encode_config(sealed)?,
+        fingerprint: mapping_export::fingerprint(&mapping_export::encode_config(normalized)?),
+    }))
+}
+
+/// Serializes a compiled mapping into a versioned binary that
+/// `import_mapping/2` compiles back on any node. The `hash_key` is replaced
+/// by its key ID.
+#[rustler::nif]
+fn export_mapping<'a>(env: Env<'a>, compiled: ResourceArc<CompiledMappingResource>) -> Term<'a> {
+    let exported = mapping_export::export(&compiled.config);
+    let mut binary = NewBinary::new(env, exported.len());
+    binary.as_mut_slice().copy_from_slice(&exported);
+    binary.into()
+}
+
+/// Compiles a binary produced by `export_mapping/1`, given the `hash_key`
+/// the mapping was compiled with, if any.
+///
+/// Returns `{:ok, resource}`, or `{:error, reason}` for a malformed binary,
+/// an unsupported format version, a missing or different `hash_key`, or a
+/// config that no longer compiles.
+#[rustler::nif]
+fn import_mapping<'a>(env: Env<'a>, exported: Binary<'a>, hash_key: Option<String>) -> Term<'a> {
+    let compiled = mapping_export::import(env, exported.as_slice()).and_then(|sealed| {
+        let config = mapping_export::unseal_hash_key(env, sealed, hash_key.as_deref())?;
+        compile_resource(env, config, sealed)
+    });
+    match compiled {
+        Ok(resource) => (atoms::ok(), resource).encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// A UUID-formatted content fingerprint of the mapping's normalised config.
+/// Configs that differ only in how they spell the same settings share a
+/// fingerprint; see `mapping_export::normalize`.
+#[rustler::nif]
+fn mapping_fingerprint(compiled: ResourceArc<CompiledMappingResource>) -> String {
+    compiled.fingerprint.clone()
+}
+
+/// Returns the per-field counters a mapping compiled with `collect_stats` has
+/// accumulated since the last call, and resets them. `nil` otherwise.
+#[rustler::nif]
+fn mapping_stats<'a>(env: Env<'a>, compiled: ResourceArc<CompiledMappingResource>) -> Term<'a> {
+    match &compiled.mapping.stats {
+        Some(stats) => stats.drain(env, &compiled.mapping),
+        None => atoms::nil().encode(env),
+    }
+}
+
+/// Maps a single document using a pre-compiled mapping and its configured
+/// output. Returns `:drop` when the mapping's `sample` does not keep it.
 #[rustler::nif]
 fn map<'a>(
     env: Env<'a>,
@@ -63,74 +149,574 @@ fn map<'a>(
     compiled: ResourceArc<CompiledMappingResource>,
     options: Term<'a>,
 ) -> Term<'a> {
-    match &compiled.mapping.output {
-        CompiledOutput::Map => match decode_flat_keys(options) {
-            Ok(flat_keys) => mapper::map_single(env, document, &compiled.mapping, flat_keys),
-            Err(reason) => (atoms::error(), reason).encode(env),
-        },
-        CompiledOutput::ClickHouseRowBinary(layout) => {
-            match map_clickhouse_output(env, document, &compiled.mapping, layout, options) {
-                Ok(binary) => (atoms::ok(), binary.release(env)).encode(env),
-                Err(reason) => (atoms::error(), reason).encode(env),
+    let mapping = &compiled.mapping;
+    let document = mapper::Document::Term(document);
+    match map_document(env, document, mapping, options, Decide::Sample) {
+        Ok(Some((_, output))) => output,
+        Ok(None) => atoms::drop().encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Like `map/3`, but first applies the mapping's `drop_when` and `route`
+/// sections: returns `:drop`, or `{route_name | nil, output}` where output is
+/// what `map/3` returns.
+#[rustler::nif]
+fn map_routed<'a>(
+    env: Env<'a>,
+    document: Term<'a>,
+    compiled: ResourceArc<CompiledMappingResource>,
+    options: Term<'a>,
+) -> Term<'a> {
+    let mapping = &compiled.mapping;
+    let document = mapper::Document::Term(document);
+    match map_document(env, document, mapping, options, Decide::Route) {
+        Ok(Some((route, output))) => (encode_route(env, mapping, route), output).encode(env),
+        Ok(None) => atoms::drop().encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Maps a raw JSON object like `map/3`. Paths are evaluated over the parsed
+/// JSON, so only the values they resolve to are built as terms. Returns
+/// `{:error, reason}` for invalid JSON.
+#[rustler::nif]
+fn map_json<'a>(
+    env: Env<'a>,
+    json: Binary<'a>,
+    compiled: ResourceArc<CompiledMappingResource>,
+    options: Term<'a>,
+) -> Term<'a> {
+    let mapping = &compiled.mapping;
+    let output = parse_json_documents(&[json], mapping, options).and_then(|mut documents| {
+        let document = documents.pop().expect("one document per JSON object");
+        map_document(env, document, mapping, options, Decide::Sample)
+    });
+    match output {
+        Ok(Some((_, output))) => output,
+        Ok(None) => atoms::drop().encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Which of the mapping's document-level decisions an entry point applies
+/// before mapping each document.
+#[derive(Clone, Copy, PartialEq)]
+enum Decide {
+    /// Only `sample`, so `map` and `map_many` spend no coercion work on
+    /// documents it drops.
+    Sample,
+    /// `drop_when`, `sample` and `route`.
+    Route,
+}
+
+/// Decides on `document` and, unless it is dropped, maps it into `scratch`,
+/// which keeps the paths the decisions resolved.
+fn decide_and_map<'a>(
+    env: Env<'a>,
+    document: mapper::Document<'a>,
+    mapping: &CompiledMapping,
+    flat_keys: bool,
+    nil: Term<'a>,
+    scratch: &mut mapper::MapScratch<'a>,
+    decide: Decide,
+) -> mapper::Decision {
+    let document = scratch.start(document);
+    let decision = match decide {
+        Decide::Sample => mapper::sample(env, document, mapping, flat_keys, nil, scratch),
+        Decide::Route => mapper::decide(env, document, mapping, flat_keys, nil, scratch),
+    };
+    if decision != mapper::Decision::Drop {
+        mapper::map_values_into(env, document, mapping, flat_keys, nil, scratch);
+    }
+    decision
+}
+
+/// Maps one document to the output `map/3` returns, paired with its route.
+/// `None` when the document is dropped.
+fn map_document<'a>(
+    env: Env<'a>,
+    document: mapper::Document<'a>,
+    mapping: &CompiledMapping,
+    options: Term<'a>,
+    decide: Decide,
+) -> Result<Option<(Option<usize>, Term<'a>)>, String> {
+    let (flat_keys, context) = match &mapping.output {
+        CompiledOutput::ClickHouseRowBinary(_) => {
+            decode_clickhouse_options(options, atoms::clickhouse_row_binary())
+                .map(|(flat_keys, context)| (flat_keys, Some(context)))?
+        }
+        CompiledOutput::ClickHouseNative(_) => {
+            decode_clickhouse_options(options, atoms::clickhouse_native())
+                .map(|(flat_keys, context)| (flat_keys, Some(context)))?
+        }
+        _ => (decode_flat_keys(options)?, None),
+    };
+    let nil = atoms::nil().encode(env);
+    let mut scratch = mapper::MapScratch::new(mapping, nil);
+    let mapper::Decision::Keep(route) =
+        decide_and_map(env, document, mapping, flat_keys, nil, &mut scratch, decide)
+    else {
+        return Ok(None);
+    };
+
+    let output = match RowEncoder::new(&mapping.output, false)? {
+        None => mapper::output_map(env, mapping, scratch.values()),
+        Some(mut encoder) => {
+            encoder.append(scratch.values(), context, nil)?;
+            (atoms::ok(), encoder.finish(env)?).encode(env)
+        }
+    };
+    Ok(Some((route, output)))
+}
+
+/// One payload of a serialized output, built a mapped row at a time.
+enum RowEncoder<'m> {
+    RowBinary(
+        &'m clickhouse_rowbinary::CompiledLayout,
+        clickhouse_rowbinary::BinaryBuilder,
+    ),
+    Native(clickhouse_native::NativeBlockBuilder<'m>),
+    Arrow(
+        &'m arrow_ipc::CompiledArrowLayout,
+        arrow_ipc::ArrowBatchBuilder<'m>,
+    ),
+    /// One JSON object per row; batches are newline-terminated NDJSON.
+    Json(&'m json_output::CompiledJsonLayout, Vec<u8>, bool),
+    Otlp(otlp_protobuf::OtlpRequestBuilder<'m>),
+}
+
+impl<'m> RowEncoder<'m> {
+    /// An empty payload for `output`; `None` for map output.
+    fn new(output: &'m CompiledOutput, newline_delimited: bool) -> Result<Option<Self>, String> {
+        Ok(Some(match output {
+            CompiledOutput::Map => return Ok(None),
+            CompiledOutput::ClickHouseRowBinary(layout) => {
+                RowEncoder::RowBinary(layout, clickhouse_rowbinary::BinaryBuilder::new()?)
+            }
+            CompiledOutput::ClickHouseNative(layout) => {
+                RowEncoder::Native(clickhouse_native::NativeBlockBuilder::new(layout))
+            }
+            CompiledOutput::ArrowIpc(layout) => {
+                RowEncoder::Arrow(layout, arrow_ipc::ArrowBatchBuilder::new(layout))
             }
+            CompiledOutput::Json(layout) => RowEncoder::Json(layout, Vec::new(), newline_delimited),
+            CompiledOutput::OtlpProtobuf(layout) => {
+                RowEncoder::Otlp(otlp_protobuf::OtlpRequestBuilder::new(layout))
+            }
+        }))
+    }
+
+    /// Appends one document's mapped values. ClickHouse outputs require the
+    /// document's output context.
+    fn append<'a>(
+        &mut self,
+        values: &[Term<'a>],
+        context: Option<Term<'a>>,
+        nil: Term<'a>,
+    ) -> Result<(), String> {
+        let clickhouse_context = || {
+            context
+                .ok_or_else(|| "ClickHouse output requires an output_context".to_string())
+                .and_then(decode_clickhouse_context)
+        };
+        match self {
+            RowEncoder::RowBinary(layout, output) => {
+                let (mapping_config_id, envelope) = clickhouse_context()?;
+                clickhouse_rowbinary::append_row(
+                    output,
+                    layout,
+                    values,
+                    envelope,
+                    mapping_config_id,
+                )
+            }
+            RowEncoder::Native(block) => {
+                let (mapping_config_id, envelope) = clickhouse_context()?;
+                block.append_row(values, envelope, mapping_config_id)
+            }
+            RowEncoder::Arrow(_, batch) => batch.append_row(values, nil),
+            RowEncoder::Json(layout, output, newline_delimited) => {
+                json_output::write_row(output, layout, values, nil)?;
+                if *newline_delimited {
+                    output.push(b'\n');
+                }
+                Ok(())
+            }
+            RowEncoder::Otlp(request) => request.append_row(values),
         }
     }
-}
 
-fn decode_flat_keys(options: Term) -> Result<bool, String> {
-    if let Ok(flat_keys) = options.decode::<bool>() {
-        return Ok(flat_keys);
+    /// Arrow IPC output returns `{schema_message, record_batch_messages}`, the
+    /// same shape as the BigQuery IPC encoder, with no batches when no rows
+    /// were appended. Every other output returns one binary.
+    fn finish<'a>(self, env: Env<'a>) -> Result<Term<'a>, String> {
+        let (output, what) = match self {
+            RowEncoder::RowBinary(_, output) => {
+                return Ok(output.finish()?.release(env).encode(env))
+            }
+            RowEncoder::Native(block) => return Ok(block.finish()?.release(env).encode(env)),
+            RowEncoder::Arrow(layout, batch) => {
+                let schema = layout.schema_message()?.release(env);
+                let batches: Vec<Term<'a>> = batch
+                    .finish()?
+                    .into_iter()
+                    .map(|message| message.release(env).encode(env))
+                    .collect();
+                return Ok((schema, batches).encode(env));
+            }
+            RowEncoder::Json(_, output, _) => (output, "JSON"),
+            RowEncoder::Otlp(request) => (request.finish()?, "OTLP protobuf"),
+        };
+        let mut binary = rustler::OwnedBinary::new(output.len())
+            .ok_or_else(|| format!("failed to allocate {} output", what))?;
+        binary.as_mut_slice().copy_from_slice(&output);
+        Ok(binary.release(env).encode(env))
     }
-    options
-        .decode::<(bool, Term)>()
-        .map(|(flat_keys, _)| flat_keys)
-        .map_err(|_| "mapper options must contain flat_keys".to_string())
 }
 
-fn map_clickhouse_output<'a>(
+/// Maps a single document and returns one map per field describing which
+/// source matched, which filters and lookups fired, and whether the default
+/// was used. Ignores the configured output format.
+#[rustler::nif]
+fn explain<'a>(
     env: Env<'a>,
     document: Term<'a>,
+    compiled: ResourceArc<CompiledMappingResource>,
+    options: Term<'a>,
+) -> Term<'a> {
+    match decode_flat_keys(options) {
+        Ok(flat_keys) => (
+            atoms::ok(),
+            explain::explain_single(env, document, &compiled.mapping, flat_keys),
+        )
+            .encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Compares two mapping configs field by field and, when `documents` is not
+/// empty, the values both produce for them.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn diff_mappings<'a>(
+    env: Env<'a>,
+    old_config: Term<'a>,
+    new_config: Term<'a>,
+    documents: Vec<Term<'a>>,
+    flat_keys: bool,
+) -> Term<'a> {
+    let compiled = mapping::decode_mapping(env, old_config)
+        .map_err(|reason| format!("old mapping: {}", reason))
+        .and_then(|old| {
+            mapping::decode_mapping(env, new_config)
+                .map(|new| (old, new))
+                .map_err(|reason| format!("new mapping: {}", reason))
+        });
+    match compiled {
+        Ok((old, new)) => {
+            let old = diff::Side {
+                config: old_config,
+                mapping: &old,
+            };
+            let new = diff::Side {
+                config: new_config,
+                mapping: &new,
+            };
+            let diff = diff::diff_mappings(env, old, new, &documents, flat_keys);
+            (atoms::ok(), diff).encode(env)
+        }
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Compiles a config and returns warnings for settings that compile but
+/// cannot take effect.
+#[rustler::nif]
+fn lint_mapping<'a>(env: Env<'a>, config: Term<'a>) -> Term<'a> {
+    match mapping::decode_mapping(env, config) {
+        Ok(mapping) => (atoms::ok(), lint::lint_mapping(env, config, &mapping)).encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Proposes a mapping config from sample documents. Options: `max_depth`
+/// and `value_map_limit`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn infer_mapping<'a>(env: Env<'a>, documents: Vec<Term<'a>>, options: Term<'a>) -> Term<'a> {
+    let defaults = infer::InferOptions::default();
+    let option = |key, default: usize| {
+        mapping::get_int_key(env, options, key).map_or(default, |value| value.max(0) as usize)
+    };
+    let options = infer::InferOptions {
+        max_depth: option("max_depth", defaults.max_depth),
+        value_map_limit: option("value_map_limit", defaults.value_map_limit),
+    };
+    match infer::infer_mapping(env, &documents, options) {
+        Ok(inferred) => (atoms::ok(), inferred).encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Describes a compiled mapping: each field's type, source and paths with
+/// their cache assignments, and the mapping's path cache layout.
+#[rustler::nif]
+fn inspect_mapping<'a>(env: Env<'a>, compiled: ResourceArc<CompiledMappingResource>) -> Term<'a> {
+    inspect::inspect_mapping(env, &compiled.mapping)
+}
+
+/// Maps a batch of documents with one compiled mapping.
+///
+/// Map output returns the mapped maps in input order. Serialized outputs
+/// return one payload for the whole batch: concatenated RowBinary rows or a
+/// single Native block. Documents the mapping's `sample` does not keep are
+/// `:drop` in map output and left out of serialized payloads.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn map_many<'a>(
+    env: Env<'a>,
+    documents: Vec<Term<'a>>,
+    compiled: ResourceArc<CompiledMappingResource>,
+    options: Term<'a>,
+) -> Term<'a> {
+    let mapping = &compiled.mapping;
+    let documents = documents.into_iter().map(mapper::Document::Term).collect();
+    match map_many_output(env, documents, mapping, options, Decide::Sample) {
+        Ok(output) if matches!(mapping.output, CompiledOutput::Map) => output,
+        Ok(output) => (atoms::ok(), output).encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Like `map_many/3`, but first applies the mapping's `drop_when` and
+/// `route` sections to every document.
+///
+/// Map output returns `:drop` or `{route_name | nil, map}` per document, in
+/// input order. Serialized outputs return `{:ok, [{route_name | nil, payload}]}`
+/// with one payload per route, ordered by each route's first document; dropped
+/// documents are left out and their output contexts are ignored.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn map_many_routed<'a>(
+    env: Env<'a>,
+    documents: Vec<Term<'a>>,
+    compiled: ResourceArc<CompiledMappingResource>,
+    options: Term<'a>,
+) -> Term<'a> {
+    let mapping = &compiled.mapping;
+    let documents = documents.into_iter().map(mapper::Document::Term).collect();
+    match map_many_output(env, documents, mapping, options, Decide::Route) {
+        Ok(output) if matches!(mapping.output, CompiledOutput::Map) => output,
+        Ok(output) => (atoms::ok(), output).encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// Maps a batch of raw JSON objects like `map_many/3`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn map_many_json<'a>(
+    env: Env<'a>,
+    jsons: Vec<Binary<'a>>,
+    compiled: ResourceArc<CompiledMappingResource>,
+    options: Term<'a>,
+) -> Term<'a> {
+    let mapping = &compiled.mapping;
+    let output = parse_json_documents(&jsons, mapping, options)
+        .and_then(|documents| map_many_output(env, documents, mapping, options, Decide::Sample));
+    match output {
+        Ok(output) if matches!(mapping.output, CompiledOutput::Map) => output,
+        Ok(output) => (atoms::ok(), output).encode(env),
+        Err(reason) => (atoms::error(), reason).encode(env),
+    }
+}
+
+/// JSON object keys are strings, so an atom-keyed mapping would map every
+/// document to its defaults; it is refused instead.
+fn parse_json_documents<'a>(
+    jsons: &[Binary<'a>],
+    mapping: &CompiledMapping,
+    options: Term<'a>,
+) -> Result<Vec<mapper::Document<'a>>, String> {
+    if mapping.key_mode == query::KeyMode::Atom {
+        return Err("map_json requires key_mode binary or either".to_string());
+    }
+    let projections = &mapping.json_projections;
+    let projection = if decode_flat_keys(options)? {
+        &projections.flat
+    } else {
+        &projections.nested
+    };
+    jsons
+        .iter()
+        .map(|json| json_input::parse(json.as_slice(), projection).map(mapper::Document::Json))
+        .collect()
+}
+
+/// Maps a batch with one scratch, each document decided on and mapped in a
+/// single pass.
+///
+/// Map output returns one entry per document: its map, `:drop`, or under
+/// `Decide::Route` `{route, map}`. Serialized outputs return one payload, or
+/// under `Decide::Route` a `{route, payload}` pair per route in order of each
+/// route's first document.
+fn map_many_output<'a>(
+    env: Env<'a>,
+    documents: Vec<mapper::Document<'a>>,
     mapping: &CompiledMapping,
-    layout: &clickhouse_rowbinary::CompiledLayout,
     options: Term<'a>,
-) -> Result<rustler::OwnedBinary, String> {
-    let (flat_keys, mapping_config_id, envelope) = decode_clickhouse_options(options)?;
-    let envelope = decode_clickhouse_envelope(envelope)?;
+    decide: Decide,
+) -> Result<Term<'a>, String> {
     let nil = atoms::nil().encode(env);
     let mut scratch = mapper::MapScratch::new(mapping, nil);
-    mapper::map_values_into(env, document, mapping, flat_keys, nil, &mut scratch);
-    let mut output = clickhouse_rowbinary::BinaryBuilder::new()?;
-    clickhouse_rowbinary::append_row(
-        &mut output,
-        layout,
-        scratch.values(),
-        envelope,
-        mapping_config_id,
-    )?;
-    output.finish()
+
+    if let CompiledOutput::Map = mapping.output {
+        let flat_keys = decode_flat_keys(options)?;
+        let rows = documents.into_iter().map(|document| {
+            match decide_and_map(env, document, mapping, flat_keys, nil, &mut scratch, decide) {
+                mapper::Decision::Drop => atoms::drop().encode(env),
+                mapper::Decision::Keep(route) => {
+                    let map = mapper::output_map(env, mapping, scratch.values());
+                    match decide {
+                        Decide::Route => (encode_route(env, mapping, route), map).encode(env),
+                        Decide::Sample => map,
+                    }
+                }
+            }
+        });
+        return Ok(rows.collect::<Vec<Term<'a>>>().encode(env));
+    }
+
+    let (flat_keys, contexts) = match &mapping.output {
+        CompiledOutput::ClickHouseRowBinary(_) => {
+            decode_batch_options(options, documents.len(), atoms::clickhouse_row_binary())?
+        }
+        CompiledOutput::ClickHouseNative(_) => {
+            decode_batch_options(options, documents.len(), atoms::clickhouse_native())?
+        }
+        _ => (decode_flat_keys(options)?, Vec::new()),
+    };
+
+    // One encoder per route, in order of each route's first document.
+    let mut groups: Vec<(Option<usize>, RowEncoder)> = Vec::new();
+    if decide == Decide::Sample {
+        groups.extend(RowEncoder::new(&mapping.output, true)?.map(|encoder| (None, encoder)));
+    }
+    for (index, document) in documents.into_iter().enumerate() {
+        let mapper::Decision::Keep(route) =
+            decide_and_map(env, document, mapping, flat_keys, nil, &mut scratch, decide)
+        else {
+            continue;
+        };
+        let group = match groups.iter().position(|(group, _)| *group == route) {
+            Some(group) => group,
+            None => {
+                let encoder = RowEncoder::new(&mapping.output, true)?
+                    .ok_or_else(|| "map output has no row encoder".to_string())?;
+                groups.push((route, encoder));
+                groups.len() - 1
+            }
+        };
+        groups[group]
+            .1
+            .append(scratch.values(), contexts.get(index).copied(), nil)?;
+    }
+
+    match decide {
+        Decide::Sample => match groups.pop() {
+            Some((_, encoder)) => encoder.finish(env),
+            None => Err("map output has no row encoder".to_string()),
+        },
+        Decide::Route => {
+            let payloads = groups
+                .into_iter()
+                .map(|(route, encoder)| {
+                    Ok((encode_route(env, mapping, route), encoder.finish(env)?).encode(env))
+                })
+                .collect::<Result<Vec<Term<'a>>, String>>()?;
+            Ok(payloads.encode(env))
+        }
+    }
+}
+
+fn encode_route<'a>(env: Env<'a>, mapping: &CompiledMapping, route: Option<usize>) -> Term<'a> {
+    match route {
+        Some(index) => encode_string(env, &mapping.decisions.routes[index].name),
+        None => atoms::nil().encode(env),
+    }
+}
+
+fn decode_flat_keys(options: Term) -> Result<bool, String> {
+    if let Ok(flat_keys) = options.decode::<bool>() {
+        return Ok(flat_keys);
+    }
+    options
+        .decode::<(bool, Term)>()
+        .map(|(flat_keys, _)| flat_keys)
+        .map_err(|_| "mapper options must contain flat_keys".to_string())
 }
 
 fn decode_clickhouse_options<'a>(
     options: Term<'a>,
-) -> Result<(bool, Binary<'a>, Term<'a>), String> {
+    format: rustler::types::atom::Atom,
+) -> Result<(bool, Term<'a>), String> {
     let (flat_keys, output_context): (bool, Term<'a>) = options
         .decode()
         .map_err(|_| "mapper options must contain flat_keys and output_context".to_string())?;
-    let (format, mapping_config_id, envelope): (rustler::types::atom::Atom, Term<'a>, Term<'a>) =
-        output_context.decode().map_err(|_| {
+    check_output_context_format(output_context, format)?;
+    Ok((flat_keys, output_context))
+}
+
+/// Decodes and checks one output context per document.
+fn decode_batch_options<'a>(
+    options: Term<'a>,
+    count: usize,
+    format: rustler::types::atom::Atom,
+) -> Result<(bool, Vec<Term<'a>>), String> {
+    let (flat_keys, output_contexts): (bool, Vec<Term<'a>>) = options
+        .decode()
+        .map_err(|_| "mapper options must contain flat_keys and output_contexts".to_string())?;
+    if output_contexts.len() != count {
+        return Err(format!(
+            "expected {} output contexts, got {}",
+            count,
+            output_contexts.len()
+        ));
+    }
+    for context in &output_contexts {
+        check_output_context_format(*context, format)?;
+    }
+    Ok((flat_keys, output_contexts))
+}
+
+fn check_output_context_format(
+    output_context: Term,
+    format: rustler::types::atom::Atom,
+) -> Result<(), String> {
+    let expected = || {
+        if format == atoms::clickhouse_native() {
+            "ClickHouse Native output requires a clickhouse_native output_context".to_string()
+        } else {
             "ClickHouse RowBinary output requires a clickhouse_row_binary output_context"
                 .to_string()
-        })?;
-    if format != atoms::clickhouse_row_binary() {
-        return Err(
-            "ClickHouse RowBinary output requires a clickhouse_row_binary output_context"
-                .to_string(),
-        );
+        }
+    };
+    let (tag, _, _): (rustler::types::atom::Atom, Term, Term) =
+        output_context.decode().map_err(|_| expected())?;
+    if tag == format {
+        Ok(())
+    } else {
+        Err(expected())
     }
+}
+
+fn decode_clickhouse_context<'a>(
+    output_context: Term<'a>,
+) -> Result<(Binary<'a>, clickhouse_rowbinary::RowEnvelope<'a>), String> {
+    let (_format, mapping_config_id, envelope): (rustler::types::atom::Atom, Term<'a>, Term<'a>) =
+        output_context
+            .decode()
+            .map_err(|_| "ClickHouse output requires an output_context".to_string())?;
     let mapping_config_id = mapping_config_id
         .decode::<Binary>()
         .map_err(|_| "mapping_config_id must be a pre-encoded 16-byte UUID binary".to_string())?;
-    Ok((flat_keys, mapping_config_id, envelope))
+    Ok((mapping_config_id, decode_clickhouse_envelope(envelope)?))
 }
 
 fn decode_clickhouse_envelope<'a>(
@@ -0,0 +1,298 @@
+use rustler::types::list::ListIterator;
+use rustler::types::map::MapIterator;
+use rustler::{Binary, Encoder, Env, NewBinary, Term};
+
+use crate::mapping::{CompiledField, FieldType};
+
+/// Per-field size limits. Values over a limit are truncated, and truncated
+/// `Json` and `FlatMap` values are marked with a `_truncated` key.
+#[derive(Debug, Default)]
+pub struct FieldLimits {
+    pub max_string_bytes: Option<usize>,
+    pub max_map_entries: Option<usize>,
+    /// Deepest map or list nesting kept; the field value itself is depth 1.
+    pub max_depth: Option<usize>,
+    pub max_array_length: Option<usize>,
+}
+
+pub const TRUNCATED_KEY: &str = "_truncated";
+
+impl FieldLimits {
+    pub fn is_empty(&self) -> bool {
+        self.max_string_bytes.is_none()
+            && self.max_map_entries.is_none()
+            && self.max_depth.is_none()
+            && self.max_array_length.is_none()
+    }
+
+    /// The limits a `FlatMap` field applies while flattening. The entry cap
+    /// waits for `limit_field` when key rules or redaction may still remove
+    /// entries, or it would count entries that are later dropped.
+    pub fn flat(&self, entries_final: bool) -> FlatLimits {
+        FlatLimits {
+            max_depth: self.max_depth,
+            max_entries: self.max_map_entries.filter(|_| entries_final),
+        }
+    }
+}
+
+/// Limits enforced by the flattener, so entries past them are never built.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlatLimits {
+    /// Deepest key kept, counted in key segments.
+    pub max_depth: Option<usize>,
+    pub max_entries: Option<usize>,
+}
+
+impl FlatLimits {
+    pub const NONE: FlatLimits = FlatLimits {
+        max_depth: None,
+        max_entries: None,
+    };
+}
+
+/// Applies `limits` to a field's mapped value. `flattened_truncated` says
+/// whether the flattener already dropped `FlatMap` entries. Returns `None`
+/// when nothing was truncated.
+pub fn limit_field<'a>(
+    env: Env<'a>,
+    field: &CompiledField,
+    value: Term<'a>,
+    limits: &FieldLimits,
+    flattened_truncated: bool,
+) -> Option<Term<'a>> {
+    match field.field_type {
+        FieldType::FlatMap => {
+            let limited = match limit_flat_map(env, value, limits) {
+                Some(limited) => limited,
+                None if flattened_truncated => value,
+                None => return None,
+            };
+            Some(mark_truncated(env, limited, "true".encode(env)))
+        }
+        FieldType::Json => {
+            let limited = limit_term(env, value, limits, 1)?;
+            Some(mark_truncated(env, limited, true.encode(env)))
+        }
+        _ => limit_term(env, value, limits, 1),
+    }
+}
+
+fn mark_truncated<'a>(env: Env<'a>, value: Term<'a>, marker: Term<'a>) -> Term<'a> {
+    if !value.is_map() {
+        return value;
+    }
+    value
+        .map_put(crate::encode_string(env, TRUNCATED_KEY), marker)
+        .unwrap_or(value)
+}
+
+/// Truncates strings, map entries, list elements and containers nested below
+/// `max_depth`, keeping the first entries in term order.
+fn limit_term<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    limits: &FieldLimits,
+    depth: usize,
+) -> Option<Term<'a>> {
+    if let Ok(binary) = value.decode::<Binary>() {
+        let max = limits.max_string_bytes?;
+        return truncate_bytes(binary.as_slice(), max).map(|bytes| {
+            let mut truncated = NewBinary::new(env, bytes.len());
+            truncated.as_mut_slice().copy_from_slice(bytes);
+            truncated.into()
+        });
+    }
+
+    let nested_allowed = limits.max_depth.is_none_or(|max| depth < max);
+    let limit_child = |child: Term<'a>, truncated: &mut bool| -> Option<Term<'a>> {
+        if !nested_allowed && (child.is_map() || child.is_list()) {
+            *truncated = true;
+            return None;
+        }
+        match limit_term(env, child, limits, depth + 1) {
+            Some(limited) => {
+                *truncated = true;
+                Some(limited)
+            }
+            None => Some(child),
+        }
+    };
+
+    if let Some(iter) = MapIterator::new(value) {
+        let max_entries = limits.max_map_entries.unwrap_or(usize::MAX);
+        let mut truncated = false;
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for (key, child) in iter {
+            if keys.len() == max_entries {
+                truncated = true;
+                break;
+            }
+            if let Some(child) = limit_child(child, &mut truncated) {
+                keys.push(key);
+                values.push(child);
+            }
+        }
+        return truncated.then(|| {
+            Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+        });
+    }
+
+    if let Ok(iter) = value.decode::<ListIterator>() {
+        let max_length = limits.max_array_length.unwrap_or(usize::MAX);
+        let mut truncated = false;
+        let mut elements = Vec::new();
+        for element in iter {
+            if elements.len() == max_length {
+                truncated = true;
+                break;
+            }
+            if let Some(element) = limit_child(element, &mut truncated) {
+                elements.push(element);
+            }
+        }
+        return truncated.then(|| elements.encode(env));
+    }
+
+    None
+}
+
+/// Applies the entry cap and string limit to a flattened map. Key depth was
+/// enforced by the flattener.
+fn limit_flat_map<'a>(env: Env<'a>, value: Term<'a>, limits: &FieldLimits) -> Option<Term<'a>> {
+    let iter = MapIterator::new(value)?;
+    let max_entries = limits.max_map_entries.unwrap_or(usize::MAX);
+    let mut truncated = false;
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for (key, child) in iter {
+        if keys.len() == max_entries {
+            truncated = true;
+            break;
+        }
+        keys.push(key);
+        match limit_term(env, child, limits, 1) {
+            Some(limited) => {
+                truncated = true;
+                values.push(limited);
+            }
+            None => values.push(child),
+        }
+    }
+    truncated.then(|| {
+        Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+    })
+}
+
+/// Counts the non-overlapping occurrences of `needle`, e.g. separators in a
+/// flattened key.
+pub fn count_occurrences(haystack: &[u8], needle: &[u8]) -> usize {
+    let mut count = 0;
+    let mut rest = haystack;
+    while let Some(position) = rest
+        .windows(needle.len())
+        .position(|window| window == needle)
+    {
+        count += 1;
+        rest = &rest[position + needle.len()..];
+    }
+    count
+}
+
+/// Cuts `bytes` to at most `max` bytes without splitting a UTF-8 character.
+fn truncate_bytes(bytes: &[u8], max: usize) -> Option<&[u8]> {
+    if bytes.len() <= max {
+        return None;
+    }
+    let mut end = max;
+    // Back off continuation bytes (0b10xx_xxxx) to the start of a character.
+    while end > 0 && bytes[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    Some(&bytes[..end])
+}
+
+/// Rough encoded size of a mapped value: string bytes plus 8 bytes per
+/// scalar, summed over map keys, values and list elements.
+pub fn estimated_size(value: Term<'_>) -> usize {
+    if let Ok(binary) = value.decode::<Binary>() {
+        return binary.len();
+    }
+    if let Some(iter) = MapIterator::new(value) {
+        return iter
+            .map(|(key, child)| estimated_size(key) + estimated_size(child))
+            .sum();
+    }
+    if let Ok(iter) = value.decode::<ListIterator>() {
+        return iter.map(estimated_size).sum();
+    }
+    8
+}
+
+/// Enforces a mapping-wide row size by replacing the largest string, JSON,
+/// map and array values, biggest first, until the row fits. JSON and
+/// `FlatMap` values become a map holding only the `_truncated` marker;
+/// strings and arrays become their type's empty value.
+pub fn enforce_row_limit<'a>(
+    env: Env<'a>,
+    values: &mut [Term<'a>],
+    fields: &[CompiledField],
+    max_row_bytes: usize,
+) {
+    let sizes: Vec<usize> = values.iter().map(|&value| estimated_size(value)).collect();
+    let mut total: usize = sizes.iter().sum();
+    if total <= max_row_bytes {
+        return;
+    }
+
+    let mut candidates: Vec<usize> = (0..values.len())
+        .filter(|&index| is_variable_size(&fields[index].field_type))
+        .collect();
+    candidates.sort_by_key(|&index| std::cmp::Reverse(sizes[index]));
+
+    for index in candidates {
+        if total <= max_row_bytes {
+            break;
+        }
+        let field_type = &fields[index].field_type;
+        let replacement = match field_type {
+            FieldType::Json => mark_truncated(env, Term::map_new(env), true.encode(env)),
+            FieldType::FlatMap => mark_truncated(env, Term::map_new(env), "true".encode(env)),
+            FieldType::String => crate::encode_string(env, ""),
+            _ => Vec::<Term<'a>>::new().encode(env),
+        };
+        total = total - sizes[index] + estimated_size(replacement);
+        values[index] = replacement;
+    }
+}
+
+fn is_variable_size(field_type: &FieldType) -> bool {
+    matches!(
+        field_type,
+        FieldType::String
+            | FieldType::Json
+            | FieldType::FlatMap
+            | FieldType::ArrayString
+            | FieldType::ArrayUInt64
+            | FieldType::ArrayFloat64
+            | FieldType::ArrayDateTime64 { .. }
+            | FieldType::ArrayJson
+            | FieldType::ArrayMap
+            | FieldType::ArrayFlatMap
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_character_boundaries() {
+        assert_eq!(truncate_bytes(b"abc", 3), None);
+        assert_eq!(truncate_bytes(b"abcd", 3), Some(&b"abc"[..]));
+        // "é" is two bytes; cutting inside it keeps only the "a".
+        assert_eq!(truncate_bytes("aéb".as_bytes(), 2), Some(&b"a"[..]));
+        assert_eq!(truncate_bytes("aéb".as_bytes(), 3), Some("aé".as_bytes()));
+    }
+}
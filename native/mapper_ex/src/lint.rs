@@ -0,0 +1,263 @@
+use rustler::types::map::MapIterator;
+use rustler::{Atom, Encoder, Env, Term};
+
+use crate::mapping::{
+    get_int_key, get_term_key, CaseSource, CompiledField, CompiledMapping, FieldType, PathSource,
+};
+use crate::path::CompiledPath;
+
+mod atoms {
+    rustler::atoms! {
+        field,
+        code,
+        message,
+        value_map_not_allowed,
+        unreachable_path,
+        duplicate_path,
+        filters_ignored,
+        pick_ignored,
+        unknown_enum_result,
+        enum_value_out_of_range,
+        invalid_precision,
+    }
+}
+
+/// A config that compiles but is almost certainly not what was meant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lint {
+    /// A `value_map` key that `allowed_values` rejects before the lookup.
+    ValueMapNotAllowed,
+    /// A coalesce path after `$`, which always resolves.
+    UnreachablePath,
+    /// A coalesce path listed twice.
+    DuplicatePath,
+    /// `filters` only apply to string fields.
+    FiltersIgnored,
+    /// `pick` only applies to `json` and `flat_map` fields.
+    PickIgnored,
+    /// An Enum8 `infer` rule whose `result` is not in `enum_values`.
+    UnknownEnumResult,
+    /// An `enum_values` value outside the Int8 range, which wraps.
+    EnumValueOutOfRange,
+    /// A DateTime64 `precision` outside 0-9.
+    InvalidPrecision,
+}
+
+impl Lint {
+    fn atom(self) -> Atom {
+        match self {
+            Lint::ValueMapNotAllowed => atoms::value_map_not_allowed(),
+            Lint::UnreachablePath => atoms::unreachable_path(),
+            Lint::DuplicatePath => atoms::duplicate_path(),
+            Lint::FiltersIgnored => atoms::filters_ignored(),
+            Lint::PickIgnored => atoms::pick_ignored(),
+            Lint::UnknownEnumResult => atoms::unknown_enum_result(),
+            Lint::EnumValueOutOfRange => atoms::enum_value_out_of_range(),
+            Lint::InvalidPrecision => atoms::invalid_precision(),
+        }
+    }
+}
+
+struct Warning {
+    field: String,
+    lint: Lint,
+    message: String,
+}
+
+/// Checks a config that compiled into `mapping` for mistakes the compiler
+/// accepts. `config` is read for settings the compiled form no longer holds.
+pub fn lint_mapping<'a>(env: Env<'a>, config: Term<'a>, mapping: &CompiledMapping) -> Term<'a> {
+    let field_terms: Vec<Term<'a>> = get_term_key(env, config, "fields")
+        .and_then(|fields| fields.decode().ok())
+        .unwrap_or_default();
+
+    let mut warnings = Vec::new();
+    for (index, field) in mapping.fields.iter().enumerate() {
+        let mut warn = |lint, message: String| {
+            warnings.push(Warning {
+                field: field.name.clone(),
+                lint,
+                message,
+            })
+        };
+        lint_field(field, &mut warn);
+        if let Some(&term) = field_terms.get(index) {
+            lint_field_config(env, term, field, &mut warn);
+        }
+    }
+
+    warnings
+        .iter()
+        .map(|warning| encode_warning(env, warning))
+        .collect::<Vec<_>>()
+        .encode(env)
+}
+
+fn lint_field(field: &CompiledField, warn: &mut impl FnMut(Lint, String)) {
+    if !field.allowed_values.is_empty() {
+        let allowed: Vec<String> = field
+            .allowed_values
+            .iter()
+            .map(|value| String::from_utf8_lossy(value).to_lowercase())
+            .collect();
+        let mut keys: Vec<&String> = field
+            .value_map
+            .keys()
+            .chain(field.value_map_str.keys())
+            .filter(|key| !allowed.contains(key))
+            .collect();
+        keys.sort();
+        for key in keys {
+            warn(
+                Lint::ValueMapNotAllowed,
+                format!("value_map key '{}' is not in allowed_values", key),
+            );
+        }
+    }
+
+    let mut coalesces = Vec::new();
+    collect_coalesces(&field.path_source, &mut coalesces);
+    for paths in coalesces {
+        for (lint, index) in coalesce_lints(paths) {
+            let message = match lint {
+                Lint::UnreachablePath => {
+                    format!(
+                        "coalesce path {} follows '$', which always resolves",
+                        index + 1
+                    )
+                }
+                _ => format!("coalesce path {} repeats an earlier path", index + 1),
+            };
+            warn(lint, message);
+        }
+    }
+
+    if field.filters.is_some() && field.field_type != FieldType::String {
+        warn(
+            Lint::FiltersIgnored,
+            format!(
+                "filters have no effect on {} fields",
+                field.field_type.name()
+            ),
+        );
+    }
+
+    if !field.pick.is_empty() && !matches!(field.field_type, FieldType::Json | FieldType::FlatMap) {
+        warn(
+            Lint::PickIgnored,
+            format!("pick has no effect on {} fields", field.field_type.name()),
+        );
+    }
+
+    if let Some(enum8) = &field.enum8_data {
+        for rule in &enum8.infer_rules {
+            if !enum8.value_map.contains_key(&rule.result) {
+                warn(
+                    Lint::UnknownEnumResult,
+                    format!("infer result '{}' is not in enum_values", rule.result),
+                );
+            }
+        }
+    }
+}
+
+fn lint_field_config<'a>(
+    env: Env<'a>,
+    term: Term<'a>,
+    field: &CompiledField,
+    warn: &mut impl FnMut(Lint, String),
+) {
+    if matches!(
+        field.field_type,
+        FieldType::DateTime64 { .. } | FieldType::ArrayDateTime64 { .. }
+    ) {
+        if let Some(precision) =
+            get_int_key(env, term, "precision").filter(|p| !(0..=9).contains(p))
+        {
+            warn(
+                Lint::InvalidPrecision,
+                format!("precision {} is outside 0-9", precision),
+            );
+        }
+    }
+
+    if let Some(values) = get_term_key(env, term, "enum_values").and_then(MapIterator::new) {
+        let mut out_of_range: Vec<(String, i64)> = values
+            .filter_map(|(key, value)| Some((key.decode().ok()?, value.decode().ok()?)))
+            .filter(|(_, value)| i8::try_from(*value).is_err())
+            .collect();
+        out_of_range.sort();
+        for (key, value) in out_of_range {
+            warn(
+                Lint::EnumValueOutOfRange,
+                format!(
+                    "enum_values '{}' => {} is outside the Enum8 range",
+                    key, value
+                ),
+            );
+        }
+    }
+}
+
+fn collect_coalesces<'m>(source: &'m PathSource, out: &mut Vec<&'m [CompiledPath]>) {
+    match source {
+        PathSource::Coalesce(paths) => out.push(paths),
+        PathSource::Cases(cases) => {
+            for case in cases {
+                if let CaseSource::Path(source) = &case.source {
+                    collect_coalesces(source, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Coalesce candidates that can never supply a value, by index.
+fn coalesce_lints(paths: &[CompiledPath]) -> Vec<(Lint, usize)> {
+    let mut lints = Vec::new();
+    let mut root_seen = false;
+    for (index, path) in paths.iter().enumerate() {
+        if root_seen {
+            lints.push((Lint::UnreachablePath, index));
+        } else if paths[..index]
+            .iter()
+            .any(|earlier| earlier.segments == path.segments)
+        {
+            lints.push((Lint::DuplicatePath, index));
+        }
+        root_seen |= path.segments.is_empty();
+    }
+    lints
+}
+
+fn encode_warning<'a>(env: Env<'a>, warning: &Warning) -> Term<'a> {
+    let keys = [
+        atoms::field().encode(env),
+        atoms::code().encode(env),
+        atoms::message().encode(env),
+    ];
+    let values = [
+        crate::encode_string(env, &warning.field),
+        warning.lint.atom().encode(env),
+        crate::encode_string(env, &warning.message),
+    ];
+    Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_unreachable_coalesce_paths() {
+        let paths: Vec<CompiledPath> = ["$.a", "$.b", "$.a", "$", "$.c"]
+            .iter()
+            .map(|path| crate::path::compile(path).unwrap())
+            .collect();
+        assert_eq!(
+            coalesce_lints(&paths),
+            [(Lint::DuplicatePath, 2), (Lint::UnreachablePath, 4)]
+        );
+    }
+}
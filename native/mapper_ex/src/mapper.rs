@@ -1,13 +1,22 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
 use rustler::types::list::ListIterator;
 use rustler::types::map::MapIterator;
 use rustler::{Binary, Encoder, Env, Term};
 
+use crate::aggregate;
 use crate::coerce;
+use crate::json_input::JsonValue;
+use crate::limits::{self, FlatLimits};
 use crate::mapping::{
-    CompiledField, CompiledMapping, Enum8Data, FieldType, PathSource, Predicate, PredicateValue,
+    CaseSource, CompiledField, CompiledMapping, Condition, Enum8Data, FieldTransform, FieldType,
+    FlattenOptions, HashKeys, KeyRules, ListStrategy, PathSource, Predicate, PredicateValue,
+    Sample,
 };
-use crate::query;
-use crate::string_filters;
+use crate::query::{self, KeyMode};
+use crate::redact::{self, Redactor};
+use crate::sampling;
 
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::{Serialize, Serializer};
@@ -32,6 +41,13 @@ fn is_array_type(field_type: &FieldType) -> bool {
     )
 }
 
+/// A document to map: a term, or a parsed JSON object whose paths are
+/// evaluated without building it as a term.
+pub enum Document<'a> {
+    Term(Term<'a>),
+    Json(JsonValue<'a>),
+}
+
 pub struct MapScratch<'a> {
     values: Vec<Term<'a>>,
     query_cache: query::QueryCache<'a>,
@@ -43,7 +59,9 @@ impl<'a> MapScratch<'a> {
             values: Vec::with_capacity(mapping.fields.len()),
             query_cache: query::QueryCache::new(
                 mapping.path_cache_size,
-                mapping.root_cache_size,
+                Arc::clone(&mapping.preloads),
+                mapping.key_slot_count,
+                mapping.key_mode,
                 nil,
             ),
         }
@@ -52,22 +70,26 @@ impl<'a> MapScratch<'a> {
     pub fn values(&self) -> &[Term<'a>] {
         &self.values
     }
-}
 
-/// Execute the mapping on a single document, returning the mapped output map.
-///
-/// When `flat_keys` is true, dotted paths are resolved as literal flat-key
-/// lookups on the input map instead of nested map navigation.
-pub fn map_single<'a>(
-    env: Env<'a>,
-    body: Term<'a>,
-    mapping: &CompiledMapping,
-    flat_keys: bool,
-) -> Term<'a> {
-    let nil = atoms::nil().encode(env);
-    let mut scratch = MapScratch::new(mapping, nil);
-    map_values_into(env, body, mapping, flat_keys, nil, &mut scratch);
+    /// Resets the scratch so the next document can reuse its allocations.
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.query_cache.clear();
+    }
 
+    /// Resets the scratch for `document` and returns the term the mapping
+    /// core is given as its body.
+    pub fn start(&mut self, document: Document<'a>) -> Term<'a> {
+        self.clear();
+        match document {
+            Document::Term(term) => term,
+            Document::Json(json) => self.query_cache.load_json(json),
+        }
+    }
+}
+
+/// Builds the output map of one document from its mapped `values`.
+pub fn output_map<'a>(env: Env<'a>, mapping: &CompiledMapping, values: &[Term<'a>]) -> Term<'a> {
     let keys: Vec<Term<'a>> = mapping
         .fields
         .iter()
@@ -76,7 +98,7 @@ pub fn map_single<'a>(
 
     // Build the output map in a single allocation via enif_make_map_from_arrays.
     // Duplicate field names are rejected at compile time so this should not fail.
-    Term::map_from_term_arrays(env, &keys, scratch.values()).unwrap_or_else(|_| Term::map_new(env))
+    Term::map_from_term_arrays(env, &keys, values).unwrap_or_else(|_| Term::map_new(env))
 }
 
 /// Execute the shared mapping core into field-order storage.
@@ -91,17 +113,191 @@ pub fn map_values_into<'a>(
     nil: Term<'a>,
     scratch: &mut MapScratch<'a>,
 ) {
-    let values = &mut scratch.values;
-    let query_cache = &mut scratch.query_cache;
+    match &mapping.stats {
+        Some(stats) => {
+            let mut trace = stats.trace();
+            map_values_traced(env, body, mapping, flat_keys, nil, scratch, &mut trace)
+        }
+        None => map_values_traced(env, body, mapping, flat_keys, nil, scratch, &mut ()),
+    }
+}
 
-    if !flat_keys
-        && !mapping.root_cache_keys.is_empty()
-        && body.map_size().unwrap_or(usize::MAX) <= mapping.root_cache_scan_limit
-    {
-        query_cache.preload_root(body, &mapping.root_cache_keys);
+/// Outcome of a mapping's `drop_when`, `sample` and `route` sections for one
+/// document.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    Drop,
+    /// Index of the first matching route, or `None` when no route matched.
+    Keep(Option<usize>),
+}
+
+/// Evaluates the mapping's document-level decisions.
+///
+/// Resolved paths stay in the scratch's query cache, so mapping the same
+/// document afterwards with `map_values_into` and the uncleared scratch
+/// reuses them.
+pub fn decide<'a>(
+    env: Env<'a>,
+    body: Term<'a>,
+    mapping: &CompiledMapping,
+    flat_keys: bool,
+    nil: Term<'a>,
+    scratch: &mut MapScratch<'a>,
+) -> Decision {
+    let decisions = &mapping.decisions;
+    let cache = &mut scratch.query_cache;
+
+    if let Some(condition) = &decisions.drop_when {
+        if condition_matches(env, body, condition, nil, flat_keys, cache) {
+            return Decision::Drop;
+        }
+    }
+    if let Some(sample) = &decisions.sample {
+        if !sample_keeps(env, body, sample, nil, flat_keys, cache) {
+            return Decision::Drop;
+        }
+    }
+
+    Decision::Keep(
+        decisions.routes.iter().position(|route| {
+            condition_matches(env, body, &route.condition, nil, flat_keys, cache)
+        }),
+    )
+}
+
+/// Evaluates only the mapping's `sample`, as `map` and `map_many` do.
+pub fn sample<'a>(
+    env: Env<'a>,
+    body: Term<'a>,
+    mapping: &CompiledMapping,
+    flat_keys: bool,
+    nil: Term<'a>,
+    scratch: &mut MapScratch<'a>,
+) -> Decision {
+    match &mapping.decisions.sample {
+        Some(sample)
+            if !sample_keeps(env, body, sample, nil, flat_keys, &mut scratch.query_cache) =>
+        {
+            Decision::Drop
+        }
+        _ => Decision::Keep(None),
+    }
+}
+
+/// Documents without a usable sampling key are kept.
+fn sample_keeps<'a>(
+    env: Env<'a>,
+    body: Term<'a>,
+    sample: &Sample,
+    nil: Term<'a>,
+    flat_keys: bool,
+    cache: &mut query::QueryCache<'a>,
+) -> bool {
+    let mut buffer = itoa::Buffer::new();
+    let rate = sample
+        .severity
+        .as_ref()
+        .and_then(|path| {
+            let severity = query::evaluate(env, body, path, nil, flat_keys, cache);
+            let severity = sample_key(severity, &mut buffer)?.to_ascii_lowercase();
+            sample.severity_rates.get(&severity).copied()
+        })
+        .unwrap_or(sample.rate);
+
+    let key = query::evaluate(env, body, &sample.key, nil, flat_keys, cache);
+    match sample_key(key, &mut buffer) {
+        Some(key) if !key.is_empty() => sampling::keeps(key, rate),
+        _ => true,
+    }
+}
+
+/// The bytes of a string value, or the decimal digits of an integer.
+fn sample_key<'b, 'a: 'b>(value: Term<'a>, buffer: &'b mut itoa::Buffer) -> Option<&'b [u8]> {
+    if let Ok(binary) = value.decode::<Binary<'a>>() {
+        Some(binary.as_slice())
+    } else {
+        value
+            .decode::<i64>()
+            .ok()
+            .map(|i| buffer.format(i).as_bytes())
     }
+}
+
+/// Where a field's value was resolved from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceMatch {
+    Root,
+    Path,
+    /// Index of the first coalesce path that produced a usable value.
+    Coalesce(usize),
+    /// Index of the referenced output field.
+    FromOutput(usize),
+    /// Array field mapped element-wise through a wildcard path.
+    Wildcard,
+    /// Summary of the values under a wildcard path.
+    Aggregate,
+    /// Literal value of the matching case.
+    Literal,
+}
+
+/// Why a field fell back to its default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefaultReason {
+    Source,
+    AllowedValues,
+    ValueMap,
+    Enum8,
+    Redacted,
+}
 
-    for field in &mapping.fields {
+/// How an Enum8 field's value was decided.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Enum8Match {
+    Lookup,
+    Integer,
+    InferRule(usize),
+    Default,
+}
+
+/// Observer for the decisions the mapping core makes for each field.
+///
+/// Every hook defaults to a no-op, and `()` is the tracer `map_values_into`
+/// uses unless the mapping collects stats, so the hot path compiles without
+/// any tracing.
+pub trait MapTrace<'a> {
+    fn field(&mut self, _index: usize) {}
+    fn case(&mut self, _index: usize) {}
+    fn source(&mut self, _source: SourceMatch, _raw: Term<'a>) {}
+    fn skipped(&mut self, _index: usize, _value: Term<'a>, _skip: query::Skip) {}
+    fn default_used(&mut self, _reason: DefaultReason) {}
+    fn allowed_values(&mut self, _allowed: bool) {}
+    fn value_map(&mut self, _hit: bool) {}
+    fn enum8(&mut self, _resolution: Enum8Match) {}
+    /// A scalar value was coerced to the field's type; `fell_back` as
+    /// reported by `coerce::coerce_checked`.
+    fn coerce(&mut self, _fell_back: bool) {}
+    fn value(&mut self, _value: Term<'a>) {}
+}
+
+impl<'a> MapTrace<'a> for () {}
+
+/// `map_values_into` reporting each field's decisions to `trace`.
+pub fn map_values_traced<'a, T: MapTrace<'a>>(
+    env: Env<'a>,
+    body: Term<'a>,
+    mapping: &CompiledMapping,
+    flat_keys: bool,
+    nil: Term<'a>,
+    scratch: &mut MapScratch<'a>,
+    trace: &mut T,
+) {
+    let values = &mut scratch.values;
+    let query_cache = &mut scratch.query_cache;
+    let redactor = mapping.redactor.as_ref();
+    let key_mode = mapping.key_mode;
+
+    for (index, field) in mapping.fields.iter().enumerate() {
+        trace.field(index);
         let is_array = is_array_type(&field.field_type);
 
         if is_array {
@@ -121,10 +317,15 @@ pub fn map_values_into<'a>(
                             &field.field_type,
                             inner_type.as_ref(),
                             field.filter_nil,
+                            key_mode,
                             nil,
                         )
                     },
                 ) {
+                    trace.source(SourceMatch::Wildcard, value);
+                    let value = redact_array(env, value, redactor);
+                    let value = limit_value(env, field, value, false);
+                    trace.value(value);
                     values.push(value);
                     continue;
                 }
@@ -135,35 +336,50 @@ pub fn map_values_into<'a>(
         // resolve raw value (no default), then enum8 handler does lookup + inference + default
         let is_enum8 = matches!(&field.field_type, FieldType::Enum8 { .. });
 
-        let value = if is_enum8 {
-            resolve_value_raw(env, body, field, values, nil, flat_keys, query_cache)
+        let (value, defaulted) = if is_enum8 {
+            let raw =
+                resolve_value_raw(env, body, field, values, nil, flat_keys, query_cache, trace);
+            (raw, false)
         } else {
-            resolve_value(env, body, field, values, nil, flat_keys, query_cache)
+            resolve_value(env, body, field, values, nil, flat_keys, query_cache, trace)
         };
 
         // Array types skip transform, allowed_values, value_map, enum8, and json operations
         if is_array {
-            let value = coerce::coerce_array(env, value, &field.field_type, field.filter_nil, nil);
+            let value = coerce::coerce_array(
+                env,
+                value,
+                &field.field_type,
+                field.filter_nil,
+                key_mode,
+                nil,
+            );
+            let value = redact_array(env, value, redactor);
+            let value = limit_value(env, field, value, false);
+            trace.value(value);
             values.push(value);
             continue;
         }
 
-        // Apply transform if configured
+        // Apply transform if configured. A default is never hashed, or every
+        // document missing the value would share one real-looking pseudonym.
         let value = match &field.transform {
+            Some(FieldTransform::Hash { .. }) if defaulted => value,
             Some(transform) => coerce::apply_transform(env, value, transform, nil),
             None => value,
         };
 
-        // Check allowed_values whitelist
+        // Check allowed_values whitelist. Non-string values are never in the
+        // allowed list and fall back to the default.
         let value = if !field.allowed_values.is_empty() && value != nil {
-            if let Ok(b) = value.decode::<Binary>() {
-                if field.allowed_values.contains(b.as_slice()) {
-                    value
-                } else {
-                    coerce::encode_default(env, &field.default, nil)
-                }
+            let allowed = value
+                .decode::<Binary>()
+                .is_ok_and(|b| field.allowed_values.contains(b.as_slice()));
+            trace.allowed_values(allowed);
+            if allowed {
+                value
             } else {
-                // Non-string values are not in the allowed list, fall back to default
+                trace.default_used(DefaultReason::AllowedValues);
                 coerce::encode_default(env, &field.default, nil)
             }
         } else {
@@ -176,31 +392,39 @@ pub fn map_values_into<'a>(
         // string->integer lookup, e.g. severity_text -> severity_number). At most
         // one is non-empty, so this is a cheap branch, not per-event inference.
         // Either way, values absent from the map fall back to the default.
-        let value = if !field.value_map_str.is_empty() {
-            let mapped = coerce::apply_value_map_str(env, value, &field.value_map_str, nil);
-            if mapped != nil {
-                mapped
-            } else {
-                coerce::encode_default(env, &field.default, nil)
-            }
+        let mapped = if !field.value_map_str.is_empty() {
+            Some(coerce::apply_value_map_str(
+                env,
+                value,
+                &field.value_map_str,
+                nil,
+            ))
         } else if !field.value_map.is_empty() {
-            let mapped = coerce::apply_value_map(env, value, &field.value_map, nil);
-            if mapped != nil {
-                mapped
-            } else {
-                coerce::encode_default(env, &field.default, nil)
-            }
+            Some(coerce::apply_value_map(env, value, &field.value_map, nil))
         } else {
-            value
+            None
+        };
+        let value = match mapped {
+            Some(mapped) => {
+                trace.value_map(mapped != nil);
+                if mapped != nil {
+                    mapped
+                } else {
+                    trace.default_used(DefaultReason::ValueMap);
+                    coerce::encode_default(env, &field.default, nil)
+                }
+            }
+            None => value,
         };
 
         // For Enum8 fields, handle enum resolution (string->int lookup + inference + default)
         let value = if is_enum8 {
-            resolve_enum8(env, body, field, value, nil, flat_keys, query_cache)
+            resolve_enum8(env, body, field, value, nil, flat_keys, query_cache, trace)
         } else {
             value
         };
 
+        let mut flattened_truncated = false;
         let value = match field.field_type {
             FieldType::Json => {
                 if flat_keys {
@@ -210,23 +434,78 @@ pub fn map_values_into<'a>(
                 }
             }
             FieldType::FlatMap => {
-                if flat_keys {
+                let limits = field
+                    .limits
+                    .flat(field.key_rules.is_empty() && redactor.is_none());
+                let (value, truncated) = if flat_keys {
                     let value =
                         apply_json_operations_flat(env, body, field, value, nil, query_cache);
-                    stringify_values(env, value, nil)
+                    let options = &field.flatten;
+                    stringify_values(env, value, nil, options, key_mode, redactor, limits)
                 } else {
-                    flatten_field(env, body, field, value, nil, query_cache)
+                    flatten_field(env, body, field, value, nil, redactor, limits, query_cache)
+                };
+                flattened_truncated = truncated;
+                let value = if field.key_rules.is_empty() {
+                    value
+                } else {
+                    apply_key_rules(env, value, &field.key_rules)
+                };
+                match &field.hash_keys {
+                    Some(hash_keys) => hash_flat_values(env, value, hash_keys, nil),
+                    None => value,
                 }
             }
-            _ => coerce::coerce(env, value, &field.field_type, nil),
+            _ => {
+                let (value, fell_back) = coerce::coerce_checked(env, value, &field.field_type, nil);
+                trace.coerce(fell_back);
+                value
+            }
         };
 
+        // FlatMap values were redacted while flattening.
+        let redacted = match (&field.field_type, redactor) {
+            (FieldType::String, Some(redactor)) => redact::redact_string(env, value, redactor),
+            (FieldType::Json, Some(redactor)) => redact::redact_nested(env, value, redactor),
+            _ => Some(value),
+        };
+        let value = match redacted {
+            Some(value) => value,
+            None => {
+                trace.default_used(DefaultReason::Redacted);
+                let default = coerce::encode_default(env, &field.default, nil);
+                coerce::coerce(env, default, &field.field_type, nil)
+            }
+        };
+        let value = limit_value(env, field, value, flattened_truncated);
+
+        trace.value(value);
         values.push(value);
     }
+
+    if let Some(max_row_bytes) = mapping.max_row_bytes {
+        limits::enforce_row_limit(env, values, &mapping.fields, max_row_bytes);
+    }
+}
+
+/// Applies the field's size limits, last so they bound what is emitted.
+/// `flattened_truncated` says whether flattening already dropped entries.
+#[inline]
+fn limit_value<'a>(
+    env: Env<'a>,
+    field: &CompiledField,
+    value: Term<'a>,
+    flattened_truncated: bool,
+) -> Term<'a> {
+    if field.limits.is_empty() {
+        return value;
+    }
+    limits::limit_field(env, field, value, &field.limits, flattened_truncated).unwrap_or(value)
 }
 
 /// Resolve the source value without applying defaults (for enum8 fields).
-fn resolve_value_raw<'a>(
+#[allow(clippy::too_many_arguments)]
+fn resolve_value_raw<'a, T: MapTrace<'a>>(
     env: Env<'a>,
     body: Term<'a>,
     field: &CompiledField,
@@ -234,27 +513,26 @@ fn resolve_value_raw<'a>(
     nil: Term<'a>,
     flat_keys: bool,
     cache: &mut query::QueryCache<'a>,
+    trace: &mut T,
 ) -> Term<'a> {
-    match &field.path_source {
-        PathSource::Root => body,
-        PathSource::Single(path) => query::evaluate(env, body, path, nil, flat_keys, cache),
-        PathSource::Coalesce(paths) => {
-            query::evaluate_first(env, body, paths, (false, None), nil, flat_keys, cache)
-        }
-        PathSource::FromOutput(idx) => {
-            let v = output_values[*idx];
-            if v != nil {
-                v
-            } else {
-                nil
-            }
-        }
-        PathSource::FromOutputName(_) => unreachable!("FromOutputName should be resolved"),
-    }
+    resolve_source(
+        env,
+        body,
+        field,
+        output_values,
+        &field.path_source,
+        (nil, false),
+        flat_keys,
+        cache,
+        trace,
+    )
+    .unwrap_or(nil)
 }
 
-/// Resolve the source value for a field from the input document.
-fn resolve_value<'a>(
+/// Resolve the source value for a field from the input document, and whether
+/// the field's default stands in for it.
+#[allow(clippy::too_many_arguments)]
+fn resolve_value<'a, T: MapTrace<'a>>(
     env: Env<'a>,
     body: Term<'a>,
     field: &CompiledField,
@@ -262,67 +540,115 @@ fn resolve_value<'a>(
     nil: Term<'a>,
     flat_keys: bool,
     cache: &mut query::QueryCache<'a>,
-) -> Term<'a> {
+    trace: &mut T,
+) -> (Term<'a>, bool) {
     let skip_empty = field.field_type == FieldType::String;
+    let resolved = resolve_source(
+        env,
+        body,
+        field,
+        output_values,
+        &field.path_source,
+        (nil, skip_empty),
+        flat_keys,
+        cache,
+        trace,
+    );
+
+    match resolved {
+        Some(value) => (value, false),
+        None => {
+            trace.default_used(DefaultReason::Source);
+            (coerce::encode_default(env, &field.default, nil), true)
+        }
+    }
+}
 
-    match &field.path_source {
-        PathSource::Root => body,
+/// Resolve one of the field's path sources, returning `None` when no usable
+/// value was found. With `skip_empty`, empty strings and strings failing the
+/// field's filters are not usable.
+#[allow(clippy::too_many_arguments)]
+fn resolve_source<'a, T: MapTrace<'a>>(
+    env: Env<'a>,
+    body: Term<'a>,
+    field: &CompiledField,
+    output_values: &[Term<'a>],
+    path_source: &PathSource,
+    (nil, skip_empty): (Term<'a>, bool),
+    flat_keys: bool,
+    cache: &mut query::QueryCache<'a>,
+    trace: &mut T,
+) -> Option<Term<'a>> {
+    let filters = field.filters.as_ref();
+    let (source, value) = match path_source {
+        PathSource::Root => (SourceMatch::Root, query::root(env, body, cache)),
         PathSource::Single(path) => {
-            let v = query::evaluate(env, body, path, nil, flat_keys, cache);
-            if v == nil {
-                coerce::encode_default(env, &field.default, nil)
-            } else if skip_empty {
-                // Check binary length without allocating a String
-                if let Ok(b) = v.decode::<Binary>() {
-                    if b.is_empty() {
-                        coerce::encode_default(env, &field.default, nil)
-                    } else if let Some(ref f) = field.filters {
-                        if !string_filters::passes_filters(b.as_slice(), f) {
-                            coerce::encode_default(env, &field.default, nil)
-                        } else {
-                            v
-                        }
-                    } else {
-                        v
-                    }
-                } else {
-                    v
-                }
-            } else {
-                v
+            let value = query::evaluate(env, body, path, nil, flat_keys, cache);
+            if let Some(skip) = query::rejection(value, skip_empty, filters, nil) {
+                trace.skipped(0, value, skip);
+                return None;
             }
+            (SourceMatch::Path, value)
         }
         PathSource::Coalesce(paths) => {
-            let string_filters = field.filters.as_ref();
-            let result = query::evaluate_first(
+            let (index, value) = query::evaluate_first_indexed(
                 env,
                 body,
                 paths,
-                (skip_empty, string_filters),
+                (skip_empty, filters),
                 nil,
                 flat_keys,
                 cache,
-            );
-            if result == nil {
-                coerce::encode_default(env, &field.default, nil)
-            } else {
-                result
-            }
+                |index, value, skip| trace.skipped(index, value, skip),
+            )?;
+            (SourceMatch::Coalesce(index), value)
+        }
+        PathSource::Aggregate(aggregate) => {
+            let value = aggregate::evaluate(env, body, aggregate, nil, flat_keys, cache)?;
+            (SourceMatch::Aggregate, value)
         }
         PathSource::FromOutput(idx) => {
-            let v = output_values[*idx];
-            if v != nil {
-                v
-            } else {
-                coerce::encode_default(env, &field.default, nil)
+            let value = output_values[*idx];
+            if value == nil {
+                return None;
+            }
+            (SourceMatch::FromOutput(*idx), value)
+        }
+        PathSource::Cases(cases) => {
+            let (index, case) = cases.iter().enumerate().find(|(_, case)| {
+                condition_matches(env, body, &case.condition, nil, flat_keys, cache)
+            })?;
+            trace.case(index);
+            match &case.source {
+                CaseSource::Literal(value) => (
+                    SourceMatch::Literal,
+                    coerce::encode_default(env, value, nil),
+                ),
+                CaseSource::Path(source) => {
+                    return resolve_source(
+                        env,
+                        body,
+                        field,
+                        output_values,
+                        source,
+                        (nil, skip_empty),
+                        flat_keys,
+                        cache,
+                        trace,
+                    );
+                }
             }
         }
         PathSource::FromOutputName(_) => unreachable!("FromOutputName should be resolved"),
-    }
+    };
+
+    trace.source(source, value);
+    Some(value)
 }
 
 /// Resolve Enum8 value: explicit path -> string lookup in enum_values, then inference.
-fn resolve_enum8<'a>(
+#[allow(clippy::too_many_arguments)]
+fn resolve_enum8<'a, T: MapTrace<'a>>(
     env: Env<'a>,
     body: Term<'a>,
     field: &CompiledField,
@@ -330,30 +656,42 @@ fn resolve_enum8<'a>(
     nil: Term<'a>,
     flat_keys: bool,
     cache: &mut query::QueryCache<'a>,
+    trace: &mut T,
 ) -> Term<'a> {
     let enum8_data = match &field.enum8_data {
         Some(data) => data,
-        None => return coerce::encode_default(env, &field.default, nil),
+        None => {
+            trace.enum8(Enum8Match::Default);
+            trace.default_used(DefaultReason::Enum8);
+            return coerce::encode_default(env, &field.default, nil);
+        }
     };
 
     // Step 1: If resolved value is a string, look up in enum_values (case-insensitive)
     if resolved_value != nil {
         // Keys are pre-lowercased at compile time
         if let Some(val) = coerce::case_insensitive_get(&enum8_data.value_map, resolved_value) {
+            trace.enum8(Enum8Match::Lookup);
             return (*val as i64).encode(env);
         }
         // If it's already an integer, pass through
         if resolved_value.decode::<i64>().is_ok() {
+            trace.enum8(Enum8Match::Integer);
             return resolved_value;
         }
     }
 
     // Step 2: Try inference rules
-    if let Some(result_val) = evaluate_infer_rules(env, body, enum8_data, nil, flat_keys, cache) {
+    if let Some((rule, result_val)) =
+        evaluate_infer_rules(env, body, enum8_data, nil, flat_keys, cache)
+    {
+        trace.enum8(Enum8Match::InferRule(rule));
         return (result_val as i64).encode(env);
     }
 
     // Step 3: Default
+    trace.enum8(Enum8Match::Default);
+    trace.default_used(DefaultReason::Enum8);
     coerce::encode_default(env, &field.default, nil)
 }
 
@@ -373,16 +711,18 @@ fn apply_json_operations<'a>(
         return value;
     }
 
+    let key_mode = cache.key_mode();
+
     // Apply exclude_keys
     let value = if !field.exclude_keys.is_empty() {
-        apply_exclude_keys(env, value, &field.exclude_keys)
+        apply_exclude_keys(env, value, &field.exclude_keys, key_mode)
     } else {
         value
     };
 
     // Apply elevate_keys
     let value = if !field.elevate_keys.is_empty() {
-        apply_elevate_keys(env, value, &field.elevate_keys)
+        apply_elevate_keys(env, value, &field.elevate_keys, key_mode)
     } else {
         value
     };
@@ -447,7 +787,12 @@ fn build_pick_map<'a>(
 }
 
 /// Remove specified keys from a map.
-fn apply_exclude_keys<'a>(env: Env<'a>, map: Term<'a>, exclude: &[Vec<u8>]) -> Term<'a> {
+fn apply_exclude_keys<'a>(
+    env: Env<'a>,
+    map: Term<'a>,
+    exclude: &[Vec<u8>],
+    key_mode: KeyMode,
+) -> Term<'a> {
     let iter = match MapIterator::new(map) {
         Some(it) => it,
         None => return map,
@@ -457,8 +802,8 @@ fn apply_exclude_keys<'a>(env: Env<'a>, map: Term<'a>, exclude: &[Vec<u8>]) -> T
     let mut values: Vec<Term<'a>> = Vec::with_capacity(32);
 
     for (k, v) in iter {
-        if let Ok(b) = k.decode::<Binary>() {
-            if exclude.iter().any(|ek| ek.as_slice() == b.as_slice()) {
+        if let Some(name) = key_mode.key_name(k) {
+            if exclude.iter().any(|ek| ek.as_slice() == name.as_ref()) {
                 continue;
             }
         }
@@ -476,9 +821,14 @@ fn apply_exclude_keys<'a>(env: Env<'a>, map: Term<'a>, exclude: &[Vec<u8>]) -> T
 /// for top-level overrides. Cannot use a single map_from_term_arrays call
 /// because duplicate keys (between elevated children and top-level) would
 /// cause enif_make_map_from_arrays to fail.
-fn apply_elevate_keys<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u8>]) -> Term<'a> {
+fn apply_elevate_keys<'a>(
+    env: Env<'a>,
+    map: Term<'a>,
+    elevate: &[Vec<u8>],
+    key_mode: KeyMode,
+) -> Term<'a> {
     if elevate.len() > 1 {
-        return apply_multiple_elevate_keys(env, map, elevate);
+        return apply_multiple_elevate_keys(env, map, elevate, key_mode);
     }
 
     let iter = match MapIterator::new(map) {
@@ -492,8 +842,8 @@ fn apply_elevate_keys<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u8>]) -> T
     let mut elevated_values: Vec<Term<'a>> = Vec::with_capacity(16);
 
     for (k, v) in iter {
-        if let Ok(b) = k.decode::<Binary>() {
-            if elevate.iter().any(|ek| ek.as_slice() == b.as_slice()) {
+        if let Some(name) = key_mode.key_name(k) {
+            if elevate.iter().any(|ek| ek.as_slice() == name.as_ref()) {
                 // This key should be elevated: merge its children into parent
                 if let Some(child_iter) = MapIterator::new(v) {
                     for (ck, cv) in child_iter {
@@ -528,7 +878,12 @@ fn apply_elevate_keys<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u8>]) -> T
     result
 }
 
-fn apply_multiple_elevate_keys<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u8>]) -> Term<'a> {
+fn apply_multiple_elevate_keys<'a>(
+    env: Env<'a>,
+    map: Term<'a>,
+    elevate: &[Vec<u8>],
+    key_mode: KeyMode,
+) -> Term<'a> {
     let mut result = Term::map_new(env);
 
     // Apply in reverse so the first configured elevate key wins when child
@@ -538,7 +893,7 @@ fn apply_multiple_elevate_keys<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u
         let Ok(elevate_key) = std::str::from_utf8(elevate_key) else {
             continue;
         };
-        let Ok(child) = map.map_get(crate::encode_string(env, elevate_key)) else {
+        let Some(child) = query::lookup_key(env, map, elevate_key, key_mode) else {
             continue;
         };
         let Some(children) = MapIterator::new(child) else {
@@ -553,10 +908,9 @@ fn apply_multiple_elevate_keys<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u
     if let Some(entries) = MapIterator::new(map) {
         for (key, value) in entries {
             let elevated = value.is_map()
-                && key
-                    .decode::<Binary>()
-                    .ok()
-                    .is_some_and(|binary| elevate.iter().any(|item| item == binary.as_slice()));
+                && key_mode
+                    .key_name(key)
+                    .is_some_and(|name| elevate.iter().any(|item| item == name.as_ref()));
             if !elevated {
                 result = result.map_put(key, value).unwrap_or(result);
             }
@@ -584,14 +938,16 @@ fn apply_json_operations_flat<'a>(
         return value;
     }
 
+    let key_mode = cache.key_mode();
+
     let value = if !field.exclude_keys.is_empty() {
-        apply_exclude_keys_flat(env, value, &field.exclude_keys)
+        apply_exclude_keys_flat(env, value, &field.exclude_keys, key_mode)
     } else {
         value
     };
 
     let value = if !field.elevate_keys.is_empty() {
-        apply_elevate_keys_flat(env, value, &field.elevate_keys)
+        apply_elevate_keys_flat(env, value, &field.elevate_keys, key_mode)
     } else {
         value
     };
@@ -600,7 +956,12 @@ fn apply_json_operations_flat<'a>(
 }
 
 /// Remove keys that match exactly OR start with `"<exclude_key>."`.
-fn apply_exclude_keys_flat<'a>(env: Env<'a>, map: Term<'a>, exclude: &[Vec<u8>]) -> Term<'a> {
+fn apply_exclude_keys_flat<'a>(
+    env: Env<'a>,
+    map: Term<'a>,
+    exclude: &[Vec<u8>],
+    key_mode: KeyMode,
+) -> Term<'a> {
     let iter = match MapIterator::new(map) {
         Some(it) => it,
         None => return map,
@@ -610,8 +971,8 @@ fn apply_exclude_keys_flat<'a>(env: Env<'a>, map: Term<'a>, exclude: &[Vec<u8>])
     let mut values: Vec<Term<'a>> = Vec::with_capacity(32);
 
     for (k, v) in iter {
-        if let Ok(b) = k.decode::<Binary>() {
-            let key_bytes = b.as_slice();
+        if let Some(name) = key_mode.key_name(k) {
+            let key_bytes = name.as_ref();
             let excluded = exclude.iter().any(|ek| {
                 key_bytes == ek.as_slice()
                     || (key_bytes.len() > ek.len()
@@ -632,9 +993,14 @@ fn apply_exclude_keys_flat<'a>(env: Env<'a>, map: Term<'a>, exclude: &[Vec<u8>])
 /// Elevate flat keys: keys starting with `"<elevate_key>."` get their prefix
 /// stripped. The elevate key itself (exact match) is dropped. Top-level keys
 /// (those not starting with any elevate prefix) win over elevated children.
-fn apply_elevate_keys_flat<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u8>]) -> Term<'a> {
+fn apply_elevate_keys_flat<'a>(
+    env: Env<'a>,
+    map: Term<'a>,
+    elevate: &[Vec<u8>],
+    key_mode: KeyMode,
+) -> Term<'a> {
     if elevate.len() > 1 {
-        return apply_multiple_elevate_keys_flat(env, map, elevate);
+        return apply_multiple_elevate_keys_flat(env, map, elevate, key_mode);
     }
 
     let iter = match MapIterator::new(map) {
@@ -647,8 +1013,8 @@ fn apply_elevate_keys_flat<'a>(env: Env<'a>, map: Term<'a>, elevate: &[Vec<u8>])
     let mut elevated_values: Vec<Term<'a>> = Vec::with_capacity(16);
 
     for (k, v) in iter {
-        if let Ok(b) = k.decode::<Binary>() {
-            let key_bytes = b.as_slice();
+        if let Some(name) = key_mode.key_name(k) {
+            let key_bytes = name.as_ref();
 
             // Check if this key matches an elevate prefix
             let mut matched = false;
@@ -705,6 +1071,7 @@ fn apply_multiple_elevate_keys_flat<'a>(
     env: Env<'a>,
     map: Term<'a>,
     elevate: &[Vec<u8>],
+    key_mode: KeyMode,
 ) -> Term<'a> {
     let Some(entries) = MapIterator::new(map) else {
         return map;
@@ -714,11 +1081,11 @@ fn apply_multiple_elevate_keys_flat<'a>(
     let mut top_entries = Vec::new();
 
     for (key, value) in entries {
-        let Ok(binary) = key.decode::<Binary>() else {
+        let Some(name) = key_mode.key_name(key) else {
             top_entries.push((key, value));
             continue;
         };
-        let key_bytes = binary.as_slice();
+        let key_bytes = name.as_ref();
         let mut matched = false;
 
         for (index, elevate_key) in elevate.iter().enumerate() {
@@ -756,7 +1123,7 @@ fn apply_multiple_elevate_keys_flat<'a>(
     result
 }
 
-/// Evaluate inference conditions and return the matching rule's result.
+/// Evaluate inference conditions and return the matching rule's index and result.
 pub fn evaluate_infer_rules<'a>(
     env: Env<'a>,
     body: Term<'a>,
@@ -764,28 +1131,12 @@ pub fn evaluate_infer_rules<'a>(
     nil: Term<'a>,
     flat_keys: bool,
     cache: &mut query::QueryCache<'a>,
-) -> Option<i8> {
-    for rule in &enum8_data.infer_rules {
-        let any_match = if rule.any.is_empty() {
-            true
-        } else {
-            rule.any
-                .iter()
-                .any(|cond| evaluate_condition(env, body, cond, nil, flat_keys, cache))
-        };
-
-        let all_match = if rule.all.is_empty() {
-            true
-        } else {
-            rule.all
-                .iter()
-                .all(|cond| evaluate_condition(env, body, cond, nil, flat_keys, cache))
-        };
-
-        if any_match && all_match {
+) -> Option<(usize, i8)> {
+    for (index, rule) in enum8_data.infer_rules.iter().enumerate() {
+        if condition_matches(env, body, &rule.condition, nil, flat_keys, cache) {
             // Both result and value_map keys are pre-lowercased at compile time
             if let Some(val) = enum8_data.value_map.get(&rule.result) {
-                return Some(*val);
+                return Some((index, *val));
             }
         }
     }
@@ -793,6 +1144,28 @@ pub fn evaluate_infer_rules<'a>(
     None
 }
 
+/// Evaluate a condition tree against the document. Groups short-circuit, and
+/// predicate paths are resolved through the shared query cache.
+fn condition_matches<'a>(
+    env: Env<'a>,
+    body: Term<'a>,
+    condition: &Condition,
+    nil: Term<'a>,
+    flat_keys: bool,
+    cache: &mut query::QueryCache<'a>,
+) -> bool {
+    match condition {
+        Condition::Predicate(cond) => evaluate_condition(env, body, cond, nil, flat_keys, cache),
+        Condition::All(children) => children
+            .iter()
+            .all(|child| condition_matches(env, body, child, nil, flat_keys, cache)),
+        Condition::Any(children) => children
+            .iter()
+            .any(|child| condition_matches(env, body, child, nil, flat_keys, cache)),
+        Condition::Not(child) => !condition_matches(env, body, child, nil, flat_keys, cache),
+    }
+}
+
 /// Evaluate a single inference condition.
 fn evaluate_condition<'a>(
     env: Env<'a>,
@@ -903,6 +1276,12 @@ fn evaluate_condition<'a>(
         }
         Predicate::IsList => value != nil && value.is_list(),
         Predicate::IsMap => value != nil && value.is_map(),
+        Predicate::StringMatch(matcher) => value
+            .decode::<Binary>()
+            .is_ok_and(|b| matcher.is_match(b.as_slice())),
+        Predicate::CharClass(class) => value
+            .decode::<Binary>()
+            .is_ok_and(|b| class.matches(b.as_slice())),
     }
 }
 
@@ -942,22 +1321,32 @@ fn matches_predicate_value(term: Term, expected: &PredicateValue) -> bool {
 
 // ── FlatMap: flatten nested maps to dot-notation keys with string values ─────
 
+/// Flattens a `FlatMap` field's value, returning whether `limits` dropped
+/// entries.
+#[allow(clippy::too_many_arguments)]
 fn flatten_field<'a>(
     env: Env<'a>,
     body: Term<'a>,
     field: &CompiledField,
     value: Term<'a>,
     nil: Term<'a>,
+    redactor: Option<&Redactor>,
+    limits: FlatLimits,
     cache: &mut query::QueryCache<'a>,
-) -> Term<'a> {
+) -> (Term<'a>, bool) {
     let value = select_json_value(env, body, field, value, nil, false, cache);
+    let options = &field.flatten;
+    let key_mode = cache.key_mode();
 
     if field.exclude_keys.is_empty() && field.elevate_keys.is_empty() {
-        return flatten_and_stringify(env, value, nil);
+        return flatten_limited(env, value, nil, options, key_mode, redactor, limits);
     }
 
+    let operations = (field.exclude_keys.as_slice(), field.elevate_keys.as_slice());
+    let capacity = value.map_size().unwrap_or(0);
+    let flattener = Flattener::new(env, nil, options, key_mode, limits, capacity);
     if let Some(flattened) =
-        try_flatten_with_operations(env, value, &field.exclude_keys, &field.elevate_keys, nil)
+        try_flatten_with_operations(env, value, operations, flattener, redactor)
     {
         return flattened;
     }
@@ -965,41 +1354,39 @@ fn flatten_field<'a>(
     let value = if field.exclude_keys.is_empty() {
         value
     } else {
-        apply_exclude_keys(env, value, &field.exclude_keys)
+        apply_exclude_keys(env, value, &field.exclude_keys, key_mode)
     };
     let value = if field.elevate_keys.is_empty() {
         value
     } else {
-        apply_elevate_keys(env, value, &field.elevate_keys)
+        apply_elevate_keys(env, value, &field.elevate_keys, key_mode)
     };
-    flatten_and_stringify(env, value, nil)
+    flatten_limited(env, value, nil, options, key_mode, redactor, limits)
 }
 
 fn try_flatten_with_operations<'a>(
     env: Env<'a>,
     value: Term<'a>,
-    exclude: &[Vec<u8>],
-    elevate: &[Vec<u8>],
-    nil: Term<'a>,
-) -> Option<Term<'a>> {
-    if value == nil || !value.is_map() {
-        return Some(Term::map_new(env));
+    (exclude, elevate): (&[Vec<u8>], &[Vec<u8>]),
+    mut flattener: Flattener<'a, '_>,
+    redactor: Option<&Redactor>,
+) -> Option<(Term<'a>, bool)> {
+    let key_mode = flattener.key_mode;
+    if value == flattener.nil || !value.is_map() {
+        return Some((Term::map_new(env), false));
     }
     if elevate.len() > 1 {
         return None;
     }
 
-    let capacity = value.map_size().unwrap_or(0);
-    let mut keys = Vec::with_capacity(capacity);
-    let mut values = Vec::with_capacity(capacity);
-    let mut prefix = String::new();
-
     for (key, child) in MapIterator::new(value)? {
-        let binary = match key.decode::<Binary>() {
-            Ok(binary) => binary,
-            Err(_) => continue,
+        if flattener.is_full() {
+            break;
+        }
+        let Some(name) = key_mode.key_name(key) else {
+            continue;
         };
-        let bytes = binary.as_slice();
+        let bytes = name.as_ref();
 
         if exclude.iter().any(|excluded| excluded.as_slice() == bytes) {
             continue;
@@ -1007,43 +1394,48 @@ fn try_flatten_with_operations<'a>(
         if elevate.iter().any(|elevated| elevated.as_slice() == bytes) {
             if let Some(children) = MapIterator::new(child) {
                 for (child_key, child_value) in children {
-                    if top_level_wins(value, child_key, exclude, elevate) {
+                    if top_level_wins(value, child_key, exclude, elevate, key_mode) {
                         continue;
                     }
-                    flatten_map_entry(
-                        env,
-                        child_key,
-                        child_value,
-                        &mut prefix,
-                        &mut keys,
-                        &mut values,
-                        nil,
-                    );
+                    flattener.entry(child_key, child_value);
                 }
                 continue;
             }
         }
 
-        flatten_map_entry(env, key, child, &mut prefix, &mut keys, &mut values, nil);
+        flattener.entry(key, child);
     }
 
+    let Flattener {
+        mut keys,
+        mut values,
+        truncated,
+        ..
+    } = flattener;
+    redact_flat_entries(env, &mut keys, &mut values, redactor);
     if keys.is_empty() {
-        Some(Term::map_new(env))
+        Some((Term::map_new(env), truncated))
     } else {
-        Term::map_from_term_arrays(env, &keys, &values).ok()
+        let flattened = Term::map_from_term_arrays(env, &keys, &values).ok()?;
+        Some((flattened, truncated))
     }
 }
 
-fn top_level_wins(map: Term<'_>, key: Term<'_>, exclude: &[Vec<u8>], elevate: &[Vec<u8>]) -> bool {
+fn top_level_wins(
+    map: Term<'_>,
+    key: Term<'_>,
+    exclude: &[Vec<u8>],
+    elevate: &[Vec<u8>],
+    key_mode: KeyMode,
+) -> bool {
     if map.map_get(key).is_err() {
         return false;
     }
 
-    let binary = match key.decode::<Binary>() {
-        Ok(binary) => binary,
-        Err(_) => return false,
+    let Some(name) = key_mode.key_name(key) else {
+        return false;
     };
-    let bytes = binary.as_slice();
+    let bytes = name.as_ref();
 
     !exclude
         .iter()
@@ -1060,130 +1452,418 @@ fn top_level_wins(map: Term<'_>, key: Term<'_>, exclude: &[Vec<u8>], elevate: &[
 /// - Scalars: coerced to string (`integer.to_string()`, `"true"`, etc.)
 /// - nil values: omitted from output
 /// - Empty/nil input: returns `%{}`
-pub fn flatten_and_stringify<'a>(env: Env<'a>, value: Term<'a>, nil: Term<'a>) -> Term<'a> {
+///
+/// The separator, list handling and depth come from `options`; the above
+/// is the default. With a redactor, each stringified value is redacted and
+/// entries a `drop` rule matches are left out.
+pub fn flatten_and_stringify<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    nil: Term<'a>,
+    options: &FlattenOptions,
+    key_mode: KeyMode,
+    redactor: Option<&Redactor>,
+) -> Term<'a> {
+    flatten_limited(
+        env,
+        value,
+        nil,
+        options,
+        key_mode,
+        redactor,
+        FlatLimits::NONE,
+    )
+    .0
+}
+
+/// `flatten_and_stringify`, skipping the keys past `limits` and returning
+/// whether any were skipped.
+fn flatten_limited<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    nil: Term<'a>,
+    options: &FlattenOptions,
+    key_mode: KeyMode,
+    redactor: Option<&Redactor>,
+    limits: FlatLimits,
+) -> (Term<'a>, bool) {
     if value == nil || !value.is_map() {
-        return Term::map_new(env);
+        return (Term::map_new(env), false);
     }
 
     let capacity = value.map_size().unwrap_or(0);
-    let mut keys: Vec<Term<'a>> = Vec::with_capacity(capacity);
-    let mut values: Vec<Term<'a>> = Vec::with_capacity(capacity);
-    let mut prefix = String::new();
-    flatten_map_recursive(env, value, &mut prefix, &mut keys, &mut values, nil);
-
-    build_flat_map(env, &keys, &values)
+    let mut flattener = Flattener::new(env, nil, options, key_mode, limits, capacity);
+    flattener.map(value);
+    let Flattener {
+        mut keys,
+        mut values,
+        truncated,
+        ..
+    } = flattener;
+    redact_flat_entries(env, &mut keys, &mut values, redactor);
+
+    (build_flat_map(env, &keys, &values), truncated)
 }
 
 /// Stringify values in an already-flat map without recursive flattening.
 ///
 /// Used when `flat_keys` is true — the input is already single-level with
 /// dot-notation keys, so we only need to coerce values to strings.
-/// nil values are omitted. Lists are JSON-encoded.
-pub fn stringify_values<'a>(env: Env<'a>, value: Term<'a>, nil: Term<'a>) -> Term<'a> {
+/// nil values are omitted. Lists follow the list strategy in `options`.
+/// Outside `KeyMode::Binary`, atom keys are emitted as strings. Returns
+/// whether `limits` dropped entries; a key's depth is its segment count.
+pub fn stringify_values<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    nil: Term<'a>,
+    options: &FlattenOptions,
+    key_mode: KeyMode,
+    redactor: Option<&Redactor>,
+    limits: FlatLimits,
+) -> (Term<'a>, bool) {
     if value == nil || !value.is_map() {
-        return Term::map_new(env);
+        return (Term::map_new(env), false);
     }
 
     let iter = match MapIterator::new(value) {
         Some(it) => it,
-        None => return Term::map_new(env),
+        None => return (Term::map_new(env), false),
     };
 
     let capacity = value.map_size().unwrap_or(0);
-    let mut keys: Vec<Term<'a>> = Vec::with_capacity(capacity);
-    let mut values: Vec<Term<'a>> = Vec::with_capacity(capacity);
+    let mut flattener = Flattener::new(env, nil, options, key_mode, limits, capacity);
+    let expand_lists = !matches!(options.lists, ListStrategy::Json);
 
     for (k, v) in iter {
         if v == nil {
             continue;
         }
+        if flattener.is_full() {
+            break;
+        }
+        let depth = match limits.max_depth {
+            Some(_) => key_mode.key_name(k).map_or(1, |name| {
+                1 + limits::count_occurrences(&name, options.separator.as_bytes())
+            }),
+            None => 1,
+        };
+        if limits.max_depth.is_some_and(|max| depth > max) {
+            flattener.truncated = true;
+            continue;
+        }
+        if expand_lists && v.is_list() {
+            // Expanded elements sit below the key's own segments.
+            flattener.depth = depth - 1;
+            flattener.entry(k, v);
+            flattener.depth = 0;
+            continue;
+        }
+        let k = match key_mode {
+            KeyMode::Binary => k,
+            _ => match key_mode.key_name(k) {
+                Some(name) if k.is_atom() => {
+                    crate::encode_string(env, std::str::from_utf8(&name).unwrap_or(""))
+                }
+                Some(_) => k,
+                None => continue,
+            },
+        };
 
         // If value is already a string, pass through without allocation
         if v.is_binary() {
-            keys.push(k);
-            values.push(v);
+            flattener.push(k, v);
             continue;
         }
 
-        keys.push(k);
-        values.push(term_to_string_term(env, v, nil));
+        flattener.push(k, term_to_string_term(env, v, nil));
     }
 
-    if keys.is_empty() {
+    let Flattener {
+        mut keys,
+        mut values,
+        truncated,
+        ..
+    } = flattener;
+    redact_flat_entries(env, &mut keys, &mut values, redactor);
+    let flattened = if keys.is_empty() {
         Term::map_new(env)
     } else {
         Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
-    }
+    };
+    (flattened, truncated)
 }
 
-fn flatten_map_recursive<'a>(
+/// Redacts flattened string values in place, removing entries a `drop` rule
+/// matched.
+fn redact_flat_entries<'a>(
     env: Env<'a>,
-    map: Term<'a>,
-    prefix: &mut String,
     keys: &mut Vec<Term<'a>>,
     values: &mut Vec<Term<'a>>,
-    nil: Term<'a>,
+    redactor: Option<&Redactor>,
 ) {
-    let iter = match MapIterator::new(map) {
-        Some(it) => it,
-        None => return,
+    let Some(redactor) = redactor else {
+        return;
     };
+    let mut kept = 0;
+    for index in 0..values.len() {
+        if let Some(value) = redact::redact_string(env, values[index], redactor) {
+            keys[kept] = keys[index];
+            values[kept] = value;
+            kept += 1;
+        }
+    }
+    keys.truncate(kept);
+    values.truncate(kept);
+}
 
-    for (key, value) in iter {
-        flatten_map_entry(env, key, value, prefix, keys, values, nil);
+/// Normalises, renames and filters flattened keys. When several keys end up
+/// the same, the first in key order is kept.
+fn apply_key_rules<'a>(env: Env<'a>, value: Term<'a>, rules: &KeyRules) -> Term<'a> {
+    let Some(iter) = MapIterator::new(value) else {
+        return value;
+    };
+    let capacity = value.map_size().unwrap_or(0);
+    let mut changed = false;
+    let mut seen: HashSet<String> = HashSet::with_capacity(capacity);
+    let mut keys = Vec::with_capacity(capacity);
+    let mut values = Vec::with_capacity(capacity);
+    for (key, child) in iter {
+        let Some(original) = key
+            .decode::<Binary>()
+            .ok()
+            .and_then(|binary| std::str::from_utf8(binary.as_slice()).ok())
+        else {
+            keys.push(key);
+            values.push(child);
+            continue;
+        };
+        let normalized = rules.normalize(original);
+        let renamed = rules
+            .rename
+            .get(normalized.as_ref())
+            .map_or(normalized.as_ref(), String::as_str);
+        if !rules.keeps(renamed) || !seen.insert(renamed.to_string()) {
+            changed = true;
+            continue;
+        }
+        if renamed == original {
+            keys.push(key);
+        } else {
+            changed = true;
+            keys.push(crate::encode_string(env, renamed));
+        }
+        values.push(child);
+    }
+    if !changed {
+        return value;
     }
+    build_flat_map(env, &keys, &values)
 }
 
-fn flatten_map_entry<'a>(
+/// Replaces the values of flattened keys matching `hash_keys` with their keyed
+/// hash. Returns the map as-is when no key matches.
+fn hash_flat_values<'a>(
     env: Env<'a>,
-    key: Term<'a>,
     value: Term<'a>,
-    prefix: &mut String,
-    keys: &mut Vec<Term<'a>>,
-    values: &mut Vec<Term<'a>>,
+    hash_keys: &HashKeys,
     nil: Term<'a>,
-) {
-    let binary = match key.decode::<Binary>() {
-        Ok(binary) => binary,
-        Err(_) => return,
-    };
-    let key = match std::str::from_utf8(binary.as_slice()) {
-        Ok(key) => key,
-        Err(_) => return,
+) -> Term<'a> {
+    let Some(iter) = MapIterator::new(value) else {
+        return value;
     };
+    let mut changed = false;
+    let mut keys = Vec::with_capacity(value.map_size().unwrap_or(0));
+    let mut values = Vec::with_capacity(keys.capacity());
+    for (key, child) in iter {
+        let matched = key
+            .decode::<Binary>()
+            .is_ok_and(|key| hash_keys.patterns.is_match(key.as_slice()));
+        keys.push(key);
+        if matched {
+            changed = true;
+            values.push(coerce::hash_term(env, child, &hash_keys.hash, false, nil));
+        } else {
+            values.push(child);
+        }
+    }
+    if !changed {
+        return value;
+    }
+    Term::map_from_term_arrays(env, &keys, &values).unwrap_or(value)
+}
 
-    let prefix_len = prefix.len();
-    if prefix_len != 0 {
-        prefix.push('.');
+/// Redacts the strings inside array field values; numeric arrays have none.
+fn redact_array<'a>(env: Env<'a>, value: Term<'a>, redactor: Option<&Redactor>) -> Term<'a> {
+    match redactor {
+        Some(redactor) => redact::redact_nested(env, value, redactor).unwrap_or(value),
+        None => value,
     }
-    prefix.push_str(key);
+}
 
-    if value == nil {
-        prefix.truncate(prefix_len);
-        return;
+/// Accumulates the `key => string` entries of a flattened map.
+struct Flattener<'a, 'o> {
+    env: Env<'a>,
+    nil: Term<'a>,
+    options: &'o FlattenOptions,
+    key_mode: KeyMode,
+    limits: FlatLimits,
+    prefix: String,
+    /// Number of key segments in `prefix`.
+    depth: usize,
+    keys: Vec<Term<'a>>,
+    values: Vec<Term<'a>>,
+    /// Whether `limits` dropped an entry.
+    truncated: bool,
+}
+
+impl<'a, 'o> Flattener<'a, 'o> {
+    fn new(
+        env: Env<'a>,
+        nil: Term<'a>,
+        options: &'o FlattenOptions,
+        key_mode: KeyMode,
+        limits: FlatLimits,
+        capacity: usize,
+    ) -> Self {
+        let capacity = limits.max_entries.map_or(capacity, |max| capacity.min(max));
+        Flattener {
+            env,
+            nil,
+            options,
+            key_mode,
+            limits,
+            prefix: String::new(),
+            depth: 0,
+            keys: Vec::with_capacity(capacity),
+            values: Vec::with_capacity(capacity),
+            truncated: false,
+        }
     }
 
-    if value.is_map() {
-        if value.map_size().unwrap_or(0) == 0 {
-            keys.push(crate::encode_string(env, prefix));
-            values.push(crate::encode_string(env, "{}"));
+    fn map(&mut self, map: Term<'a>) {
+        let iter = match MapIterator::new(map) {
+            Some(it) => it,
+            None => return,
+        };
+
+        for (key, value) in iter {
+            if self.is_full() {
+                return;
+            }
+            self.entry(key, value);
+        }
+    }
+
+    /// Whether the entry cap has already turned an entry away.
+    fn is_full(&self) -> bool {
+        self.truncated && self.limits.max_entries == Some(self.keys.len())
+    }
+
+    fn entry(&mut self, key: Term<'a>, value: Term<'a>) {
+        let Some(name) = self.key_mode.key_name(key) else {
+            return;
+        };
+        if let Ok(key) = std::str::from_utf8(&name) {
+            self.segment(key, value);
+        }
+    }
+
+    fn segment(&mut self, segment: &str, value: Term<'a>) {
+        let prefix_len = self.prefix.len();
+        if prefix_len != 0 {
+            self.prefix.push_str(&self.options.separator);
+        }
+        self.prefix.push_str(segment);
+        self.depth += 1;
+        if self.limits.max_depth.is_some_and(|max| self.depth > max) {
+            // Any non-nil value would emit at least one key this deep.
+            self.truncated |= value != self.nil;
         } else {
-            flatten_map_recursive(env, value, prefix, keys, values, nil);
+            self.value(value);
         }
-        prefix.truncate(prefix_len);
-        return;
+        self.depth -= 1;
+        self.prefix.truncate(prefix_len);
     }
 
-    keys.push(crate::encode_string(env, prefix));
-    if value.is_list() {
-        values.push(crate::encode_string(
-            env,
-            &term_to_json_string(value, nil, "[]"),
-        ));
-    } else {
-        values.push(term_to_string_term(env, value, nil));
+    fn value(&mut self, value: Term<'a>) {
+        if value == self.nil {
+            return;
+        }
+
+        // Containers at the depth limit are kept whole, as JSON.
+        let descend = self.options.max_depth.is_none_or(|max| self.depth < max);
+
+        if value.is_map() {
+            if value.map_size().unwrap_or(0) == 0 {
+                self.emit(crate::encode_string(self.env, "{}"));
+            } else if descend {
+                self.map(value);
+            } else {
+                let json = term_to_json_string(value, self.nil, "{}");
+                self.emit(crate::encode_string(self.env, &json));
+            }
+            return;
+        }
+
+        if let Ok(iter) = value.decode::<ListIterator>() {
+            match &self.options.lists {
+                ListStrategy::Index if descend && !value.is_empty_list() => {
+                    let mut index = itoa::Buffer::new();
+                    for (i, element) in iter.enumerate() {
+                        self.segment(index.format(i), element);
+                    }
+                }
+                ListStrategy::Join(delimiter) => {
+                    let joined = join_list(iter, delimiter, self.nil);
+                    self.emit(crate::encode_string(self.env, &joined));
+                }
+                _ => {
+                    let json = term_to_json_string(value, self.nil, "[]");
+                    self.emit(crate::encode_string(self.env, &json));
+                }
+            }
+            return;
+        }
+
+        self.emit(term_to_string_term(self.env, value, self.nil));
     }
-    prefix.truncate(prefix_len);
+
+    fn emit(&mut self, value: Term<'a>) {
+        let key = crate::encode_string(self.env, &self.prefix);
+        self.push(key, value);
+    }
+
+    fn push(&mut self, key: Term<'a>, value: Term<'a>) {
+        if self.limits.max_entries == Some(self.keys.len()) {
+            self.truncated = true;
+            return;
+        }
+        self.keys.push(key);
+        self.values.push(value);
+    }
+}
+
+/// Joins list elements as strings, skipping nils; nested maps and lists are
+/// JSON-encoded.
+fn join_list<'a>(iter: ListIterator<'a>, delimiter: &str, nil: Term<'a>) -> String {
+    let mut joined = String::new();
+    for (i, element) in iter.filter(|&element| element != nil).enumerate() {
+        if i != 0 {
+            joined.push_str(delimiter);
+        }
+        if let Ok(binary) = element.decode::<Binary>() {
+            joined.push_str(&String::from_utf8_lossy(binary.as_slice()));
+        } else if element.is_map() || element.is_list() {
+            joined.push_str(&term_to_json_string(element, nil, ""));
+        } else if let Ok(i) = element.decode::<i64>() {
+            joined.push_str(itoa::Buffer::new().format(i));
+        } else if let Ok(f) = element.decode::<f64>() {
+            joined.push_str(&f.to_string());
+        } else if let Ok(atom) = element.atom_to_string() {
+            joined.push_str(&atom);
+        }
+    }
+    joined
 }
 
 fn build_flat_map<'a>(env: Env<'a>, keys: &[Term<'a>], values: &[Term<'a>]) -> Term<'a> {
@@ -1232,13 +1912,15 @@ fn term_to_string_term<'a>(env: Env<'a>, value: Term<'a>, nil: Term<'a>) -> Term
     crate::encode_string(env, "")
 }
 
-fn term_to_json_string<'a>(value: Term<'a>, nil: Term<'a>, fallback: &str) -> String {
+pub(crate) fn term_to_json_string<'a>(value: Term<'a>, nil: Term<'a>, fallback: &str) -> String {
     serde_json::to_string(&JsonTerm { value, nil }).unwrap_or_else(|_| fallback.to_string())
 }
 
-struct JsonTerm<'a> {
-    value: Term<'a>,
-    nil: Term<'a>,
+/// Serializes a BEAM term as JSON; `nil` becomes `null` and map keys, binary
+/// or atom, are sorted.
+pub(crate) struct JsonTerm<'a> {
+    pub(crate) value: Term<'a>,
+    pub(crate) nil: Term<'a>,
 }
 
 impl Serialize for JsonTerm<'_> {
@@ -1285,20 +1967,26 @@ impl Serialize for JsonTerm<'_> {
             return sequence.end();
         }
         if let Some(iter) = MapIterator::new(self.value) {
+            // Keys are named as `KeyMode::Either` reads them, so atom-keyed
+            // maps keep their entries; a binary key wins over an atom of the
+            // same name.
             let mut entries = Vec::new();
             for (key, value) in iter {
-                let Ok(binary) = key.decode::<Binary>() else {
+                let Some(name) = KeyMode::Either.key_name(key) else {
                     continue;
                 };
-                if std::str::from_utf8(binary.as_slice()).is_ok() {
-                    entries.push((binary, value));
+                if std::str::from_utf8(&name).is_ok() {
+                    entries.push((name, !key.is_binary(), value));
                 }
             }
-            entries.sort_unstable_by(|(left, _), (right, _)| left.as_slice().cmp(right.as_slice()));
+            entries.sort_unstable_by(|(left, left_atom, _), (right, right_atom, _)| {
+                left.cmp(right).then(left_atom.cmp(right_atom))
+            });
+            entries.dedup_by(|(name, _, _), (kept, _, _)| name == kept);
 
             let mut map = serializer.serialize_map(Some(entries.len()))?;
-            for (binary, value) in entries {
-                let key = std::str::from_utf8(binary.as_slice()).expect("validated UTF-8 key");
+            for (name, _, value) in entries {
+                let key = std::str::from_utf8(&name).expect("validated UTF-8 key");
                 map.serialize_entry(
                     key,
                     &JsonTerm {
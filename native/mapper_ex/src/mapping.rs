@@ -1,30 +1,82 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use rustler::types::map::MapIterator;
 use rustler::{Encoder, Env, Term};
 
+use crate::aggregate::{self, Aggregate};
+use crate::json_input::{JsonProjection, JsonProjections};
+use crate::keyed_hash::{HashAlgorithm, KeyedHash};
+use crate::limits::FieldLimits;
 use crate::path::{self, CompiledPath, PathSegment};
-use crate::string_filters::{CharClass, StringFilters};
+use crate::query::{KeyMode, PreloadNode, Preloads};
+use crate::redact::{self, RedactAction, RedactRule, Redactor};
+use crate::stats::MappingStats;
+use crate::string_filters::{CharClass, StringFilters, StringMatcher};
 
 // ── Data structures ────────────────────────────────────────────────────────
 
-const ROOT_CACHE_MIN_REFERENCES: usize = 8;
+/// Key references below one map that make scanning it once per document
+/// cheaper than a lookup per key.
+const PRELOAD_MIN_REFERENCES: usize = 8;
 
 #[derive(Debug)]
 pub enum CompiledOutput {
     Map,
     ClickHouseRowBinary(crate::clickhouse_rowbinary::CompiledLayout),
+    ClickHouseNative(crate::clickhouse_native::CompiledNativeLayout),
+    ArrowIpc(crate::arrow_ipc::CompiledArrowLayout),
+    Json(crate::json_output::CompiledJsonLayout),
+    OtlpProtobuf(Box<crate::otlp_protobuf::CompiledOtlpLayout>),
 }
 
 #[derive(Debug)]
 pub struct CompiledMapping {
     pub fields: Vec<CompiledField>,
     pub path_cache_size: usize,
-    pub root_cache_size: usize,
-    pub root_cache_scan_limit: usize,
-    pub root_cache_keys: HashMap<Vec<u8>, usize>,
+    /// Number of distinct path keys; see `CompiledPath::key_slots`.
+    pub key_slot_count: usize,
+    pub preloads: Arc<Preloads>,
     pub output: CompiledOutput,
+    pub decisions: Decisions,
+    pub redactor: Option<Redactor>,
+    /// Estimated row size above which the largest values are truncated.
+    pub max_row_bytes: Option<usize>,
+    /// Which document keys paths, `exclude_keys`, `elevate_keys` and
+    /// flattening read.
+    pub key_mode: KeyMode,
+    /// What `map_json` decodes from raw JSON input.
+    pub json_projections: JsonProjections,
+    /// Per-field counters, when the config sets `collect_stats`.
+    pub stats: Option<MappingStats>,
+}
+
+/// Document-level `drop_when`, `sample` and `route` sections, evaluated
+/// against the input document before any field is mapped.
+#[derive(Debug, Default)]
+pub struct Decisions {
+    pub drop_when: Option<Condition>,
+    pub sample: Option<Sample>,
+    /// Checked in order; the first matching route names the document.
+    pub routes: Vec<Route>,
+}
+
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub condition: Condition,
+}
+
+/// Deterministic sampling keyed on the value at `key`.
+#[derive(Debug)]
+pub struct Sample {
+    pub key: CompiledPath,
+    pub rate: f64,
+    pub severity: Option<CompiledPath>,
+    /// Keep rates by ASCII-lowercased severity, overriding `rate`.
+    pub severity_rates: HashMap<Vec<u8>, f64>,
 }
 
 #[derive(Debug)]
@@ -44,6 +96,10 @@ pub struct CompiledField {
     pub filter_nil: bool,
     pub flat_map_value_type: FlatMapValueType,
     pub filters: Option<StringFilters>,
+    pub hash_keys: Option<HashKeys>,
+    pub limits: FieldLimits,
+    pub key_rules: KeyRules,
+    pub flatten: FlattenOptions,
 }
 
 #[derive(Debug)]
@@ -55,6 +111,23 @@ pub enum PathSource {
     FromOutput(usize),
     /// Temporary: unresolved field name, converted to FromOutput(usize) during compilation.
     FromOutputName(String),
+    /// Conditional sources; the first case whose conditions match supplies the value.
+    Cases(Vec<FieldCase>),
+    /// A count, sum or other summary of the values under a wildcard path.
+    Aggregate(Aggregate),
+}
+
+/// One `when` clause of a `cases` source.
+#[derive(Debug)]
+pub struct FieldCase {
+    pub condition: Condition,
+    pub source: CaseSource,
+}
+
+#[derive(Debug)]
+pub enum CaseSource {
+    Path(PathSource),
+    Literal(DefaultValue),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -85,6 +158,32 @@ pub enum FieldType {
     ArrayFlatMap,
 }
 
+impl FieldType {
+    /// The config name of the type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::UInt8 => "uint8",
+            FieldType::UInt32 => "uint32",
+            FieldType::UInt64 => "uint64",
+            FieldType::Int32 => "int32",
+            FieldType::Float64 => "float64",
+            FieldType::Bool => "bool",
+            FieldType::Enum8 { .. } => "enum8",
+            FieldType::DateTime64 { .. } => "datetime64",
+            FieldType::Json => "json",
+            FieldType::ArrayString => "array_string",
+            FieldType::ArrayUInt64 => "array_uint64",
+            FieldType::ArrayFloat64 => "array_float64",
+            FieldType::ArrayDateTime64 { .. } => "array_datetime64",
+            FieldType::ArrayJson => "array_json",
+            FieldType::ArrayMap => "array_map",
+            FieldType::FlatMap => "flat_map",
+            FieldType::ArrayFlatMap => "array_flat_map",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FlatMapValueType {
     String,
@@ -102,10 +201,96 @@ pub enum DefaultValue {
     EmptyMap,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum FieldTransform {
     Upcase,
     Downcase,
+    /// Keyed hash of the value: hex for string fields, the integer digest for
+    /// `UInt64` fields.
+    Hash {
+        hash: KeyedHash,
+        integer: bool,
+    },
+}
+
+/// How a FlatMap field flattens nested values into `key => string` entries.
+#[derive(Debug)]
+pub struct FlattenOptions {
+    pub separator: Cow<'static, str>,
+    pub lists: ListStrategy,
+    /// Key segments after which nested maps and lists are kept as JSON.
+    pub max_depth: Option<usize>,
+}
+
+impl FlattenOptions {
+    pub const DEFAULT: FlattenOptions = FlattenOptions {
+        separator: Cow::Borrowed("."),
+        lists: ListStrategy::Json,
+        max_depth: None,
+    };
+}
+
+#[derive(Debug)]
+pub enum ListStrategy {
+    /// `"[1,2]"`
+    Json,
+    /// One entry per element under its index, e.g. `tags.0`.
+    Index,
+    /// Elements joined with the delimiter.
+    Join(String),
+}
+
+/// Rewrites and filters flattened FlatMap keys: keys are normalised, then
+/// renamed, then kept if they match `include` (when set) and not `exclude`.
+#[derive(Debug, Default)]
+pub struct KeyRules {
+    pub include: Option<regex::bytes::RegexSet>,
+    pub exclude: Option<regex::bytes::RegexSet>,
+    /// Keyed by the normalised key.
+    pub rename: HashMap<String, String>,
+    pub lowercase: bool,
+    pub dash_to_underscore: bool,
+}
+
+impl KeyRules {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none()
+            && self.exclude.is_none()
+            && self.rename.is_empty()
+            && !self.lowercase
+            && !self.dash_to_underscore
+    }
+
+    pub fn normalize<'k>(&self, key: &'k str) -> std::borrow::Cow<'k, str> {
+        let lower = self.lowercase && key.chars().any(char::is_uppercase);
+        let dashes = self.dash_to_underscore && key.contains('-');
+        if !lower && !dashes {
+            return std::borrow::Cow::Borrowed(key);
+        }
+        let key = if lower {
+            key.to_lowercase()
+        } else {
+            key.to_string()
+        };
+        std::borrow::Cow::Owned(if dashes { key.replace('-', "_") } else { key })
+    }
+
+    pub fn keeps(&self, key: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(key.as_bytes()))
+            && !self
+                .exclude
+                .as_ref()
+                .is_some_and(|exclude| exclude.is_match(key.as_bytes()))
+    }
+}
+
+/// FlatMap keys whose values are replaced by their keyed hash.
+#[derive(Debug)]
+pub struct HashKeys {
+    pub patterns: regex::bytes::RegexSet,
+    pub hash: KeyedHash,
 }
 
 #[derive(Debug)]
@@ -117,11 +302,32 @@ pub struct PickEntry {
 /// Inference rule for Enum8 structural inference.
 #[derive(Debug)]
 pub struct InferRule {
-    pub any: Vec<InferCondition>,
-    pub all: Vec<InferCondition>,
+    pub condition: Condition,
     pub result: String,
 }
 
+/// Condition tree shared by inference rules and `cases` sources.
+#[derive(Debug)]
+pub enum Condition {
+    Predicate(InferCondition),
+    /// Matches when every child matches; an empty group always matches.
+    All(Vec<Condition>),
+    /// Matches when at least one child matches.
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    /// The condition that always matches.
+    pub fn always() -> Self {
+        Condition::All(Vec::new())
+    }
+}
+
+/// Deepest condition nesting accepted from config, bounding the recursion
+/// depth of decoding and evaluation.
+const MAX_CONDITION_DEPTH: usize = 32;
+
 #[derive(Debug)]
 pub struct InferCondition {
     pub path: CompiledPath,
@@ -145,6 +351,8 @@ pub enum Predicate {
     IsNumber,
     IsList,
     IsMap,
+    StringMatch(StringMatcher),
+    CharClass(CharClass),
 }
 
 #[derive(Debug, Clone)]
@@ -165,20 +373,157 @@ pub struct Enum8Data {
 // ── Config decoder ─────────────────────────────────────────────────────────
 
 pub fn decode_mapping<'a>(env: Env<'a>, config: Term<'a>) -> Result<CompiledMapping, String> {
-    let mut fields = decode_fields(env, config)?;
+    let hash_key = get_string_key(env, config, "hash_key")?;
+    let mut fields = decode_fields(env, config, hash_key.as_deref())?;
     let output = decode_output(env, config, &fields)?;
-    let (path_cache_size, root_cache_size, root_cache_keys) =
-        assign_path_cache_indices(&mut fields);
+    let mut decisions = decode_decisions(env, config)?;
+    let redactor = decode_redactor(env, config, hash_key.as_deref())?;
+    let max_row_bytes = decode_positive_int(env, config, "max_row_bytes")?;
+    let key_mode = match get_string_key(env, config, "key_mode")? {
+        None => KeyMode::Binary,
+        Some(s) => KeyMode::parse(&s).ok_or_else(|| format!("unknown key mode: {}", s))?,
+    };
+    let (path_cache_size, preloads) = assign_path_cache_indices(&mut fields, &mut decisions);
+    let key_slot_count = assign_key_slots(&mut fields, &mut decisions);
+    let json_projections = build_json_projections(&fields, &decisions);
+    let stats = get_term_key(env, config, "collect_stats")
+        .is_some_and(|t| t.decode::<bool>().unwrap_or(false))
+        .then(|| MappingStats::new(fields.len()));
     Ok(CompiledMapping {
         fields,
         path_cache_size,
-        root_cache_size,
-        root_cache_scan_limit: root_cache_keys.len().max(ROOT_CACHE_MIN_REFERENCES),
-        root_cache_keys,
+        key_slot_count,
+        preloads: Arc::new(preloads),
         output,
+        decisions,
+        redactor,
+        max_row_bytes,
+        key_mode,
+        json_projections,
+        stats,
     })
 }
 
+fn decode_redactor<'a>(
+    env: Env<'a>,
+    config: Term<'a>,
+    hash_key: Option<&str>,
+) -> Result<Option<Redactor>, String> {
+    let Some(term) = get_term_key(env, config, "redact") else {
+        return Ok(None);
+    };
+    let list: Vec<Term> = term
+        .decode()
+        .map_err(|_| "redact must be a list".to_string())?;
+    if list.is_empty() {
+        return Ok(None);
+    }
+
+    let mut rules = Vec::with_capacity(list.len());
+    for rule in list {
+        let action = match get_string_key(env, rule, "action")?.as_deref() {
+            None | Some("mask") => RedactAction::Mask(
+                get_string_key(env, rule, "replacement")?
+                    .unwrap_or_else(|| redact::DEFAULT_MASK.to_string()),
+            ),
+            Some("hash") => match hash_key {
+                Some(key) if !key.is_empty() => {
+                    RedactAction::Hash(KeyedHash::new(HashAlgorithm::SipHash, key.as_bytes()))
+                }
+                _ => {
+                    return Err("redaction action 'hash' requires a mapping 'hash_key'".to_string())
+                }
+            },
+            Some("drop") => RedactAction::Drop,
+            Some(other) => return Err(format!("unknown redaction action: {}", other)),
+        };
+        let detector = get_string_key(env, rule, "detector")?;
+        let pattern = get_string_key(env, rule, "pattern")?;
+        rules.push(match (detector, pattern) {
+            (Some(detector), None) => RedactRule::detector(&detector, action)?,
+            (None, Some(pattern)) => RedactRule::pattern(&pattern, action)?,
+            _ => return Err("redaction rule requires one of 'detector' or 'pattern'".to_string()),
+        });
+    }
+    Ok(Some(Redactor::new(rules)))
+}
+
+fn decode_decisions<'a>(env: Env<'a>, config: Term<'a>) -> Result<Decisions, String> {
+    let drop_when = get_term_key(env, config, "drop_when")
+        .map(|term| decode_condition(env, term, 0))
+        .transpose()?;
+
+    let mut routes = Vec::new();
+    if let Some(term) = get_term_key(env, config, "route") {
+        let list: Vec<Term> = term
+            .decode()
+            .map_err(|_| "route must be a list".to_string())?;
+        for route in list {
+            let name = get_string_key(env, route, "name")?
+                .ok_or_else(|| "route missing 'name'".to_string())?;
+            routes.push(Route {
+                name,
+                condition: decode_condition_group(env, route, 0)?,
+            });
+        }
+    }
+
+    let sample = get_term_key(env, config, "sample")
+        .map(|term| decode_sample(env, term))
+        .transpose()?;
+
+    Ok(Decisions {
+        drop_when,
+        sample,
+        routes,
+    })
+}
+
+fn decode_sample<'a>(env: Env<'a>, sample: Term<'a>) -> Result<Sample, String> {
+    let key =
+        get_string_key(env, sample, "path")?.ok_or_else(|| "sample missing 'path'".to_string())?;
+    let rate = get_term_key(env, sample, "rate")
+        .ok_or_else(|| "sample missing 'rate'".to_string())
+        .and_then(decode_sample_rate)?;
+    let severity = get_string_key(env, sample, "severity_path")?
+        .map(|path| path::compile(&path))
+        .transpose()?;
+
+    let mut severity_rates = HashMap::new();
+    if let Some(term) = get_term_key(env, sample, "severity_rates") {
+        let iter =
+            MapIterator::new(term).ok_or_else(|| "severity_rates must be a map".to_string())?;
+        for (severity, rate) in iter {
+            let severity: String = severity
+                .decode()
+                .map_err(|_| "severity_rates keys must be strings".to_string())?;
+            severity_rates.insert(
+                severity.to_ascii_lowercase().into_bytes(),
+                decode_sample_rate(rate)?,
+            );
+        }
+    }
+
+    Ok(Sample {
+        key: path::compile(&key)?,
+        rate,
+        severity,
+        severity_rates,
+    })
+}
+
+fn decode_sample_rate(term: Term) -> Result<f64, String> {
+    let rate = term
+        .decode::<f64>()
+        .or_else(|_| term.decode::<i64>().map(|i| i as f64))
+        .map_err(|_| "sample rates must be numbers".to_string())?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!("sample rate must be between 0 and 1, got {rate}"))
+    }
+}
+
 fn decode_output<'a>(
     env: Env<'a>,
     config: Term<'a>,
@@ -202,98 +547,297 @@ fn decode_output<'a>(
             let layout = crate::clickhouse_rowbinary::compile_layout(&row_type, &fields_by_name)?;
             Ok(CompiledOutput::ClickHouseRowBinary(layout))
         }
+        "clickhouse_native" => {
+            let row_type = get_string_key(env, output, "row_type")?
+                .ok_or_else(|| "ClickHouse Native output row_type is required".to_string())?;
+            let compression = match get_string_key(env, output, "compression")?.as_deref() {
+                None | Some("none") => crate::clickhouse_native::Compression::None,
+                Some("lz4") => crate::clickhouse_native::Compression::Lz4,
+                Some(other) => {
+                    return Err(format!(
+                        "unsupported ClickHouse Native compression '{other}' (supported: none, lz4)"
+                    ))
+                }
+            };
+            let layout = crate::clickhouse_native::compile_layout(&row_type, fields, compression)?;
+            Ok(CompiledOutput::ClickHouseNative(layout))
+        }
+        "arrow_ipc" => {
+            let compression = match get_string_key(env, output, "compression")?.as_deref() {
+                None | Some("none") => crate::arrow_ipc::Compression::None,
+                Some("lz4") => crate::arrow_ipc::Compression::Lz4,
+                Some("zstd") => crate::arrow_ipc::Compression::Zstd,
+                Some(other) => {
+                    return Err(format!(
+                        "unsupported Arrow IPC compression '{other}' (supported: none, lz4, zstd)"
+                    ))
+                }
+            };
+            let layout = crate::arrow_ipc::compile_layout(fields, compression)?;
+            Ok(CompiledOutput::ArrowIpc(layout))
+        }
+        "json" => {
+            let key_order = match get_string_key(env, output, "key_order")?.as_deref() {
+                None | Some("config") => crate::json_output::KeyOrder::Config,
+                Some("sorted") => crate::json_output::KeyOrder::Sorted,
+                Some(other) => {
+                    return Err(format!(
+                        "unsupported JSON key_order '{other}' (supported: config, sorted)"
+                    ))
+                }
+            };
+            let key_shape = match get_string_key(env, output, "key_shape")?.as_deref() {
+                None | Some("dotted") => crate::json_output::KeyShape::Dotted,
+                Some("nested") => crate::json_output::KeyShape::Nested,
+                Some(other) => {
+                    return Err(format!(
+                        "unsupported JSON key_shape '{other}' (supported: dotted, nested)"
+                    ))
+                }
+            };
+            let omit_defaults = get_term_key(env, output, "omit_defaults")
+                .map(|t| t.decode::<bool>().unwrap_or(false))
+                .unwrap_or(false);
+            let layout =
+                crate::json_output::compile_layout(fields, key_order, key_shape, omit_defaults)?;
+            Ok(CompiledOutput::Json(layout))
+        }
+        "otlp_protobuf" => {
+            let row_type = get_string_key(env, output, "row_type")?
+                .ok_or_else(|| "OTLP protobuf output row_type is required".to_string())?;
+            let layout = crate::otlp_protobuf::compile_layout(&row_type, fields)?;
+            Ok(CompiledOutput::OtlpProtobuf(Box::new(layout)))
+        }
         _ => Err(format!("unsupported mapping output format '{format}'")),
     }
 }
 
+/// Gives repeated path prefixes a slot in the per-document path cache, as
+/// well as every key below a map that enough paths read, which is scanned
+/// once per document instead; see `Preloads`. Returns the cache size.
 fn assign_path_cache_indices(
     fields: &mut [CompiledField],
-) -> (usize, usize, HashMap<Vec<u8>, usize>) {
+    decisions: &mut Decisions,
+) -> (usize, Preloads) {
     let mut counts = HashMap::new();
-    visit_paths(fields, |path| collect_cached_prefixes(path, &mut counts));
-    let root_reference_count: usize = counts
-        .iter()
-        .filter(|(prefix, _)| prefix.len() == 1 && matches!(prefix[0], PathSegment::Key(_)))
-        .map(|(_, count)| count)
-        .sum();
-    let preload_root = root_reference_count >= ROOT_CACHE_MIN_REFERENCES;
+    let mut count = |path: &CompiledPath| collect_cached_prefixes(path, &mut counts);
+    visit_decision_paths(decisions, &mut count);
+    visit_paths(fields, count);
+
+    let mut references: HashMap<&[PathSegment], usize> = HashMap::new();
+    for (prefix, count) in &counts {
+        if let Some((PathSegment::Key(_), parent)) = prefix.split_last() {
+            *references.entry(parent).or_default() += count;
+        }
+    }
+    let mut preloaded: Vec<Vec<PathSegment>> = references
+        .into_iter()
+        .filter(|(_, count)| *count >= PRELOAD_MIN_REFERENCES)
+        .map(|(parent, _)| parent.to_vec())
+        .collect();
+    // Shallower maps first, so the root is node 0 and its keys come first.
+    preloaded.sort_by_key(Vec::len);
 
     let mut indices = HashMap::new();
-    if preload_root {
-        for prefix in counts.keys().filter(|prefix| {
-            prefix.len() == 1 && matches!(prefix.first(), Some(PathSegment::Key(_)))
-        }) {
-            let index = indices.len();
-            indices.insert(prefix.clone(), index);
+    let mut nodes = Vec::with_capacity(preloaded.len());
+    for parent in preloaded {
+        let mut keys = HashMap::new();
+        for prefix in counts.keys() {
+            if let Some((PathSegment::Key(key), rest)) = prefix.split_last() {
+                if rest == parent.as_slice() {
+                    let index = indices.len();
+                    indices.insert(prefix.clone(), index);
+                    keys.insert(key.as_bytes().to_vec(), index);
+                }
+            }
         }
+        nodes.push(PreloadNode {
+            prefix: parent,
+            scan_limit: keys.len().max(PRELOAD_MIN_REFERENCES),
+            keys,
+        });
     }
-    let root_cache_size = indices.len();
 
-    visit_paths_mut(fields, |path| {
-        assign_cached_prefixes(path, &counts, &mut indices, preload_root)
-    });
+    let mut assign = |path: &mut CompiledPath| assign_cached_prefixes(path, &counts, &mut indices);
+    visit_decision_paths_mut(decisions, &mut assign);
+    visit_paths_mut(fields, assign);
 
-    let root_cache_keys = if preload_root {
-        indices
-            .iter()
-            .filter_map(|(prefix, index)| match prefix.as_slice() {
-                [PathSegment::Key(key)] => Some((key.as_bytes().to_vec(), *index)),
-                _ => None,
-            })
-            .collect()
-    } else {
-        HashMap::new()
+    let mut node_of = vec![None; indices.len()];
+    for (node, preload) in nodes.iter().enumerate() {
+        for &index in preload.keys.values() {
+            node_of[index] = Some(node);
+        }
+    }
+    (indices.len(), Preloads { nodes, node_of })
+}
+
+/// Gives every distinct key (and flat key) used by a path a slot in the
+/// mapping's key table. Returns the table size.
+fn assign_key_slots(fields: &mut [CompiledField], decisions: &mut Decisions) -> usize {
+    let mut slots: HashMap<String, usize> = HashMap::new();
+    let mut slot_of = |key: &str| {
+        let next = slots.len();
+        *slots.entry(key.to_string()).or_insert(next)
+    };
+    let mut assign = |path: &mut CompiledPath| {
+        for (segment, slot) in path.segments.iter().zip(path.key_slots.iter_mut()) {
+            if let PathSegment::Key(key) = segment {
+                *slot = Some(slot_of(key));
+            }
+        }
+        path.flat_key_slot = path.flat_key.as_deref().map(&mut slot_of);
+    };
+    visit_decision_paths_mut(decisions, &mut assign);
+    visit_paths_mut(fields, assign);
+    slots.len()
+}
+
+/// Collects every path the mapping reads into the projections `map_json`
+/// decodes. A field reading the whole document keeps all of it.
+fn build_json_projections(fields: &[CompiledField], decisions: &Decisions) -> JsonProjections {
+    let reads_root = fields.iter().any(|field| reads_root(&field.path_source));
+    if reads_root {
+        return JsonProjections {
+            nested: JsonProjection::whole(),
+            flat: JsonProjection::whole(),
+        };
+    }
+
+    let mut projections = JsonProjections::default();
+    let mut insert = |path: &CompiledPath| {
+        projections.nested.insert(&path.segments);
+        projections.flat.insert_flat(path);
     };
+    visit_decision_paths(decisions, &mut insert);
+    visit_paths(fields, insert);
+    projections
+}
+
+fn reads_root(source: &PathSource) -> bool {
+    match source {
+        PathSource::Root => true,
+        PathSource::Cases(cases) => cases.iter().any(|case| match &case.source {
+            CaseSource::Path(source) => reads_root(source),
+            CaseSource::Literal(_) => false,
+        }),
+        _ => false,
+    }
+}
 
-    (indices.len(), root_cache_size, root_cache_keys)
+fn visit_decision_paths(decisions: &Decisions, visitor: &mut impl FnMut(&CompiledPath)) {
+    if let Some(condition) = &decisions.drop_when {
+        visit_condition_paths(condition, visitor);
+    }
+    if let Some(sample) = &decisions.sample {
+        visitor(&sample.key);
+        sample.severity.iter().for_each(&mut *visitor);
+    }
+    for route in &decisions.routes {
+        visit_condition_paths(&route.condition, visitor);
+    }
+}
+
+fn visit_decision_paths_mut(
+    decisions: &mut Decisions,
+    visitor: &mut impl FnMut(&mut CompiledPath),
+) {
+    if let Some(condition) = &mut decisions.drop_when {
+        visit_condition_paths_mut(condition, visitor);
+    }
+    if let Some(sample) = &mut decisions.sample {
+        visitor(&mut sample.key);
+        sample.severity.iter_mut().for_each(&mut *visitor);
+    }
+    for route in &mut decisions.routes {
+        visit_condition_paths_mut(&mut route.condition, visitor);
+    }
 }
 
 fn visit_paths(fields: &[CompiledField], mut visitor: impl FnMut(&CompiledPath)) {
     for field in fields {
-        match &field.path_source {
-            PathSource::Single(path) => visitor(path),
-            PathSource::Coalesce(paths) => paths.iter().for_each(&mut visitor),
-            PathSource::Root | PathSource::FromOutput(_) | PathSource::FromOutputName(_) => {}
-        }
+        visit_source_paths(&field.path_source, &mut visitor);
         for entry in &field.pick {
             entry.paths.iter().for_each(&mut visitor);
         }
         if let Some(enum8) = &field.enum8_data {
             for rule in &enum8.infer_rules {
-                rule.any
-                    .iter()
-                    .for_each(|condition| visitor(&condition.path));
-                rule.all
-                    .iter()
-                    .for_each(|condition| visitor(&condition.path));
+                visit_condition_paths(&rule.condition, &mut visitor);
             }
         }
     }
 }
 
+fn visit_source_paths(source: &PathSource, visitor: &mut impl FnMut(&CompiledPath)) {
+    match source {
+        PathSource::Single(path) => visitor(path),
+        PathSource::Aggregate(aggregate) => visitor(&aggregate.path),
+        PathSource::Coalesce(paths) => paths.iter().for_each(visitor),
+        PathSource::Cases(cases) => {
+            for case in cases {
+                visit_condition_paths(&case.condition, visitor);
+                if let CaseSource::Path(source) = &case.source {
+                    visit_source_paths(source, visitor);
+                }
+            }
+        }
+        PathSource::Root | PathSource::FromOutput(_) | PathSource::FromOutputName(_) => {}
+    }
+}
+
+fn visit_condition_paths(condition: &Condition, visitor: &mut impl FnMut(&CompiledPath)) {
+    match condition {
+        Condition::Predicate(condition) => visitor(&condition.path),
+        Condition::All(children) | Condition::Any(children) => children
+            .iter()
+            .for_each(|child| visit_condition_paths(child, visitor)),
+        Condition::Not(child) => visit_condition_paths(child, visitor),
+    }
+}
+
 fn visit_paths_mut(fields: &mut [CompiledField], mut visitor: impl FnMut(&mut CompiledPath)) {
     for field in fields {
-        match &mut field.path_source {
-            PathSource::Single(path) => visitor(path),
-            PathSource::Coalesce(paths) => paths.iter_mut().for_each(&mut visitor),
-            PathSource::Root | PathSource::FromOutput(_) | PathSource::FromOutputName(_) => {}
-        }
+        visit_source_paths_mut(&mut field.path_source, &mut visitor);
         for entry in &mut field.pick {
             entry.paths.iter_mut().for_each(&mut visitor);
         }
         if let Some(enum8) = &mut field.enum8_data {
             for rule in &mut enum8.infer_rules {
-                rule.any
-                    .iter_mut()
-                    .for_each(|condition| visitor(&mut condition.path));
-                rule.all
-                    .iter_mut()
-                    .for_each(|condition| visitor(&mut condition.path));
+                visit_condition_paths_mut(&mut rule.condition, &mut visitor);
             }
         }
     }
 }
 
+fn visit_source_paths_mut(source: &mut PathSource, visitor: &mut impl FnMut(&mut CompiledPath)) {
+    match source {
+        PathSource::Single(path) => visitor(path),
+        PathSource::Aggregate(aggregate) => visitor(&mut aggregate.path),
+        PathSource::Coalesce(paths) => paths.iter_mut().for_each(visitor),
+        PathSource::Cases(cases) => {
+            for case in cases {
+                visit_condition_paths_mut(&mut case.condition, visitor);
+                if let CaseSource::Path(source) = &mut case.source {
+                    visit_source_paths_mut(source, visitor);
+                }
+            }
+        }
+        PathSource::Root | PathSource::FromOutput(_) | PathSource::FromOutputName(_) => {}
+    }
+}
+
+fn visit_condition_paths_mut(
+    condition: &mut Condition,
+    visitor: &mut impl FnMut(&mut CompiledPath),
+) {
+    match condition {
+        Condition::Predicate(condition) => visitor(&mut condition.path),
+        Condition::All(children) | Condition::Any(children) => children
+            .iter_mut()
+            .for_each(|child| visit_condition_paths_mut(child, visitor)),
+        Condition::Not(child) => visit_condition_paths_mut(child, visitor),
+    }
+}
+
 fn collect_cached_prefixes(path: &CompiledPath, counts: &mut HashMap<Vec<PathSegment>, usize>) {
     let mut prefix = Vec::new();
     for segment in &path.segments {
@@ -305,11 +849,12 @@ fn collect_cached_prefixes(path: &CompiledPath, counts: &mut HashMap<Vec<PathSeg
     }
 }
 
+/// Points a path's segments at their cache slots. Preloaded keys already
+/// have one; other prefixes get one when several paths share them.
 fn assign_cached_prefixes(
     path: &mut CompiledPath,
     counts: &HashMap<Vec<PathSegment>, usize>,
     indices: &mut HashMap<Vec<PathSegment>, usize>,
-    preload_root: bool,
 ) {
     let mut prefix = Vec::new();
     for (offset, segment) in path.segments.iter().enumerate() {
@@ -318,10 +863,7 @@ fn assign_cached_prefixes(
         }
         prefix.push(segment.clone());
         let repeated = counts.get(&prefix).copied().unwrap_or(0) > 1;
-        let preloaded = preload_root
-            && prefix.len() == 1
-            && matches!(prefix.first(), Some(PathSegment::Key(_)));
-        if repeated || preloaded {
+        if repeated || indices.contains_key(&prefix) {
             let next_index = indices.len();
             let index = *indices.entry(prefix.clone()).or_insert(next_index);
             path.cache_indices[offset] = Some(index);
@@ -333,7 +875,11 @@ fn assign_cached_prefixes(
     }
 }
 
-fn decode_fields<'a>(env: Env<'a>, config: Term<'a>) -> Result<Vec<CompiledField>, String> {
+fn decode_fields<'a>(
+    env: Env<'a>,
+    config: Term<'a>,
+    hash_key: Option<&str>,
+) -> Result<Vec<CompiledField>, String> {
     let fields_term = get_term_key(env, config, "fields")
         .ok_or_else(|| "missing 'fields' key in config".to_string())?;
 
@@ -345,19 +891,13 @@ fn decode_fields<'a>(env: Env<'a>, config: Term<'a>) -> Result<Vec<CompiledField
     let mut name_to_index: HashMap<String, usize> = HashMap::with_capacity(field_list.len());
 
     for field_term in field_list {
-        let mut field = decode_field(env, field_term)?;
+        let mut field = decode_field(env, field_term, hash_key)?;
 
         if name_to_index.contains_key(&field.name) {
             return Err(format!("duplicate field name: '{}'", field.name));
         }
 
-        // Resolve from_output field name to index
-        if let PathSource::FromOutputName(ref name) = field.path_source {
-            let idx = name_to_index.get(name).ok_or_else(|| {
-                format!("from_output '{}' references unknown or later field", name)
-            })?;
-            field.path_source = PathSource::FromOutput(*idx);
-        }
+        resolve_output_names(&mut field.path_source, &name_to_index)?;
 
         let idx = compiled_fields.len();
         name_to_index.insert(field.name.clone(), idx);
@@ -366,7 +906,39 @@ fn decode_fields<'a>(env: Env<'a>, config: Term<'a>) -> Result<Vec<CompiledField
     Ok(compiled_fields)
 }
 
-fn decode_field<'a>(env: Env<'a>, field: Term<'a>) -> Result<CompiledField, String> {
+/// Resolves `from_output` field names to indices, including inside cases.
+fn resolve_output_names(
+    source: &mut PathSource,
+    name_to_index: &HashMap<String, usize>,
+) -> Result<(), String> {
+    match source {
+        PathSource::FromOutputName(name) => {
+            let idx = name_to_index.get(name.as_str()).ok_or_else(|| {
+                format!("from_output '{}' references unknown or later field", name)
+            })?;
+            *source = PathSource::FromOutput(*idx);
+        }
+        PathSource::Cases(cases) => {
+            for case in cases {
+                if let CaseSource::Path(source) = &mut case.source {
+                    resolve_output_names(source, name_to_index)?;
+                }
+            }
+        }
+        PathSource::Root
+        | PathSource::Single(_)
+        | PathSource::Coalesce(_)
+        | PathSource::FromOutput(_)
+        | PathSource::Aggregate(_) => {}
+    }
+    Ok(())
+}
+
+fn decode_field<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+    hash_key: Option<&str>,
+) -> Result<CompiledField, String> {
     let name =
         get_string_key(env, field, "name")?.ok_or_else(|| "field missing 'name'".to_string())?;
 
@@ -376,7 +948,13 @@ fn decode_field<'a>(env: Env<'a>, field: Term<'a>) -> Result<CompiledField, Stri
     let field_type = parse_field_type(env, field, &type_lower)?;
     let default = decode_default(env, field, &field_type)?;
     let path_source = decode_path_source(env, field)?;
-    let transform = decode_transform(env, field)?;
+    if aggregates(&path_source) && !aggregates_into(&field_type) {
+        return Err(format!(
+            "aggregate on field '{}' requires a numeric or bool field",
+            name
+        ));
+    }
+    let transform = decode_transform(env, field, &name, &field_type, hash_key)?;
     let allowed_values = decode_allowed_values(env, field);
     // Resolve which value_map variant this field uses once, here at compile
     // time: string fields get a string->string remap, all non-string fields get
@@ -387,7 +965,7 @@ fn decode_field<'a>(env: Env<'a>, field: Term<'a>) -> Result<CompiledField, Stri
     } else {
         (decode_value_map(env, field)?, HashMap::new())
     };
-    let exclude_keys = decode_string_list_bytes(env, field, "exclude_keys");
+    let mut exclude_keys = decode_string_list_bytes(env, field, "exclude_keys");
     let elevate_keys = decode_string_list_bytes(env, field, "elevate_keys");
     let pick = decode_pick(env, field)?;
 
@@ -399,7 +977,11 @@ fn decode_field<'a>(env: Env<'a>, field: Term<'a>) -> Result<CompiledField, Stri
 
     let filter_nil = decode_filter_nil(env, field);
     let flat_map_value_type = decode_flat_map_value_type(env, field)?;
-    let filters = decode_filters(env, field);
+    let filters = decode_filters(env, field)?;
+    let hash_keys = decode_hash_keys(env, field, &name, &field_type, hash_key)?;
+    let limits = decode_limits(env, field)?;
+    let key_rules = decode_key_rules(env, field, &name, &field_type, &mut exclude_keys)?;
+    let flatten = decode_flatten(env, field, &name, &field_type)?;
 
     Ok(CompiledField {
         name,
@@ -417,6 +999,10 @@ fn decode_field<'a>(env: Env<'a>, field: Term<'a>) -> Result<CompiledField, Stri
         filter_nil,
         flat_map_value_type,
         filters,
+        hash_keys,
+        limits,
+        key_rules,
+        flatten,
     })
 }
 
@@ -455,53 +1041,55 @@ fn decode_default<'a>(
     field: Term<'a>,
     field_type: &FieldType,
 ) -> Result<DefaultValue, String> {
-    let val = match get_term_key(env, field, "default") {
-        Some(t) => t,
-        None => {
-            return Ok(match field_type {
-                FieldType::String => DefaultValue::Str(String::new()),
-                FieldType::Json | FieldType::FlatMap => DefaultValue::EmptyMap,
-                FieldType::ArrayString
-                | FieldType::ArrayUInt64
-                | FieldType::ArrayFloat64
-                | FieldType::ArrayDateTime64 { .. }
-                | FieldType::ArrayJson
-                | FieldType::ArrayMap
-                | FieldType::ArrayFlatMap => DefaultValue::EmptyList,
-                _ => DefaultValue::Nil,
-            });
-        }
-    };
+    if let Some(val) = get_term_key(env, field, "default") {
+        return Ok(decode_default_value(val));
+    }
 
+    Ok(match field_type {
+        FieldType::String => DefaultValue::Str(String::new()),
+        FieldType::Json | FieldType::FlatMap => DefaultValue::EmptyMap,
+        FieldType::ArrayString
+        | FieldType::ArrayUInt64
+        | FieldType::ArrayFloat64
+        | FieldType::ArrayDateTime64 { .. }
+        | FieldType::ArrayJson
+        | FieldType::ArrayMap
+        | FieldType::ArrayFlatMap => DefaultValue::EmptyList,
+        _ => DefaultValue::Nil,
+    })
+}
+
+/// Decodes a configured default or case literal.
+fn decode_default_value(val: Term) -> DefaultValue {
     // Check for nil atom
     if val.is_atom() {
         if let Ok(a) = rustler::types::atom::Atom::from_term(val) {
             if a == rustler::types::atom::nil() {
-                return Ok(DefaultValue::Nil);
+                return DefaultValue::Nil;
             }
         }
         if let Ok(b) = val.decode::<bool>() {
-            return Ok(DefaultValue::Bool(b));
+            return DefaultValue::Bool(b);
         }
     }
 
     if let Ok(i) = val.decode::<i64>() {
         if i >= 0 {
-            return Ok(DefaultValue::Uint(i as u64));
+            return DefaultValue::Uint(i as u64);
         }
-        return Ok(DefaultValue::Int(i));
+        return DefaultValue::Int(i);
     }
 
     if let Ok(f) = val.decode::<f64>() {
-        return Ok(DefaultValue::Flt(f));
+        return DefaultValue::Flt(f);
     }
 
     if let Ok(s) = val.decode::<String>() {
         // Handle special string defaults
         match s.as_str() {
-            "{}" => return Ok(DefaultValue::EmptyMap),
-            "[]" => return Ok(DefaultValue::EmptyList),
-            _ => return Ok(DefaultValue::Str(s)),
+            "{}" => return DefaultValue::EmptyMap,
+            "[]" => return DefaultValue::EmptyList,
+            _ => return DefaultValue::Str(s),
         }
     }
 
@@ -509,28 +1097,76 @@ fn decode_default<'a>(
         // Check if it's an empty map
         if let Ok(iter) = val.decode::<Vec<(Term, Term)>>() {
             if iter.is_empty() {
-                return Ok(DefaultValue::EmptyMap);
+                return DefaultValue::EmptyMap;
             }
         }
-        return Ok(DefaultValue::EmptyMap);
+        return DefaultValue::EmptyMap;
     }
 
     if val.is_list() {
         if let Ok(list) = val.decode::<Vec<Term>>() {
             if list.is_empty() {
-                return Ok(DefaultValue::EmptyList);
+                return DefaultValue::EmptyList;
             }
         }
-        return Ok(DefaultValue::EmptyList);
+        return DefaultValue::EmptyList;
     }
 
-    Ok(DefaultValue::Nil)
+    DefaultValue::Nil
 }
 
 fn decode_path_source<'a>(env: Env<'a>, field: Term<'a>) -> Result<PathSource, String> {
+    let source = decode_plain_path_source(env, field)?;
+    let Some(cases_term) = get_term_key(env, field, "cases") else {
+        // Default to root
+        return Ok(source.unwrap_or(PathSource::Root));
+    };
+
+    let case_list: Vec<Term> = cases_term
+        .decode()
+        .map_err(|_| "cases must be a list".to_string())?;
+    let mut cases = Vec::with_capacity(case_list.len() + 1);
+    for case in case_list {
+        cases.push(decode_case(env, case)?);
+    }
+    // The field's own path source, if any, applies when no case matches.
+    if let Some(source) = source {
+        cases.push(FieldCase {
+            condition: Condition::always(),
+            source: CaseSource::Path(source),
+        });
+    }
+    Ok(PathSource::Cases(cases))
+}
+
+fn decode_case<'a>(env: Env<'a>, case: Term<'a>) -> Result<FieldCase, String> {
+    let condition = decode_condition_group(env, case, 0)?;
+    let source = match get_term_key(env, case, "value") {
+        Some(value) => CaseSource::Literal(decode_default_value(value)),
+        None => CaseSource::Path(decode_plain_path_source(env, case)?.ok_or_else(|| {
+            "case requires 'value', 'path', 'paths' or 'from_output'".to_string()
+        })?),
+    };
+    Ok(FieldCase { condition, source })
+}
+
+/// Decodes `from_output`, `paths` or `path`, returning `None` when none is set.
+/// With `aggregate`, `path` is the wildcard path it summarises.
+fn decode_plain_path_source<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+) -> Result<Option<PathSource>, String> {
+    if let Some(function) = get_string_key(env, field, "aggregate")? {
+        let path = get_string_key(env, field, "path")?
+            .ok_or_else(|| format!("aggregate '{}' requires a 'path'", function))?;
+        return Ok(Some(PathSource::Aggregate(aggregate::compile(
+            &function, &path,
+        )?)));
+    }
+
     // Check from_output first (resolved to index in decode_fields)
     if let Some(from) = get_string_key(env, field, "from_output")? {
-        return Ok(PathSource::FromOutputName(from));
+        return Ok(Some(PathSource::FromOutputName(from)));
     }
 
     // Check "paths" (coalesce)
@@ -543,7 +1179,7 @@ fn decode_path_source<'a>(env: Env<'a>, field: Term<'a>) -> Result<PathSource, S
                         .map_err(|e| format!("failed to compile path '{}': {}", p, e))?;
                     compiled_paths.push(path);
                 }
-                return Ok(PathSource::Coalesce(compiled_paths));
+                return Ok(Some(PathSource::Coalesce(compiled_paths)));
             }
         }
     }
@@ -551,28 +1187,232 @@ fn decode_path_source<'a>(env: Env<'a>, field: Term<'a>) -> Result<PathSource, S
     // Check "path" (single)
     if let Some(path_str) = get_string_key(env, field, "path")? {
         if path_str == "$" {
-            return Ok(PathSource::Root);
+            return Ok(Some(PathSource::Root));
         }
         let path = path::compile(&path_str)
             .map_err(|e| format!("failed to compile path '{}': {}", path_str, e))?;
-        return Ok(PathSource::Single(path));
+        return Ok(Some(PathSource::Single(path)));
+    }
+
+    Ok(None)
+}
+
+fn aggregates(source: &PathSource) -> bool {
+    match source {
+        PathSource::Aggregate(_) => true,
+        PathSource::Cases(cases) => cases.iter().any(|case| match &case.source {
+            CaseSource::Path(source) => aggregates(source),
+            CaseSource::Literal(_) => false,
+        }),
+        _ => false,
     }
+}
 
-    // Default to root
-    Ok(PathSource::Root)
+/// Types an aggregate's number or boolean coerces into.
+fn aggregates_into(field_type: &FieldType) -> bool {
+    matches!(
+        field_type,
+        FieldType::UInt8
+            | FieldType::UInt32
+            | FieldType::UInt64
+            | FieldType::Int32
+            | FieldType::Float64
+            | FieldType::Bool
+    )
 }
 
-fn decode_transform<'a>(env: Env<'a>, field: Term<'a>) -> Result<Option<FieldTransform>, String> {
+fn decode_transform<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+    name: &str,
+    field_type: &FieldType,
+    hash_key: Option<&str>,
+) -> Result<Option<FieldTransform>, String> {
     match get_string_key(env, field, "transform")? {
         None => Ok(None),
         Some(s) => match s.to_lowercase().as_str() {
             "upcase" => Ok(Some(FieldTransform::Upcase)),
             "downcase" => Ok(Some(FieldTransform::Downcase)),
+            "hash" => {
+                let integer = match field_type {
+                    FieldType::String => false,
+                    FieldType::UInt64 => true,
+                    _ => {
+                        return Err(format!(
+                            "hash transform on field '{}' requires a string or uint64 field",
+                            name
+                        ))
+                    }
+                };
+                let hash = decode_keyed_hash(env, field, name, hash_key)?;
+                Ok(Some(FieldTransform::Hash { hash, integer }))
+            }
             other => Err(format!("unknown transform: {}", other)),
         },
     }
 }
 
+/// Decodes `hash_keys`: glob patterns over flattened FlatMap keys, where `*`
+/// matches any run of characters.
+fn decode_hash_keys<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+    name: &str,
+    field_type: &FieldType,
+    hash_key: Option<&str>,
+) -> Result<Option<HashKeys>, String> {
+    let globs = decode_string_list_bytes(env, field, "hash_keys");
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    if !matches!(field_type, FieldType::FlatMap) {
+        return Err(format!(
+            "hash_keys on field '{}' requires a flat_map field",
+            name
+        ));
+    }
+    let patterns = regex::bytes::RegexSet::new(globs.iter().map(|glob| glob_pattern(glob)))
+        .map_err(|e| format!("invalid hash_keys on field '{}': {}", name, e))?;
+    let hash = decode_keyed_hash(env, field, name, hash_key)?;
+    Ok(Some(HashKeys { patterns, hash }))
+}
+
+fn decode_flatten<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+    name: &str,
+    field_type: &FieldType,
+) -> Result<FlattenOptions, String> {
+    let Some(flatten) = get_term_key(env, field, "flatten") else {
+        return Ok(FlattenOptions::DEFAULT);
+    };
+    if !matches!(field_type, FieldType::FlatMap) {
+        return Err(format!(
+            "flatten on field '{}' requires a flat_map field",
+            name
+        ));
+    }
+
+    let separator = match get_string_key(env, flatten, "separator")? {
+        None => Cow::Borrowed("."),
+        Some(separator) if separator.is_empty() => {
+            return Err("flatten separator must not be empty".to_string())
+        }
+        Some(separator) => Cow::Owned(separator),
+    };
+    let lists = match get_string_key(env, flatten, "lists")?.as_deref() {
+        None | Some("json") => ListStrategy::Json,
+        Some("index") => ListStrategy::Index,
+        Some("join") => ListStrategy::Join(
+            get_string_key(env, flatten, "list_delimiter")?.unwrap_or_else(|| ",".to_string()),
+        ),
+        Some(other) => return Err(format!("unknown flatten list strategy: {}", other)),
+    };
+    let max_depth = decode_positive_int(env, flatten, "max_depth")?;
+
+    Ok(FlattenOptions {
+        separator,
+        lists,
+        max_depth,
+    })
+}
+
+/// Decodes `include_keys`, `rename_keys` and `normalize_keys`, and moves glob
+/// entries of a FlatMap's `exclude_keys` out of the exact-match list.
+fn decode_key_rules<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+    name: &str,
+    field_type: &FieldType,
+    exclude_keys: &mut Vec<Vec<u8>>,
+) -> Result<KeyRules, String> {
+    let include = decode_string_list_bytes(env, field, "include_keys");
+    let rename: HashMap<String, String> = match get_term_key(env, field, "rename_keys") {
+        Some(term) => term.decode().map_err(|_| {
+            format!(
+                "rename_keys on field '{}' must map strings to strings",
+                name
+            )
+        })?,
+        None => HashMap::new(),
+    };
+    let normalize = decode_string_list_bytes(env, field, "normalize_keys");
+    let exclude: Vec<Vec<u8>> = if matches!(field_type, FieldType::FlatMap) {
+        let (globs, exact) = exclude_keys.drain(..).partition(|key| key.contains(&b'*'));
+        *exclude_keys = exact;
+        globs
+    } else {
+        Vec::new()
+    };
+
+    if include.is_empty() && rename.is_empty() && normalize.is_empty() && exclude.is_empty() {
+        return Ok(KeyRules::default());
+    }
+    if !matches!(field_type, FieldType::FlatMap) {
+        return Err(format!(
+            "include_keys, rename_keys and normalize_keys on field '{}' require a flat_map field",
+            name
+        ));
+    }
+
+    let mut rules = KeyRules::default();
+    for option in normalize {
+        match option.as_slice() {
+            b"lowercase" => rules.lowercase = true,
+            b"dash_to_underscore" => rules.dash_to_underscore = true,
+            other => {
+                return Err(format!(
+                    "unknown normalize_keys option: {}",
+                    String::from_utf8_lossy(other)
+                ))
+            }
+        }
+    }
+    let glob_set = |globs: &[Vec<u8>]| {
+        regex::bytes::RegexSet::new(globs.iter().map(|glob| glob_pattern(glob)))
+            .map_err(|e| format!("invalid key pattern on field '{}': {}", name, e))
+    };
+    if !include.is_empty() {
+        rules.include = Some(glob_set(&include)?);
+    }
+    if !exclude.is_empty() {
+        rules.exclude = Some(glob_set(&exclude)?);
+    }
+    rules.rename = rename
+        .into_iter()
+        .map(|(from, to)| (rules.normalize(&from).into_owned(), to))
+        .collect();
+    Ok(rules)
+}
+
+fn glob_pattern(glob: &[u8]) -> String {
+    let parts: Vec<String> = glob
+        .split(|&byte| byte == b'*')
+        .map(|part| regex::escape(&String::from_utf8_lossy(part)))
+        .collect();
+    format!("^{}$", parts.join(".*"))
+}
+
+fn decode_keyed_hash<'a>(
+    env: Env<'a>,
+    field: Term<'a>,
+    name: &str,
+    hash_key: Option<&str>,
+) -> Result<KeyedHash, String> {
+    let algorithm = match get_string_key(env, field, "hash_algorithm")? {
+        None => HashAlgorithm::SipHash,
+        Some(s) => HashAlgorithm::parse(&s.to_lowercase())
+            .ok_or_else(|| format!("unknown hash algorithm: {}", s))?,
+    };
+    match hash_key {
+        Some(key) if !key.is_empty() => Ok(KeyedHash::new(algorithm, key.as_bytes())),
+        _ => Err(format!(
+            "hashing on field '{}' requires a mapping 'hash_key'",
+            name
+        )),
+    }
+}
+
 fn decode_allowed_values<'a>(env: Env<'a>, map: Term<'a>) -> HashSet<Vec<u8>> {
     match get_term_key(env, map, "allowed_values") {
         Some(t) => t
@@ -692,8 +1532,36 @@ fn decode_flat_map_value_type<'a>(
     }
 }
 
-fn decode_filters<'a>(env: Env<'a>, field: Term<'a>) -> Option<StringFilters> {
-    let filters_term = get_term_key(env, field, "filters")?;
+fn decode_limits<'a>(env: Env<'a>, field: Term<'a>) -> Result<FieldLimits, String> {
+    let Some(limits) = get_term_key(env, field, "limits") else {
+        return Ok(FieldLimits::default());
+    };
+    Ok(FieldLimits {
+        max_string_bytes: decode_positive_int(env, limits, "max_string_bytes")?,
+        max_map_entries: decode_positive_int(env, limits, "max_map_entries")?,
+        max_depth: decode_positive_int(env, limits, "max_depth")?,
+        max_array_length: decode_positive_int(env, limits, "max_array_length")?,
+    })
+}
+
+fn decode_positive_int<'a>(
+    env: Env<'a>,
+    map: Term<'a>,
+    key: &str,
+) -> Result<Option<usize>, String> {
+    match get_term_key(env, map, key) {
+        None => Ok(None),
+        Some(term) => match term.decode::<i64>() {
+            Ok(n) if n > 0 => Ok(Some(n as usize)),
+            _ => Err(format!("{} must be a positive integer", key)),
+        },
+    }
+}
+
+fn decode_filters<'a>(env: Env<'a>, field: Term<'a>) -> Result<Option<StringFilters>, String> {
+    let Some(filters_term) = get_term_key(env, field, "filters") else {
+        return Ok(None);
+    };
 
     let len_eq = get_int_key(env, filters_term, "len_eq").map(|v| v as usize);
     let len_gt = get_int_key(env, filters_term, "len_gt").map(|v| v as usize);
@@ -704,12 +1572,15 @@ fn decode_filters<'a>(env: Env<'a>, field: Term<'a>) -> Option<StringFilters> {
     let char_class = get_string_key(env, filters_term, "char_class")
         .ok()
         .flatten()
-        .and_then(|s| match s.to_lowercase().as_str() {
-            "alpha" => Some(CharClass::Alpha),
-            "numeric" => Some(CharClass::Numeric),
-            "alphanumeric" => Some(CharClass::Alphanumeric),
-            _ => None,
-        });
+        .and_then(|s| CharClass::parse(&s));
+
+    let case_insensitive = decode_case_insensitive(env, filters_term);
+    let mut matchers = Vec::new();
+    for op in StringMatcher::OPERATORS {
+        if let Some(pattern) = get_string_key(env, filters_term, op)? {
+            matchers.push(StringMatcher::new(op, &pattern, case_insensitive)?);
+        }
+    }
 
     // Return None if no filter options were set
     if len_eq.is_none()
@@ -718,18 +1589,27 @@ fn decode_filters<'a>(env: Env<'a>, field: Term<'a>) -> Option<StringFilters> {
         && len_lt.is_none()
         && len_lte.is_none()
         && char_class.is_none()
+        && matchers.is_empty()
     {
-        return None;
+        return Ok(None);
     }
 
-    Some(StringFilters {
+    Ok(Some(StringFilters {
         len_eq,
         len_gt,
         len_gte,
         len_lt,
         len_lte,
         char_class,
-    })
+        matchers,
+    }))
+}
+
+fn decode_case_insensitive<'a>(env: Env<'a>, term: Term<'a>) -> bool {
+    match get_term_key(env, term, "case_insensitive") {
+        Some(t) => t.decode::<bool>().unwrap_or(false),
+        None => false,
+    }
 }
 
 // ── Enum8-specific decoders ────────────────────────────────────────────────
@@ -786,37 +1666,71 @@ fn decode_infer_rule<'a>(env: Env<'a>, rule: Term<'a>) -> Result<InferRule, Stri
     let result = get_string_key(env, rule, "result")?
         .ok_or_else(|| "infer rule missing 'result'".to_string())?;
 
-    let any = match get_term_key(env, rule, "any") {
-        Some(t) => decode_conditions(env, t)?,
-        None => vec![],
-    };
-
-    let all = match get_term_key(env, rule, "all") {
-        Some(t) => decode_conditions(env, t)?,
-        None => vec![],
-    };
-
     // Pre-normalize to lowercase for case-insensitive lookup against enum_values
     Ok(InferRule {
-        any,
-        all,
+        condition: decode_condition_group(env, rule, 0)?,
         result: result.to_lowercase(),
     })
 }
 
-fn decode_conditions<'a>(env: Env<'a>, term: Term<'a>) -> Result<Vec<InferCondition>, String> {
+/// Decodes the `any` and `all` lists of a rule, case or nested group into
+/// one condition: `(any is empty OR one matches) AND every all-condition`.
+fn decode_condition_group<'a>(
+    env: Env<'a>,
+    term: Term<'a>,
+    depth: usize,
+) -> Result<Condition, String> {
+    let mut all = Vec::new();
+    if let Some(t) = get_term_key(env, term, "any") {
+        let any = decode_conditions(env, t, depth)?;
+        if !any.is_empty() {
+            all.push(Condition::Any(any));
+        }
+    }
+    if let Some(t) = get_term_key(env, term, "all") {
+        all.extend(decode_conditions(env, t, depth)?);
+    }
+
+    Ok(match all.len() {
+        1 => all.pop().expect("one condition"),
+        _ => Condition::All(all),
+    })
+}
+
+fn decode_conditions<'a>(
+    env: Env<'a>,
+    term: Term<'a>,
+    depth: usize,
+) -> Result<Vec<Condition>, String> {
     let list: Vec<Term> = term
         .decode()
         .map_err(|_| "conditions must be a list".to_string())?;
 
     let mut conditions = Vec::with_capacity(list.len());
     for item in list {
-        conditions.push(decode_condition(env, item)?);
+        conditions.push(decode_condition(env, item, depth + 1)?);
     }
     Ok(conditions)
 }
 
-fn decode_condition<'a>(env: Env<'a>, cond: Term<'a>) -> Result<InferCondition, String> {
+/// Decodes a predicate, or a nested group with `any`/`all` lists or `not`.
+fn decode_condition<'a>(env: Env<'a>, cond: Term<'a>, depth: usize) -> Result<Condition, String> {
+    if depth > MAX_CONDITION_DEPTH {
+        return Err(format!(
+            "conditions are nested deeper than {MAX_CONDITION_DEPTH} levels"
+        ));
+    }
+    if let Some(negated) = get_term_key(env, cond, "not") {
+        return Ok(Condition::Not(Box::new(decode_condition(
+            env,
+            negated,
+            depth + 1,
+        )?)));
+    }
+    if get_term_key(env, cond, "any").is_some() || get_term_key(env, cond, "all").is_some() {
+        return decode_condition_group(env, cond, depth);
+    }
+
     let path_str =
         get_string_key(env, cond, "path")?.ok_or_else(|| "condition missing 'path'".to_string())?;
 
@@ -828,7 +1742,7 @@ fn decode_condition<'a>(env: Env<'a>, cond: Term<'a>) -> Result<InferCondition,
 
     let predicate = parse_predicate(env, cond, &pred_str)?;
 
-    Ok(InferCondition { path, predicate })
+    Ok(Condition::Predicate(InferCondition { path, predicate }))
 }
 
 fn parse_predicate<'a>(env: Env<'a>, cond: Term<'a>, pred_str: &str) -> Result<Predicate, String> {
@@ -863,6 +1777,17 @@ fn parse_predicate<'a>(env: Env<'a>, cond: Term<'a>, pred_str: &str) -> Result<P
             let vals = get_comparison_values(env, cond)?;
             Ok(Predicate::In(vals))
         }
+        op @ ("starts_with" | "ends_with" | "contains" | "matches") => {
+            let pattern = get_comparison_string(env, cond)?;
+            let case_insensitive = decode_case_insensitive(env, cond);
+            StringMatcher::new(op, &pattern, case_insensitive).map(Predicate::StringMatch)
+        }
+        "char_class" => {
+            let name = get_comparison_string(env, cond)?;
+            CharClass::parse(&name)
+                .map(Predicate::CharClass)
+                .ok_or_else(|| format!("unknown char_class: {}", name))
+        }
         other => Err(format!("unknown predicate: {}", other)),
     }
 }
@@ -885,6 +1810,11 @@ fn get_comparison_f64<'a>(env: Env<'a>, cond: Term<'a>) -> Result<f64, String> {
     Err("comparison_value must be numeric".to_string())
 }
 
+fn get_comparison_string<'a>(env: Env<'a>, cond: Term<'a>) -> Result<String, String> {
+    get_string_key(env, cond, "comparison_value")?
+        .ok_or_else(|| "predicate requires a string 'comparison_value'".to_string())
+}
+
 fn get_comparison_value<'a>(env: Env<'a>, cond: Term<'a>) -> Result<PredicateValue, String> {
     let term = get_term_key(env, cond, "comparison_value")
         .ok_or_else(|| "predicate requires 'comparison_value'".to_string())?;
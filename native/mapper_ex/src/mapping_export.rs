@@ -0,0 +1,445 @@
+use rustler::types::map::MapIterator;
+use rustler::types::tuple::{get_tuple, make_tuple};
+use rustler::types::ListIterator;
+use rustler::{Atom, Binary, Encoder, Env, NewBinary, Term};
+use sha2::{Digest, Sha256};
+
+use crate::mapping::{get_string_key, get_term_key};
+
+/// Header of an exported mapping: magic bytes, then the format version.
+const MAGIC: &[u8; 4] = b"LFMC";
+const VERSION: u8 = 1;
+
+/// Deepest config nesting accepted on import, bounding decoder recursion.
+const MAX_DEPTH: usize = 64;
+
+const TAG_MAP: u8 = b'm';
+const TAG_LIST: u8 = b'l';
+const TAG_TUPLE: u8 = b't';
+const TAG_BINARY: u8 = b'b';
+const TAG_INT: u8 = b'i';
+const TAG_UINT: u8 = b'u';
+const TAG_FLOAT: u8 = b'f';
+const TAG_ATOM: u8 = b'a';
+
+/// Config key an export holds in place of the `hash_key` secret.
+const HASH_KEY_ID: &str = "hash_key_id";
+
+/// Encodes a mapping config into its canonical form: a tagged binary in
+/// which map entries are sorted by their encoded keys, so configs that are
+/// equal as terms encode to the same bytes whatever their map order.
+pub fn encode_config(config: Term) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    encode_term(config, &mut out)?;
+    Ok(out)
+}
+
+/// Wraps a canonical config in the versioned export header.
+pub fn export(config: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAGIC.len() + 1 + config.len());
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(config);
+    out
+}
+
+/// Checks an exported mapping's header and decodes its sealed config; see
+/// `unseal_hash_key`.
+pub fn import<'a>(env: Env<'a>, bytes: &'a [u8]) -> Result<Term<'a>, String> {
+    let payload = bytes
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| "not an exported mapping".to_string())?;
+    let (&version, payload) = payload
+        .split_first()
+        .ok_or_else(|| "exported mapping is truncated".to_string())?;
+    if version != VERSION {
+        return Err(format!(
+            "unsupported exported mapping version {} (expected {})",
+            version, VERSION
+        ));
+    }
+
+    let mut reader = Reader { bytes: payload };
+    let config = reader.term(env, 0)?;
+    if !reader.bytes.is_empty() {
+        return Err("exported mapping has trailing bytes".to_string());
+    }
+    Ok(config)
+}
+
+/// Identifies a `hash_key` without revealing it.
+pub fn hash_key_id(hash_key: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(b"mapping hash_key:")
+        .chain_update(hash_key.as_bytes())
+        .finalize();
+    digest[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Replaces a config's `hash_key` secret with its key ID, so the config can
+/// be exported and fingerprinted without it.
+pub fn seal_hash_key<'a>(env: Env<'a>, config: Term<'a>) -> Result<Term<'a>, String> {
+    let Some(hash_key) = get_string_key(env, config, "hash_key")? else {
+        return Ok(config);
+    };
+    let sealed = remove_key(env, config, "hash_key")?;
+    sealed
+        .map_put(
+            crate::encode_string(env, HASH_KEY_ID),
+            hash_key_id(&hash_key),
+        )
+        .map_err(|_| "mapping config must be a map".to_string())
+}
+
+/// Restores the `hash_key` of a sealed config. A config sealed with a key
+/// needs the same key back; any other config ignores `hash_key`.
+pub fn unseal_hash_key<'a>(
+    env: Env<'a>,
+    sealed: Term<'a>,
+    hash_key: Option<&str>,
+) -> Result<Term<'a>, String> {
+    let Some(id) = get_string_key(env, sealed, HASH_KEY_ID)? else {
+        return Ok(sealed);
+    };
+    let hash_key = hash_key
+        .ok_or_else(|| "exported mapping requires the hash_key it was compiled with".to_string())?;
+    if hash_key_id(hash_key) != id {
+        return Err("hash_key does not match the exported mapping".to_string());
+    }
+    let config = remove_key(env, sealed, HASH_KEY_ID)?;
+    config
+        .map_put(crate::encode_string(env, "hash_key"), hash_key)
+        .map_err(|_| "mapping config must be a map".to_string())
+}
+
+/// Removes `key` from a config map, whether it is keyed by string or atom.
+fn remove_key<'a>(env: Env<'a>, map: Term<'a>, key: &str) -> Result<Term<'a>, String> {
+    let map = map
+        .map_remove(crate::encode_string(env, key))
+        .map_err(|_| "mapping config must be a map".to_string())?;
+    match Atom::try_from_bytes(env, key.as_bytes()) {
+        Ok(Some(atom)) => map
+            .map_remove(atom)
+            .map_err(|_| "mapping config must be a map".to_string()),
+        _ => Ok(map),
+    }
+}
+
+/// Rewrites a sealed config into the form its fingerprint covers, spelling
+/// each setting the one way the compiler reads it: string keys without nil
+/// values, lowercase field types, a one-element `paths` as `path`, and no
+/// `default` or `precision` that only restates the field type's own.
+pub fn normalize<'a>(env: Env<'a>, config: Term<'a>) -> Result<Term<'a>, String> {
+    let mut entries = string_keyed(config)?;
+    for (key, value) in &mut entries {
+        if key == "fields" {
+            let fields: Vec<Term<'a>> = value
+                .decode()
+                .map_err(|_| "fields must be a list".to_string())?;
+            *value = fields
+                .into_iter()
+                .map(|field| normalize_field(env, field))
+                .collect::<Result<Vec<_>, _>>()?
+                .encode(env);
+        }
+    }
+    Ok(map_from_entries(env, entries))
+}
+
+fn normalize_field<'a>(env: Env<'a>, field: Term<'a>) -> Result<Term<'a>, String> {
+    let field_type = match get_string_key(env, field, "type")? {
+        Some(field_type) if field_type.eq_ignore_ascii_case("boolean") => "bool".to_string(),
+        Some(field_type) => field_type.to_lowercase(),
+        None => "string".to_string(),
+    };
+    let has_cases = get_term_key(env, field, "cases").is_some();
+
+    let mut entries = string_keyed(field)?;
+    normalize_source(&mut entries);
+    entries.retain(|(key, value)| match key.as_str() {
+        "type" => false,
+        // A field without cases reads the root when it has no path.
+        "path" => has_cases || value.decode::<&str>().map_or(true, |path| path != "$"),
+        "precision" => {
+            field_type.ends_with("datetime64") && value.decode::<i64>().is_ok_and(|p| p != 9)
+        }
+        "default" => !is_implicit_default(&field_type, *value),
+        _ => true,
+    });
+    for (key, value) in &mut entries {
+        if key == "cases" {
+            let cases: Vec<Term<'a>> = value
+                .decode()
+                .map_err(|_| "cases must be a list".to_string())?;
+            let mut normalized = Vec::with_capacity(cases.len());
+            for case in cases {
+                let mut case_entries = string_keyed(case)?;
+                normalize_source(&mut case_entries);
+                normalized.push(map_from_entries(env, case_entries));
+            }
+            *value = normalized.encode(env);
+        }
+    }
+    entries.push(("type".to_string(), crate::encode_string(env, &field_type)));
+    Ok(map_from_entries(env, entries))
+}
+
+/// Spells a one-element `paths` as `path` and drops an empty one. Aggregates
+/// read only `path`, so their entries are left alone.
+fn normalize_source(entries: &mut Vec<(String, Term<'_>)>) {
+    if entries.iter().any(|(key, _)| key == "aggregate") {
+        return;
+    }
+    let Some(index) = entries.iter().position(|(key, _)| key == "paths") else {
+        return;
+    };
+    let Ok(paths) = entries[index].1.decode::<Vec<Term>>() else {
+        return;
+    };
+    match paths.as_slice() {
+        [] => {
+            entries.remove(index);
+        }
+        [path] if path.is_binary() => {
+            let path = *path;
+            entries.remove(index);
+            entries.retain(|(key, _)| key != "path");
+            entries.push(("path".to_string(), path));
+        }
+        _ => {}
+    }
+}
+
+/// Whether `default` is the value a field of `field_type` has without one.
+fn is_implicit_default(field_type: &str, default: Term) -> bool {
+    let Ok(default) = default.decode::<&str>() else {
+        return false;
+    };
+    match field_type {
+        "string" => default.is_empty(),
+        "json" | "flat_map" => default == "{}",
+        array if array.starts_with("array_") => default == "[]",
+        _ => false,
+    }
+}
+
+/// A map's entries keyed by string, leaving out nil values, which the
+/// compiler reads as absent.
+fn string_keyed(map: Term<'_>) -> Result<Vec<(String, Term<'_>)>, String> {
+    let entries = MapIterator::new(map).ok_or_else(|| "expected a config map".to_string())?;
+    let nil = rustler::types::atom::nil();
+    let mut keyed = Vec::new();
+    for (key, value) in entries {
+        if value.decode::<Atom>().is_ok_and(|atom| atom == nil) {
+            continue;
+        }
+        let key = match key.decode::<String>() {
+            Ok(key) => key,
+            Err(_) => key
+                .atom_to_string()
+                .map_err(|_| "config keys must be strings or atoms".to_string())?,
+        };
+        keyed.push((key, value));
+    }
+    Ok(keyed)
+}
+
+fn map_from_entries<'a>(env: Env<'a>, entries: Vec<(String, Term<'a>)>) -> Term<'a> {
+    let (keys, values): (Vec<Term<'a>>, Vec<Term<'a>>) = entries
+        .into_iter()
+        .map(|(key, value)| (crate::encode_string(env, &key), value))
+        .unzip();
+    Term::map_from_term_arrays(env, &keys, &values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+/// A content fingerprint of a canonical config, formatted as a UUID (version
+/// 8) so it can stand in for a `mapping_config_id`.
+pub fn fingerprint(config: &[u8]) -> String {
+    let digest = Sha256::digest(config);
+    let mut bytes: [u8; 16] = digest[..16].try_into().expect("16-byte prefix");
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn encode_term(term: Term, out: &mut Vec<u8>) -> Result<(), String> {
+    if let Ok(binary) = term.decode::<Binary>() {
+        out.push(TAG_BINARY);
+        encode_bytes(binary.as_slice(), out);
+    } else if let Some(entries) = MapIterator::new(term) {
+        let mut encoded = Vec::new();
+        for (key, value) in entries {
+            let mut key_bytes = Vec::new();
+            encode_term(key, &mut key_bytes)?;
+            let mut value_bytes = Vec::new();
+            encode_term(value, &mut value_bytes)?;
+            encoded.push((key_bytes, value_bytes));
+        }
+        encoded.sort_unstable();
+        out.push(TAG_MAP);
+        encode_len(encoded.len(), out);
+        for (key, value) in encoded {
+            out.extend_from_slice(&key);
+            out.extend_from_slice(&value);
+        }
+    } else if let Ok(iter) = term.decode::<ListIterator>() {
+        let elements: Vec<Term> = iter.collect();
+        out.push(TAG_LIST);
+        encode_len(elements.len(), out);
+        for element in elements {
+            encode_term(element, out)?;
+        }
+    } else if let Ok(elements) = get_tuple(term) {
+        out.push(TAG_TUPLE);
+        encode_len(elements.len(), out);
+        for element in elements {
+            encode_term(element, out)?;
+        }
+    } else if let Ok(value) = term.decode::<i64>() {
+        out.push(TAG_INT);
+        out.extend_from_slice(&value.to_be_bytes());
+    } else if let Ok(value) = term.decode::<u64>() {
+        out.push(TAG_UINT);
+        out.extend_from_slice(&value.to_be_bytes());
+    } else if let Ok(value) = term.decode::<f64>() {
+        out.push(TAG_FLOAT);
+        out.extend_from_slice(&value.to_bits().to_be_bytes());
+    } else if term.is_atom() {
+        let name = term
+            .atom_to_string()
+            .map_err(|_| "mapping config contains an unreadable atom".to_string())?;
+        out.push(TAG_ATOM);
+        encode_bytes(name.as_bytes(), out);
+    } else {
+        return Err(format!(
+            "mapping config contains an unsupported term: {:?}",
+            term.get_type()
+        ));
+    }
+    Ok(())
+}
+
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    encode_len(bytes.len(), out);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], String> {
+        if self.bytes.len() < len {
+            return Err("exported mapping is truncated".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("exact length"))
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(u32::from_be_bytes(self.array()?) as usize)
+    }
+
+    fn term<'a>(&mut self, env: Env<'a>, depth: usize) -> Result<Term<'a>, String> {
+        if depth > MAX_DEPTH {
+            return Err("exported mapping is nested too deeply".to_string());
+        }
+        let [tag] = self.array()?;
+        match tag {
+            TAG_BINARY => {
+                let len = self.len()?;
+                let bytes = self.take(len)?;
+                let mut binary = NewBinary::new(env, len);
+                binary.as_mut_slice().copy_from_slice(bytes);
+                Ok(binary.into())
+            }
+            TAG_MAP => {
+                let len = self.len()?;
+                let mut keys = Vec::new();
+                let mut values = Vec::new();
+                for _ in 0..len {
+                    keys.push(self.term(env, depth + 1)?);
+                    values.push(self.term(env, depth + 1)?);
+                }
+                Term::map_from_term_arrays(env, &keys, &values)
+                    .map_err(|_| "exported mapping holds duplicate map keys".to_string())
+            }
+            TAG_LIST | TAG_TUPLE => {
+                let len = self.len()?;
+                let mut elements = Vec::new();
+                for _ in 0..len {
+                    elements.push(self.term(env, depth + 1)?);
+                }
+                Ok(if tag == TAG_LIST {
+                    elements.encode(env)
+                } else {
+                    make_tuple(env, &elements)
+                })
+            }
+            TAG_INT => Ok(i64::from_be_bytes(self.array()?).encode(env)),
+            TAG_UINT => Ok(u64::from_be_bytes(self.array()?).encode(env)),
+            TAG_FLOAT => Ok(f64::from_bits(u64::from_be_bytes(self.array()?)).encode(env)),
+            TAG_ATOM => {
+                let len = self.len()?;
+                let name = self.take(len)?;
+                // Only existing atoms: an import must not grow the atom table.
+                match Atom::try_from_bytes(env, name) {
+                    Ok(Some(atom)) => Ok(atom.encode(env)),
+                    _ => Err("exported mapping holds an unknown atom".to_string()),
+                }
+            }
+            _ => Err(format!("exported mapping holds an unknown tag {}", tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_key_ids_identify_keys() {
+        let id = hash_key_id("s3cret");
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, hash_key_id("s3cret"));
+        assert_ne!(id, hash_key_id("other"));
+        assert!(!id.contains("s3cret"));
+    }
+
+    #[test]
+    fn fingerprints_are_version_8_uuids() {
+        let id = fingerprint(b"config");
+        assert_eq!(id, fingerprint(b"config"));
+        assert_ne!(id, fingerprint(b"other config"));
+
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('8'));
+        assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+    }
+}
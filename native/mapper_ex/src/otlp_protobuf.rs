@@ -0,0 +1,1028 @@
+use std::collections::HashMap;
+
+use rustler::types::map::MapIterator;
+use rustler::{Binary, Term};
+
+use crate::clickhouse_rowbinary::{
+    self, decode_f64, decode_i64, decode_u64, log_severity, EncodeResult,
+};
+use crate::mapping::{CompiledField, FieldType};
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+const SPAN_KINDS: &[&str] = &[
+    "unspecified",
+    "internal",
+    "server",
+    "client",
+    "producer",
+    "consumer",
+];
+const STATUS_CODES: &[&str] = &["unset", "ok", "error"];
+const AGGREGATION_TEMPORALITIES: &[&str] = &["unspecified", "delta", "cumulative"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MetricKind {
+    Gauge,
+    Sum,
+    Histogram,
+    ExponentialHistogram,
+    Summary,
+}
+
+impl MetricKind {
+    fn from_label(label: &str) -> Option<Self> {
+        match label.to_ascii_lowercase().as_str() {
+            "gauge" => Some(Self::Gauge),
+            "sum" => Some(Self::Sum),
+            "histogram" => Some(Self::Histogram),
+            "exponential_histogram" => Some(Self::ExponentialHistogram),
+            "summary" => Some(Self::Summary),
+            _ => None,
+        }
+    }
+
+    /// Field number of this kind in the `Metric.data` oneof.
+    fn field(self) -> u32 {
+        match self {
+            Self::Gauge => 5,
+            Self::Sum => 7,
+            Self::Histogram => 9,
+            Self::ExponentialHistogram => 10,
+            Self::Summary => 11,
+        }
+    }
+}
+
+/// A DateTime64 field and the factor converting its values to nanoseconds.
+#[derive(Debug, Clone, Copy)]
+struct TimeColumn {
+    index: usize,
+    scale: i64,
+}
+
+#[derive(Debug)]
+struct ResourceColumns {
+    service_name: usize,
+    resource_attributes: usize,
+    resource_schema_url: Option<usize>,
+    scope_name: usize,
+    scope_version: usize,
+    scope_attributes: Option<usize>,
+    scope_schema_url: Option<usize>,
+}
+
+#[derive(Debug)]
+struct LogColumns {
+    trace_id: usize,
+    span_id: usize,
+    trace_flags: usize,
+    severity_text: usize,
+    severity_number_alt: usize,
+    severity_number: usize,
+    event_message: usize,
+    log_attributes: usize,
+    timestamp: TimeColumn,
+}
+
+#[derive(Debug)]
+struct TraceColumns {
+    trace_id: usize,
+    span_id: usize,
+    parent_span_id: usize,
+    trace_state: usize,
+    span_name: usize,
+    span_kind: usize,
+    duration: usize,
+    start_time: TimeColumn,
+    end_time: TimeColumn,
+    status_code: usize,
+    status_message: usize,
+    span_attributes: usize,
+    event_timestamps: TimeColumn,
+    event_names: usize,
+    event_attributes: usize,
+    link_trace_ids: usize,
+    link_span_ids: usize,
+    link_trace_states: usize,
+    link_attributes: usize,
+}
+
+#[derive(Debug)]
+struct MetricColumns {
+    time_unix: TimeColumn,
+    start_time_unix: TimeColumn,
+    timestamp: TimeColumn,
+    metric_name: usize,
+    metric_description: usize,
+    metric_unit: usize,
+    metric_type: usize,
+    metric_kinds: HashMap<i64, MetricKind>,
+    attributes: usize,
+    aggregation_temporality: usize,
+    is_monotonic: usize,
+    flags: usize,
+    value: usize,
+    count: usize,
+    sum: usize,
+    min: usize,
+    max: usize,
+    scale: usize,
+    zero_count: usize,
+    positive_offset: usize,
+    negative_offset: usize,
+    bucket_counts: usize,
+    explicit_bounds: usize,
+    positive_bucket_counts: usize,
+    negative_bucket_counts: usize,
+    quantile_values: usize,
+    quantiles: usize,
+    exemplar_attributes: usize,
+    exemplar_times: TimeColumn,
+    exemplar_values: usize,
+    exemplar_span_ids: usize,
+    exemplar_trace_ids: usize,
+}
+
+#[derive(Debug)]
+enum Signal {
+    Logs(LogColumns),
+    Metrics(Box<MetricColumns>),
+    Traces(TraceColumns),
+}
+
+/// Field indices for one OTel row type, compiled once per mapping.
+///
+/// Mappings are validated against the same field set as ClickHouse RowBinary
+/// output, so one mapping can feed either backend.
+#[derive(Debug)]
+pub struct CompiledOtlpLayout {
+    resource: ResourceColumns,
+    signal: Signal,
+}
+
+pub fn compile_layout(
+    row_type: &str,
+    fields: &[CompiledField],
+) -> EncodeResult<CompiledOtlpLayout> {
+    let fields_by_name: HashMap<&str, (usize, FieldType)> = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| (field.name.as_str(), (index, field.field_type)))
+        .collect();
+    clickhouse_rowbinary::compile_layout(row_type, &fields_by_name)?;
+
+    let optional = |name: &str| fields_by_name.get(name).map(|(index, _)| *index);
+    let index = |name: &str| {
+        optional(name)
+            .ok_or_else(|| format!("compiled mapping is missing required OTLP field '{name}'"))
+    };
+    let time = |name: &str| -> EncodeResult<TimeColumn> {
+        let index = index(name)?;
+        let precision = match fields[index].field_type {
+            FieldType::DateTime64 { precision } | FieldType::ArrayDateTime64 { precision } => {
+                precision
+            }
+            _ => 9,
+        };
+        let scale = 10i64
+            .checked_pow(9u32.saturating_sub(u32::from(precision)))
+            .ok_or_else(|| format!("unsupported DateTime64 precision {precision}"))?;
+        Ok(TimeColumn { index, scale })
+    };
+
+    let resource = ResourceColumns {
+        service_name: index("service_name")?,
+        resource_attributes: index("resource_attributes")?,
+        resource_schema_url: optional("resource_schema_url"),
+        scope_name: index("scope_name")?,
+        scope_version: index("scope_version")?,
+        scope_attributes: optional("scope_attributes"),
+        scope_schema_url: optional("scope_schema_url"),
+    };
+
+    let signal = match row_type {
+        "log" => Signal::Logs(LogColumns {
+            trace_id: index("trace_id")?,
+            span_id: index("span_id")?,
+            trace_flags: index("trace_flags")?,
+            severity_text: index("severity_text")?,
+            severity_number_alt: index("severity_number_alt")?,
+            severity_number: index("severity_number")?,
+            event_message: index("event_message")?,
+            log_attributes: index("log_attributes")?,
+            timestamp: time("timestamp")?,
+        }),
+        "trace" => Signal::Traces(TraceColumns {
+            trace_id: index("trace_id")?,
+            span_id: index("span_id")?,
+            parent_span_id: index("parent_span_id")?,
+            trace_state: index("trace_state")?,
+            span_name: index("span_name")?,
+            span_kind: index("span_kind")?,
+            duration: index("duration")?,
+            start_time: time("start_time")?,
+            end_time: time("end_time")?,
+            status_code: index("status_code")?,
+            status_message: index("status_message")?,
+            span_attributes: index("span_attributes")?,
+            event_timestamps: time("events.timestamp")?,
+            event_names: index("events.name")?,
+            event_attributes: index("events.attributes")?,
+            link_trace_ids: index("links.trace_id")?,
+            link_span_ids: index("links.span_id")?,
+            link_trace_states: index("links.trace_state")?,
+            link_attributes: index("links.attributes")?,
+        }),
+        _ => {
+            let metric_type = index("metric_type")?;
+            Signal::Metrics(Box::new(MetricColumns {
+                time_unix: time("time_unix")?,
+                start_time_unix: time("start_time_unix")?,
+                timestamp: time("timestamp")?,
+                metric_name: index("metric_name")?,
+                metric_description: index("metric_description")?,
+                metric_unit: index("metric_unit")?,
+                metric_type,
+                metric_kinds: metric_kinds(&fields[metric_type]),
+                attributes: index("attributes")?,
+                aggregation_temporality: index("aggregation_temporality")?,
+                is_monotonic: index("is_monotonic")?,
+                flags: index("flags")?,
+                value: index("value")?,
+                count: index("count")?,
+                sum: index("sum")?,
+                min: index("min")?,
+                max: index("max")?,
+                scale: index("scale")?,
+                zero_count: index("zero_count")?,
+                positive_offset: index("positive_offset")?,
+                negative_offset: index("negative_offset")?,
+                bucket_counts: index("bucket_counts")?,
+                explicit_bounds: index("explicit_bounds")?,
+                positive_bucket_counts: index("positive_bucket_counts")?,
+                negative_bucket_counts: index("negative_bucket_counts")?,
+                quantile_values: index("quantile_values")?,
+                quantiles: index("quantiles")?,
+                exemplar_attributes: index("exemplars.filtered_attributes")?,
+                exemplar_times: time("exemplars.time_unix")?,
+                exemplar_values: index("exemplars.value")?,
+                exemplar_span_ids: index("exemplars.span_id")?,
+                exemplar_trace_ids: index("exemplars.trace_id")?,
+            }))
+        }
+    };
+
+    Ok(CompiledOtlpLayout { resource, signal })
+}
+
+/// Resolves `metric_type` enum values to metric kinds by label, falling back
+/// to the default ClickHouse numbering (gauge = 1 … summary = 5).
+fn metric_kinds(field: &CompiledField) -> HashMap<i64, MetricKind> {
+    let labelled: HashMap<i64, MetricKind> = field
+        .enum8_data
+        .iter()
+        .flat_map(|enum8| &enum8.value_map)
+        .filter_map(|(label, value)| Some((i64::from(*value), MetricKind::from_label(label)?)))
+        .collect();
+    if !labelled.is_empty() {
+        return labelled;
+    }
+
+    [
+        MetricKind::Gauge,
+        MetricKind::Sum,
+        MetricKind::Histogram,
+        MetricKind::ExponentialHistogram,
+        MetricKind::Summary,
+    ]
+    .into_iter()
+    .zip(1..)
+    .map(|(kind, value)| (value, kind))
+    .collect()
+}
+
+// ── Protobuf wire encoding ────────────────────────────────────────────────
+
+/// Minimal protobuf writer. Scalar setters skip proto3 default values;
+/// `*_always` variants are for oneof members and `optional` fields, which
+/// must be written to record presence.
+#[derive(Default)]
+struct ProtoWriter {
+    buffer: Vec<u8>,
+}
+
+impl ProtoWriter {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buffer.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buffer.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint((u64::from(field) << 3) | wire_type);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, WIRE_VARINT);
+            self.varint(value);
+        }
+    }
+
+    fn sint32(&mut self, field: u32, value: i32) {
+        self.uint(field, u64::from(((value << 1) ^ (value >> 31)) as u32));
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, WIRE_FIXED64);
+            self.buffer.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        if value != 0 {
+            self.key(field, WIRE_FIXED32);
+            self.buffer.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn double(&mut self, field: u32, value: f64) {
+        if value != 0.0 {
+            self.double_always(field, value);
+        }
+    }
+
+    fn double_always(&mut self, field: u32, value: f64) {
+        self.key(field, WIRE_FIXED64);
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        if !value.is_empty() {
+            self.bytes_always(field, value);
+        }
+    }
+
+    fn bytes_always(&mut self, field: u32, value: &[u8]) {
+        self.key(field, WIRE_LEN);
+        self.varint(value.len() as u64);
+        self.buffer.extend_from_slice(value);
+    }
+
+    fn message<F>(&mut self, field: u32, write: F) -> EncodeResult<()>
+    where
+        F: FnOnce(&mut ProtoWriter) -> EncodeResult<()>,
+    {
+        let mut nested = ProtoWriter::default();
+        write(&mut nested)?;
+        self.bytes_always(field, &nested.buffer);
+        Ok(())
+    }
+
+    fn packed_fixed64(&mut self, field: u32, values: &[u64]) {
+        if !values.is_empty() {
+            self.key(field, WIRE_LEN);
+            self.varint((values.len() * 8) as u64);
+            for value in values {
+                self.buffer.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    fn packed_double(&mut self, field: u32, values: &[f64]) {
+        if !values.is_empty() {
+            self.key(field, WIRE_LEN);
+            self.varint((values.len() * 8) as u64);
+            for value in values {
+                self.buffer.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    fn packed_varint(&mut self, field: u32, values: &[u64]) {
+        if !values.is_empty() {
+            let mut packed = ProtoWriter::default();
+            for value in values {
+                packed.varint(*value);
+            }
+            self.bytes_always(field, &packed.buffer);
+        }
+    }
+}
+
+// ── Batch grouping ────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MetricKey {
+    name: Vec<u8>,
+    description: Vec<u8>,
+    unit: Vec<u8>,
+    kind: MetricKind,
+    temporality: u64,
+    is_monotonic: bool,
+}
+
+struct MetricGroup {
+    key: MetricKey,
+    data_points: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct ScopeGroup {
+    scope: Vec<u8>,
+    schema_url: Vec<u8>,
+    records: Vec<Vec<u8>>,
+    metrics: Vec<MetricGroup>,
+    metric_index: HashMap<MetricKey, usize>,
+}
+
+#[derive(Default)]
+struct ResourceGroup {
+    resource: Vec<u8>,
+    schema_url: Vec<u8>,
+    scopes: Vec<ScopeGroup>,
+    scope_index: HashMap<(Vec<u8>, Vec<u8>), usize>,
+}
+
+/// Accumulates mapped rows into one OTLP export request.
+///
+/// Rows sharing encoded resource and scope messages (attributes are sorted
+/// by key) are grouped under one `Resource*`/`Scope*` entry, in first-seen
+/// order. Metric data points are further grouped into one `Metric` per name,
+/// description, unit, kind, temporality and monotonicity.
+pub struct OtlpRequestBuilder<'layout> {
+    layout: &'layout CompiledOtlpLayout,
+    resources: Vec<ResourceGroup>,
+    resource_index: HashMap<(Vec<u8>, Vec<u8>), usize>,
+}
+
+impl<'layout> OtlpRequestBuilder<'layout> {
+    pub fn new(layout: &'layout CompiledOtlpLayout) -> Self {
+        Self {
+            layout,
+            resources: Vec::new(),
+            resource_index: HashMap::new(),
+        }
+    }
+
+    pub fn append_row(&mut self, values: &[Term]) -> EncodeResult<()> {
+        let layout = self.layout;
+        let scope = self.scope_group(values)?;
+        match &layout.signal {
+            Signal::Logs(columns) => {
+                let mut record = ProtoWriter::default();
+                write_log_record(&mut record, columns, values)?;
+                scope.records.push(record.buffer);
+            }
+            Signal::Traces(columns) => {
+                let mut span = ProtoWriter::default();
+                write_span(&mut span, columns, values)?;
+                scope.records.push(span.buffer);
+            }
+            Signal::Metrics(columns) => {
+                let key = metric_key(columns, values)?;
+                let mut data_point = ProtoWriter::default();
+                write_data_point(&mut data_point, columns, key.kind, values)?;
+                let index = match scope.metric_index.get(&key) {
+                    Some(index) => *index,
+                    None => {
+                        scope.metric_index.insert(key.clone(), scope.metrics.len());
+                        scope.metrics.push(MetricGroup {
+                            key,
+                            data_points: Vec::new(),
+                        });
+                        scope.metrics.len() - 1
+                    }
+                };
+                scope.metrics[index].data_points.push(data_point.buffer);
+            }
+        }
+        Ok(())
+    }
+
+    fn scope_group(&mut self, values: &[Term]) -> EncodeResult<&mut ScopeGroup> {
+        let layout = self.layout;
+        let columns = &layout.resource;
+
+        let mut resource = ProtoWriter::default();
+        let mut attributes = string_entries(values[columns.resource_attributes])?;
+        let service_name = text(values[columns.service_name])?;
+        if !service_name.is_empty() && !attributes.iter().any(|(key, _)| *key == b"service.name") {
+            attributes.push((&b"service.name"[..], service_name));
+            attributes.sort_unstable();
+        }
+        write_key_values(&mut resource, 1, &attributes)?;
+        let resource_schema_url = optional_text(values, columns.resource_schema_url)?;
+
+        let mut scope = ProtoWriter::default();
+        scope.bytes(1, text(values[columns.scope_name])?);
+        scope.bytes(2, text(values[columns.scope_version])?);
+        if let Some(index) = columns.scope_attributes {
+            write_key_values(&mut scope, 3, &string_entries(values[index])?)?;
+        }
+        let scope_schema_url = optional_text(values, columns.scope_schema_url)?;
+
+        let resource_key = (resource.buffer, resource_schema_url.to_vec());
+        let resource_group = match self.resource_index.get(&resource_key) {
+            Some(index) => &mut self.resources[*index],
+            None => {
+                self.resource_index
+                    .insert(resource_key.clone(), self.resources.len());
+                self.resources.push(ResourceGroup {
+                    resource: resource_key.0,
+                    schema_url: resource_key.1,
+                    ..ResourceGroup::default()
+                });
+                self.resources
+                    .last_mut()
+                    .expect("resource group was just pushed")
+            }
+        };
+
+        let scope_key = (scope.buffer, scope_schema_url.to_vec());
+        let index = match resource_group.scope_index.get(&scope_key) {
+            Some(index) => *index,
+            None => {
+                let index = resource_group.scopes.len();
+                resource_group.scope_index.insert(scope_key.clone(), index);
+                resource_group.scopes.push(ScopeGroup {
+                    scope: scope_key.0,
+                    schema_url: scope_key.1,
+                    ..ScopeGroup::default()
+                });
+                index
+            }
+        };
+        Ok(&mut resource_group.scopes[index])
+    }
+
+    /// Encodes the export request. `Resource*`, `Scope*` and record field
+    /// numbers are identical across logs, traces and metrics.
+    pub fn finish(self) -> EncodeResult<Vec<u8>> {
+        let mut request = ProtoWriter::default();
+        for resource in &self.resources {
+            request.message(1, |resource_writer| {
+                resource_writer.bytes_always(1, &resource.resource);
+                for scope in &resource.scopes {
+                    resource_writer.message(2, |scope_writer| {
+                        scope_writer.bytes_always(1, &scope.scope);
+                        for record in &scope.records {
+                            scope_writer.bytes_always(2, record);
+                        }
+                        for metric in &scope.metrics {
+                            scope_writer
+                                .message(2, |metric_writer| write_metric(metric_writer, metric))?;
+                        }
+                        scope_writer.bytes(3, &scope.schema_url);
+                        Ok(())
+                    })?;
+                }
+                resource_writer.bytes(3, &resource.schema_url);
+                Ok(())
+            })?;
+        }
+        Ok(request.buffer)
+    }
+}
+
+// ── Record encoders ───────────────────────────────────────────────────────
+
+fn write_log_record(
+    record: &mut ProtoWriter,
+    columns: &LogColumns,
+    values: &[Term],
+) -> EncodeResult<()> {
+    record.fixed64(1, time_nanos(values, columns.timestamp)?);
+    record.uint(
+        2,
+        u64::from(log_severity(
+            values[columns.severity_number_alt],
+            values[columns.severity_number],
+        )?),
+    );
+    record.bytes(3, text(values[columns.severity_text])?);
+    record.message(5, |body| {
+        body.bytes_always(1, text(values[columns.event_message])?);
+        Ok(())
+    })?;
+    write_key_values(record, 6, &string_entries(values[columns.log_attributes])?)?;
+    record.fixed32(8, u32::from(decode_u8(values[columns.trace_flags])?));
+    record.bytes(9, &hex_id(text(values[columns.trace_id])?));
+    record.bytes(10, &hex_id(text(values[columns.span_id])?));
+    Ok(())
+}
+
+fn write_span(span: &mut ProtoWriter, columns: &TraceColumns, values: &[Term]) -> EncodeResult<()> {
+    let start_time = time_nanos(values, columns.start_time)?;
+    let mut end_time = time_nanos(values, columns.end_time)?;
+    if end_time == 0 && start_time != 0 {
+        end_time = start_time.saturating_add(decode_u64(values[columns.duration])?);
+    }
+
+    span.bytes(1, &hex_id(text(values[columns.trace_id])?));
+    span.bytes(2, &hex_id(text(values[columns.span_id])?));
+    span.bytes(3, text(values[columns.trace_state])?);
+    span.bytes(4, &hex_id(text(values[columns.parent_span_id])?));
+    span.bytes(5, text(values[columns.span_name])?);
+    span.uint(
+        6,
+        otel_enum(text(values[columns.span_kind])?, "SPAN_KIND_", SPAN_KINDS),
+    );
+    span.fixed64(7, start_time);
+    span.fixed64(8, end_time);
+    write_key_values(span, 9, &string_entries(values[columns.span_attributes])?)?;
+
+    let event_times = list_values(values[columns.event_timestamps.index])?;
+    let event_names = list_values(values[columns.event_names])?;
+    let event_attributes = list_values(values[columns.event_attributes])?;
+    for (position, name) in event_names.iter().enumerate() {
+        span.message(11, |event| {
+            if let Some(time) = event_times.get(position) {
+                event.fixed64(1, nanos(decode_i64(*time)?, columns.event_timestamps.scale));
+            }
+            event.bytes(2, text(*name)?);
+            if let Some(attributes) = event_attributes.get(position) {
+                write_key_values(event, 3, &string_entries(*attributes)?)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    let link_trace_ids = list_values(values[columns.link_trace_ids])?;
+    let link_span_ids = list_values(values[columns.link_span_ids])?;
+    let link_trace_states = list_values(values[columns.link_trace_states])?;
+    let link_attributes = list_values(values[columns.link_attributes])?;
+    for (position, trace_id) in link_trace_ids.iter().enumerate() {
+        span.message(13, |link| {
+            link.bytes(1, &hex_id(text(*trace_id)?));
+            if let Some(span_id) = link_span_ids.get(position) {
+                link.bytes(2, &hex_id(text(*span_id)?));
+            }
+            if let Some(trace_state) = link_trace_states.get(position) {
+                link.bytes(3, text(*trace_state)?);
+            }
+            if let Some(attributes) = link_attributes.get(position) {
+                write_key_values(link, 4, &string_entries(*attributes)?)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    let status_message = text(values[columns.status_message])?;
+    let status_code = otel_enum(
+        text(values[columns.status_code])?,
+        "STATUS_CODE_",
+        STATUS_CODES,
+    );
+    if status_code != 0 || !status_message.is_empty() {
+        span.message(15, |status| {
+            status.bytes(2, status_message);
+            status.uint(3, status_code);
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+fn metric_key(columns: &MetricColumns, values: &[Term]) -> EncodeResult<MetricKey> {
+    let metric_type = decode_i64(values[columns.metric_type])?;
+    let kind = columns
+        .metric_kinds
+        .get(&metric_type)
+        .copied()
+        .ok_or_else(|| format!("unsupported OTLP metric_type value {metric_type}"))?;
+    Ok(MetricKey {
+        name: text(values[columns.metric_name])?.to_vec(),
+        description: text(values[columns.metric_description])?.to_vec(),
+        unit: text(values[columns.metric_unit])?.to_vec(),
+        kind,
+        temporality: otel_enum(
+            text(values[columns.aggregation_temporality])?,
+            "AGGREGATION_TEMPORALITY_",
+            AGGREGATION_TEMPORALITIES,
+        ),
+        is_monotonic: decode_bool(values[columns.is_monotonic])?,
+    })
+}
+
+fn write_metric(metric: &mut ProtoWriter, group: &MetricGroup) -> EncodeResult<()> {
+    let key = &group.key;
+    metric.bytes(1, &key.name);
+    metric.bytes(2, &key.description);
+    metric.bytes(3, &key.unit);
+    metric.message(key.kind.field(), |data| {
+        for data_point in &group.data_points {
+            data.bytes_always(1, data_point);
+        }
+        if matches!(
+            key.kind,
+            MetricKind::Sum | MetricKind::Histogram | MetricKind::ExponentialHistogram
+        ) {
+            data.uint(2, key.temporality);
+        }
+        if key.kind == MetricKind::Sum {
+            data.uint(3, u64::from(key.is_monotonic));
+        }
+        Ok(())
+    })
+}
+
+fn write_data_point(
+    point: &mut ProtoWriter,
+    columns: &MetricColumns,
+    kind: MetricKind,
+    values: &[Term],
+) -> EncodeResult<()> {
+    let time_unix = match time_nanos(values, columns.time_unix)? {
+        0 => time_nanos(values, columns.timestamp)?,
+        time_unix => time_unix,
+    };
+    let attributes = string_entries(values[columns.attributes])?;
+    let count = decode_u64(values[columns.count])?;
+    let sum = decode_f64(values[columns.sum])?;
+    let flags = decode_u64(values[columns.flags])?;
+
+    point.fixed64(2, time_nanos(values, columns.start_time_unix)?);
+    point.fixed64(3, time_unix);
+
+    match kind {
+        MetricKind::Gauge | MetricKind::Sum => {
+            point.double_always(4, decode_f64(values[columns.value])?);
+            write_exemplars(point, 5, columns, values)?;
+            write_key_values(point, 7, &attributes)?;
+            point.uint(8, flags);
+        }
+        MetricKind::Histogram => {
+            point.fixed64(4, count);
+            point.double_always(5, sum);
+            point.packed_fixed64(6, &u64_list(values[columns.bucket_counts])?);
+            point.packed_double(7, &f64_list(values[columns.explicit_bounds])?);
+            write_exemplars(point, 8, columns, values)?;
+            write_key_values(point, 9, &attributes)?;
+            point.uint(10, flags);
+            if count > 0 {
+                point.double_always(11, decode_f64(values[columns.min])?);
+                point.double_always(12, decode_f64(values[columns.max])?);
+            }
+        }
+        MetricKind::ExponentialHistogram => {
+            write_key_values(point, 1, &attributes)?;
+            point.fixed64(4, count);
+            point.double_always(5, sum);
+            point.sint32(6, decode_i32(values[columns.scale])?);
+            point.fixed64(7, decode_u64(values[columns.zero_count])?);
+            for (field, offset, counts) in [
+                (8, columns.positive_offset, columns.positive_bucket_counts),
+                (9, columns.negative_offset, columns.negative_bucket_counts),
+            ] {
+                point.message(field, |buckets| {
+                    buckets.sint32(1, decode_i32(values[offset])?);
+                    buckets.packed_varint(2, &u64_list(values[counts])?);
+                    Ok(())
+                })?;
+            }
+            point.uint(10, flags);
+            write_exemplars(point, 11, columns, values)?;
+            if count > 0 {
+                point.double_always(12, decode_f64(values[columns.min])?);
+                point.double_always(13, decode_f64(values[columns.max])?);
+            }
+        }
+        MetricKind::Summary => {
+            point.fixed64(4, count);
+            point.double(5, sum);
+            let quantiles = f64_list(values[columns.quantiles])?;
+            let quantile_values = f64_list(values[columns.quantile_values])?;
+            for (quantile, value) in quantiles.iter().zip(&quantile_values) {
+                point.message(6, |entry| {
+                    entry.double(1, *quantile);
+                    entry.double(2, *value);
+                    Ok(())
+                })?;
+            }
+            write_key_values(point, 7, &attributes)?;
+            point.uint(8, flags);
+        }
+    }
+    Ok(())
+}
+
+fn write_exemplars(
+    point: &mut ProtoWriter,
+    field: u32,
+    columns: &MetricColumns,
+    values: &[Term],
+) -> EncodeResult<()> {
+    let exemplar_values = list_values(values[columns.exemplar_values])?;
+    let attributes = list_values(values[columns.exemplar_attributes])?;
+    let times = list_values(values[columns.exemplar_times.index])?;
+    let span_ids = list_values(values[columns.exemplar_span_ids])?;
+    let trace_ids = list_values(values[columns.exemplar_trace_ids])?;
+
+    for (position, value) in exemplar_values.iter().enumerate() {
+        point.message(field, |exemplar| {
+            if let Some(time) = times.get(position) {
+                exemplar.fixed64(2, nanos(decode_i64(*time)?, columns.exemplar_times.scale));
+            }
+            exemplar.double_always(3, decode_f64(*value)?);
+            if let Some(span_id) = span_ids.get(position) {
+                exemplar.bytes(4, &hex_id(text(*span_id)?));
+            }
+            if let Some(trace_id) = trace_ids.get(position) {
+                exemplar.bytes(5, &hex_id(text(*trace_id)?));
+            }
+            if let Some(attributes) = attributes.get(position) {
+                write_key_values(exemplar, 7, &string_entries(*attributes)?)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes sorted `KeyValue` entries with string `AnyValue`s.
+fn write_key_values(
+    writer: &mut ProtoWriter,
+    field: u32,
+    entries: &[(&[u8], &[u8])],
+) -> EncodeResult<()> {
+    for (key, value) in entries {
+        writer.message(field, |key_value| {
+            key_value.bytes(1, key);
+            key_value.message(2, |any_value| {
+                any_value.bytes_always(1, value);
+                Ok(())
+            })
+        })?;
+    }
+    Ok(())
+}
+
+// ── Value helpers ─────────────────────────────────────────────────────────
+
+fn text<'a>(value: Term<'a>) -> EncodeResult<&'a [u8]> {
+    value
+        .decode::<Binary>()
+        .map(|binary| binary.as_slice())
+        .map_err(|_| "mapped string field is not a binary".to_string())
+}
+
+fn optional_text<'a>(values: &[Term<'a>], index: Option<usize>) -> EncodeResult<&'a [u8]> {
+    index.map_or(Ok(&[][..]), |index| text(values[index]))
+}
+
+fn string_entries<'a>(value: Term<'a>) -> EncodeResult<Vec<(&'a [u8], &'a [u8])>> {
+    let entries =
+        MapIterator::new(value).ok_or_else(|| "mapped attribute field is not a map".to_string())?;
+    let mut entries = entries
+        .map(|(key, value)| Ok((text(key)?, text(value)?)))
+        .collect::<EncodeResult<Vec<_>>>()?;
+    entries.sort_unstable();
+    Ok(entries)
+}
+
+fn list_values(value: Term) -> EncodeResult<Vec<Term>> {
+    Ok(clickhouse_rowbinary::list(value)?.1.collect())
+}
+
+fn u64_list(value: Term) -> EncodeResult<Vec<u64>> {
+    clickhouse_rowbinary::list(value)?
+        .1
+        .map(decode_u64)
+        .collect()
+}
+
+fn f64_list(value: Term) -> EncodeResult<Vec<f64>> {
+    clickhouse_rowbinary::list(value)?
+        .1
+        .map(decode_f64)
+        .collect()
+}
+
+fn decode_u8(value: Term) -> EncodeResult<u8> {
+    u8::try_from(decode_u64(value)?).map_err(|_| "mapped UInt8 field is out of range".to_string())
+}
+
+fn decode_i32(value: Term) -> EncodeResult<i32> {
+    i32::try_from(decode_i64(value)?).map_err(|_| "mapped Int32 field is out of range".to_string())
+}
+
+fn decode_bool(value: Term) -> EncodeResult<bool> {
+    value
+        .decode::<bool>()
+        .map_err(|_| "mapped boolean field is not a boolean".to_string())
+}
+
+/// DateTime64 values in nanoseconds; `nil` and negative times encode as 0.
+fn time_nanos(values: &[Term], column: TimeColumn) -> EncodeResult<u64> {
+    match values[column.index].decode::<Option<i64>>() {
+        Ok(Some(value)) => Ok(nanos(value, column.scale)),
+        Ok(None) => Ok(0),
+        Err(_) => Err("mapped DateTime64 field is neither nil nor an integer".to_string()),
+    }
+}
+
+fn nanos(value: i64, scale: i64) -> u64 {
+    u64::try_from(value.saturating_mul(scale)).unwrap_or(0)
+}
+
+/// Decodes hex trace and span IDs to bytes. Anything that is not an even
+/// number of hex digits is dropped.
+fn hex_id(value: &[u8]) -> Vec<u8> {
+    fn digit(byte: u8) -> Option<u8> {
+        match byte {
+            b'0'..=b'9' => Some(byte - b'0'),
+            b'a'..=b'f' => Some(byte - b'a' + 10),
+            b'A'..=b'F' => Some(byte - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    if !value.len().is_multiple_of(2) {
+        return Vec::new();
+    }
+    value
+        .chunks_exact(2)
+        .map(|pair| Some((digit(pair[0])? << 4) | digit(pair[1])?))
+        .collect::<Option<Vec<u8>>>()
+        .unwrap_or_default()
+}
+
+/// Resolves an OTel enum from its short name (`"Server"`), its proto name
+/// (`"SPAN_KIND_SERVER"`) or its number; unknown values encode as 0.
+fn otel_enum(value: &[u8], prefix: &str, names: &[&str]) -> u64 {
+    let Ok(value) = std::str::from_utf8(value) else {
+        return 0;
+    };
+    let value = value.trim();
+    let name = match value.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => &value[prefix.len()..],
+        _ => value,
+    };
+    if let Some(position) = names
+        .iter()
+        .position(|candidate| candidate.eq_ignore_ascii_case(name))
+    {
+        return position as u64;
+    }
+    match name.parse::<u64>() {
+        Ok(number) if (number as usize) < names.len() => number,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_ids_decode_to_bytes() {
+        assert_eq!(hex_id(b"0aFf"), vec![0x0a, 0xff]);
+        assert!(hex_id(b"abc").is_empty());
+        assert!(hex_id(b"zz").is_empty());
+        assert!(hex_id(b"").is_empty());
+    }
+
+    #[test]
+    fn otel_enums_accept_short_proto_and_numeric_names() {
+        assert_eq!(otel_enum(b"Server", "SPAN_KIND_", SPAN_KINDS), 2);
+        assert_eq!(
+            otel_enum(b"SPAN_KIND_CONSUMER", "SPAN_KIND_", SPAN_KINDS),
+            5
+        );
+        assert_eq!(otel_enum(b"2", "STATUS_CODE_", STATUS_CODES), 2);
+        assert_eq!(otel_enum(b"9", "STATUS_CODE_", STATUS_CODES), 0);
+        assert_eq!(
+            otel_enum(
+                b"delta",
+                "AGGREGATION_TEMPORALITY_",
+                AGGREGATION_TEMPORALITIES
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn proto_writer_skips_defaults_and_zigzags_sint32() {
+        let mut writer = ProtoWriter::default();
+        writer.uint(1, 0);
+        writer.bytes(2, b"");
+        writer.fixed64(3, 0);
+        assert!(writer.buffer.is_empty());
+
+        writer.sint32(1, -1);
+        writer.bytes_always(2, b"");
+        writer.uint(3, 300);
+        assert_eq!(
+            writer.buffer,
+            vec![0x08, 0x01, 0x12, 0x00, 0x18, 0xac, 0x02]
+        );
+    }
+}
@@ -21,6 +21,10 @@ pub struct CompiledPath {
     pub wildcard_index: Option<usize>,
     pub flat_key: Option<String>,
     pub flat_cache_index: Option<usize>,
+    /// Per segment, the slot of its key term in the mapping's key table, so
+    /// key binaries are built once per NIF call instead of per lookup.
+    pub key_slots: Vec<Option<usize>>,
+    pub flat_key_slot: Option<usize>,
 }
 
 pub fn compile(path: &str) -> Result<CompiledPath, String> {
@@ -34,6 +38,7 @@ pub fn compile(path: &str) -> Result<CompiledPath, String> {
         .collect::<Option<Vec<_>>>()
         .map(|parts| parts.join("."));
     let cache_indices = vec![None; segments.len()];
+    let key_slots = vec![None; segments.len()];
     let wildcard_index = segments
         .iter()
         .position(|segment| matches!(segment, PathSegment::Wildcard));
@@ -45,6 +50,8 @@ pub fn compile(path: &str) -> Result<CompiledPath, String> {
         wildcard_index,
         flat_key,
         flat_cache_index: None,
+        key_slots,
+        flat_key_slot: None,
     })
 }
 
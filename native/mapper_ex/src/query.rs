@@ -1,41 +1,217 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use rustler::types::map::MapIterator;
 use rustler::types::ListIterator;
-use rustler::{Binary, Encoder, Env, Term};
+use rustler::{Atom, Binary, Encoder, Env, Term};
 
+use crate::json_input::JsonValue;
 use crate::path::{CompiledPath, PathSegment};
 use crate::string_filters::{self, StringFilters};
 
+/// Which document map keys a mapping reads: binaries (the default), atoms,
+/// or either, trying the binary key first.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum KeyMode {
+    #[default]
+    Binary,
+    Atom,
+    Either,
+}
+
+impl KeyMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "binary" => Some(KeyMode::Binary),
+            "atom" => Some(KeyMode::Atom),
+            "either" => Some(KeyMode::Either),
+            _ => None,
+        }
+    }
+
+    /// The name of a map key this mode reads: a binary's bytes or an atom's
+    /// name. `None` for keys the mode ignores.
+    pub fn key_name<'a>(self, key: Term<'a>) -> Option<Cow<'a, [u8]>> {
+        if self != KeyMode::Atom {
+            if let Ok(binary) = key.decode::<Binary<'a>>() {
+                return Some(Cow::Borrowed(binary.as_slice()));
+            }
+        }
+        if self != KeyMode::Binary && key.is_atom() {
+            return key
+                .atom_to_string()
+                .ok()
+                .map(|name| Cow::Owned(name.into_bytes()));
+        }
+        None
+    }
+}
+
+/// A path key as a binary and, when the atom already exists, an atom.
+#[derive(Clone, Copy)]
+struct KeyTerms<'a> {
+    binary: Term<'a>,
+    atom: Option<Term<'a>>,
+}
+
+impl<'a> KeyTerms<'a> {
+    fn new(env: Env<'a>, key: &str, mode: KeyMode) -> Self {
+        // Only look up existing atoms: a key no atom exists for cannot be in
+        // any map, and creating one per path key would grow the atom table.
+        let atom = (mode != KeyMode::Binary)
+            .then(|| Atom::try_from_bytes(env, key.as_bytes()).ok().flatten())
+            .flatten()
+            .map(|atom| atom.to_term(env));
+        KeyTerms {
+            binary: crate::encode_string(env, key),
+            atom,
+        }
+    }
+
+    fn lookup(&self, map: Term<'a>, mode: KeyMode) -> Option<Term<'a>> {
+        let binary = || map.map_get(self.binary).ok();
+        let atom = || self.atom.and_then(|atom| map.map_get(atom).ok());
+        match mode {
+            KeyMode::Binary => binary(),
+            KeyMode::Atom => atom(),
+            KeyMode::Either => binary().or_else(atom),
+        }
+    }
+}
+
+/// Looks up a single `key` in `map` under `mode`.
+pub fn lookup_key<'a>(env: Env<'a>, map: Term<'a>, key: &str, mode: KeyMode) -> Option<Term<'a>> {
+    KeyTerms::new(env, key, mode).lookup(map, mode)
+}
+
+/// Maps whose keys are read by enough paths to be worth scanning once per
+/// document: each key's bytes are hashed against the wanted keys instead of
+/// building a key term for every lookup.
+#[derive(Debug, Default)]
+pub struct Preloads {
+    pub nodes: Vec<PreloadNode>,
+    /// Per path cache index, the node whose scan fills it.
+    pub node_of: Vec<Option<usize>>,
+}
+
+#[derive(Debug)]
+pub struct PreloadNode {
+    /// The path prefix whose value is scanned; empty for the document root.
+    pub prefix: Vec<PathSegment>,
+    /// Path cache index of each wanted key.
+    pub keys: HashMap<Vec<u8>, usize>,
+    /// Largest map scanned; bigger maps fall back to single lookups.
+    pub scan_limit: usize,
+}
+
 pub struct QueryCache<'a> {
     values: Vec<Option<Term<'a>>>,
-    root_size: usize,
-    root_preloaded: bool,
+    preloads: Arc<Preloads>,
+    /// Per preload node, whether this document's map was scanned.
+    scanned: Vec<bool>,
     nil: Term<'a>,
+    /// Key terms by key slot, allocated on the first lookup that needs one.
+    /// They belong to the NIF call's env rather than to one document, so
+    /// `clear` keeps them for the next document.
+    keys: Vec<Option<KeyTerms<'a>>>,
+    key_count: usize,
+    key_mode: KeyMode,
+    /// The JSON document being mapped, if any; paths are evaluated over it
+    /// instead of the document term. See `load_json`.
+    json: Option<JsonValue<'a>>,
+    /// The whole JSON document built as a term, once a path reads it.
+    json_root: Option<Term<'a>>,
 }
 
 impl<'a> QueryCache<'a> {
-    pub fn new(size: usize, root_size: usize, nil: Term<'a>) -> Self {
+    pub fn new(
+        size: usize,
+        preloads: Arc<Preloads>,
+        key_count: usize,
+        key_mode: KeyMode,
+        nil: Term<'a>,
+    ) -> Self {
         Self {
             values: vec![None; size],
-            root_size,
-            root_preloaded: false,
+            scanned: vec![false; preloads.nodes.len()],
+            preloads,
             nil,
+            keys: Vec::new(),
+            key_count,
+            key_mode,
+            json: None,
+            json_root: None,
         }
     }
 
-    pub fn preload_root(&mut self, document: Term<'a>, keys: &HashMap<Vec<u8>, usize>) {
-        if keys.is_empty() {
+    pub fn key_mode(&self) -> KeyMode {
+        self.key_mode
+    }
+
+    pub fn clear(&mut self) {
+        self.values.fill(None);
+        self.scanned.fill(false);
+        self.json = None;
+        self.json_root = None;
+    }
+
+    /// Maps `document` until the next `clear`: paths are evaluated over it
+    /// and only the values they resolve to are built as terms. Returns the
+    /// term to pass as the document, which nothing reads.
+    pub fn load_json(&mut self, document: JsonValue<'a>) -> Term<'a> {
+        self.json = Some(document);
+        self.nil
+    }
+
+    /// Looks `key` up in `map` under the key mode, building the key's terms
+    /// on first use of its slot.
+    #[inline]
+    fn lookup(
+        &mut self,
+        env: Env<'a>,
+        map: Term<'a>,
+        slot: Option<usize>,
+        key: &str,
+    ) -> Option<Term<'a>> {
+        let mode = self.key_mode;
+        let terms = match slot {
+            Some(slot) => {
+                if self.keys.is_empty() {
+                    self.keys.resize(self.key_count, None);
+                }
+                *self.keys[slot].get_or_insert_with(|| KeyTerms::new(env, key, mode))
+            }
+            None => KeyTerms::new(env, key, mode),
+        };
+        terms.lookup(map, mode)
+    }
+
+    /// Scans `map`, the value of preload node `node`, into the cache slots
+    /// of its wanted keys. Maps over the node's scan limit are left to
+    /// single lookups.
+    fn preload(&mut self, node: usize, map: Term<'a>) {
+        let preload = &self.preloads.nodes[node];
+        if map
+            .map_size()
+            .map_or(true, |size| size > preload.scan_limit)
+        {
             return;
         }
 
-        self.root_preloaded = true;
-        if let Some(entries) = MapIterator::new(document) {
-            for (key, value) in entries {
-                if let Ok(binary) = key.decode::<Binary>() {
-                    if let Some(index) = keys.get(binary.as_slice()) {
-                        self.values[*index] = Some(value);
-                    }
+        self.scanned[node] = true;
+        let Some(entries) = MapIterator::new(map) else {
+            return;
+        };
+        for (key, value) in entries {
+            let Some(name) = self.key_mode.key_name(key) else {
+                continue;
+            };
+            if let Some(&index) = preload.keys.get(name.as_ref()) {
+                // Under `Either` a binary key wins over an atom of the
+                // same name, as it does for lookups.
+                if self.values[index].is_none() || key.is_binary() {
+                    self.values[index] = Some(value);
                 }
             }
         }
@@ -44,11 +220,28 @@ impl<'a> QueryCache<'a> {
     #[inline]
     fn get(&self, index: Option<usize>) -> Option<Term<'a>> {
         index.and_then(|index| {
-            self.values[index]
-                .or_else(|| (self.root_preloaded && index < self.root_size).then_some(self.nil))
+            self.values[index].or_else(|| {
+                let node = self.preloads.node_of[index]?;
+                self.scanned[node].then_some(self.nil)
+            })
         })
     }
 
+    /// `get`, first scanning `map` when `index` belongs to a preload node
+    /// that has not been scanned for this document.
+    #[inline]
+    fn get_or_preload(&mut self, index: Option<usize>, map: Term<'a>) -> Option<Term<'a>> {
+        if let Some(value) = self.get(index) {
+            return Some(value);
+        }
+        let node = self.preloads.node_of[index?]?;
+        if self.scanned[node] {
+            return None;
+        }
+        self.preload(node, map);
+        self.get(index)
+    }
+
     #[inline]
     fn put(&mut self, index: Option<usize>, value: Term<'a>) {
         if let Some(index) = index {
@@ -76,17 +269,28 @@ pub fn evaluate<'a>(
     cache: &mut QueryCache<'a>,
 ) -> Term<'a> {
     if path.segments.is_empty() {
-        return term;
+        return root(env, term, cache);
+    }
+    if cache.json.is_some() {
+        return evaluate_json(env, path, nil, flat_keys, cache);
     }
 
     if flat_keys {
         return evaluate_flat(env, term, path, nil, cache);
     }
     if !path.cached {
-        return evaluate_uncached(env, term, &path.segments, nil);
+        return evaluate_uncached(env, term, &path.segments, &path.key_slots, nil, cache);
     }
 
-    evaluate_nested(env, term, &path.segments, &path.cache_indices, nil, cache)
+    evaluate_nested(
+        env,
+        term,
+        &path.segments,
+        &path.cache_indices,
+        &path.key_slots,
+        nil,
+        cache,
+    )
 }
 
 pub fn evaluate_wildcard_mapped<'a, F>(
@@ -105,6 +309,18 @@ where
         return None;
     }
     let wildcard_index = path.wildcard_index?;
+    if let Some(document) = &cache.json {
+        let Some(JsonValue::Array(elements)) = document.select(&path.segments[..wildcard_index])
+        else {
+            return Some(Vec::<Term>::new().encode(env));
+        };
+        let remaining = &path.segments[wildcard_index + 1..];
+        let results: Vec<Term<'a>> = elements
+            .iter()
+            .filter_map(|element| mapper(element.evaluate(env, remaining, nil)))
+            .collect();
+        return Some(results.encode(env));
+    }
     let current = if wildcard_index == 0 {
         term
     } else if path.cached {
@@ -113,11 +329,19 @@ where
             term,
             &path.segments[..wildcard_index],
             &path.cache_indices[..wildcard_index],
+            &path.key_slots[..wildcard_index],
             nil,
             cache,
         )
     } else {
-        evaluate_uncached(env, term, &path.segments[..wildcard_index], nil)
+        evaluate_uncached(
+            env,
+            term,
+            &path.segments[..wildcard_index],
+            &path.key_slots[..wildcard_index],
+            nil,
+            cache,
+        )
     };
 
     let iter = match current.decode::<ListIterator>() {
@@ -125,9 +349,10 @@ where
         Err(_) => return Some(Vec::<Term>::new().encode(env)),
     };
     let remaining = &path.segments[wildcard_index + 1..];
+    let remaining_slots = &path.key_slots[wildcard_index + 1..];
     let mut results = Vec::new();
     for item in iter {
-        let value = evaluate_uncached(env, item, remaining, nil);
+        let value = evaluate_uncached(env, item, remaining, remaining_slots, nil, cache);
         if let Some(value) = mapper(value) {
             results.push(value);
         }
@@ -135,20 +360,69 @@ where
     Some(results.encode(env))
 }
 
+/// The whole document: `term`, or the loaded JSON document, built as a term
+/// on first use.
+pub fn root<'a>(env: Env<'a>, term: Term<'a>, cache: &mut QueryCache<'a>) -> Term<'a> {
+    let Some(document) = &cache.json else {
+        return term;
+    };
+    if let Some(root) = cache.json_root {
+        return root;
+    }
+    let root = document.to_term(env, cache.nil);
+    cache.json_root = Some(root);
+    root
+}
+
+/// `evaluate` over the loaded JSON document. The term a path resolves to is
+/// kept in the cache slot of its last segment, for paths sharing it.
+fn evaluate_json<'a>(
+    env: Env<'a>,
+    path: &CompiledPath,
+    nil: Term<'a>,
+    flat_keys: bool,
+    cache: &mut QueryCache<'a>,
+) -> Term<'a> {
+    let index = if flat_keys {
+        path.flat_cache_index
+    } else {
+        path.cache_indices.last().copied().flatten()
+    };
+    if let Some(value) = cache.get(index) {
+        return value;
+    }
+    let Some(document) = &cache.json else {
+        return nil;
+    };
+
+    let value = if flat_keys {
+        path.flat_key
+            .as_deref()
+            .and_then(|key| document.get(key))
+            .map_or(nil, |value| value.to_term(env, nil))
+    } else {
+        document.evaluate(env, &path.segments, nil)
+    };
+    cache.put(index, value);
+    value
+}
+
 fn evaluate_uncached<'a>(
     env: Env<'a>,
     term: Term<'a>,
     segments: &[PathSegment],
+    key_slots: &[Option<usize>],
     nil: Term<'a>,
+    cache: &mut QueryCache<'a>,
 ) -> Term<'a> {
     let mut current = term;
     let mut i = 0;
 
     while i < segments.len() {
         match &segments[i] {
-            PathSegment::Key(key) => match current.map_get(crate::encode_string(env, key)) {
-                Ok(value) => current = value,
-                Err(_) => return nil,
+            PathSegment::Key(key) => match cache.lookup(env, current, key_slots[i], key) {
+                Some(value) => current = value,
+                None => return nil,
             },
             PathSegment::Index(index) => match current.decode::<ListIterator>() {
                 Ok(mut iter) => match iter.nth(*index) {
@@ -163,7 +437,10 @@ fn evaluate_uncached<'a>(
                     Err(_) => return nil,
                 };
                 let values: Vec<Term<'a>> = iter
-                    .map(|item| evaluate_uncached(env, item, &segments[i + 1..], nil))
+                    .map(|item| {
+                        let rest = &segments[i + 1..];
+                        evaluate_uncached(env, item, rest, &key_slots[i + 1..], nil, cache)
+                    })
                     .collect();
                 return values.encode(env);
             }
@@ -180,6 +457,7 @@ fn evaluate_nested<'a>(
     term: Term<'a>,
     segments: &[PathSegment],
     cache_indices: &[Option<usize>],
+    key_slots: &[Option<usize>],
     nil: Term<'a>,
     cache: &mut QueryCache<'a>,
 ) -> Term<'a> {
@@ -188,7 +466,7 @@ fn evaluate_nested<'a>(
 
     while i < segments.len() {
         let cache_index = cache_indices[i];
-        if let Some(value) = cache.get(cache_index) {
+        if let Some(value) = cache.get_or_preload(cache_index, current) {
             if value == nil {
                 return nil;
             }
@@ -198,15 +476,14 @@ fn evaluate_nested<'a>(
         }
 
         let value = match &segments[i] {
-            PathSegment::Key(key) => current
-                .map_get(crate::encode_string(env, key))
-                .unwrap_or(nil),
+            PathSegment::Key(key) => cache.lookup(env, current, key_slots[i], key).unwrap_or(nil),
             PathSegment::Wildcard => {
                 return evaluate_wildcard(
                     env,
                     current,
                     &segments[i + 1..],
                     &cache_indices[i + 1..],
+                    &key_slots[i + 1..],
                     nil,
                     cache,
                 );
@@ -245,7 +522,9 @@ fn evaluate_flat<'a>(
         return value;
     }
 
-    let value = term.map_get(crate::encode_string(env, key)).unwrap_or(nil);
+    let value = cache
+        .lookup(env, term, path.flat_key_slot, key)
+        .unwrap_or(nil);
     cache.put(path.flat_cache_index, value);
     value
 }
@@ -255,6 +534,7 @@ fn evaluate_wildcard<'a>(
     term: Term<'a>,
     segments: &[PathSegment],
     cache_indices: &[Option<usize>],
+    key_slots: &[Option<usize>],
     nil: Term<'a>,
     cache: &mut QueryCache<'a>,
 ) -> Term<'a> {
@@ -263,11 +543,19 @@ fn evaluate_wildcard<'a>(
         Err(_) => return nil,
     };
     let results: Vec<Term<'a>> = iter
-        .map(|item| evaluate_nested(env, item, segments, cache_indices, nil, cache))
+        .map(|item| evaluate_nested(env, item, segments, cache_indices, key_slots, nil, cache))
         .collect();
     results.encode(env)
 }
 
+/// Why `evaluate_first` passed over a candidate path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Skip {
+    Missing,
+    Empty,
+    Filter(&'static str),
+}
+
 /// Evaluates multiple paths against a document, returning the first
 /// non-nil result. For String field types, also skips empty strings.
 /// When filters are provided, resolved strings must pass all filters.
@@ -281,26 +569,69 @@ pub fn evaluate_first<'a>(
     flat_keys: bool,
     cache: &mut QueryCache<'a>,
 ) -> Term<'a> {
+    evaluate_first_indexed(
+        env,
+        document,
+        paths,
+        string_options,
+        nil,
+        flat_keys,
+        cache,
+        |_, _, _| {},
+    )
+    .map_or(nil, |(_, value)| value)
+}
+
+/// Like `evaluate_first`, but returns the index of the matching path and
+/// reports every candidate passed over on the way to it.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub fn evaluate_first_indexed<'a, F>(
+    env: Env<'a>,
+    document: Term<'a>,
+    paths: &[CompiledPath],
+    string_options: (bool, Option<&StringFilters>),
+    nil: Term<'a>,
+    flat_keys: bool,
+    cache: &mut QueryCache<'a>,
+    mut on_skip: F,
+) -> Option<(usize, Term<'a>)>
+where
+    F: FnMut(usize, Term<'a>, Skip),
+{
     let (skip_empty_strings, filters) = string_options;
-    for path in paths {
+    for (index, path) in paths.iter().enumerate() {
         let result = evaluate(env, document, path, nil, flat_keys, cache);
-        if result == nil {
+        if let Some(skip) = rejection(result, skip_empty_strings, filters, nil) {
+            on_skip(index, result, skip);
             continue;
         }
-        if skip_empty_strings {
-            if let Ok(binary) = result.decode::<Binary>() {
-                if binary.is_empty() {
-                    continue;
-                }
-                if let Some(filters) = filters {
-                    if !string_filters::passes_filters(binary.as_slice(), filters) {
-                        continue;
-                    }
-                }
-            }
-        }
-        return result;
+        return Some((index, result));
     }
 
-    nil
+    None
+}
+
+/// Returns why a resolved value is unusable: missing, or for String fields an
+/// empty string or one failing the configured filters.
+#[inline]
+pub fn rejection<'a>(
+    value: Term<'a>,
+    skip_empty_strings: bool,
+    filters: Option<&StringFilters>,
+    nil: Term<'a>,
+) -> Option<Skip> {
+    if value == nil {
+        return Some(Skip::Missing);
+    }
+    if !skip_empty_strings {
+        return None;
+    }
+    let binary = value.decode::<Binary>().ok()?;
+    if binary.is_empty() {
+        return Some(Skip::Empty);
+    }
+    filters
+        .and_then(|filters| string_filters::failed_filter(binary.as_slice(), filters))
+        .map(Skip::Filter)
 }
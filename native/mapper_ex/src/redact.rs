@@ -0,0 +1,383 @@
+use std::net::Ipv6Addr;
+
+use regex::Regex;
+use rustler::types::list::ListIterator;
+use rustler::types::map::MapIterator;
+use rustler::{Binary, Encoder, Env, Term};
+
+use crate::keyed_hash::KeyedHash;
+
+/// Mapping-level redaction rules, applied in order to string field values
+/// and to every string inside `Json` and `FlatMap` values.
+#[derive(Debug)]
+pub struct Redactor {
+    rules: Vec<RedactRule>,
+}
+
+#[derive(Debug)]
+pub struct RedactRule {
+    regex: Regex,
+    /// Rejects regex matches that are not really PII, e.g. digit runs that
+    /// fail the Luhn check.
+    validate: Option<Validator>,
+    /// Characters that may not directly precede or follow a match, for
+    /// patterns that cannot be anchored with `\b`.
+    isolate: Option<fn(char) -> bool>,
+    action: RedactAction,
+}
+
+type Validator = fn(&str) -> bool;
+
+#[derive(Debug)]
+pub enum RedactAction {
+    Mask(String),
+    /// Keyed with the mapping's `hash_key`, so matches cannot be recovered
+    /// by hashing guesses.
+    Hash(KeyedHash),
+    Drop,
+}
+
+/// Outcome of redacting one string.
+#[derive(Debug, PartialEq)]
+pub enum Redaction {
+    Unchanged,
+    Replaced(String),
+    /// A `drop` rule matched, so the whole value is discarded.
+    Drop,
+}
+
+pub const DEFAULT_MASK: &str = "[REDACTED]";
+
+/// Name of the capture group that narrows a match to the part to replace,
+/// e.g. the token after `Bearer `.
+const SECRET_GROUP: &str = "secret";
+
+impl RedactRule {
+    /// Builds a rule from a built-in detector name.
+    pub fn detector(name: &str, action: RedactAction) -> Result<Self, String> {
+        let (pattern, validate): (&str, Option<Validator>) = match name {
+            "email" => (
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+                None,
+            ),
+            "card_number" => (r"\b\d(?:[ -]?\d){12,18}\b", Some(luhn_valid)),
+            "bearer_token" => (r"(?i)\bbearer\s+(?P<secret>[A-Za-z0-9\-._~+/]+=*)", None),
+            "ipv4" => (
+                r"\b(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\b",
+                None,
+            ),
+            "ipv6" => (
+                r"[0-9A-Fa-f]*:[0-9A-Fa-f:]*:[0-9A-Fa-f:.]*",
+                Some(ipv6_valid),
+            ),
+            other => return Err(format!("unknown redaction detector: {}", other)),
+        };
+        // An address can start or end with `:`, so `\b` cannot anchor it; this
+        // keeps `::` in names such as `Foo::Bar` and `std::fs::read` intact.
+        let isolate: Option<fn(char) -> bool> = match name {
+            "ipv6" => Some(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'),
+            _ => None,
+        };
+        Ok(RedactRule {
+            regex: Regex::new(pattern).expect("built-in detector patterns are valid"),
+            validate,
+            isolate,
+            action,
+        })
+    }
+
+    /// Builds a rule from a custom regex. A `(?P<secret>...)` group limits the
+    /// replacement to that part of the match.
+    pub fn pattern(pattern: &str, action: RedactAction) -> Result<Self, String> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("invalid redaction pattern {:?}: {}", pattern, e))?;
+        Ok(RedactRule {
+            regex,
+            validate: None,
+            isolate: None,
+            action,
+        })
+    }
+
+    fn apply(&self, input: &str) -> Redaction {
+        let has_secret = self
+            .regex
+            .capture_names()
+            .flatten()
+            .any(|n| n == SECRET_GROUP);
+        let mut output = String::new();
+        let mut last = 0;
+        let mut matched = false;
+
+        let spans = self.regex.captures_iter(input).filter_map(|captures| {
+            let span = if has_secret {
+                captures.name(SECRET_GROUP)?
+            } else {
+                captures.get(0)?
+            };
+            let touches = |isolate: fn(char) -> bool| {
+                input[..span.start()]
+                    .chars()
+                    .next_back()
+                    .is_some_and(isolate)
+                    || input[span.end()..].chars().next().is_some_and(isolate)
+            };
+            match (self.validate, self.isolate) {
+                _ if span.is_empty() => None,
+                (Some(validate), _) if !validate(span.as_str()) => None,
+                (_, Some(isolate)) if touches(isolate) => None,
+                _ => Some(span),
+            }
+        });
+
+        for span in spans {
+            let replacement = match &self.action {
+                RedactAction::Drop => return Redaction::Drop,
+                RedactAction::Mask(mask) => mask.clone(),
+                RedactAction::Hash(hash) => hash.hash_hex(span.as_str().as_bytes()),
+            };
+            output.push_str(&input[last..span.start()]);
+            output.push_str(&replacement);
+            last = span.end();
+            matched = true;
+        }
+
+        if !matched {
+            return Redaction::Unchanged;
+        }
+        output.push_str(&input[last..]);
+        Redaction::Replaced(output)
+    }
+}
+
+impl Redactor {
+    pub fn new(rules: Vec<RedactRule>) -> Self {
+        Redactor { rules }
+    }
+
+    pub fn redact(&self, input: &str) -> Redaction {
+        let mut current: Option<String> = None;
+        for rule in &self.rules {
+            match rule.apply(current.as_deref().unwrap_or(input)) {
+                Redaction::Unchanged => {}
+                Redaction::Replaced(redacted) => current = Some(redacted),
+                Redaction::Drop => return Redaction::Drop,
+            }
+        }
+        current.map_or(Redaction::Unchanged, Redaction::Replaced)
+    }
+}
+
+fn luhn_valid(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (1, doubled) if doubled > 9 => doubled - 9,
+            (1, doubled) => doubled,
+            _ => d,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn ipv6_valid(candidate: &str) -> bool {
+    candidate.trim_end_matches('.').parse::<Ipv6Addr>().is_ok()
+}
+
+/// Redacts a string term. Returns `None` when a `drop` rule matched;
+/// non-string and non-UTF-8 terms are returned unchanged.
+pub fn redact_string<'a>(env: Env<'a>, value: Term<'a>, redactor: &Redactor) -> Option<Term<'a>> {
+    redact_term(env, value, redactor, false).into_term(value)
+}
+
+/// Redacts every string nested in maps and lists. Map entries and list
+/// elements whose value is dropped are removed; unchanged containers are
+/// returned as-is without rebuilding.
+pub fn redact_nested<'a>(env: Env<'a>, value: Term<'a>, redactor: &Redactor) -> Option<Term<'a>> {
+    redact_term(env, value, redactor, true).into_term(value)
+}
+
+enum Redacted<'a> {
+    Unchanged,
+    Changed(Term<'a>),
+    Dropped,
+}
+
+impl<'a> Redacted<'a> {
+    fn into_term(self, original: Term<'a>) -> Option<Term<'a>> {
+        match self {
+            Redacted::Unchanged => Some(original),
+            Redacted::Changed(term) => Some(term),
+            Redacted::Dropped => None,
+        }
+    }
+}
+
+fn redact_term<'a>(
+    env: Env<'a>,
+    value: Term<'a>,
+    redactor: &Redactor,
+    nested: bool,
+) -> Redacted<'a> {
+    if let Ok(binary) = value.decode::<Binary>() {
+        let Ok(text) = std::str::from_utf8(binary.as_slice()) else {
+            return Redacted::Unchanged;
+        };
+        return match redactor.redact(text) {
+            Redaction::Unchanged => Redacted::Unchanged,
+            Redaction::Replaced(redacted) => {
+                Redacted::Changed(crate::encode_string(env, &redacted))
+            }
+            Redaction::Drop => Redacted::Dropped,
+        };
+    }
+    if !nested {
+        return Redacted::Unchanged;
+    }
+
+    if let Some(iter) = MapIterator::new(value) {
+        let mut changed = false;
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        for (key, child) in iter {
+            match redact_term(env, child, redactor, true) {
+                Redacted::Unchanged => {
+                    keys.push(key);
+                    values.push(child);
+                }
+                Redacted::Changed(redacted) => {
+                    changed = true;
+                    keys.push(key);
+                    values.push(redacted);
+                }
+                Redacted::Dropped => changed = true,
+            }
+        }
+        return if changed {
+            Redacted::Changed(
+                Term::map_from_term_arrays(env, &keys, &values)
+                    .unwrap_or_else(|_| Term::map_new(env)),
+            )
+        } else {
+            Redacted::Unchanged
+        };
+    }
+
+    if let Ok(iter) = value.decode::<ListIterator>() {
+        let mut changed = false;
+        let mut elements = Vec::new();
+        for element in iter {
+            match redact_term(env, element, redactor, true) {
+                Redacted::Unchanged => elements.push(element),
+                Redacted::Changed(redacted) => {
+                    changed = true;
+                    elements.push(redacted);
+                }
+                Redacted::Dropped => changed = true,
+            }
+        }
+        return if changed {
+            Redacted::Changed(elements.encode(env))
+        } else {
+            Redacted::Unchanged
+        };
+    }
+
+    Redacted::Unchanged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyed_hash::HashAlgorithm;
+
+    fn redactor(detectors: &[&str], action: impl Fn() -> RedactAction) -> Redactor {
+        Redactor::new(
+            detectors
+                .iter()
+                .map(|name| RedactRule::detector(name, action()).unwrap())
+                .collect(),
+        )
+    }
+
+    fn mask() -> RedactAction {
+        RedactAction::Mask(DEFAULT_MASK.to_string())
+    }
+
+    #[test]
+    fn masks_built_in_detectors() {
+        let redactor = redactor(
+            &["email", "card_number", "bearer_token", "ipv4", "ipv6"],
+            mask,
+        );
+        let redact = |input: &str| match redactor.redact(input) {
+            Redaction::Replaced(output) => output,
+            other => panic!("expected a replacement for {input:?}, got {other:?}"),
+        };
+
+        assert_eq!(
+            redact("mail jane.doe@example.co.uk now"),
+            "mail [REDACTED] now"
+        );
+        assert_eq!(redact("card 4111 1111 1111 1111."), "card [REDACTED].");
+        assert_eq!(
+            redact("Authorization: Bearer abc.def-123"),
+            "Authorization: Bearer [REDACTED]"
+        );
+        assert_eq!(redact("from 10.0.0.12:443"), "from [REDACTED]:443");
+        assert_eq!(redact("peer 2001:db8::1 closed"), "peer [REDACTED] closed");
+        assert_eq!(
+            redactor.redact("order 4111111111111112"),
+            Redaction::Unchanged
+        );
+        assert_eq!(redactor.redact("at 12:30:45"), Redaction::Unchanged);
+        assert_eq!(redact("[::1]:8080 up"), "[[REDACTED]]:8080 up");
+        for name in [
+            "** (UndefinedFunctionError) Foo::Bar",
+            "Enum.map::1",
+            "thread panicked at std::fs::read",
+            "ActiveRecord::Base.connection",
+            "Net::HTTP::Get",
+        ] {
+            assert_eq!(redactor.redact(name), Redaction::Unchanged, "{name}");
+        }
+    }
+
+    #[test]
+    fn hashes_and_drops() {
+        let keyed = |secret: &'static [u8]| {
+            move || RedactAction::Hash(KeyedHash::new(HashAlgorithm::SipHash, secret))
+        };
+        let hashing = redactor(&["email"], keyed(b"secret"));
+        let Redaction::Replaced(first) = hashing.redact("a@example.com") else {
+            panic!("expected a hash");
+        };
+        assert_eq!(first.len(), 16);
+        assert_eq!(
+            hashing.redact("a@example.com"),
+            Redaction::Replaced(first.clone())
+        );
+        let rekeyed = redactor(&["email"], keyed(b"other"));
+        assert_ne!(rekeyed.redact("a@example.com"), Redaction::Replaced(first));
+
+        let dropping = redactor(&["email"], || RedactAction::Drop);
+        assert_eq!(dropping.redact("to a@example.com"), Redaction::Drop);
+        assert_eq!(dropping.redact("nothing here"), Redaction::Unchanged);
+    }
+
+    #[test]
+    fn custom_pattern_with_secret_group() {
+        let rule = RedactRule::pattern(r"api_key=(?P<secret>\w+)", mask()).unwrap();
+        assert_eq!(
+            Redactor::new(vec![rule]).redact("?api_key=s3cr3t&x=1"),
+            Redaction::Replaced("?api_key=[REDACTED]&x=1".to_string())
+        );
+        assert!(RedactRule::pattern("(", mask()).is_err());
+    }
+}
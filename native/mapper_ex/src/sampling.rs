@@ -0,0 +1,34 @@
+/// Returns whether a document whose sampling key is `key` is kept at `rate`,
+/// a fraction between 0 and 1.
+///
+/// A document is kept when the hash of its sampling key falls below the keep
+/// rate, so every document sharing a key (e.g. all spans and logs of one
+/// trace) gets the same decision, on every node and across restarts.
+pub fn keeps(key: &[u8], rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+    let hash = (cityhash_rs::cityhash_102_128(key) >> 64) as u64;
+    // Top 53 bits as a uniform fraction in [0, 1).
+    ((hash >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_a_stable_fraction_of_keys() {
+        let kept = (0..10_000)
+            .filter(|i| keeps(format!("trace-{i}").as_bytes(), 0.25))
+            .count();
+        assert!((2_300..2_700).contains(&kept), "kept {kept}");
+
+        assert_eq!(keeps(b"abc", 0.5), keeps(b"abc", 0.5));
+        assert!(keeps(b"abc", 1.0));
+        assert!(!keeps(b"abc", 0.0));
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+
+use rustler::{Encoder, Env, Term};
+
+use crate::mapper::{DefaultReason, Enum8Match, MapTrace};
+use crate::mapping::CompiledMapping;
+use crate::query::Skip;
+
+mod atoms {
+    rustler::atoms! {
+        documents,
+        fields,
+        field,
+        default_used,
+        coercion_failed,
+        filter_rejected,
+        value_map_miss,
+        enum8_unmatched,
+    }
+}
+
+/// Counters a mapping accumulates across every call that maps with it, until
+/// drained. Shared by all processes using the compiled mapping.
+#[derive(Debug)]
+pub struct MappingStats {
+    /// Held shared while a document is traced and exclusively while draining,
+    /// so a drain never splits one document's counts across two snapshots.
+    drain: RwLock<()>,
+    documents: AtomicU64,
+    fields: Vec<FieldCounters>,
+}
+
+#[derive(Debug, Default)]
+struct FieldCounters {
+    /// Values replaced by the field's default, for any reason.
+    default_used: AtomicU64,
+    /// Non-nil values the field's type could not represent, coerced to zero.
+    coercion_failed: AtomicU64,
+    /// Resolved strings a coalesce candidate's `filters` rejected.
+    filter_rejected: AtomicU64,
+    /// Values absent from the field's `value_map`.
+    value_map_miss: AtomicU64,
+    /// Enum8 values that matched no `enum_values` entry or `infer` rule.
+    enum8_unmatched: AtomicU64,
+}
+
+impl MappingStats {
+    pub fn new(field_count: usize) -> Self {
+        MappingStats {
+            drain: RwLock::new(()),
+            documents: AtomicU64::new(0),
+            fields: (0..field_count).map(|_| FieldCounters::default()).collect(),
+        }
+    }
+
+    /// A tracer recording one document's decisions into these counters.
+    pub fn trace(&self) -> StatsTrace<'_> {
+        let guard = self.drain.read().unwrap_or_else(PoisonError::into_inner);
+        bump(&self.documents);
+        StatsTrace {
+            stats: self,
+            index: 0,
+            _guard: guard,
+        }
+    }
+
+    /// Returns the counters accumulated since the last drain and resets them.
+    ///
+    /// Waits for documents being traced to finish, so every document in the
+    /// snapshot is counted with all of its field counts.
+    pub fn drain<'a>(&self, env: Env<'a>, mapping: &CompiledMapping) -> Term<'a> {
+        let (documents, counts) = {
+            let _guard = self.drain.write().unwrap_or_else(PoisonError::into_inner);
+            let counts: Vec<[u64; 5]> = self
+                .fields
+                .iter()
+                .map(|counters| {
+                    [
+                        take(&counters.default_used),
+                        take(&counters.coercion_failed),
+                        take(&counters.filter_rejected),
+                        take(&counters.value_map_miss),
+                        take(&counters.enum8_unmatched),
+                    ]
+                })
+                .collect();
+            (take(&self.documents), counts)
+        };
+
+        let keys = [
+            atoms::field().encode(env),
+            atoms::default_used().encode(env),
+            atoms::coercion_failed().encode(env),
+            atoms::filter_rejected().encode(env),
+            atoms::value_map_miss().encode(env),
+            atoms::enum8_unmatched().encode(env),
+        ];
+        let fields: Vec<Term<'a>> = mapping
+            .fields
+            .iter()
+            .zip(&counts)
+            .map(|(field, counts)| {
+                let values = [
+                    crate::encode_string(env, &field.name),
+                    counts[0].encode(env),
+                    counts[1].encode(env),
+                    counts[2].encode(env),
+                    counts[3].encode(env),
+                    counts[4].encode(env),
+                ];
+                encode_map(env, &keys, &values)
+            })
+            .collect();
+
+        encode_map(
+            env,
+            &[atoms::documents().encode(env), atoms::fields().encode(env)],
+            &[documents.encode(env), fields.encode(env)],
+        )
+    }
+}
+
+pub struct StatsTrace<'s> {
+    stats: &'s MappingStats,
+    index: usize,
+    _guard: RwLockReadGuard<'s, ()>,
+}
+
+impl StatsTrace<'_> {
+    fn counters(&self) -> &FieldCounters {
+        &self.stats.fields[self.index]
+    }
+}
+
+impl<'a> MapTrace<'a> for StatsTrace<'_> {
+    fn field(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    fn skipped(&mut self, _index: usize, _value: Term<'a>, skip: Skip) {
+        if let Skip::Filter(_) = skip {
+            bump(&self.counters().filter_rejected);
+        }
+    }
+
+    fn default_used(&mut self, _reason: DefaultReason) {
+        bump(&self.counters().default_used);
+    }
+
+    fn value_map(&mut self, hit: bool) {
+        if !hit {
+            bump(&self.counters().value_map_miss);
+        }
+    }
+
+    fn enum8(&mut self, resolution: Enum8Match) {
+        if resolution == Enum8Match::Default {
+            bump(&self.counters().enum8_unmatched);
+        }
+    }
+
+    fn coerce(&mut self, fell_back: bool) {
+        if fell_back {
+            bump(&self.counters().coercion_failed);
+        }
+    }
+}
+
+#[inline]
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+fn take(counter: &AtomicU64) -> u64 {
+    counter.swap(0, Ordering::Relaxed)
+}
+
+fn encode_map<'a>(env: Env<'a>, keys: &[Term<'a>], values: &[Term<'a>]) -> Term<'a> {
+    Term::map_from_term_arrays(env, keys, values).unwrap_or_else(|_| Term::map_new(env))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draining_resets_counters() {
+        let stats = MappingStats::new(2);
+        bump(&stats.documents);
+        bump(&stats.fields[1].default_used);
+        bump(&stats.fields[1].default_used);
+
+        assert_eq!(take(&stats.documents), 1);
+        assert_eq!(take(&stats.fields[0].default_used), 0);
+        assert_eq!(take(&stats.fields[1].default_used), 2);
+        assert_eq!(take(&stats.fields[1].default_used), 0);
+    }
+}
@@ -1,8 +1,12 @@
+use regex::bytes::{Regex, RegexBuilder};
+
 /// String filters for validating resolved values during path coalescing.
 ///
 /// Filters are checked after a string value is resolved: if the value
 /// doesn't pass, it's treated as "not found" and the next path is tried.
 /// All configured filters must pass (AND logic).
+///
+/// `CharClass` and `StringMatcher` are also used by inference predicates.
 
 #[derive(Debug)]
 pub struct StringFilters {
@@ -12,6 +16,7 @@ pub struct StringFilters {
     pub len_lt: Option<usize>,
     pub len_lte: Option<usize>,
     pub char_class: Option<CharClass>,
+    pub matchers: Vec<StringMatcher>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -19,61 +24,240 @@ pub enum CharClass {
     Alpha,
     Numeric,
     Alphanumeric,
+    UnicodeAlpha,
+    UnicodeNumeric,
+    UnicodeAlphanumeric,
+    /// ASCII hex digits of either case.
+    Hex,
+    /// Hyphenated 8-4-4-4-12 hex form.
+    Uuid,
+    /// Standard alphabet with `=` padding to a multiple of four.
+    Base64,
+}
+
+impl CharClass {
+    pub fn parse(name: &str) -> Option<CharClass> {
+        match name.to_lowercase().as_str() {
+            "alpha" => Some(CharClass::Alpha),
+            "numeric" => Some(CharClass::Numeric),
+            "alphanumeric" => Some(CharClass::Alphanumeric),
+            "unicode_alpha" => Some(CharClass::UnicodeAlpha),
+            "unicode_numeric" => Some(CharClass::UnicodeNumeric),
+            "unicode_alphanumeric" => Some(CharClass::UnicodeAlphanumeric),
+            "hex" => Some(CharClass::Hex),
+            "uuid" => Some(CharClass::Uuid),
+            "base64" => Some(CharClass::Base64),
+            _ => None,
+        }
+    }
+
+    /// ASCII classes work on bytes; Unicode classes require valid UTF-8.
+    pub fn matches(self, bytes: &[u8]) -> bool {
+        match self {
+            CharClass::Alpha => bytes.iter().all(|b| b.is_ascii_alphabetic()),
+            CharClass::Numeric => bytes.iter().all(|b| b.is_ascii_digit()),
+            CharClass::Alphanumeric => bytes.iter().all(|b| b.is_ascii_alphanumeric()),
+            CharClass::UnicodeAlpha => all_chars(bytes, char::is_alphabetic),
+            CharClass::UnicodeNumeric => all_chars(bytes, char::is_numeric),
+            CharClass::UnicodeAlphanumeric => all_chars(bytes, char::is_alphanumeric),
+            CharClass::Hex => bytes.iter().all(|b| b.is_ascii_hexdigit()),
+            CharClass::Uuid => is_uuid(bytes),
+            CharClass::Base64 => is_base64(bytes),
+        }
+    }
+}
+
+fn all_chars(bytes: &[u8], pred: fn(char) -> bool) -> bool {
+    std::str::from_utf8(bytes).is_ok_and(|s| s.chars().all(pred))
 }
 
-/// Check if a byte slice passes all configured filters.
+fn is_uuid(bytes: &[u8]) -> bool {
+    bytes.len() == 36
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => *b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+fn is_base64(bytes: &[u8]) -> bool {
+    if !bytes.len().is_multiple_of(4) {
+        return false;
+    }
+    let data = bytes
+        .strip_suffix(b"==")
+        .or_else(|| bytes.strip_suffix(b"="));
+    data.unwrap_or(bytes)
+        .iter()
+        .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+}
+
+/// A prefix, suffix, substring or regex test against a string value.
+///
+/// Plain prefix/suffix/substring tests compare bytes directly. A `matches`
+/// pattern, or any operator with `case_insensitive` set, is compiled to a
+/// regex so that case folding follows Unicode rules.
+#[derive(Debug)]
+pub enum StringMatcher {
+    StartsWith(Vec<u8>),
+    EndsWith(Vec<u8>),
+    Contains(Vec<u8>),
+    Regex(&'static str, Regex),
+}
+
+impl StringMatcher {
+    /// Operator names accepted by `new`, in the order filters check them.
+    pub const OPERATORS: [&'static str; 4] = ["starts_with", "ends_with", "contains", "matches"];
+
+    pub fn new(op: &str, pattern: &str, case_insensitive: bool) -> Result<Self, String> {
+        let (name, source) = match op {
+            "starts_with" if !case_insensitive => {
+                return Ok(StringMatcher::StartsWith(pattern.as_bytes().to_vec()))
+            }
+            "ends_with" if !case_insensitive => {
+                return Ok(StringMatcher::EndsWith(pattern.as_bytes().to_vec()))
+            }
+            "contains" if !case_insensitive => {
+                return Ok(StringMatcher::Contains(pattern.as_bytes().to_vec()))
+            }
+            "starts_with" => ("starts_with", format!(r"\A{}", regex::escape(pattern))),
+            "ends_with" => ("ends_with", format!(r"{}\z", regex::escape(pattern))),
+            "contains" => ("contains", regex::escape(pattern)),
+            "matches" => ("matches", pattern.to_string()),
+            other => return Err(format!("unknown string operator: {}", other)),
+        };
+
+        RegexBuilder::new(&source)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map(|regex| StringMatcher::Regex(name, regex))
+            .map_err(|e| format!("invalid {} pattern {:?}: {}", name, pattern, e))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StringMatcher::StartsWith(_) => "starts_with",
+            StringMatcher::EndsWith(_) => "ends_with",
+            StringMatcher::Contains(_) => "contains",
+            StringMatcher::Regex(name, _) => name,
+        }
+    }
+
+    pub fn is_match(&self, bytes: &[u8]) -> bool {
+        match self {
+            StringMatcher::StartsWith(prefix) => bytes.starts_with(prefix),
+            StringMatcher::EndsWith(suffix) => bytes.ends_with(suffix),
+            StringMatcher::Contains(needle) => {
+                needle.is_empty() || bytes.windows(needle.len()).any(|w| w == needle.as_slice())
+            }
+            StringMatcher::Regex(_, regex) => regex.is_match(bytes),
+        }
+    }
+}
+
+/// Returns the name of the first configured filter the byte slice fails, or
+/// `None` when it passes all of them.
 ///
 /// Length checks are O(1) and run first, gating the more expensive
-/// char class check which is O(n) with early exit.
+/// char class check which is O(n) with early exit. Matchers run last.
 #[inline]
-pub fn passes_filters(bytes: &[u8], filters: &StringFilters) -> bool {
+pub fn failed_filter(bytes: &[u8], filters: &StringFilters) -> Option<&'static str> {
     let len = bytes.len();
 
     if let Some(eq) = filters.len_eq {
         if len != eq {
-            return false;
+            return Some("len_eq");
         }
     }
     if let Some(gt) = filters.len_gt {
         if len <= gt {
-            return false;
+            return Some("len_gt");
         }
     }
     if let Some(gte) = filters.len_gte {
         if len < gte {
-            return false;
+            return Some("len_gte");
         }
     }
     if let Some(lt) = filters.len_lt {
         if len >= lt {
-            return false;
+            return Some("len_lt");
         }
     }
     if let Some(lte) = filters.len_lte {
         if len > lte {
-            return false;
+            return Some("len_lte");
         }
     }
 
-    if let Some(ref class) = filters.char_class {
-        match class {
-            CharClass::Alpha => {
-                if !bytes.iter().all(|b| b.is_ascii_alphabetic()) {
-                    return false;
-                }
-            }
-            CharClass::Numeric => {
-                if !bytes.iter().all(|b| b.is_ascii_digit()) {
-                    return false;
-                }
-            }
-            CharClass::Alphanumeric => {
-                if !bytes.iter().all(|b| b.is_ascii_alphanumeric()) {
-                    return false;
-                }
-            }
+    if let Some(class) = filters.char_class {
+        if !class.matches(bytes) {
+            return Some("char_class");
+        }
+    }
+
+    filters
+        .matchers
+        .iter()
+        .find(|matcher| !matcher.is_match(bytes))
+        .map(StringMatcher::name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(len_gte: Option<usize>, char_class: Option<CharClass>) -> StringFilters {
+        StringFilters {
+            len_eq: None,
+            len_gt: None,
+            len_gte,
+            len_lt: None,
+            len_lte: None,
+            char_class,
+            matchers: Vec::new(),
         }
     }
 
-    true
+    #[test]
+    fn reports_first_failing_filter() {
+        let filters = filters(Some(3), Some(CharClass::Numeric));
+        assert_eq!(failed_filter(b"12", &filters), Some("len_gte"));
+        assert_eq!(failed_filter(b"12a", &filters), Some("char_class"));
+        assert_eq!(failed_filter(b"123", &filters), None);
+    }
+
+    #[test]
+    fn checks_extended_char_classes() {
+        assert!(CharClass::UnicodeAlpha.matches("Zürich".as_bytes()));
+        assert!(!CharClass::Alpha.matches("Zürich".as_bytes()));
+        assert!(!CharClass::UnicodeAlpha.matches(b"\xff"));
+        assert!(CharClass::Hex.matches(b"00ffAB"));
+        assert!(CharClass::Uuid.matches(b"123e4567-e89b-12d3-a456-426614174000"));
+        assert!(!CharClass::Uuid.matches(b"123e4567e89b12d3a456426614174000"));
+        assert!(CharClass::Base64.matches(b"aGk="));
+        assert!(!CharClass::Base64.matches(b"aG=k"));
+        assert!(!CharClass::Base64.matches(b"aGk"));
+    }
+
+    #[test]
+    fn matches_with_optional_case_folding() {
+        let prefix = StringMatcher::new("starts_with", "GET ", false).unwrap();
+        assert!(prefix.is_match(b"GET /"));
+        assert!(!prefix.is_match(b"get /"));
+
+        let prefix = StringMatcher::new("starts_with", "GET ", true).unwrap();
+        assert!(prefix.is_match(b"get /"));
+        assert!(!prefix.is_match(b"/ get"));
+
+        let suffix = StringMatcher::new("ends_with", ".Ü", true).unwrap();
+        assert!(suffix.is_match("file.ü".as_bytes()));
+
+        let contains = StringMatcher::new("contains", "a.b", false).unwrap();
+        assert!(contains.is_match(b"xa.by"));
+        assert!(!contains.is_match(b"xaXby"));
+
+        let regex = StringMatcher::new("matches", r"^\d{3}$", false).unwrap();
+        assert!(regex.is_match(b"404"));
+        assert!(StringMatcher::new("matches", "(", false).is_err());
+    }
 }
@@ -10,9 +10,14 @@ defmodule Logflare.Backends.Adaptor.ClickHouseAdaptor.MapperOutputTest do
   alias Logflare.Mapper
   alias Logflare.Mapper.MappingConfig
   alias Logflare.Mapper.MappingConfig.FieldConfig, as: Field
+  alias Logflare.Mapper.MappingConfig.InferCondition
   alias Logflare.Mapper.MappingConfig.OutputFormat
+  alias Logflare.Mapper.MappingConfig.Route
   alias Logflare.Mapper.Native
   alias Logflare.Mapper.OutputContext
+  alias Opentelemetry.Proto.Collector.Logs.V1.ExportLogsServiceRequest
+  alias Opentelemetry.Proto.Collector.Metrics.V1.ExportMetricsServiceRequest
+  alias Opentelemetry.Proto.Collector.Trace.V1.ExportTraceServiceRequest
 
   test "fused log output is byte-identical and applies explicit severity numbers" do
     event =
@@ -163,6 +168,244 @@ defmodule Logflare.Backends.Adaptor.ClickHouseAdaptor.MapperOutputTest do
     end
   end
 
+  test "map_many output matches mapping each document separately" do
+    for event_type <- [:log, :metric, :trace] do
+      events =
+        Enum.map(1..5, fn index ->
+          raw_event(event_type, %{
+            "event_message" => "event-#{index}",
+            "timestamp" => 1_700_000_000_000_000 + index
+          })
+        end)
+
+      output_compiled = Mapper.compile!(MappingDefaults.for_type(event_type))
+      map_compiled = compile_map_output(event_type)
+      config_id = encoded_config_id(event_type)
+      bodies = Enum.map(events, & &1.body)
+      contexts = Enum.map(events, &OutputContext.clickhouse_row_binary(&1, config_id))
+
+      expected =
+        events
+        |> Enum.map(&separate_row(&1, event_type, map_compiled, config_id))
+        |> IO.iodata_to_binary()
+
+      assert Mapper.map_many(bodies, output_compiled, output_contexts: contexts) == expected
+
+      assert Mapper.map_many(bodies, map_compiled) ==
+               Enum.map(bodies, &Mapper.map(&1, map_compiled))
+    end
+  end
+
+  test "map_json output matches mapping the decoded document" do
+    for event_type <- [:log, :metric, :trace] do
+      events =
+        Enum.map(1..3, fn index ->
+          raw_event(event_type, %{
+            "event_message" => "event-#{index}",
+            "resource" => %{"service" => %{"name" => "api"}},
+            "metadata" => %{"region" => "us-east-1", "tags" => ["a", "b"]},
+            "timestamp" => 1_700_000_000_000_000 + index
+          })
+        end)
+
+      output_compiled = Mapper.compile!(MappingDefaults.for_type(event_type))
+      map_compiled = compile_map_output(event_type)
+      config_id = encoded_config_id(event_type)
+      bodies = Enum.map(events, & &1.body)
+      jsons = Enum.map(bodies, &Jason.encode!/1)
+      contexts = Enum.map(events, &OutputContext.clickhouse_row_binary(&1, config_id))
+
+      for {event, json, context} <- Enum.zip([events, jsons, contexts]) do
+        assert Mapper.map_json(json, map_compiled) == Mapper.map(event.body, map_compiled)
+
+        assert Mapper.map_json(json, output_compiled, output_context: context) ==
+                 Mapper.map(event.body, output_compiled, output_context: context)
+      end
+
+      assert Mapper.map_many_json(jsons, output_compiled, output_contexts: contexts) ==
+               Mapper.map_many(bodies, output_compiled, output_contexts: contexts)
+    end
+  end
+
+  test "routed RowBinary output matches map_many of each route's documents" do
+    events =
+      Enum.map(1..4, fn index ->
+        raw_event(:log, %{
+          "event_message" => "event-#{index}",
+          "severity_text" => if(rem(index, 2) == 0, do: "debug", else: "info"),
+          "timestamp" => 1_700_000_000_000_000 + index
+        })
+      end)
+
+    debug = %InferCondition{
+      path: "$.severity_text",
+      predicate: "equals",
+      comparison_value: "debug"
+    }
+
+    config = MappingDefaults.for_type(:log)
+    plain = Mapper.compile!(config)
+    routed = Mapper.compile!(%{config | route: [%Route{name: "debug", any: [debug]}]})
+    config_id = encoded_config_id(:log)
+    bodies = Enum.map(events, & &1.body)
+    contexts = Enum.map(events, &OutputContext.clickhouse_row_binary(&1, config_id))
+
+    {debug_rows, info_rows} =
+      bodies
+      |> Enum.zip(contexts)
+      |> Enum.split_with(fn {body, _context} -> body["severity_text"] == "debug" end)
+
+    expected =
+      for {route, rows} <- [{nil, info_rows}, {"debug", debug_rows}] do
+        {route_bodies, route_contexts} = Enum.unzip(rows)
+        {route, Mapper.map_many(route_bodies, plain, output_contexts: route_contexts)}
+      end
+
+    assert Mapper.map_many_routed(bodies, routed, output_contexts: contexts) == expected
+
+    assert_raise ArgumentError, ~r/expected 4 output contexts, got 0/, fn ->
+      Mapper.map_many_routed(bodies, routed)
+    end
+  end
+
+  test "native output encodes one columnar block per batch" do
+    events =
+      Enum.map(1..3, fn index ->
+        raw_event(:log, %{
+          "event_message" => "event-#{index}",
+          "metadata" => %{"index" => "#{index}"},
+          "timestamp" => 1_700_000_000_000_000 + index
+        })
+      end)
+
+    config_id = encoded_config_id(:log)
+    contexts = Enum.map(events, &OutputContext.clickhouse_native(&1, config_id))
+    bodies = Enum.map(events, & &1.body)
+
+    compiled = compile_native_output(:log)
+    block = Mapper.map_many(bodies, compiled, output_contexts: contexts)
+
+    assert <<21, 3, 2, "id", 4, "UUID", _rest::binary>> = block
+    assert :binary.match(block, "Map(String, String)") != :nomatch
+    assert :binary.match(block, "event-3") != :nomatch
+
+    assert Mapper.map_many([], compiled, output_contexts: []) == ""
+
+    assert {:error, "expected 3 output contexts, got 1"} =
+             Mapper.map_many_result(bodies, compiled, output_contexts: Enum.take(contexts, 1))
+
+    row_binary_context = OutputContext.clickhouse_row_binary(hd(events), config_id)
+
+    assert {:error, reason} =
+             Mapper.map_result(hd(bodies), compiled, output_context: row_binary_context)
+
+    assert reason =~ "requires a clickhouse_native output_context"
+  end
+
+  test "native output frames LZ4 blocks with a checksum and compression header" do
+    event = raw_event(:trace, %{"span_name" => "GET /", "timestamp" => 1_700_000_000_000_001})
+    config_id = encoded_config_id(:trace)
+    context = OutputContext.clickhouse_native(event, config_id)
+
+    plain = Mapper.map(event.body, compile_native_output(:trace), output_context: context)
+
+    compressed =
+      Mapper.map(event.body, compile_native_output(:trace, compression: :lz4),
+        output_context: context
+      )
+
+    assert <<_checksum::binary-size(16), 0x82, compressed_size::little-32,
+             uncompressed_size::little-32, _data::binary>> = compressed
+
+    assert compressed_size == byte_size(compressed) - 16
+    assert uncompressed_size == byte_size(plain)
+  end
+
+  test "OTLP log output groups records by resource and scope" do
+    bodies =
+      for {service, message} <- [{"api", "one"}, {"worker", "two"}, {"api", "three"}] do
+        %{
+          "event_message" => message,
+          "severity_text" => "error",
+          "trace_id" => "0102030405060708090a0b0c0d0e0f10",
+          "span_id" => "0102030405060708",
+          "resource" => %{"service" => %{"name" => service}},
+          "scope" => %{"name" => "plug"},
+          "metadata" => %{"request_id" => "req-#{message}"},
+          "timestamp" => 1_700_000_000_000_001
+        }
+      end
+
+    request =
+      bodies
+      |> Mapper.map_many(compile_otlp_output(:log))
+      |> Protobuf.decode(ExportLogsServiceRequest)
+
+    assert [api, worker] = request.resource_logs
+    assert [%{scope: %{name: "plug"}, log_records: [one, three]}] = api.scope_logs
+    assert [%{log_records: [two]}] = worker.scope_logs
+
+    assert Enum.map([one, two, three], & &1.body.value) ==
+             [{:string_value, "one"}, {:string_value, "two"}, {:string_value, "three"}]
+
+    assert one.severity_number == :SEVERITY_NUMBER_ERROR
+    assert one.trace_id == Base.decode16!("0102030405060708090A0B0C0D0E0F10")
+    assert one.span_id == Base.decode16!("0102030405060708")
+    assert one.time_unix_nano > 0
+
+    assert %{key: "service.name", value: %{value: {:string_value, "api"}}} in api.resource.attributes
+  end
+
+  test "OTLP trace output encodes spans with kind, status, and derived end time" do
+    body = %{
+      "trace_id" => "0102030405060708090a0b0c0d0e0f10",
+      "span_id" => "0102030405060708",
+      "span_name" => "GET /items",
+      "span_kind" => "server",
+      "status" => %{"code" => "STATUS_CODE_ERROR", "message" => "boom"},
+      "start_time" => 1_700_000_000_000_000_000,
+      "duration" => 250,
+      "resource" => %{"service" => %{"name" => "api"}},
+      "timestamp" => 1_700_000_000_000_000_000
+    }
+
+    request =
+      [body]
+      |> Mapper.map_many(compile_otlp_output(:trace))
+      |> Protobuf.decode(ExportTraceServiceRequest)
+
+    assert [%{scope_spans: [%{spans: [span]}]}] = request.resource_spans
+    assert span.name == "GET /items"
+    assert span.kind == :SPAN_KIND_SERVER
+    assert span.status.code == :STATUS_CODE_ERROR
+    assert span.status.message == "boom"
+    assert span.end_time_unix_nano - span.start_time_unix_nano == 250
+  end
+
+  test "OTLP metric output groups data points by metric identity" do
+    bodies =
+      for {name, value} <- [{"cpu", 1.5}, {"memory", 10.0}, {"cpu", 2.5}] do
+        %{
+          "metric_name" => name,
+          "metric_type" => "gauge",
+          "value" => value,
+          "resource" => %{"service" => %{"name" => "api"}},
+          "timestamp" => 1_700_000_000_000_000_000
+        }
+      end
+
+    request =
+      bodies
+      |> Mapper.map_many(compile_otlp_output(:metric))
+      |> Protobuf.decode(ExportMetricsServiceRequest)
+
+    assert [%{scope_metrics: [%{metrics: [cpu, memory]}]}] = request.resource_metrics
+    assert cpu.name == "cpu"
+    assert {:gauge, %{data_points: cpu_points}} = cpu.data
+    assert Enum.map(cpu_points, & &1.value) == [{:as_double, 1.5}, {:as_double, 2.5}]
+    assert {:gauge, %{data_points: [_memory_point]}} = memory.data
+  end
+
   property "fused log rows match the separate encoder for varied scalar and map values" do
     output_compiled = Mapper.compile!(MappingDefaults.for_log())
     map_compiled = compile_map_output(:log)
@@ -416,6 +659,16 @@ defmodule Logflare.Backends.Adaptor.ClickHouseAdaptor.MapperOutputTest do
     Mapper.compile!(%{config | output: nil})
   end
 
+  defp compile_otlp_output(event_type) do
+    config = MappingDefaults.for_type(event_type)
+    Mapper.compile!(%{config | output: OutputFormat.otlp_protobuf(event_type)})
+  end
+
+  defp compile_native_output(event_type, opts \\ []) do
+    config = MappingDefaults.for_type(event_type)
+    Mapper.compile!(%{config | output: OutputFormat.clickhouse_native(event_type, opts)})
+  end
+
   defp native_envelope(event) do
     ingested_at = if event.ingested_at, do: DateTime.to_unix(event.ingested_at, :microsecond)
     {event.id, Atom.to_string(event.source_uuid), event.source_name || "", ingested_at}
@@ -2,12 +2,16 @@ defmodule Logflare.Mapper.MappingConfigTest do
   use ExUnit.Case, async: true
 
   alias Logflare.Mapper.MappingConfig
+  alias Logflare.Mapper.MappingConfig.FieldCase
   alias Logflare.Mapper.MappingConfig.FieldConfig
   alias Logflare.Mapper.MappingConfig.FieldConfig, as: Field
   alias Logflare.Mapper.MappingConfig.InferCondition
   alias Logflare.Mapper.MappingConfig.InferRule
   alias Logflare.Mapper.MappingConfig.OutputFormat
   alias Logflare.Mapper.MappingConfig.PickEntry
+  alias Logflare.Mapper.MappingConfig.RedactRule
+  alias Logflare.Mapper.MappingConfig.Route
+  alias Logflare.Mapper.MappingConfig.Sample
 
   describe "FieldConfig constructors" do
     test "string/2 creates correct struct" do
@@ -337,6 +341,273 @@ defmodule Logflare.Mapper.MappingConfigTest do
       assert all_cond.comparison_value == "cumulative"
     end
 
+    test "round-trip preserves nested condition groups" do
+      rule = %InferRule{
+        result: "x",
+        any: [
+          %InferCondition{
+            all: [
+              %InferCondition{path: "$.a", predicate: "exists"},
+              %InferCondition{not: %InferCondition{path: "$.b", predicate: "exists"}}
+            ]
+          }
+        ]
+      }
+
+      config = MappingConfig.new([Field.enum8("mt", values: %{"x" => 1}, infer: [rule])])
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, restored} = MappingConfig.from_json(json)
+
+      [%{infer: [%InferRule{any: [group]}]}] = restored.fields
+      assert [%InferCondition{path: "$.a"}, %InferCondition{not: negated}] = group.all
+      assert %InferCondition{path: "$.b", predicate: "exists"} = negated
+
+      [nif_field] = MappingConfig.to_nif_map(config)["fields"]
+
+      assert [
+               %{
+                 "any" => [
+                   %{"all" => [%{"path" => "$.a"}, %{"not" => %{"path" => "$.b"}}]}
+                 ]
+               }
+             ] = nif_field["infer"]
+    end
+
+    test "from_json/1 requires a path and predicate on non-group conditions" do
+      json =
+        ~s({"fields": [{"name": "mt", "type": "enum8", "infer": [{"result": "x", "any": [{}]}]}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "round-trip preserves cases" do
+      config =
+        MappingConfig.new([
+          Field.uint8("priority",
+            cases: [
+              %FieldCase{
+                value: 5,
+                any: [%InferCondition{path: "$.urgent", predicate: "exists"}]
+              },
+              %FieldCase{paths: ["$.priority", "$.prio"]}
+            ]
+          )
+        ])
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, restored} = MappingConfig.from_json(json)
+
+      [field] = restored.fields
+      assert [literal, paths] = field.cases
+      assert literal.value == "5"
+      assert [%InferCondition{path: "$.urgent", predicate: "exists"}] = literal.any
+      assert paths.paths == ["$.priority", "$.prio"]
+    end
+
+    test "round-trip preserves aggregate" do
+      config =
+        MappingConfig.new([
+          Field.uint32("event_count", path: "$.events[*]", aggregate: "count")
+        ])
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, restored} = MappingConfig.from_json(json)
+      assert [%{aggregate: "count", path: "$.events[*]"}] = restored.fields
+    end
+
+    test "from_json/1 rejects an unknown aggregate" do
+      json = ~s({"fields": [{"name": "n", "type": "uint32", "aggregate": "median"}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "from_json/1 rejects a case without a source" do
+      json = ~s({"fields": [{"name": "a", "type": "string", "cases": [{"any": []}]}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "round-trip preserves drop_when and route" do
+      config =
+        MappingConfig.new([Field.string("path", path: "$.path")],
+          drop_when: %InferCondition{path: "$.path", predicate: "equals", comparison_value: "/"},
+          route: [
+            %Route{name: "debug", all: [%InferCondition{path: "$.debug", predicate: "exists"}]}
+          ]
+        )
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, restored} = MappingConfig.from_json(json)
+
+      assert %InferCondition{path: "$.path", comparison_value: "/"} = restored.drop_when
+      assert [%Route{name: "debug", all: [%InferCondition{path: "$.debug"}]}] = restored.route
+
+      assert %{"drop_when" => %{"predicate" => "equals"}, "route" => [%{"name" => "debug"}]} =
+               MappingConfig.to_nif_map(restored)
+    end
+
+    test "round-trip preserves sample" do
+      sample = %Sample{
+        path: "$.trace_id",
+        rate: 0.1,
+        severity_path: "$.level",
+        severity_rates: %{"error" => 1.0}
+      }
+
+      config = MappingConfig.new([Field.string("path", path: "$.path")], sample: sample)
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{sample: ^sample}} = MappingConfig.from_json(json)
+    end
+
+    test "from_json/1 rejects a sample rate above 1" do
+      json = ~s({"fields": [], "sample": {"path": "$.trace_id", "rate": 1.5}})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "round-trip preserves redaction rules" do
+      rules = [
+        %RedactRule{detector: "email", action: "hash"},
+        %RedactRule{pattern: "token=(?P<secret>\\w+)", replacement: "***"}
+      ]
+
+      config = MappingConfig.new([Field.string("msg", path: "$.msg")], redact: rules)
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{redact: ^rules}} = MappingConfig.from_json(json)
+
+      assert %{"redact" => [%{"detector" => "email", "action" => "hash"}, masked]} =
+               MappingConfig.to_nif_map(config)
+
+      assert masked == %{
+               "pattern" => "token=(?P<secret>\\w+)",
+               "action" => "mask",
+               "replacement" => "***"
+             }
+    end
+
+    test "from_json/1 rejects a redaction rule without a detector or pattern" do
+      json = ~s({"fields": [], "redact": [{"action": "mask"}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "round-trip preserves the hash key and hash options" do
+      fields = [
+        Field.string("user", path: "$.user", transform: "hash", hash_algorithm: "sha256"),
+        Field.flat_map("attrs", path: "$", hash_keys: ["*.email"])
+      ]
+
+      config = MappingConfig.new(fields, hash_key: "s3cret")
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{hash_key: "s3cret", fields: ^fields}} =
+               MappingConfig.from_json(json)
+
+      assert %{"hash_key" => "s3cret", "fields" => [user, attrs]} =
+               MappingConfig.to_nif_map(config)
+
+      assert %{"transform" => "hash", "hash_algorithm" => "sha256"} = user
+      assert %{"hash_keys" => ["*.email"]} = attrs
+    end
+
+    test "round-trip preserves field limits and max_row_bytes" do
+      limits = %{max_string_bytes: 1024, max_depth: 3}
+      fields = [Field.json("attrs", path: "$", limits: limits)]
+      config = MappingConfig.new(fields, max_row_bytes: 65_536)
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{max_row_bytes: 65_536, fields: [field]}} =
+               MappingConfig.from_json(json)
+
+      assert %{"max_row_bytes" => 65_536, "fields" => [%{"limits" => nif_limits}]} =
+               MappingConfig.to_nif_map(%MappingConfig{config | fields: [field]})
+
+      assert nif_limits == %{"max_string_bytes" => 1024, "max_depth" => 3}
+    end
+
+    test "round-trip preserves key_mode" do
+      config = MappingConfig.new([Field.string("message", path: "$.message")], key_mode: :atom)
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{key_mode: :atom}} = MappingConfig.from_json(json)
+      assert %{"key_mode" => "atom"} = MappingConfig.to_nif_map(config)
+      refute Map.has_key?(MappingConfig.to_nif_map(MappingConfig.new([])), "key_mode")
+    end
+
+    test "round-trip preserves collect_stats" do
+      config = MappingConfig.new([Field.string("message")], collect_stats: true)
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{collect_stats: true}} = MappingConfig.from_json(json)
+      assert %{"collect_stats" => true} = MappingConfig.to_nif_map(config)
+      refute Map.has_key?(MappingConfig.to_nif_map(MappingConfig.new([])), "collect_stats")
+    end
+
+    test "from_json/1 rejects an unknown key_mode" do
+      json = ~s({"fields": [], "key_mode": "charlist"})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "from_json/1 rejects non-positive limits" do
+      json = ~s({"fields": [{"name": "a", "type": "json", "limits": {"max_depth": 0}}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "round-trip preserves flat_map key rules" do
+      field =
+        Field.flat_map("attrs",
+          path: "$",
+          include_keys: ["http.*"],
+          exclude_keys: ["*.password"],
+          rename_keys: %{"http.status" => "http.status_code"},
+          normalize_keys: ["lowercase"]
+        )
+
+      config = MappingConfig.new([field])
+
+      assert {:ok, json} = MappingConfig.to_json(config)
+      assert {:ok, %MappingConfig{fields: [^field]}} = MappingConfig.from_json(json)
+
+      assert %{"fields" => [nif_field]} = MappingConfig.to_nif_map(config)
+
+      assert %{
+               "include_keys" => ["http.*"],
+               "exclude_keys" => ["*.password"],
+               "rename_keys" => %{"http.status" => "http.status_code"},
+               "normalize_keys" => ["lowercase"]
+             } = nif_field
+    end
+
+    test "flatten options are sent to the NIF with string keys" do
+      field = Field.flat_map("attrs", path: "$", flatten: %{separator: "_", lists: "index"})
+
+      assert %{"fields" => [%{"flatten" => %{"separator" => "_", "lists" => "index"}}]} =
+               MappingConfig.to_nif_map(MappingConfig.new([field]))
+    end
+
+    test "from_json/1 rejects an unknown flatten list strategy" do
+      json = ~s({"fields": [{"name": "a", "type": "flat_map", "flatten": {"lists": "csv"}}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "from_json/1 rejects an unknown key normalization" do
+      json = ~s({"fields": [{"name": "a", "type": "flat_map", "normalize_keys": ["camel"]}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
+    test "from_json/1 rejects an unknown hash algorithm" do
+      json = ~s({"fields": [{"name": "u", "type": "string", "hash_algorithm": "md5"}]})
+
+      assert {:error, %Ecto.Changeset{valid?: false}} = MappingConfig.from_json(json)
+    end
+
     test "round-trip preserves flat_map value_type" do
       config =
         MappingConfig.new([
@@ -472,6 +743,37 @@ defmodule Logflare.Mapper.MappingConfigTest do
                field["infer"]
     end
 
+    test "serializes cases with literal values typed by the field" do
+      config =
+        MappingConfig.new([
+          Field.uint8("priority",
+            cases: [
+              %FieldCase{
+                value: 5,
+                all: [
+                  %InferCondition{path: "$.level", predicate: "equals", comparison_value: "error"}
+                ]
+              }
+            ],
+            path: "$.priority"
+          )
+        ])
+
+      nif_map = MappingConfig.to_nif_map(config)
+      [field] = nif_map["fields"]
+
+      assert field["path"] == "$.priority"
+
+      assert [
+               %{
+                 "value" => 5,
+                 "all" => [%{"path" => "$.level", "comparison_value" => "error"}]
+               } = case_map
+             ] = field["cases"]
+
+      refute Map.has_key?(case_map, "any")
+    end
+
     test "serializes flat_map value_type" do
       config =
         MappingConfig.new([
@@ -7,10 +7,10 @@ defmodule Logflare.MapperTest do
   alias Logflare.Mapper.MappingConfig.InferCondition
   alias Logflare.Mapper.MappingConfig.InferRule
 
-  defp compile_and_map(fields, document) do
-    config = MappingConfig.new(fields)
+  defp compile_and_map(fields, document, config_opts \\ [], map_opts \\ []) do
+    config = MappingConfig.new(fields, config_opts)
     compiled = Mapper.compile!(config)
-    Mapper.map(document, compiled)
+    Mapper.map(document, compiled, map_opts)
   end
 
   # ── Path resolution ───────────────────────────────────────────────────
@@ -97,6 +97,33 @@ defmodule Logflare.MapperTest do
 
       assert result["body"] == doc
     end
+
+    test "many keys under one nested map resolve through a scan of that map" do
+      keys = Enum.map(1..9, &"k#{&1}")
+      fields = Enum.map(keys, &Field.string(&1, path: "$.attributes.#{&1}", default: ""))
+      expected = Map.new(keys, &{&1, ""})
+
+      small = %{"attributes" => %{"k1" => "one", "k9" => "nine", "other" => "x"}}
+      large = %{"attributes" => Map.new(1..40, &{"k#{&1}", "v"})}
+
+      assert compile_and_map(fields, small) ==
+               %{expected | "k1" => "one", "k9" => "nine"}
+
+      assert compile_and_map(fields, large) == Map.new(keys, &{&1, "v"})
+      assert compile_and_map(fields, %{"attributes" => "not a map"}) == expected
+      assert compile_and_map(fields, %{}) == expected
+    end
+
+    test "a scanned nested map prefers the string key under either mode" do
+      fields = Enum.map(1..8, &Field.string("k#{&1}", path: "$.attributes.k#{&1}", default: ""))
+      document = %{"attributes" => %{:k1 => "atom", "k1" => "string", :k2 => "atom only"}}
+
+      result = compile_and_map(fields, document, key_mode: :either)
+
+      assert result["k1"] == "string"
+      assert result["k2"] == "atom only"
+      assert result["k3"] == ""
+    end
   end
 
   # ── Type coercion ─────────────────────────────────────────────────────
@@ -1052,6 +1079,133 @@ defmodule Logflare.MapperTest do
     end
   end
 
+  # ── Condition groups ────────────────────────────────────────────────
+
+  describe "condition groups" do
+    # (A and B) or (C and not D)
+    @grouped_rule %InferRule{
+      result: "histogram",
+      any: [
+        %InferCondition{
+          all: [
+            %InferCondition{path: "$.a", predicate: "exists"},
+            %InferCondition{path: "$.b", predicate: "exists"}
+          ]
+        },
+        %InferCondition{
+          all: [
+            %InferCondition{path: "$.c", predicate: "exists"},
+            %InferCondition{not: %InferCondition{path: "$.d", predicate: "exists"}}
+          ]
+        }
+      ]
+    }
+
+    defp infer_grouped(document) do
+      compile_and_map(
+        [
+          Field.enum8("mt",
+            paths: ["$.metric_type"],
+            values: %{"gauge" => 1, "histogram" => 3},
+            infer: [@grouped_rule],
+            default: 1
+          )
+        ],
+        document
+      )["mt"]
+    end
+
+    test "nested and/or/not groups" do
+      assert infer_grouped(%{"a" => 1, "b" => 1}) == 3
+      assert infer_grouped(%{"c" => 1}) == 3
+      assert infer_grouped(%{"a" => 1, "c" => 1, "d" => 1}) == 1
+      assert infer_grouped(%{"b" => 1, "d" => 1}) == 1
+    end
+
+    test "string matching and char_class predicates" do
+      rule =
+        %InferRule{
+          result: "histogram",
+          all: [
+            %InferCondition{
+              path: "$.name",
+              predicate: "ends_with",
+              comparison_value: "_BUCKET",
+              case_insensitive: true
+            },
+            %InferCondition{path: "$.name", predicate: "matches", comparison_value: "^http_"},
+            %InferCondition{path: "$.trace", predicate: "char_class", comparison_value: "hex"}
+          ]
+        }
+
+      infer = fn document ->
+        compile_and_map(
+          [
+            Field.enum8("mt",
+              paths: ["$.metric_type"],
+              values: %{"gauge" => 1, "histogram" => 3},
+              infer: [rule],
+              default: 1
+            )
+          ],
+          document
+        )["mt"]
+      end
+
+      assert infer.(%{"name" => "http_latency_bucket", "trace" => "0af7"}) == 3
+      assert infer.(%{"name" => "http_latency_bucket", "trace" => "xyz"}) == 1
+      assert infer.(%{"name" => "grpc_latency_bucket", "trace" => "0af7"}) == 1
+      assert infer.(%{"name" => 1, "trace" => "0af7"}) == 1
+    end
+
+    test "groups apply to field cases" do
+      alias Logflare.Mapper.MappingConfig.FieldCase
+
+      fields = [
+        Field.string("message",
+          cases: [
+            %FieldCase{
+              path: "$.msg",
+              all: [
+                %InferCondition{
+                  not: %InferCondition{
+                    any: [
+                      %InferCondition{path: "$.msg", predicate: "not_exists"},
+                      %InferCondition{path: "$.legacy", predicate: "exists"}
+                    ]
+                  }
+                }
+              ]
+            }
+          ],
+          path: "$.message"
+        )
+      ]
+
+      assert compile_and_map(fields, %{"msg" => "a", "message" => "b"})["message"] == "a"
+
+      legacy = compile_and_map(fields, %{"msg" => "a", "legacy" => true, "message" => "b"})
+      assert legacy["message"] == "b"
+    end
+
+    test "excessive nesting is rejected at compile time" do
+      deep =
+        Enum.reduce(1..40, %InferCondition{path: "$.a", predicate: "exists"}, fn _, acc ->
+          %InferCondition{not: acc}
+        end)
+
+      rule = %InferRule{result: "x", all: [deep]}
+      field = Field.enum8("mt", values: %{"x" => 1}, infer: [rule])
+
+      assert {:error, reason} =
+               [field]
+               |> MappingConfig.new()
+               |> Mapper.compile()
+
+      assert reason =~ "nested deeper than 32"
+    end
+  end
+
   # ── FromOutput ────────────────────────────────────────────────────────
 
   describe "FromOutput" do
@@ -2191,6 +2345,95 @@ defmodule Logflare.MapperTest do
 
       assert result["attrs"]["mixed"] == ~s(["a",1,true,null])
     end
+
+    test "include_keys and glob exclude_keys filter flattened keys" do
+      field =
+        Field.flat_map("attrs",
+          path: "$",
+          include_keys: ["http.*", "user.*"],
+          exclude_keys: ["*.password", "debug"]
+        )
+
+      document = %{
+        "http" => %{"method" => "GET", "status" => 200},
+        "user" => %{"id" => "u1", "password" => "hunter2"},
+        "debug" => "x",
+        "other" => "y"
+      }
+
+      assert compile_and_map([field], document)["attrs"] == %{
+               "http.method" => "GET",
+               "http.status" => "200",
+               "user.id" => "u1"
+             }
+    end
+
+    test "normalizes then renames keys, keeping the first on collision" do
+      field =
+        Field.flat_map("attrs",
+          path: "$",
+          normalize_keys: ["lowercase", "dash_to_underscore"],
+          rename_keys: %{"HTTP.Status-Code" => "http.response.status_code"}
+        )
+
+      document = %{
+        "HTTP" => %{"Status-Code" => 404, "User-Agent" => "curl"},
+        "http" => %{"user_agent" => "wget"}
+      }
+
+      assert compile_and_map([field], document)["attrs"] == %{
+               "http.response.status_code" => "404",
+               "http.user_agent" => "curl"
+             }
+    end
+
+    test "applies key rules to flat_keys input" do
+      field = Field.flat_map("attrs", path: "$", normalize_keys: ["lowercase"])
+      compiled = Mapper.compile!(MappingConfig.new([field]))
+
+      assert Mapper.map(%{"Service.Name" => "api"}, compiled, flat_keys: true)["attrs"] ==
+               %{"service.name" => "api"}
+    end
+
+    test "flatten uses a custom separator and max_depth" do
+      field = Field.flat_map("attrs", path: "$", flatten: %{separator: "_", max_depth: 2})
+      document = %{"a" => %{"b" => %{"c" => 1}, "d" => 2}}
+
+      assert compile_and_map([field], document)["attrs"] == %{
+               "a_b" => ~s({"c":1}),
+               "a_d" => "2"
+             }
+    end
+
+    test "flatten index-expands lists" do
+      field = Field.flat_map("attrs", path: "$", flatten: %{lists: "index"})
+      document = %{"tags" => ["a", "b"], "spans" => [%{"id" => 1}], "empty" => []}
+
+      assert compile_and_map([field], document)["attrs"] == %{
+               "tags.0" => "a",
+               "tags.1" => "b",
+               "spans.0.id" => "1",
+               "empty" => "[]"
+             }
+    end
+
+    test "flatten joins lists with a delimiter, also for flat_keys input" do
+      field =
+        Field.flat_map("attrs", path: "$", flatten: %{lists: "join", list_delimiter: "|"})
+
+      document = %{"tags" => ["a", 1, nil, true]}
+      compiled = Mapper.compile!(MappingConfig.new([field]))
+
+      assert Mapper.map(document, compiled)["attrs"] == %{"tags" => "a|1|true"}
+      assert Mapper.map(document, compiled, flat_keys: true)["attrs"] == %{"tags" => "a|1|true"}
+    end
+
+    test "rejects key rules on non-flat_map fields" do
+      field = %{Field.json("attrs", path: "$") | include_keys: ["a.*"]}
+
+      assert {:error, reason} = Mapper.compile(MappingConfig.new([field]))
+      assert reason =~ "require a flat_map field"
+    end
   end
 
   # ── flat_keys option ─────────────────────────────────────────────────
@@ -2790,5 +3033,1492 @@ defmodule Logflare.MapperTest do
       # Non-binary values are not filtered, they pass through to coercion
       assert result["val"] == "12345"
     end
+
+    test "char_class uuid, hex and unicode_alpha" do
+      fields = [
+        Field.string("id", paths: ["$.a", "$.b"], filters: %{char_class: "uuid"}),
+        Field.string("hex", paths: ["$.a", "$.c"], filters: %{char_class: "hex"}),
+        Field.string("city", paths: ["$.a", "$.d"], filters: %{char_class: "unicode_alpha"})
+      ]
+
+      uuid = "123e4567-e89b-12d3-a456-426614174000"
+      doc = %{"a" => "Zürich 1", "b" => uuid, "c" => "00ffAB", "d" => "Zürich"}
+
+      assert %{"id" => ^uuid, "hex" => "00ffAB", "city" => "Zürich"} =
+               compile_and_map(fields, doc)
+    end
+
+    test "starts_with, ends_with and contains with case_insensitive" do
+      field = fn filters ->
+        Field.string("path", paths: ["$.a", "$.b"], default: "", filters: filters)
+      end
+
+      doc = %{"a" => "GET /health", "b" => "get /api/users.json"}
+
+      assert compile_and_map([field.(%{starts_with: "GET"})], doc)["path"] == "GET /health"
+
+      assert compile_and_map([field.(%{ends_with: ".JSON"})], doc)["path"] == ""
+
+      assert compile_and_map([field.(%{ends_with: ".JSON", case_insensitive: true})], doc)[
+               "path"
+             ] == "get /api/users.json"
+
+      assert compile_and_map([field.(%{contains: "/api/"})], doc)["path"] ==
+               "get /api/users.json"
+    end
+
+    test "matches: regex filter falls through to the next path" do
+      result =
+        compile_and_map(
+          [
+            Field.string("status",
+              paths: ["$.status", "$.code"],
+              default: "",
+              filters: %{matches: "^[1-5]\\d{2}$"}
+            )
+          ],
+          %{"status" => "ok", "code" => "404"}
+        )
+
+      assert result["status"] == "404"
+    end
+
+    test "matches: invalid regex fails compilation" do
+      config =
+        MappingConfig.new([Field.string("status", path: "$.s", filters: %{matches: "("})])
+
+      assert {:error, reason} = Mapper.compile(config)
+      assert reason =~ "invalid matches pattern"
+    end
+  end
+
+  describe "arrow_ipc output" do
+    alias Logflare.Mapper.MappingConfig.OutputFormat
+
+    @arrow_fields [
+      Field.string("message", path: "$.message"),
+      Field.uint64("count", path: "$.count"),
+      Field.float64("ratio", path: "$.ratio"),
+      Field.bool("ok", path: "$.ok"),
+      Field.array_string("tags", path: "$.tags"),
+      Field.json("meta", path: "$.meta")
+    ]
+
+    defp compile_arrow(fields, opts \\ []) do
+      fields
+      |> MappingConfig.new(output: OutputFormat.arrow_ipc(opts))
+      |> Mapper.compile!()
+    end
+
+    defp load_ipc({schema, batches}) do
+      Explorer.DataFrame.load_ipc_stream!(
+        IO.iodata_to_binary([schema, batches, <<0xFFFFFFFF::32, 0::32>>])
+      )
+    end
+
+    test "builds one typed record batch from mapped values" do
+      compiled = compile_arrow(@arrow_fields)
+
+      documents = [
+        %{
+          "message" => "first",
+          "count" => 3,
+          "ratio" => 0.5,
+          "ok" => true,
+          "tags" => ["a", "b"],
+          "meta" => %{"k" => 1}
+        },
+        %{"message" => "second", "count" => "7", "ratio" => 2, "tags" => []}
+      ]
+
+      assert {schema, [_batch]} = output = Mapper.map_many(documents, compiled)
+      assert is_binary(schema)
+
+      columns = output |> load_ipc() |> Explorer.DataFrame.to_columns()
+
+      assert columns["message"] == ["first", "second"]
+      assert columns["count"] == [3, 7]
+      assert columns["ratio"] == [0.5, 2.0]
+      assert columns["ok"] == [true, false]
+      assert columns["tags"] == [["a", "b"], []]
+      assert columns["meta"] == [~s({"k":1}), nil]
+    end
+
+    test "single documents and empty batches share the compiled schema" do
+      compiled = compile_arrow(@arrow_fields, compression: :zstd)
+
+      assert {schema, [_batch]} = Mapper.map(%{"message" => "only"}, compiled)
+      assert {^schema, []} = Mapper.map_many([], compiled)
+    end
+
+    test "unsupported DateTime64 precision is rejected at compile time" do
+      assert {:error, reason} =
+               [Field.datetime64("ts", path: "$.ts", precision: 4)]
+               |> MappingConfig.new(output: OutputFormat.arrow_ipc())
+               |> Mapper.compile()
+
+      assert reason =~ "does not support DateTime64 precision 4"
+    end
+  end
+
+  describe "json output" do
+    alias Logflare.Mapper.MappingConfig.OutputFormat
+
+    @json_fields [
+      Field.string("service.name", path: "$.service"),
+      Field.uint64("count", path: "$.count", default: 1),
+      Field.string("level", path: "$.level", default: "info"),
+      Field.flat_map("attributes", path: "$.attributes")
+    ]
+
+    defp compile_json(opts) do
+      @json_fields
+      |> MappingConfig.new(output: OutputFormat.json(opts))
+      |> Mapper.compile!()
+    end
+
+    test "writes keys in field order with dotted names by default" do
+      compiled = compile_json([])
+      document = %{"service" => "api", "count" => 2, "attributes" => %{"a" => %{"b" => 1}}}
+
+      assert Mapper.map(document, compiled) ==
+               ~s({"service.name":"api","count":2,"level":"info","attributes":{"a.b":"1"}})
+    end
+
+    test "nested key shape and sorted key order" do
+      compiled = compile_json(key_shape: :nested, key_order: :sorted)
+
+      assert Mapper.map(%{"service" => "api", "level" => "warn"}, compiled) ==
+               ~s({"attributes":{},"count":1,"level":"warn","service":{"name":"api"}})
+    end
+
+    test "omit_defaults drops configured defaults, zero values and empty objects" do
+      compiled = compile_json(key_shape: :nested, omit_defaults: true)
+
+      assert Mapper.map(%{"level" => "info", "count" => 1}, compiled) == "{}"
+
+      assert Mapper.map(%{"level" => "error", "count" => 3}, compiled) ==
+               ~s({"count":3,"level":"error"})
+    end
+
+    test "map_many produces NDJSON matching per-document output" do
+      compiled = compile_json(key_order: :sorted)
+      documents = [%{"service" => "a"}, %{"service" => "b", "count" => 5}]
+
+      expected = Enum.map_join(documents, &(Mapper.map(&1, compiled) <> "\n"))
+
+      assert Mapper.map_many(documents, compiled) == expected
+      assert Jason.decode!(hd(String.split(expected, "\n")))["service.name"] == "a"
+      assert Mapper.map_many([], compiled) == ""
+    end
+
+    test "conflicting nested keys are rejected at compile time" do
+      assert {:error, reason} =
+               [Field.string("a", path: "$.a"), Field.string("a.b", path: "$.b")]
+               |> MappingConfig.new(output: OutputFormat.json(key_shape: :nested))
+               |> Mapper.compile()
+
+      assert reason =~ "'a.b' conflicts"
+    end
+  end
+
+  # ── Cases ───────────────────────────────────────────────────────────────
+
+  describe "cases" do
+    alias Logflare.Mapper.MappingConfig.FieldCase
+
+    @pino_case %FieldCase{
+      path: "$.msg",
+      all: [%InferCondition{path: "$.logger", predicate: "equals", comparison_value: "pino"}]
+    }
+
+    test "first matching case supplies the source, field path is the fallback" do
+      fields = [Field.string("event_message", cases: [@pino_case], path: "$.message")]
+
+      pino = compile_and_map(fields, %{"logger" => "pino", "msg" => "a", "message" => "b"})
+      other = compile_and_map(fields, %{"logger" => "bunyan", "msg" => "a", "message" => "b"})
+
+      assert pino["event_message"] == "a"
+      assert other["event_message"] == "b"
+    end
+
+    test "without a fallback path an unmatched field uses its default" do
+      fields = [Field.string("event_message", cases: [@pino_case], default: "none")]
+
+      assert compile_and_map(fields, %{"message" => "b"})["event_message"] == "none"
+    end
+
+    test "literal values are coerced to the field type" do
+      fields = [
+        Field.uint8("priority",
+          cases: [
+            %FieldCase{
+              value: 9,
+              any: [%InferCondition{path: "$.urgent", predicate: "exists"}]
+            },
+            %FieldCase{paths: ["$.priority", "$.prio"]}
+          ],
+          default: 1
+        )
+      ]
+
+      assert compile_and_map(fields, %{"urgent" => true, "priority" => 3})["priority"] == 9
+      assert compile_and_map(fields, %{"prio" => 4})["priority"] == 4
+      assert compile_and_map(fields, %{})["priority"] == 1
+    end
+
+    test "cases can read earlier output fields and share filters" do
+      fields = [
+        Field.string("level", path: "$.level"),
+        Field.string("trace_id",
+          cases: [
+            %FieldCase{
+              paths: ["$.short_id", "$.trace_id"],
+              all: [%InferCondition{path: "$.level", predicate: "exists"}]
+            }
+          ],
+          filters: %{len_eq: 4}
+        ),
+        Field.string("severity",
+          cases: [
+            %FieldCase{
+              from_output: "level",
+              all: [%InferCondition{path: "$.level", predicate: "not_empty"}]
+            }
+          ],
+          default: "INFO"
+        )
+      ]
+
+      result =
+        compile_and_map(fields, %{"level" => "WARN", "short_id" => "ab", "trace_id" => "abcd"})
+
+      assert result["trace_id"] == "abcd"
+      assert result["severity"] == "WARN"
+    end
+
+    test "explain reports the matching case" do
+      compiled =
+        [Field.string("event_message", cases: [@pino_case], path: "$.message")]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+
+      assert {:ok, [%{case: 1, source: :path, value: "b"}]} =
+               Mapper.explain(%{"logger" => "bunyan", "message" => "b"}, compiled)
+    end
+
+    test "cases without a source are rejected at compile time" do
+      assert {:error, reason} =
+               [Field.string("a", cases: [%FieldCase{any: []}])]
+               |> MappingConfig.new()
+               |> Mapper.compile()
+
+      assert reason =~ "case requires"
+    end
+  end
+
+  # ── Drop and route ──────────────────────────────────────────────────────
+
+  describe "drop and route" do
+    alias Logflare.Mapper.MappingConfig.OutputFormat
+    alias Logflare.Mapper.MappingConfig.Route
+    alias Logflare.Mapper.MappingConfig.Sample
+
+    @routed_fields [Field.string("path", path: "$.path"), Field.string("level", path: "$.level")]
+
+    defp compile_routed(opts) do
+      opts =
+        Keyword.merge(
+          [
+            drop_when: %InferCondition{
+              path: "$.path",
+              predicate: "in",
+              comparison_values: ["/health", "/ready"]
+            },
+            route: [
+              %Route{
+                name: "debug",
+                any: [
+                  %InferCondition{path: "$.level", predicate: "equals", comparison_value: "debug"}
+                ]
+              }
+            ]
+          ],
+          opts
+        )
+
+      @routed_fields
+      |> MappingConfig.new(opts)
+      |> Mapper.compile!()
+    end
+
+    test "map_routed drops, routes or passes documents through" do
+      compiled = compile_routed([])
+
+      assert Mapper.map_routed(%{"path" => "/health"}, compiled) == :drop
+
+      assert Mapper.map_routed(%{"path" => "/a", "level" => "debug"}, compiled) ==
+               {"debug", %{"path" => "/a", "level" => "debug"}}
+
+      assert Mapper.map_routed(%{"path" => "/a", "level" => "info"}, compiled) ==
+               {nil, %{"path" => "/a", "level" => "info"}}
+    end
+
+    test "map ignores drop_when and route" do
+      compiled = compile_routed([])
+
+      assert Mapper.map(%{"path" => "/health"}, compiled) ==
+               %{"path" => "/health", "level" => nil}
+    end
+
+    test "map_many_routed keeps input order for map output" do
+      compiled = compile_routed([])
+      documents = [
+        %{"path" => "/ready"},
+        %{"path" => "/b", "level" => "debug"},
+        %{"path" => "/c"}
+      ]
+
+      assert [:drop, {"debug", %{"path" => "/b"}}, {nil, %{"path" => "/c"}}] =
+               Mapper.map_many_routed(documents, compiled)
+    end
+
+    test "map_many_routed groups serialized output by route" do
+      compiled = compile_routed(output: OutputFormat.json([]))
+
+      documents = [
+        %{"path" => "/a", "level" => "debug"},
+        %{"path" => "/health"},
+        %{"path" => "/b", "level" => "info"},
+        %{"path" => "/c", "level" => "debug"}
+      ]
+
+      assert [{"debug", debug}, {nil, rest}] = Mapper.map_many_routed(documents, compiled)
+      assert debug == Mapper.map_many([Enum.at(documents, 0), Enum.at(documents, 3)], compiled)
+      assert rest == Mapper.map_many([Enum.at(documents, 2)], compiled)
+      assert Mapper.map_many_routed([%{"path" => "/health"}], compiled) == []
+    end
+
+    test "a route without conditions catches every remaining document" do
+      compiled = compile_routed(route: [%Route{name: "all"}])
+
+      assert {"all", _} = Mapper.map_routed(%{"path" => "/a"}, compiled)
+    end
+
+    test "sample keeps whole traces and honours severity overrides" do
+      sample = %Sample{
+        path: "$.trace_id",
+        rate: 0.5,
+        severity_path: "$.level",
+        severity_rates: %{"error" => 1.0}
+      }
+
+      compiled = compile_routed(sample: sample, route: [])
+      traces = Enum.map(1..200, &"trace-#{&1}")
+
+      kept =
+        Enum.filter(traces, fn trace ->
+          info = Mapper.map_routed(%{"trace_id" => trace, "level" => "info"}, compiled)
+          debug = Mapper.map_routed(%{"trace_id" => trace, "level" => "debug"}, compiled)
+
+          # Every document of a trace gets the same decision.
+          assert (info == :drop) == (debug == :drop)
+          info != :drop
+        end)
+
+      assert length(kept) in 70..130
+
+      for trace <- traces do
+        assert {nil, _} = Mapper.map_routed(%{"trace_id" => trace, "level" => "ERROR"}, compiled)
+      end
+
+      assert {nil, _} = Mapper.map_routed(%{"level" => "info"}, compiled)
+    end
+
+    test "map and map_many apply the sample and drop what it does not keep" do
+      compiled = compile_routed(sample: %Sample{path: "$.trace_id", rate: 0.5}, route: [])
+      documents = Enum.map(1..100, &%{"trace_id" => "trace-#{&1}", "path" => "/a"})
+
+      expected =
+        Enum.map(documents, fn document ->
+          case Mapper.map_routed(document, compiled) do
+            {nil, map} -> map
+            :drop -> :drop
+          end
+        end)
+
+      assert :drop in expected
+      assert Enum.map(documents, &Mapper.map(&1, compiled)) == expected
+      assert Mapper.map_many(documents, compiled) == expected
+
+      jsons = Enum.map(documents, &Jason.encode!/1)
+      assert Mapper.map_many_json(jsons, compiled) == expected
+    end
+
+    test "map_many spends no mapping work on documents the sample drops" do
+      compiled =
+        @routed_fields
+        |> MappingConfig.new(
+          sample: %Sample{path: "$.trace_id", rate: 0.5},
+          collect_stats: true
+        )
+        |> Mapper.compile!()
+
+      documents = Enum.map(1..100, &%{"trace_id" => "trace-#{&1}", "level" => "info"})
+      kept = documents |> Mapper.map_many(compiled) |> Enum.count(&(&1 != :drop))
+
+      assert kept in 30..70
+      assert %{documents: ^kept, fields: [%{default_used: ^kept} | _]} =
+               Mapper.mapping_stats(compiled)
+    end
+
+    test "sample rate must be between 0 and 1" do
+      assert {:error, reason} =
+               @routed_fields
+               |> MappingConfig.new(sample: %Sample{path: "$.trace_id", rate: 2})
+               |> Mapper.compile()
+
+      assert reason =~ "between 0 and 1"
+    end
+
+    test "route requires a name" do
+      assert {:error, reason} =
+               @routed_fields
+               |> MappingConfig.new(route: [%Route{}])
+               |> Mapper.compile()
+
+      assert reason =~ "route missing 'name'"
+    end
+  end
+
+  # ── Redaction ───────────────────────────────────────────────────────────
+
+  describe "redaction" do
+    alias Logflare.Mapper.MappingConfig.RedactRule
+
+    test "masks string fields with built-in detectors" do
+      rules = [%RedactRule{detector: "email"}, %RedactRule{detector: "bearer_token"}]
+
+      result =
+        compile_and_map(
+          [Field.string("msg", path: "$.msg"), Field.string("auth", path: "$.auth")],
+          %{"msg" => "login by jane@example.com", "auth" => "Bearer abc.def"},
+          redact: rules
+        )
+
+      assert result["msg"] == "login by [REDACTED]"
+      assert result["auth"] == "Bearer [REDACTED]"
+    end
+
+    test "redacts recursively inside json and flat_map values" do
+      rules = [%RedactRule{detector: "ipv4", replacement: "x.x.x.x"}]
+      document = %{"meta" => %{"client" => %{"ip" => "10.1.2.3"}, "tags" => ["10.0.0.1", "ok"]}}
+
+      result =
+        compile_and_map(
+          [Field.json("json", path: "$.meta"), Field.flat_map("flat", path: "$.meta")],
+          document,
+          redact: rules
+        )
+
+      assert result["json"] == %{"client" => %{"ip" => "x.x.x.x"}, "tags" => ["x.x.x.x", "ok"]}
+      assert result["flat"]["client.ip"] == "x.x.x.x"
+    end
+
+    test "redacts flat_map values with flat_keys input" do
+      result =
+        compile_and_map(
+          [Field.flat_map("attributes", path: "$")],
+          %{"user.email" => "a@example.com", "user.id" => "42"},
+          [redact: [%RedactRule{detector: "email", action: "drop"}]],
+          flat_keys: true
+        )
+
+      assert result["attributes"] == %{"user.id" => "42"}
+    end
+
+    test "drop falls back to the field default" do
+      result =
+        compile_and_map(
+          [Field.string("card", path: "$.card", default: "none")],
+          %{"card" => "4111-1111-1111-1111"},
+          redact: [%RedactRule{detector: "card_number", action: "drop"}]
+        )
+
+      assert result["card"] == "none"
+    end
+
+    test "hash replaces matches with a digest keyed by hash_key" do
+      rules = [%RedactRule{pattern: "user=(?P<secret>\\w+)", action: "hash"}]
+      fields = [Field.string("a", path: "$.a"), Field.string("b", path: "$.b")]
+      document = %{"a" => "user=alice", "b" => "user=alice!"}
+
+      result = compile_and_map(fields, document, redact: rules, hash_key: "secret")
+      assert "user=" <> digest = result["a"]
+      assert String.length(digest) == 16
+      assert result["b"] == "user=" <> digest <> "!"
+      rekeyed = compile_and_map(fields, document, redact: rules, hash_key: "other")
+      refute rekeyed["a"] == result["a"]
+
+      assert {:error, reason} =
+               fields |> MappingConfig.new(redact: rules) |> Mapper.compile()
+
+      assert reason =~ "requires a mapping 'hash_key'"
+    end
+
+    test "invalid custom pattern fails compilation" do
+      assert {:error, reason} =
+               [Field.string("a", path: "$.a")]
+               |> MappingConfig.new(redact: [%RedactRule{pattern: "("}])
+               |> Mapper.compile()
+
+      assert reason =~ "invalid redaction pattern"
+    end
+  end
+
+  # ── Keyed hashing ─────────────────────────────────────────────────────
+
+  describe "keyed hashing" do
+    @hash_key [hash_key: "s3cret"]
+
+    test "string fields get stable hex pseudonyms per algorithm" do
+      fields = [
+        Field.string("sip", path: "$.user", transform: "hash"),
+        Field.string("xx", path: "$.user", transform: "hash", hash_algorithm: "xxhash"),
+        Field.string("sha", path: "$.user", transform: "hash", hash_algorithm: "sha256"),
+        Field.string("id", path: "$.id", transform: "hash")
+      ]
+
+      document = %{"user" => "jane@example.com", "id" => 42}
+      result = compile_and_map(fields, document, @hash_key)
+
+      assert result["sip"] =~ ~r/\A[0-9a-f]{16}\z/
+      assert result["xx"] =~ ~r/\A[0-9a-f]{16}\z/
+      assert result["sha"] =~ ~r/\A[0-9a-f]{64}\z/
+      assert result == compile_and_map(fields, document, @hash_key)
+      assert result["id"] == compile_and_map(fields, %{"id" => "42"}, @hash_key)["id"]
+      refute result["sip"] == compile_and_map(fields, document, hash_key: "other")["sip"]
+    end
+
+    test "uint64 fields store the digest as an integer" do
+      fields = [
+        Field.uint64("user_key", path: "$.user", transform: "hash"),
+        Field.string("user_hex", path: "$.user", transform: "hash")
+      ]
+
+      result = compile_and_map(fields, %{"user" => "u-1"}, @hash_key)
+
+      assert is_integer(result["user_key"])
+      assert result["user_hex"] == result["user_key"] |> Integer.to_string(16) |> pad_hex()
+    end
+
+    test "missing sources keep the default instead of a hash" do
+      fields = [
+        Field.string("user", path: "$.user", transform: "hash"),
+        Field.uint64("user_key", path: "$.user", transform: "hash"),
+        Field.string("trace_id", path: "$.trace_id", transform: "hash", default: "none")
+      ]
+
+      assert compile_and_map(fields, %{}, @hash_key) == %{
+               "user" => "",
+               "user_key" => 0,
+               "trace_id" => "none"
+             }
+    end
+
+    test "flat_map hash_keys hash matching keys only" do
+      field = Field.flat_map("attrs", path: "$", hash_keys: ["user.*", "*.email"])
+      document = %{"user" => %{"id" => 7}, "billing" => %{"email" => "a@b.co"}, "plan" => "pro"}
+
+      result = compile_and_map([field], document, @hash_key)["attrs"]
+
+      assert result["plan"] == "pro"
+      assert result["user.id"] =~ ~r/\A[0-9a-f]{16}\z/
+      assert result["billing.email"] =~ ~r/\A[0-9a-f]{16}\z/
+    end
+
+    test "hashing without a hash_key fails compilation" do
+      assert {:error, reason} =
+               [Field.string("user", path: "$.user", transform: "hash")]
+               |> MappingConfig.new()
+               |> Mapper.compile()
+
+      assert reason =~ "requires a mapping 'hash_key'"
+    end
+
+    defp pad_hex(hex), do: hex |> String.downcase() |> String.pad_leading(16, "0")
+  end
+
+  # ── Size limits ───────────────────────────────────────────────────────
+
+  describe "size limits" do
+    test "truncates strings on a character boundary" do
+      field = Field.string("msg", path: "$.msg", limits: %{max_string_bytes: 4})
+
+      assert compile_and_map([field], %{"msg" => "abcdef"})["msg"] == "abcd"
+      assert compile_and_map([field], %{"msg" => "abcé"})["msg"] == "abc"
+      assert compile_and_map([field], %{"msg" => "ab"})["msg"] == "ab"
+    end
+
+    test "truncates json entries, depth and arrays and marks the value" do
+      limits = %{max_map_entries: 2, max_depth: 2, max_array_length: 1}
+      field = Field.json("attrs", path: "$.attrs", limits: limits)
+      document = %{"attrs" => %{"a" => [1, 2, 3], "b" => %{"c" => %{"d" => 1}}, "z" => 1}}
+
+      assert compile_and_map([field], document)["attrs"] == %{
+               "a" => [1],
+               "b" => %{},
+               "_truncated" => true
+             }
+
+      assert compile_and_map([field], %{"attrs" => %{"a" => 1}})["attrs"] == %{"a" => 1}
+    end
+
+    test "drops flat_map keys past max_depth and marks the map" do
+      field = Field.flat_map("attrs", path: "$", limits: %{max_depth: 2, max_string_bytes: 3})
+      document = %{"a" => %{"b" => "long value", "c" => %{"d" => "x"}}}
+
+      assert compile_and_map([field], document)["attrs"] ==
+               %{"a.b" => "lon", "_truncated" => "true"}
+    end
+
+    test "caps flat_map entries while flattening, after key rules when set" do
+      document = %{"a" => %{"x" => 1, "y" => 2}, "b" => 3, "c" => %{"z" => nil}}
+      field = Field.flat_map("attrs", path: "$", limits: %{max_map_entries: 2})
+
+      assert compile_and_map([field], document)["attrs"] ==
+               %{"a.x" => "1", "a.y" => "2", "_truncated" => "true"}
+
+      limits = %{max_map_entries: 2}
+      field = Field.flat_map("attrs", path: "$", include_keys: ["b", "a.y"], limits: limits)
+
+      assert compile_and_map([field], document)["attrs"] == %{"a.y" => "2", "b" => "3"}
+    end
+
+    test "max_row_bytes empties the largest values first" do
+      fields = [
+        Field.string("small", path: "$.small"),
+        Field.json("big", path: "$.big"),
+        Field.uint64("n", path: "$.n")
+      ]
+
+      document = %{"small" => "ok", "big" => %{"blob" => String.duplicate("x", 500)}, "n" => 1}
+      result = compile_and_map(fields, document, max_row_bytes: 100)
+
+      assert result == %{"small" => "ok", "big" => %{"_truncated" => true}, "n" => 1}
+      assert compile_and_map(fields, document, max_row_bytes: 1_000)["big"] == document["big"]
+    end
+  end
+
+  # ── Key modes ─────────────────────────────────────────────────────────
+
+  describe "key modes" do
+    @atom_document %{
+      message: "hello",
+      metadata: %{level: "info", user: %{id: 7}},
+      id: "evt-1"
+    }
+
+    test "binary mode ignores atom keys" do
+      fields = [Field.string("message", path: "$.message", default: "")]
+
+      assert compile_and_map(fields, @atom_document, key_mode: :binary) == %{"message" => ""}
+    end
+
+    test "atom mode resolves nested paths over atom keys" do
+      fields = [
+        Field.string("message", path: "$.message"),
+        Field.string("level", path: "$.metadata.level"),
+        Field.uint64("user_id", path: "$.metadata.user.id")
+      ]
+
+      assert compile_and_map(fields, @atom_document, key_mode: :atom) ==
+               %{"message" => "hello", "level" => "info", "user_id" => 7}
+    end
+
+    test "either mode reads mixed keys and prefers the string key" do
+      fields = [
+        Field.string("message", path: "$.message"),
+        Field.string("level", path: "$.metadata.level")
+      ]
+
+      document = %{
+        "message" => "string wins",
+        :message => "atom",
+        :metadata => %{"level" => "warn"}
+      }
+
+      assert compile_and_map(fields, document, key_mode: :either) ==
+               %{"message" => "string wins", "level" => "warn"}
+    end
+
+    test "exclude_keys, elevate_keys and flattening work over atom keys" do
+      fields = [
+        Field.flat_map("attrs", path: "$", exclude_keys: ["id"], elevate_keys: ["metadata"]),
+        Field.json("json", path: "$", exclude_keys: ["message"])
+      ]
+
+      result = compile_and_map(fields, @atom_document, key_mode: :atom)
+
+      assert result["attrs"] == %{"message" => "hello", "level" => "info", "user.id" => "7"}
+      assert result["json"] == %{metadata: %{level: "info", user: %{id: 7}}, id: "evt-1"}
+    end
+
+    test "atom keys are kept when Json fields and FlatMap lists are serialized" do
+      fields = [
+        Field.json("metadata", path: "$.metadata"),
+        Field.flat_map("attrs", path: "$.attrs")
+      ]
+      output = Logflare.Mapper.MappingConfig.OutputFormat.json([])
+
+      document = %{
+        metadata: %{level: "info", user: %{id: 7}},
+        attrs: %{tags: [%{:name => "atom", "name" => "string", :kind => "k"}]}
+      }
+
+      tags = ~s([{"kind":"k","name":"string"}])
+
+      assert compile_and_map(fields, document, key_mode: :atom)["attrs"] == %{"tags" => tags}
+
+      assert fields
+             |> compile_and_map(document, key_mode: :atom, output: output)
+             |> Jason.decode!() == %{
+               "metadata" => %{"level" => "info", "user" => %{"id" => 7}},
+               "attrs" => %{"tags" => tags}
+             }
+    end
+  end
+
+  # ── JSON input ────────────────────────────────────────────────────────
+
+  describe "map_json" do
+    @json_input_fields [
+      Field.string("message", path: "$.message"),
+      Field.string("level", paths: ["$.metadata.level", "$.level"], default: "info"),
+      Field.array_string("names", path: "$.events[*].name"),
+      Field.uint64("second", path: "$.counts[1]"),
+      Field.flat_map("labels", path: "$.labels")
+    ]
+
+    test "matches mapping the decoded document" do
+      compiled = @json_input_fields |> MappingConfig.new() |> Mapper.compile!()
+
+      document = %{
+        "message" => "caf\u00e9 \"quoted\"",
+        "metadata" => %{"level" => "warn", "unread" => %{"deep" => [1, 2, 3]}},
+        "events" => [%{"name" => "a", "skip" => 1}, %{"name" => "b"}],
+        "counts" => [1, 2, 3],
+        "labels" => %{"env" => "prod", "nested" => %{"zone" => "b"}, "n" => 1.5},
+        "ignored" => %{"big" => String.duplicate("x", 100)}
+      }
+
+      json = Jason.encode!(document)
+
+      assert Mapper.map_json(json, compiled) == Mapper.map(document, compiled)
+      assert Mapper.map_many_json([json, json], compiled) ==
+               Mapper.map_many([document, document], compiled)
+    end
+
+    test "supports flat_keys input" do
+      compiled = @json_input_fields |> MappingConfig.new() |> Mapper.compile!()
+      json = ~s({"message": "hi", "metadata.level": "error", "metadata": {"level": "x"}})
+
+      assert %{"message" => "hi", "level" => "error"} =
+               Mapper.map_json(json, compiled, flat_keys: true)
+    end
+
+    test "whole-document fields keep every key" do
+      compiled =
+        [Field.json("body", path: "$", exclude_keys: ["id"])]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+
+      json = ~s({"id": 1, "a": {"b": [true, null]}, "c": "d"})
+
+      assert Mapper.map_json(json, compiled) ==
+               %{"body" => %{"a" => %{"b" => [true, nil]}, "c" => "d"}}
+    end
+
+    test "evaluates picks, conditions, aggregates and the sample over the JSON" do
+      compiled =
+        [
+          Field.flat_map("resource", path: "$.resource", pick: [{"host", ["$.meta.host"]}]),
+          Field.uint32("retries", path: "$.events[*].name", aggregate: "distinct_count"),
+          Field.bool("has_trace", path: "$.trace_id"),
+          Field.string("kind", path: "$.kind", default: "none"),
+          Field.uint64("big", path: "$.big")
+        ]
+        |> MappingConfig.new(
+          sample: %Logflare.Mapper.MappingConfig.Sample{path: "$.trace_id", rate: 0.5}
+        )
+        |> Mapper.compile!()
+
+      jsons =
+        for index <- 1..20 do
+          ~s({"trace_id": "t-#{index}", "meta": {"host": "h-#{index}"}, "kind": "a",
+              "events": [{"name": "x"}, {"name": "y"}], "kind": "b", "big": 18446744073709551615})
+        end
+
+      assert Enum.map(jsons, &Mapper.map_json(&1, compiled)) ==
+               Enum.map(jsons, &Mapper.map(Jason.decode!(&1), compiled))
+
+      assert Mapper.map_many_json(jsons, compiled) ==
+               jsons |> Enum.map(&Jason.decode!/1) |> Mapper.map_many(compiled)
+
+      assert %{"kind" => "b", "retries" => 2, "resource" => %{"host" => "h-" <> _}} =
+               jsons |> Mapper.map_many_json(compiled) |> Enum.find(&(&1 != :drop))
+    end
+
+    test "returns errors for invalid JSON and non-object documents" do
+      compiled = @json_input_fields |> MappingConfig.new() |> Mapper.compile!()
+
+      assert {:error, "invalid JSON" <> _} = Mapper.map_json_result(~s({"message": ), compiled)
+      assert {:error, "invalid JSON" <> _} = Mapper.map_json_result(~s({} trailing), compiled)
+
+      assert {:error, "JSON document must be an object"} =
+               Mapper.map_json_result(~s([1, 2]), compiled)
+
+      assert_raise ArgumentError, fn -> Mapper.map_many_json(["{}", "nope"], compiled) end
+    end
+
+    test "refuses atom-keyed mappings" do
+      compiled = @json_input_fields |> MappingConfig.new(key_mode: :atom) |> Mapper.compile!()
+      error = {:error, "map_json requires key_mode binary or either"}
+
+      assert Mapper.map_json_result(~s({"message": "hi"}), compiled) == error
+      assert_raise ArgumentError, ~r/key_mode binary or either/, fn ->
+        Mapper.map_many_json([~s({"message": "hi"})], compiled)
+      end
+    end
+  end
+
+  # ── Export and import ─────────────────────────────────────────────────
+
+  describe "export and import" do
+    @export_fields [
+      Field.string("message", path: "$.message", transform: "upcase"),
+      Field.uint8("level", path: "$.level", value_map: %{"info" => 9, "error" => 17}),
+      Field.flat_map("attrs", path: "$.attributes", exclude_keys: ["secret"])
+    ]
+
+    test "an imported mapping maps like the original" do
+      compiled = @export_fields |> MappingConfig.new() |> Mapper.compile!()
+      exported = Mapper.export_mapping(compiled)
+
+      assert {:ok, imported} = Mapper.import_mapping(exported)
+
+      document = %{"message" => "hi", "level" => "error", "attributes" => %{"a" => 1}}
+      assert Mapper.map(document, imported) == Mapper.map(document, compiled)
+      assert Mapper.export_mapping(imported) == exported
+      assert Mapper.fingerprint(imported) == Mapper.fingerprint(compiled)
+    end
+
+    test "fingerprints identify equal configs" do
+      fingerprint = fn config ->
+        config |> Mapper.compile!() |> Mapper.fingerprint()
+      end
+
+      config = MappingConfig.new(@export_fields)
+      id = fingerprint.(config)
+
+      assert id =~ ~r/^[0-9a-f]{8}-[0-9a-f]{4}-8[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$/
+      assert fingerprint.(MappingConfig.new(@export_fields)) == id
+      refute fingerprint.(MappingConfig.new(Enum.take(@export_fields, 2))) == id
+      refute fingerprint.(MappingConfig.new(@export_fields, max_row_bytes: 1_000)) == id
+    end
+
+    test "fingerprints ignore how equal settings are spelled" do
+      fingerprint = fn fields ->
+        fields |> MappingConfig.new() |> Mapper.compile!() |> Mapper.fingerprint()
+      end
+
+      id = fingerprint.([Field.string("a", path: "$.a"), Field.datetime64("ts", path: "$.ts")])
+
+      assert fingerprint.([
+               %{Field.string("a", paths: ["$.a"], default: "") | type: "String"},
+               %{Field.datetime64("ts", path: "$.ts") | precision: nil}
+             ]) == id
+
+      other_path = [Field.string("a", path: "$.b"), Field.datetime64("ts", path: "$.ts")]
+      refute fingerprint.(other_path) == id
+    end
+
+    test "exports replace the hash_key with a key ID" do
+      fields = [Field.string("user", path: "$.user", transform: "hash")]
+      compiled = fields |> MappingConfig.new(hash_key: "s3cret") |> Mapper.compile!()
+      exported = Mapper.export_mapping(compiled)
+
+      refute exported =~ "s3cret"
+
+      assert {:error, "exported mapping requires the hash_key" <> _} =
+               Mapper.import_mapping(exported)
+
+      assert {:error, "hash_key does not match" <> _} =
+               Mapper.import_mapping(exported, hash_key: "other")
+
+      assert {:ok, imported} = Mapper.import_mapping(exported, hash_key: "s3cret")
+      assert Mapper.map(%{"user" => "u-1"}, imported) == Mapper.map(%{"user" => "u-1"}, compiled)
+      assert Mapper.export_mapping(imported) == exported
+      assert Mapper.fingerprint(imported) == Mapper.fingerprint(compiled)
+
+      rekeyed = fields |> MappingConfig.new(hash_key: "other") |> Mapper.compile!()
+      refute Mapper.fingerprint(rekeyed) == Mapper.fingerprint(compiled)
+    end
+
+    test "rejects malformed exports" do
+      compiled = @export_fields |> MappingConfig.new() |> Mapper.compile!()
+      exported = Mapper.export_mapping(compiled)
+      <<magic::binary-size(4), _version, payload::binary>> = exported
+
+      assert {:error, "not an exported mapping"} = Mapper.import_mapping("nope")
+
+      assert {:error, "unsupported exported mapping version" <> _} =
+               Mapper.import_mapping(<<magic::binary, 99, payload::binary>>)
+
+      assert {:error, "exported mapping is truncated"} =
+               Mapper.import_mapping(binary_part(exported, 0, byte_size(exported) - 1))
+    end
+  end
+
+  # ── Aggregates ────────────────────────────────────────────────────────
+
+  describe "aggregate sources" do
+    @aggregate_document %{
+      "events" => [%{"name" => "start"}, %{"name" => "retry"}, %{"name" => "retry"}, nil],
+      "chunks" => [%{"bytes" => 100}, %{"bytes" => "250"}, %{"bytes" => "n/a"}],
+      "retries" => [
+        %{"latency_ms" => 12.5, "ok" => false},
+        %{"latency_ms" => 40, "ok" => true}
+      ]
+    }
+
+    test "summarises the values under a wildcard path" do
+      compiled =
+        [
+          Field.uint32("event_count", path: "$.events[*]", aggregate: "count"),
+          Field.uint32("event_names", path: "$.events[*].name", aggregate: "distinct_count"),
+          Field.uint64("total_bytes", path: "$.chunks[*].bytes", aggregate: "sum"),
+          Field.float64("min_latency", path: "$.retries[*].latency_ms", aggregate: "min"),
+          Field.float64("max_latency", path: "$.retries[*].latency_ms", aggregate: "max"),
+          Field.float64("avg_latency", path: "$.retries[*].latency_ms", aggregate: "avg"),
+          Field.bool("any_ok", path: "$.retries[*].ok", aggregate: "any"),
+          Field.bool("all_ok", path: "$.retries[*].ok", aggregate: "all")
+        ]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+
+      assert Mapper.map(@aggregate_document, compiled) == %{
+               "event_count" => 3,
+               "event_names" => 2,
+               "total_bytes" => 350,
+               "min_latency" => 12.5,
+               "max_latency" => 40.0,
+               "avg_latency" => 26.25,
+               "any_ok" => true,
+               "all_ok" => false
+             }
+    end
+
+    test "missing arrays aggregate no values" do
+      compiled =
+        [
+          Field.uint32("event_count", path: "$.events[*]", aggregate: "count"),
+          Field.uint64("total_bytes", path: "$.chunks[*].bytes", aggregate: "sum"),
+          Field.float64("max_latency",
+            path: "$.retries[*].latency_ms",
+            aggregate: "max",
+            default: -1
+          ),
+          Field.bool("all_ok", path: "$.retries[*].ok", aggregate: "all")
+        ]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+
+      assert Mapper.map(%{"events" => "none"}, compiled) == %{
+               "event_count" => 0,
+               "total_bytes" => 0,
+               "max_latency" => -1.0,
+               "all_ok" => true
+             }
+    end
+
+    test "aggregates within a case" do
+      alias Logflare.Mapper.MappingConfig.FieldCase
+
+      compiled =
+        [
+          Field.uint32("attempts",
+            cases: [
+              %FieldCase{
+                path: "$.retries[*]",
+                aggregate: "count",
+                all: [%InferCondition{path: "$.retries", predicate: "is_list"}]
+              }
+            ],
+            default: 1
+          )
+        ]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+
+      assert Mapper.map(@aggregate_document, compiled) == %{"attempts" => 2}
+      assert Mapper.map(%{}, compiled) == %{"attempts" => 1}
+    end
+
+    test "rejects aggregates without a wildcard or on non-numeric fields" do
+      no_wildcard = MappingConfig.new([Field.uint32("n", path: "$.events", aggregate: "count")])
+      assert {:error, reason} = Mapper.compile(no_wildcard)
+      assert reason =~ "must contain a [*] wildcard"
+
+      string = MappingConfig.new([Field.string("n", path: "$.events[*]", aggregate: "count")])
+      assert {:error, reason} = Mapper.compile(string)
+      assert reason =~ "requires a numeric or bool field"
+    end
+  end
+
+  # ── Mapping stats ─────────────────────────────────────────────────────
+
+  describe "mapping_stats" do
+    @stats_fields [
+      Field.string("trace_id", path: "$.trace_id", filters: %{len_eq: 4}),
+      Field.uint8("severity", path: "$.level", value_map: %{"info" => 9}),
+      Field.uint32("count", path: "$.count"),
+      Field.enum8("kind",
+        path: "$.kind",
+        values: %{"gauge" => 1, "sum" => 2},
+        infer: [
+          %InferRule{
+            result: "sum",
+            any: [%InferCondition{path: "$.is_monotonic", predicate: "exists"}],
+            all: []
+          }
+        ],
+        default: 1
+      )
+    ]
+
+    test "counts per-field fallbacks across calls and resets on drain" do
+      compiled = @stats_fields |> MappingConfig.new(collect_stats: true) |> Mapper.compile!()
+
+      Mapper.map(
+        %{"trace_id" => "abcd", "level" => "info", "count" => 3, "kind" => "gauge"},
+        compiled
+      )
+
+      Mapper.map_many(
+        [
+          %{"trace_id" => "toolong", "level" => "debug", "count" => "many"},
+          %{"level" => "info", "count" => "12", "is_monotonic" => true}
+        ],
+        compiled
+      )
+
+      assert %{documents: 3, fields: [trace_id, severity, count, kind]} =
+               Mapper.mapping_stats(compiled)
+
+      assert %{field: "trace_id", filter_rejected: 1, default_used: 2} = trace_id
+      assert %{field: "severity", value_map_miss: 1, default_used: 1} = severity
+      assert %{field: "count", coercion_failed: 1, default_used: 0} = count
+      assert %{field: "kind", enum8_unmatched: 1, default_used: 1} = kind
+
+      assert %{documents: 0, fields: [%{default_used: 0} | _]} = Mapper.mapping_stats(compiled)
+    end
+
+    test "counts clamped numbers as coercion failures" do
+      compiled = @stats_fields |> MappingConfig.new(collect_stats: true) |> Mapper.compile!()
+      documents = [%{"count" => -1}, %{"count" => 5_000_000_000}, %{"count" => 7}]
+
+      assert [%{"count" => 0}, %{"count" => 4_294_967_295}, %{"count" => 7}] =
+               Mapper.map_many(documents, compiled)
+
+      assert %{fields: [_, _, %{field: "count", coercion_failed: 2}, _]} =
+               Mapper.mapping_stats(compiled)
+    end
+
+    test "is nil unless the config collects stats" do
+      compiled = @stats_fields |> MappingConfig.new() |> Mapper.compile!()
+      Mapper.map(%{"level" => "info"}, compiled)
+
+      assert Mapper.mapping_stats(compiled) == nil
+    end
+
+    test "emit_stats/2 sends the drained counts as telemetry" do
+      compiled = @stats_fields |> MappingConfig.new(collect_stats: true) |> Mapper.compile!()
+      Mapper.map(%{"level" => "debug"}, compiled)
+
+      ref = :telemetry_test.attach_event_handlers(self(), [[:logflare, :mapper, :field_stats]])
+      :ok = Mapper.emit_stats(compiled, %{source: "test"})
+
+      assert_received {[:logflare, :mapper, :field_stats], ^ref, %{value_map_miss: 1},
+                       %{field: "severity", source: "test"}}
+    end
+  end
+
+  # ── Diff mappings ─────────────────────────────────────────────────────
+
+  describe "diff_mappings" do
+    @old_fields [
+      Field.string("message", path: "$.message"),
+      Field.string("level", path: "$.level", default: "info"),
+      Field.uint64("user_id", path: "$.user.id"),
+      Field.datetime64("ts", path: "$.ts", precision: 3)
+    ]
+
+    @new_fields [
+      Field.string("message", paths: ["$.message", "$.msg"]),
+      Field.string("level", path: "$.level", default: "INFO"),
+      Field.string("user_id", path: "$.user.id"),
+      Field.datetime64("ts", path: "$.ts", precision: 6),
+      Field.string("host", path: "$.host")
+    ]
+
+    test "reports field changes between configs" do
+      old = MappingConfig.new(@old_fields)
+      new = MappingConfig.new(@new_fields)
+
+      assert {:ok, diff} = Mapper.diff_mappings(old, new)
+
+      assert diff.added == [%{field: "host", type: "string"}]
+      assert diff.removed == []
+
+      assert diff.retyped == [
+               %{field: "user_id", old: "uint64", new: "string"},
+               %{field: "ts", old: "datetime64(3)", new: "datetime64(6)"}
+             ]
+
+      assert diff.source_changed == [
+               %{
+                 field: "message",
+                 old: %{"path" => "$.message"},
+                 new: %{"paths" => ["$.message", "$.msg"]}
+               }
+             ]
+
+      assert diff.default_changed == [%{field: "level", old: "info", new: "INFO"}]
+      refute Map.has_key?(diff, :values)
+    end
+
+    test "reports value differences on sample documents" do
+      documents = [
+        %{"message" => "a", "level" => "warn", "user" => %{"id" => 7}},
+        %{"msg" => "b", "level" => "warn", "user" => %{"id" => 8}},
+        %{"message" => "c"}
+      ]
+
+      assert {:ok, %{documents: 3, values: values}} =
+               Mapper.diff_mappings(
+                 MappingConfig.new(@old_fields),
+                 MappingConfig.new(@new_fields),
+                 documents: documents
+               )
+
+      by_field = Map.new(values, &{&1.field, &1})
+
+      assert %{changed: 1, samples: [%{index: 1, old: "", new: "b"}]} = by_field["message"]
+      assert %{changed: 1, samples: [%{index: 2, old: "info", new: "INFO"}]} = by_field["level"]
+      assert %{changed: 3, samples: [%{old: 7, new: "7"}, %{old: 8, new: "8"}, missing]} =
+               by_field["user_id"]
+
+      assert missing == %{index: 2, old: 0, new: ""}
+
+      refute Map.has_key?(by_field, "ts")
+    end
+
+    test "returns errors for configs that do not compile" do
+      invalid = MappingConfig.new([Field.string("a"), Field.string("a")])
+
+      assert {:error, "new mapping: duplicate field name: 'a'"} =
+               Mapper.diff_mappings(MappingConfig.new(@old_fields), invalid)
+    end
+  end
+
+  # ── Lint mapping ──────────────────────────────────────────────────────
+
+  describe "lint_mapping" do
+    test "a sound config has no warnings" do
+      config = MappingConfig.new([Field.string("id", paths: ["$.id", "$.trace_id"])])
+
+      assert {:ok, []} = Mapper.lint_mapping(config)
+    end
+
+    test "warns about settings that cannot take effect" do
+      alias Logflare.Mapper.MappingConfig.PickEntry
+
+      fields = [
+        Field.string("level",
+          path: "$.level",
+          allowed_values: ["info", "warn"],
+          value_map: %{"info" => "I", "fatal" => "F"}
+        ),
+        Field.string("id", paths: ["$.id", "$.id", "$", "$.trace_id"]),
+        %{Field.uint8("status", path: "$.status") | filters: %{len_eq: 3}},
+        %{Field.string("user", path: "$.user") | pick: [%PickEntry{key: "id", paths: ["$.id"]}]},
+        Field.enum8("kind",
+          path: "$.kind",
+          values: %{"gauge" => 1, "sum" => 200},
+          infer: [%InferRule{result: "histogram", any: [], all: []}]
+        ),
+        Field.datetime64("ts", path: "$.ts", precision: 12)
+      ]
+
+      assert {:ok, warnings} = fields |> MappingConfig.new() |> Mapper.lint_mapping()
+
+      assert Enum.map(warnings, &{&1.field, &1.code}) == [
+               {"level", :value_map_not_allowed},
+               {"id", :duplicate_path},
+               {"id", :unreachable_path},
+               {"status", :filters_ignored},
+               {"user", :pick_ignored},
+               {"kind", :unknown_enum_result},
+               {"kind", :enum_value_out_of_range},
+               {"ts", :invalid_precision}
+             ]
+
+      assert hd(warnings).message == "value_map key 'fatal' is not in allowed_values"
+    end
+
+    test "returns compile errors" do
+      config = MappingConfig.new([Field.string("a"), Field.string("a")])
+
+      assert {:error, "duplicate field name: 'a'"} = Mapper.lint_mapping(config)
+    end
+  end
+
+  # ── Infer mapping ─────────────────────────────────────────────────────
+
+  describe "infer_mapping" do
+    @samples [
+      %{
+        "timestamp" => "2026-01-21T17:54:48.144506Z",
+        "level" => "info",
+        "metadata" => %{"user_id" => 42, "region" => nil, "tags" => ["a", "b"]},
+        "attributes" => %{"http" => %{"method" => "GET", "status" => "200"}}
+      },
+      %{
+        "timestamp" => "2026-01-21T17:54:49.000001Z",
+        "level" => "info",
+        "metadata" => %{"user_id" => 7, "created_at" => 1_769_018_088_144},
+        "attributes" => %{"http" => %{"method" => "POST"}}
+      },
+      %{
+        "timestamp" => "2026-01-21T17:54:50.5Z",
+        "level" => "error",
+        "metadata" => %{"user_id" => 9, "region" => "eu"},
+        "attributes" => %{"http" => %{"method" => "GET"}}
+      },
+      %{"timestamp" => "2026-01-21T17:54:51Z", "level" => "error", "metadata" => %{}}
+    ]
+
+    test "proposes fields with inferred types" do
+      assert {:ok, %{config: %{"fields" => fields}}} =
+               Mapper.infer_mapping(@samples, max_depth: 2)
+
+      by_path = Map.new(fields, &{&1["path"], &1})
+
+      assert %{"name" => "timestamp", "type" => "datetime64", "precision" => 6} =
+               by_path["$.timestamp"]
+
+      assert %{"type" => "string", "value_map" => %{"info" => "info", "error" => "error"}} =
+               by_path["$.level"]
+
+      assert %{"name" => "metadata_user_id", "type" => "uint64"} = by_path["$.metadata.user_id"]
+      assert %{"type" => "datetime64", "precision" => 3} = by_path["$.metadata.created_at"]
+      assert %{"type" => "array_string"} = by_path["$.metadata.tags"]
+      assert %{"type" => "flat_map"} = by_path["$.attributes.http"]
+    end
+
+    test "the proposed config compiles and maps the samples" do
+      {:ok, %{config: config}} = Mapper.infer_mapping(@samples)
+
+      assert {:ok, mapping_config} =
+               %MappingConfig{}
+               |> MappingConfig.changeset(config)
+               |> Ecto.Changeset.apply_action(:insert)
+
+      compiled = Mapper.compile!(mapping_config)
+
+      assert %{"level" => "info", "attributes_http_method" => "GET"} =
+               Mapper.map(hd(@samples), compiled)
+    end
+
+    test "reports coverage and null rates per path" do
+      {:ok, %{paths: paths}} = Mapper.infer_mapping(@samples)
+      by_path = Map.new(paths, &{&1.path, &1})
+
+      assert %{count: 4, nulls: 0, coverage: 1.0, distinct: 2} = by_path["$.level"]
+      assert %{count: 2, nulls: 1, coverage: 0.5, null_rate: 0.5} = by_path["$.metadata.region"]
+      assert %{field: "attributes_http_status", count: 1} = by_path["$.attributes.http.status"]
+    end
+
+    test "rejects empty and non-map samples" do
+      assert {:error, "no sample documents"} = Mapper.infer_mapping([])
+      assert {:error, "sample documents must be maps"} = Mapper.infer_mapping([%{}, "nope"])
+    end
+  end
+
+  # ── Inspect mapping ───────────────────────────────────────────────────
+
+  describe "inspect_mapping" do
+    test "describes field types, sources and coalesce order" do
+      %{fields: [id, ts, severity_text, severity_number, body]} =
+        [
+          Field.string("id", paths: ["$.trace_id", "$.traceId"]),
+          Field.datetime64("timestamp", path: "$.events[*].time", precision: 6),
+          Field.string("severity_text", path: "$.level"),
+          Field.uint8("severity_number", from_output: "severity_text"),
+          Field.json("body")
+        ]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+        |> Mapper.inspect_mapping()
+
+      assert %{name: "id", type: "string", source: :coalesce} = id
+      assert Enum.map(id.paths, & &1.path) == ["$.trace_id", "$.traceId"]
+
+      assert %{type: "datetime64", precision: 6, source: :path} = ts
+      assert [%{segments: ["events", :wildcard, "time"], wildcard_index: 1}] = ts.paths
+
+      assert %{source: :path, from_output: []} = severity_text
+
+      assert %{source: :from_output, paths: [], from_output: ["severity_text"]} =
+               severity_number
+
+      assert %{source: :root, paths: []} = body
+    end
+
+    test "paths sharing a prefix share its cache slot" do
+      inspected =
+        [
+          Field.string("service", path: "$.resource.service"),
+          Field.string("host", path: "$.resource.host"),
+          Field.string("message", path: "$.message")
+        ]
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+        |> Mapper.inspect_mapping()
+
+      [service, host, message] = Enum.map(inspected.fields, &hd(&1.paths))
+
+      assert %{cached: true, cache_indices: [index, nil]} = service
+      assert %{cached: true, cache_indices: [^index, nil]} = host
+      assert %{cached: false, cache_indices: [nil]} = message
+      assert inspected.path_cache_size == 1
+      assert inspected.root_cache_keys == []
+    end
+  end
+
+  # ── Explain ─────────────────────────────────────────────────────────────
+
+  describe "explain" do
+    defp explain(fields, document) do
+      {:ok, explained} =
+        fields
+        |> MappingConfig.new()
+        |> Mapper.compile!()
+        |> then(&Mapper.explain(document, &1))
+
+      Map.new(explained, &{&1.field, &1})
+    end
+
+    test "reports the matching coalesce index and skipped candidates" do
+      explained =
+        explain(
+          [Field.string("service_name", paths: ["$.service", "$.svc", "$.app"])],
+          %{"svc" => "", "app" => "api"}
+        )
+
+      assert %{
+               source: {:coalesce, 2},
+               raw: "api",
+               skipped: [{0, :missing, nil}, {1, :empty, ""}],
+               default_used: false,
+               value: "api"
+             } = explained["service_name"]
+    end
+
+    test "reports filter rejections and the default fallback" do
+      explained =
+        explain(
+          [
+            Field.string("trace_id",
+              path: "$.trace_id",
+              filters: %{len_eq: 4},
+              default: "none"
+            )
+          ],
+          %{"trace_id" => "abc"}
+        )
+
+      assert %{
+               source: nil,
+               skipped: [{0, {:filter, :len_eq}, "abc"}],
+               default_used: true,
+               default_reason: :source,
+               value: "none"
+             } = explained["trace_id"]
+    end
+
+    test "reports allowed_values, value_map and from_output decisions" do
+      explained =
+        explain(
+          [
+            Field.string("level", path: "$.level", allowed_values: ~w(INFO), default: "INFO"),
+            Field.uint8("severity", from_output: "level", value_map: %{"INFO" => 9})
+          ],
+          %{"level" => "TRACE"}
+        )
+
+      assert %{allowed_values: :rejected, default_reason: :allowed_values, value: "INFO"} =
+               explained["level"]
+
+      assert %{
+               source: {:from_output, "level"},
+               value_map: :hit,
+               default_used: false,
+               value: 9
+             } = explained["severity"]
+    end
+
+    test "reports the matching Enum8 inference rule" do
+      explained =
+        explain(
+          [
+            Field.enum8("mt",
+              paths: ["$.metric_type"],
+              values: %{"gauge" => 1, "sum" => 2, "histogram" => 3},
+              infer: [
+                %InferRule{
+                  result: "sum",
+                  any: [%InferCondition{path: "$.is_monotonic", predicate: "exists"}],
+                  all: []
+                },
+                %InferRule{
+                  result: "histogram",
+                  any: [%InferCondition{path: "$.bucket_counts", predicate: "not_empty"}],
+                  all: []
+                }
+              ],
+              default: 1
+            )
+          ],
+          %{"bucket_counts" => [1]}
+        )
+
+      assert %{source: nil, enum8: {:infer_rule, 1}, default_used: false, value: 3} =
+               explained["mt"]
+    end
+
+    test "values match map output" do
+      fields = [
+        Field.string("name", paths: ["$.name", "$.user.name"]),
+        Field.uint64("count", path: "$.count", default: 1)
+      ]
+
+      document = %{"user" => %{"name" => "alice"}}
+      compiled = fields |> MappingConfig.new() |> Mapper.compile!()
+      {:ok, explained} = Mapper.explain(document, compiled)
+
+      assert Map.new(explained, &{&1.field, &1.value}) == Mapper.map(document, compiled)
+    end
   end
 end